@@ -4,8 +4,7 @@
 use std::rc::{Rc, Weak};
 use std::cell::{Cell, RefCell};
 use std::fmt;
-use std::collections::HashMap;
-use std::error::Error;
+use std::collections::{HashMap, BTreeMap};
 
 use chrono::NaiveDate;
 use rust_decimal::Decimal;
@@ -13,6 +12,9 @@ use rust_decimal_macros::dec;
 use serde_derive::{Serialize, Deserialize};
 
 use crate::transaction::{Transaction, ActionRecord, Polarity, TxType};
+use crate::decimal_utils::round_d128_generalized;
+use crate::errors::CrptlsError;
+use crate::holding_period::HoldingPeriodRule;
 
 #[derive(Clone, Debug, Eq, Hash, PartialEq, Serialize, Deserialize)]
 pub struct RawAccount {
@@ -20,6 +22,8 @@ pub struct RawAccount {
 	pub name: String,
 	pub ticker: String,
 	pub is_margin: bool,
+	pub margin_pair: Option<MarginPairDeclaration>,	//	None unless declared in the header row (i.e., `margin:pair1:base`)
+	pub precision: u32,	//	Decimal places its amounts are rounded to.  8 unless its ticker is in the ticker precision table.
 }
 
 impl RawAccount {
@@ -27,6 +31,24 @@ impl RawAccount {
 		self.ticker == compare
     }
 
+    /// Returns the ticker of the asset held.  A margin quote `account` that wasn't declared as part of a
+    /// margin pair follows the underscore convention (i.e., `BTC_xmr`), so its ticker is cut at the underscore.
+    pub fn underlying_ticker(&self) -> &str {
+        if self.is_margin && self.margin_pair.is_none() {
+            self.ticker.split('_').next().unwrap()
+        } else {
+            &self.ticker
+        }
+    }
+
+    /// Whether this is the quote `account` of a margin pair, either as declared or by the underscore convention.
+    pub fn is_margin_quote(&self) -> bool {
+        match &self.margin_pair {
+            Some(declaration) => declaration.is_quote,
+            None => self.is_margin && self.ticker.contains('_'),
+        }
+    }
+
     pub fn margin_string(&self) -> String {
         if self.is_margin {
             "Margin".to_string()
@@ -36,10 +58,23 @@ impl RawAccount {
     }
 }
 
+/// An `account`'s balance and (like-kind) cost basis at the start and the end of a report period (see
+/// `ImportProcessParameters::report_period`).
+#[derive(Clone, Debug, PartialEq)]
+pub struct PeriodBalances {
+	pub opening_balance: Decimal,
+	pub opening_basis: Decimal,
+	pub closing_balance: Decimal,
+	pub closing_basis: Decimal,
+}
+
 #[derive(Clone, Debug)]
 pub struct Account {
 	pub raw_key: u16,
 	pub list_of_lots: RefCell<Vec<Rc<Lot>>>,
+	/// With average cost pooling, the pool's (units, cost basis) after each `transaction` that changed it, keyed by
+	/// `transaction` number.  Only the pool's first `account` keeps them.  See `average_cost::record_pool_totals()`.
+	pub pool_totals: RefCell<BTreeMap<u32, (Decimal, Decimal)>>,
 	// pub vec_of_lot_keys: (RawAccount, u32),
 }
 
@@ -75,6 +110,36 @@ impl Account {
 		total_amount
     }
 
+	/// The balance and (like-kind) cost basis at the start of `first_date` and at the end of `last_date`, for a report
+	/// period.  Margin `account`s carry no basis of their own, so theirs is zero.
+	pub fn get_period_balances(&self, raw_acct: &RawAccount, first_date: NaiveDate, last_date: NaiveDate) -> PeriodBalances {
+
+		let (opening_balance, opening_basis) = self.get_sums_of_mvmts_where(|date| date < first_date);
+		let (closing_balance, closing_basis) = self.get_sums_of_mvmts_where(|date| date <= last_date);
+
+		// A balance summed back to zero would keep the decimal places of its `movement`s (i.e., `0.00000000`).
+		let plain_zero = |balance: Decimal| if balance == dec!(0) { dec!(0) } else { balance };
+
+		PeriodBalances {
+			opening_balance: plain_zero(opening_balance),
+			opening_basis: if raw_acct.is_margin { dec!(0) } else { opening_basis },
+			closing_balance: plain_zero(closing_balance),
+			closing_basis: if raw_acct.is_margin { dec!(0) } else { closing_basis },
+		}
+	}
+
+	/// The sums of the amounts and of the (like-kind) cost basis of the `movement`s whose dates are counted.
+	fn get_sums_of_mvmts_where(&self, is_counted: impl Fn(NaiveDate) -> bool) -> (Decimal, Decimal) {
+		let mut sums = (dec!(0), dec!(0));
+		for lot in self.list_of_lots.borrow().iter() {
+			for mvmt in lot.movements.borrow().iter().filter(|mvmt| is_counted(mvmt.date)) {
+				sums.0 += mvmt.amount;
+				sums.1 += mvmt.cost_basis_lk.get();
+			}
+		}
+		sums
+	}
+
     pub fn get_num_of_nonzero_lots(&self) -> u32 {
 
         let mut count = 0;
@@ -89,6 +154,15 @@ impl Account {
     }
 }
 
+/// A margin `account`'s place in an explicitly declared margin pair.  Every pair ID has exactly one base `account`
+/// and one quote `account`.  A base `account` may belong to several pairs (i.e., `margin:pair1+pair2:base`),
+/// but a quote `account` belongs to only one.
+#[derive(Clone, Debug, Eq, Hash, PartialEq, Serialize, Deserialize)]
+pub struct MarginPairDeclaration {
+	pub pair_ids: Vec<String>,
+	pub is_quote: bool,
+}
+
 #[derive(Clone, Debug)]
 pub struct RawMarginPair (pub Weak<RawAccount>, pub Weak<RawAccount>);	    //  always (base_acct, quote_acct)
 
@@ -120,6 +194,15 @@ impl Lot {
 		self.movements.borrow().iter().for_each(|movement| amts += movement.cost_basis.get());
 		amts
 	}
+
+	/// The cost basis per unit, as set by the first `movement` in the `lot`.  This is only meaningful
+	/// once cost basis has been added to that `movement`.
+	pub fn get_unit_cost_basis(&self) -> Decimal {
+		let movements = self.movements.borrow();
+		let first_mvmt = movements.first().expect("Couldn't get first mvmt. Lot has no movements?");
+		if first_mvmt.amount == dec!(0) { return dec!(0) }
+		first_mvmt.cost_basis.get() / first_mvmt.amount
+	}
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -136,6 +219,10 @@ pub struct Movement {
 	pub proceeds: Cell<Decimal>,	//	Initialized with 0. Set in add_proceeds_to_movements()
     pub proceeds_lk: Cell<Decimal>,
     pub cost_basis_lk: Cell<Decimal>,
+    pub wash_sale_adjustment: Cell<Decimal>,	//	Initialized with 0. Set in apply_wash_sale_treatment() when a loss is disallowed
+    pub fee_expense: Cell<Decimal>,	//	Initialized with 0. Set in add_proceeds_to_movements() when a trade's fee is expensed
+    pub is_reallocation: bool,	//	Set only on the `movement`s that reallocate_basis() uses to close and reopen `lot`s
+    pub carried_to_lot_num: Cell<Option<u32>>,	//	Outgoing only. Set in process_multiple_incoming_lots_and_mvmts() to the receiving `lot`
 }
 
 impl Movement {
@@ -190,8 +277,9 @@ impl Movement {
 		cost_basis
 	}
 
+	/// Includes any `wash_sale_adjustment`, which adds back a disallowed loss.
 	pub fn get_lk_gain_or_loss(&self) -> Decimal {
-		self.proceeds_lk.get() + self.cost_basis_lk.get()
+		self.proceeds_lk.get() + self.cost_basis_lk.get() + self.wash_sale_adjustment.get()
 	}
 
     pub fn get_orig_gain_or_loss(&self) -> Decimal {
@@ -203,6 +291,8 @@ impl Movement {
 	/// `movement`s subject to this call with have non-margin accounts.
 	pub fn get_term(
 		&self,
+		rule: &HoldingPeriodRule,
+		as_of_date: Option<NaiveDate>,
 		acct_map: &HashMap<u16, Account>,
 		ar_map: &HashMap<u32, ActionRecord>,
 		txns_map: &HashMap<u32, Transaction>
@@ -219,32 +309,18 @@ impl Movement {
 				// if there was like-kind treatment, the basis date may be before the `transaction` date.
 				let txn = txns_map.get(&self.transaction_key).unwrap();
 				if txn.action_record_idx_vec.len() == 2 {
-					let lot_date_for_basis_purposes = lot.date_for_basis_purposes;
-					if self.date.signed_duration_since(lot_date_for_basis_purposes) > chrono::Duration::days(365) {
-						return Term::LT
-					}
-					return Term::ST
+					return rule.term(lot.date_for_basis_purposes, self.date)
 				}
 
 				// For a single-`action record` `transaction`, term is meaningless, but it is being shown
-				// in the context of the holding period, in the event it were sold "today".
-				let today: NaiveDate = chrono::Local::now().naive_utc().date();
-				if today.signed_duration_since(lot.date_for_basis_purposes) > chrono::Duration::days(365) {
-					Term::LT
-				}
-				else {
-					Term::ST
-				}
+				// in the context of the holding period, in the event it were sold "today" (or on the as-of date).
+				let today: NaiveDate = as_of_date.unwrap_or_else(|| chrono::Local::now().naive_utc().date());
+				rule.term(lot.date_for_basis_purposes, today)
 			}
 
 			Polarity::Outgoing => {
 
-				let lot_date_for_basis_purposes = lot.date_for_basis_purposes;
-
-                if self.date.signed_duration_since(lot_date_for_basis_purposes) > chrono::Duration::days(365) {
-					return Term::LT
-				}
-				Term::ST
+				rule.term(lot.date_for_basis_purposes, self.date)
 			}
 		}
 	}
@@ -256,18 +332,40 @@ impl Movement {
 		raw_accts: &HashMap<u16, RawAccount>,
 		acct_map: &HashMap<u16, Account>,
 		txns_map: &HashMap<u32, Transaction>,
-	)-> Result<Decimal, Box<dyn Error>> {  //  Returns 0 or positive number
+		minor_units: u32,
+	)-> Result<Decimal, CrptlsError> {  //  Returns 0 or positive number
 
 		let txn = txns_map.get(&self.transaction_key).expect("Couldn't get txn. Tx num invalid?");
 
+		// `Movement`s of lot-swap `action record`s (see `LotReliefScope::Universal`) aren't part of the `transaction` itself.
+		if !txn.action_record_idx_vec.contains(&self.action_record_key) { return Ok(dec!(0)) }
+
 		match txn.transaction_type(ar_map, raw_accts, acct_map)? {
 
 			TxType::Flow => {
 
-				let ar = ar_map.get(&self.action_record_key).unwrap();
+				if txn.settles_realized_margin_pnl { return Ok(dec!(0)) }
 
-                if ar.direction() == Polarity::Incoming {
-					Ok(-self.proceeds_lk.get())
+				let ar = ar_map.get(&self.action_record_key).unwrap();
+				let acct = acct_map.get(&ar.account_key).unwrap();
+				let raw_acct = raw_accts.get(&acct.raw_key).unwrap();
+
+                if ar.direction() == Polarity::Incoming && !raw_acct.is_margin {
+
+					// Margin charges already expensed (see `Transaction::margin_charge`) are the basis carried out of
+					// the quote `account` with its profit, and they are added back to that profit.
+					let margin_charges_returned: Decimal = txn.action_record_idx_vec.iter()
+						.filter(|ar_num| **ar_num != self.action_record_key)
+						.flat_map(|ar_num| ar_map.get(ar_num).unwrap().movements.borrow().clone())
+						.map(|mvmt| mvmt.cost_basis_lk.get())
+						.sum();
+
+					if margin_charges_returned > dec!(0) {
+						let ratio = self.amount / ar.amount;
+						Ok(-self.proceeds_lk.get() + round_d128_generalized(&(margin_charges_returned * ratio), minor_units))
+					} else {
+						Ok(-self.proceeds_lk.get())
+					}
 				}
 				else { Ok(dec!(0)) }
 			}
@@ -282,14 +380,20 @@ impl Movement {
 		raw_accts: &HashMap<u16, RawAccount>,
 		acct_map: &HashMap<u16, Account>,
 		txns_map: &HashMap<u32, Transaction>,
-	)-> Result<Decimal, Box<dyn Error>> {  //  Returns 0 or negative number
+	)-> Result<Decimal, CrptlsError> {  //  Returns 0 or negative number
 
 		let txn = txns_map.get(&self.transaction_key).expect("Couldn't get txn. Tx num invalid?");
 
+		// `Movement`s of lot-swap `action record`s (see `LotReliefScope::Universal`) aren't part of the `transaction` itself.
+		if !txn.action_record_idx_vec.contains(&self.action_record_key) { return Ok(dec!(0)) }
+
 		match txn.transaction_type(ar_map, raw_accts, acct_map)? {
 
 			TxType::Flow => {
 
+				// A dual-`action record` `flow` settles margin profit or loss, which is never an expense.
+				if txn.settles_realized_margin_pnl || txn.action_record_idx_vec.len() == 2 { return Ok(dec!(0)) }
+
 				let ar = ar_map.get(&self.action_record_key).unwrap();
 
                 if ar.direction() == Polarity::Outgoing {
@@ -297,7 +401,7 @@ impl Movement {
                     let acct = acct_map.get(&ar.account_key).unwrap();
                     let raw_acct = raw_accts.get(&acct.raw_key).unwrap();
 
-                    if raw_acct.is_margin {
+                    if raw_acct.is_margin && txn.margin_charge.is_none() {
 
                        Ok(dec!(0))
 
@@ -309,7 +413,7 @@ impl Movement {
 				}
 				else { Ok(dec!(0)) }
 			}
-			TxType::Exchange => { Ok(dec!(0)) }
+			TxType::Exchange => { Ok(self.fee_expense.get()) }
 			TxType::ToSelf => { Ok(dec!(0)) }
 		}
     }
@@ -341,28 +445,27 @@ impl Movement {
 
 }
 
-#[derive(Clone, Debug, PartialEq)]
-pub enum Term {
-	LT,
-	ST,
+/// The holding period of disposed units, per the `HoldingPeriodRule`.  `Term`s order by `rank`, shortest first.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Term {
+	/// How many of the rule's holding periods were outlasted
+	pub rank: usize,
+	/// Shown in the reports' term columns, e.g. "LT"
+	pub abbr: String,
+	/// Used in the journal entries' gain and loss lines, e.g. "Long-term"
+	pub name: String,
 }
 
 impl Term {
 
     pub fn abbr_string(&self) -> String {
-        match *self {
-            Term::LT => "LT".to_string(),
-            Term::ST => "ST".to_string(),
-        }
+        self.abbr.clone()
     }
 }
 
 impl fmt::Display for Term {
 
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match *self {
-           Term::LT => write!(f, "LT"),
-           Term::ST => write!(f, "ST"),
-        }
+        write!(f, "{}", self.abbr)
     }
 }
@@ -0,0 +1,174 @@
+// Copyright (c) 2017-2023, scoobybejesus
+// Redistributions must include the license: https://github.com/scoobybejesus/cryptools/blob/master/LEGAL.txt
+
+use std::rc::Rc;
+use std::collections::{HashMap, BTreeMap};
+
+use rust_decimal::Decimal;
+use rust_decimal_macros::dec;
+
+use crate::account::{Account, RawAccount, Movement};
+use crate::costing_method::AverageCostPool;
+use crate::transaction::ActionRecord;
+use crate::decimal_utils::round_d128_generalized;
+
+/// Returns the keys of every `account` sharing a pool with the given `account`.
+fn get_pooled_acct_keys(
+    pool: &AverageCostPool,
+    acct_key: u16,
+    raw_acct_map: &HashMap<u16, RawAccount>,
+) -> Vec<u16> {
+
+    match pool {
+        AverageCostPool::Account => vec![acct_key],
+        AverageCostPool::Ticker => {
+            let ticker = &raw_acct_map.get(&acct_key).unwrap().ticker;
+            let mut keys: Vec<u16> = raw_acct_map.values()
+                .filter(|raw_acct| !raw_acct.is_margin && &raw_acct.ticker == ticker)
+                .map(|raw_acct| raw_acct.account_num)
+                .collect();
+            keys.sort();
+            keys
+        }
+    }
+}
+
+/// Returns the (units, cost basis) held in the pool of the given `account`, counting only `movement`s from
+/// `transaction`s numbered below `before_txn_num`.  Pass `u32::MAX` for the pool as it stands at the end.
+pub fn get_pool_totals(
+    pool: &AverageCostPool,
+    acct_key: u16,
+    before_txn_num: u32,
+    raw_acct_map: &HashMap<u16, RawAccount>,
+    acct_map: &HashMap<u16, Account>,
+) -> (Decimal, Decimal) {
+
+    let keeper_key = get_pooled_acct_keys(pool, acct_key, raw_acct_map)[0];
+    let keeper = acct_map.get(&keeper_key).unwrap();
+
+    keeper.pool_totals.borrow()
+        .range(..before_txn_num)
+        .next_back()
+        .map(|(_txn_num, totals)| *totals)
+        .unwrap_or((dec!(0), dec!(0)))
+}
+
+/// Adds the `movement`s of `ars` to the running totals of their pools as of `txn_num`.  This is called once each
+/// `transaction`'s `movement`s have their cost basis, with its `action record`s (and those of any swap or
+/// reallocation tied to it), so the pools never have to be totaled from scratch.
+pub(crate) fn record_pool_totals(
+    pool: &AverageCostPool,
+    txn_num: u32,
+    ars: &[&ActionRecord],
+    home_currency: &str,
+    raw_acct_map: &HashMap<u16, RawAccount>,
+    acct_map: &HashMap<u16, Account>,
+) {
+
+    let mut changes: BTreeMap<u16, (Decimal, Decimal)> = BTreeMap::new();
+
+    for ar in ars.iter() {
+
+        let raw_acct = raw_acct_map.get(&ar.account_key).unwrap();
+        if raw_acct.is_margin || raw_acct.is_home_currency(home_currency) { continue }
+
+        let keeper_key = get_pooled_acct_keys(pool, ar.account_key, raw_acct_map)[0];
+        let (units, basis) = changes.entry(keeper_key).or_insert((dec!(0), dec!(0)));
+
+        for mvmt in ar.movements.borrow().iter() {
+            *units += mvmt.amount;
+            *basis += mvmt.cost_basis.get();
+        }
+    }
+
+    for (keeper_key, (units, basis)) in changes {
+
+        let keeper = acct_map.get(&keeper_key).unwrap();
+        let mut pool_totals = keeper.pool_totals.borrow_mut();
+
+        let (prior_units, prior_basis) = pool_totals.range(..=txn_num)
+            .next_back()
+            .map(|(_txn_num, totals)| *totals)
+            .unwrap_or((dec!(0), dec!(0)));
+
+        pool_totals.insert(txn_num, (prior_units + units, prior_basis + basis));
+    }
+}
+
+/// The pool's average cost per unit, counting only `transaction`s numbered below `before_txn_num`.
+/// An empty pool has an average cost of zero.
+pub fn get_avg_cost_per_unit(
+    pool: &AverageCostPool,
+    acct_key: u16,
+    before_txn_num: u32,
+    raw_acct_map: &HashMap<u16, RawAccount>,
+    acct_map: &HashMap<u16, Account>,
+) -> Decimal {
+
+    let (units, basis) = get_pool_totals(pool, acct_key, before_txn_num, raw_acct_map, acct_map);
+
+    if units <= dec!(0) { return dec!(0) }
+
+    basis / units
+}
+
+/// Determines the (negative) cost basis of each outgoing `movement` of an `action record` at the pool's running
+/// average.  The basis of the whole `action record` is figured first (and if it empties the pool, it takes all the
+/// remaining basis, so no rounding residue is left behind), and then it is split across the `movement`s, with the
+/// last `movement` absorbing any rounding difference.
+pub(crate) fn get_pooled_basis_of_outgoing_mvmts(
+    pool: &AverageCostPool,
+    ar: &ActionRecord,
+    txn_num: u32,
+    movements: &[Rc<Movement>],
+    minor_units: u32,
+    raw_acct_map: &HashMap<u16, RawAccount>,
+    acct_map: &HashMap<u16, Account>,
+) -> Vec<Decimal> {
+
+    let ar_amount = ar.amount;
+    let (units, basis) = get_pool_totals(pool, ar.account_key, txn_num, raw_acct_map, acct_map);
+
+    let ar_basis = if units <= dec!(0) {
+        dec!(0)
+    } else if -ar_amount >= units {
+        basis
+    } else {
+        round_d128_generalized(&(basis * -ar_amount / units), minor_units)
+    };
+
+    let mut bases = Vec::with_capacity(movements.len());
+    let mut basis_used = dec!(0);
+
+    for (idx, mvmt) in movements.iter().enumerate() {
+        let mvmt_basis = if idx == movements.len() - 1 {
+            ar_basis - basis_used
+        } else {
+            round_d128_generalized(&(ar_basis * mvmt.amount / ar_amount), minor_units)
+        };
+        basis_used += mvmt_basis;
+        bases.push(-mvmt_basis);
+    }
+
+    bases
+}
+
+/// Formats the pool's average cost per unit for the reports.  Margin and home currency `account`s aren't
+/// pooled, so they get an empty string.
+pub fn get_avg_cost_per_unit_string(
+    pool: &AverageCostPool,
+    acct_key: u16,
+    before_txn_num: u32,
+    home_currency: &str,
+    raw_acct_map: &HashMap<u16, RawAccount>,
+    acct_map: &HashMap<u16, Account>,
+) -> String {
+
+    let raw_acct = raw_acct_map.get(&acct_key).unwrap();
+
+    if raw_acct.is_margin || raw_acct.is_home_currency(home_currency) { return "".to_string() }
+
+    let avg = get_avg_cost_per_unit(pool, acct_key, before_txn_num, raw_acct_map, acct_map);
+
+    avg.round_dp(8).normalize().to_string()
+}
@@ -0,0 +1,72 @@
+// Copyright (c) 2017-2023, scoobybejesus
+// Redistributions must include the license: https://github.com/scoobybejesus/cryptools/blob/master/LEGAL.txt
+
+use std::collections::HashMap;
+
+use chrono::NaiveDate;
+use rust_decimal::Decimal;
+use rust_decimal_macros::dec;
+
+use crate::account::RawAccount;
+use crate::transaction::{Transaction, ActionRecord};
+
+/// A point at which a non-margin `account` would spend more than it holds.
+#[derive(Clone, Debug)]
+pub struct BalanceShortfall {
+    pub acct_key: u16,
+    pub tx_number: u32,
+    /// Line of the input file, counting the header rows
+    pub csv_line: u32,
+    pub date: NaiveDate,
+    /// The part of the `transaction`'s spending that the `account` didn't hold
+    pub amount_missing: Decimal,
+    /// The `account`'s balance after the `transaction`, as if the spending had gone through
+    pub running_balance: Decimal,
+}
+
+/// Walks every `transaction` in order, keeping each `account`'s balance from the `action record` amounts alone, and
+/// returns every point where a non-margin, non-home currency `account` goes (or stays) negative.  Home currency and
+/// margin `account`s may go negative, so they aren't checked.
+///
+/// Once an `account` is short, its balance stays negative for the rest of the walk, so later spending from it is
+/// reported as missing in full.  That way every shortfall is found in one pass, before any `lot`s are created.
+pub fn get_balance_shortfalls(
+    home_currency: &str,
+    raw_acct_map: &HashMap<u16, RawAccount>,
+    ars: &HashMap<u32, ActionRecord>,
+    txns_map: &HashMap<u32, Transaction>,
+) -> Vec<BalanceShortfall> {
+
+    let mut balances: HashMap<u16, Decimal> = HashMap::new();
+    let mut shortfalls: Vec<BalanceShortfall> = [].to_vec();
+
+    for txn_num in 1..=txns_map.len() as u32 {
+
+        let txn = txns_map.get(&txn_num).unwrap();
+
+        for ar_num in txn.ar_keys_incl_fee_leg().iter() {
+
+            let ar = ars.get(ar_num).unwrap();
+            let raw_acct = raw_acct_map.get(&ar.account_key).unwrap();
+
+            if raw_acct.is_margin || raw_acct.is_home_currency(home_currency) { continue }
+
+            let balance = balances.entry(ar.account_key).or_insert(dec!(0));
+            let balance_before = *balance;
+            *balance += ar.amount;
+
+            if ar.amount < dec!(0) && *balance < dec!(0) {
+                shortfalls.push(BalanceShortfall {
+                    acct_key: ar.account_key,
+                    tx_number: txn.tx_number,
+                    csv_line: txn.csv_line,
+                    date: txn.date,
+                    amount_missing: -ar.amount - balance_before.max(dec!(0)),
+                    running_balance: *balance,
+                });
+            }
+        }
+    }
+
+    shortfalls
+}
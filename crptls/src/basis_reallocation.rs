@@ -0,0 +1,449 @@
+// Copyright (c) 2017-2023, scoobybejesus
+// Redistributions must include the license: https://github.com/scoobybejesus/cryptools/blob/master/LEGAL.txt
+
+use std::fs::File;
+use std::rc::Rc;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use chrono::NaiveDate;
+use rust_decimal::Decimal;
+use rust_decimal_macros::dec;
+
+use crate::core_functions::ImportProcessParameters;
+use crate::transaction::ActionRecord;
+use crate::account::{Account, RawAccount, Lot, Movement};
+use crate::create_lots_mvmts::{get_universal_order, wrap_mvmt_and_push};
+use crate::decimal_utils::round_d128_generalized;
+use crate::errors::CrptlsError;
+
+/// One `lot` to be held in an `account` after the reallocation: `amount` units of the `account`'s ticker, with
+/// a total cost basis of `basis` and a basis date of `basis_date`.
+#[derive(Clone, Debug)]
+pub struct LotAllocation {
+    pub account_num: u16,
+    pub basis_date: NaiveDate,
+    pub amount: Decimal,
+    pub basis: Decimal,
+}
+
+/// A `lot` closed out or opened by the reallocation, for the allocation report.
+#[derive(Clone, Debug)]
+pub struct ReallocatedLot {
+    pub ticker: String,
+    pub account_num: u16,
+    pub lot_num: u32,
+    /// `true` for a `lot` that was closed out, `false` for one that was opened
+    pub closed: bool,
+    pub basis_date: NaiveDate,
+    pub amount: Decimal,
+    pub basis: Decimal,
+}
+
+/// Imports the optional basis allocation file.  The file is a CSV with a header row and four columns: `account`,
+/// `basis_date` (%Y-%m-%d), `amount`, and `basis`.  Each row becomes one `lot` in that `account` after the
+/// reallocation.  Whether the rows add up to each `account`'s balance and to the ticker's unused basis can only be
+/// checked once the reallocation date is reached, in `reallocate_basis()`.
+pub fn import_lot_allocations(
+    allocation_file_path: &PathBuf,
+    home_currency: &str,
+    minor_units: u32,
+    raw_acct_map: &HashMap<u16, RawAccount>,
+) -> Result<Vec<LotAllocation>, CrptlsError> {
+
+    let file = match File::open(allocation_file_path) {
+        Ok(x) => x,
+        Err(e) => {
+            return Err(CrptlsError::FileOpen { path: allocation_file_path.clone(), source: e })
+        }
+    };
+
+    let mut rdr = csv::ReaderBuilder::new()
+        .has_headers(true)
+        .trim(csv::Trim::All)
+        .from_reader(file);
+
+    let mut allocations: Vec<LotAllocation> = [].to_vec();
+
+    for (idx, result) in rdr.records().enumerate() {
+
+        let record = result?;
+        let row = idx + 2;  //  The header is row 1
+
+        if record.len() != 4 {
+            return Err(CrptlsError::BasisAllocationFile {
+                row: Some(row),
+                reason: format!("expected 4 fields (account, basis_date, amount, basis), found {}.", record.len())
+            })
+        }
+
+        let account_num = record[0].parse::<u16>()
+            .map_err(|_| CrptlsError::BasisAllocationFile {
+                row: Some(row),
+                reason: format!("couldn't parse account number: {}", &record[0])
+            })?;
+
+        let basis_date = NaiveDate::parse_from_str(&record[1], "%Y-%m-%d")
+            .map_err(|_| CrptlsError::BasisAllocationFile {
+                row: Some(row),
+                reason: format!("couldn't parse basis date (use %Y-%m-%d): {}", &record[1])
+            })?;
+
+        let amount = record[2].replace(",", "").parse::<Decimal>()
+            .map_err(|_| CrptlsError::BasisAllocationFile {
+                row: Some(row),
+                reason: format!("couldn't parse amount: {}", &record[2])
+            })?;
+
+        let basis = record[3].replace(",", "").parse::<Decimal>()
+            .map_err(|_| CrptlsError::BasisAllocationFile {
+                row: Some(row),
+                reason: format!("couldn't parse basis: {}", &record[3])
+            })?;
+        let basis = round_d128_generalized(&basis, minor_units);
+
+        if basis < dec!(0) {
+            return Err(CrptlsError::BasisAllocationFile {
+                row: Some(row),
+                reason: "basis must not be negative.".to_string()
+            })
+        }
+
+        let raw_acct = match raw_acct_map.get(&account_num) {
+            Some(x) => x,
+            None => return Err(CrptlsError::BasisAllocationFile {
+                row: Some(row),
+                reason: format!("account {} doesn't exist.", account_num)
+            })
+        };
+
+        if raw_acct.is_margin || raw_acct.is_home_currency(home_currency) {
+            return Err(CrptlsError::BasisAllocationFile {
+                row: Some(row),
+                reason: format!("account {} ({}) is a margin or home currency account, so its basis can't be reallocated.",
+                    account_num, raw_acct.name)
+            })
+        }
+
+        let amount = round_d128_generalized(&amount, raw_acct.precision);
+        if amount <= dec!(0) {
+            return Err(CrptlsError::BasisAllocationFile {
+                row: Some(row),
+                reason: "amount must be positive.".to_string()
+            })
+        }
+
+        allocations.push(LotAllocation { account_num, basis_date, amount, basis });
+    }
+
+    Ok(allocations)
+}
+
+/// Reallocates the unused basis of each ticker held in non-margin `account`s as of `date`, for the transition to
+/// per-wallet tracking.  Every open `lot` of the ticker is closed out, and new `lot`s are opened so that each `account`
+/// keeps its balance and the ticker keeps its total unused basis.  Processing then continues from the new `lot`s.
+///
+/// The new `lot`s for a ticker are taken from `allocations` (the allocation file) if the ticker appears there.
+/// Otherwise the global-to-wallet rule is applied: the open `lot`s are ordered by the `InventoryCostingMethod`, and
+/// the `account`s, in account number order, are each filled from them in turn.
+///
+/// The `movement`s belong to new `action record`s, keyed from `first_ar_key` and tied to `transaction` `anchor_txn_num`,
+/// which aren't part of that `transaction`'s list of `action record`s.  Their cost basis is set here, and each `movement`'s
+/// proceeds offset its basis, so the reallocation realizes no gain or loss.
+pub(crate) fn reallocate_basis(
+    date: NaiveDate,
+    anchor_txn_num: u32,
+    settings: &ImportProcessParameters,
+    allocations: &[LotAllocation],
+    raw_acct_map: &HashMap<u16, RawAccount>,
+    acct_map: &HashMap<u16, Account>,
+    first_ar_key: u32,
+) -> Result<Vec<ActionRecord>, CrptlsError> {
+
+    let mut realloc_ars: Vec<ActionRecord> = [].to_vec();
+
+    let mut raw_accts: Vec<&RawAccount> = raw_acct_map.values()
+        .filter(|raw_acct| !raw_acct.is_margin && !raw_acct.is_home_currency(&settings.home_currency))
+        .collect();
+    raw_accts.sort_by_key(|raw_acct| raw_acct.account_num);
+
+    let mut tickers: Vec<String> = [].to_vec();
+    for raw_acct in raw_accts.iter() {
+        if !tickers.contains(&raw_acct.ticker) { tickers.push(raw_acct.ticker.clone()) }
+    }
+
+    for ticker in tickers.iter() {
+
+        let accts_with_ticker: Vec<&Account> = raw_accts.iter()
+            .filter(|raw_acct| &raw_acct.ticker == ticker)
+            .map(|raw_acct| acct_map.get(&raw_acct.account_num).unwrap())
+            .collect();
+
+        let mut open_lots: Vec<Rc<Lot>> = [].to_vec();
+        for acct in accts_with_ticker.iter() {
+            for lot in acct.list_of_lots.borrow().iter() {
+                if lot.get_sum_of_amts_in_lot() > dec!(0) { open_lots.push(lot.clone()) }
+            }
+        }
+
+        let ticker_allocations: Vec<&LotAllocation> = allocations.iter()
+            .filter(|allocation| &raw_acct_map.get(&allocation.account_num).unwrap().ticker == ticker)
+            .collect();
+
+        if open_lots.is_empty() {
+            if !ticker_allocations.is_empty() {
+                return Err(CrptlsError::BasisAllocationFile {
+                    row: None,
+                    reason: format!("it has rows for {}, but no {} is held on {}.", ticker, ticker, date)
+                })
+            }
+            continue
+        }
+
+        let new_lots: Vec<LotAllocation> = if ticker_allocations.is_empty() {
+            allocate_by_global_rule(date, &open_lots, &accts_with_ticker, settings)
+        } else {
+            check_allocations(ticker, date, &ticker_allocations, &open_lots, &accts_with_ticker, raw_acct_map)?;
+            ticker_allocations.into_iter().cloned().collect()
+        };
+
+        for acct in accts_with_ticker.iter() {
+
+            let raw_acct = raw_acct_map.get(&acct.raw_key).unwrap();
+            let lots_to_close: Vec<&Rc<Lot>> = open_lots.iter()
+                .filter(|lot| lot.account_key == acct.raw_key)
+                .collect();
+
+            if lots_to_close.is_empty() { continue }
+
+            let balance: Decimal = lots_to_close.iter().map(|lot| lot.get_sum_of_amts_in_lot()).sum();
+
+            let outgoing_ar = ActionRecord {
+                account_key: acct.raw_key,
+                amount: -balance,
+                tx_key: anchor_txn_num,
+                self_ar_key: first_ar_key + realloc_ars.len() as u32,
+                movements: RefCell::new([].to_vec()),
+            };
+            for lot in lots_to_close {
+                let mvmt = get_realloc_mvmt(
+                    -lot.get_sum_of_amts_in_lot(),
+                    -lot.get_sum_of_orig_basis_in_lot(),
+                    date,
+                    &outgoing_ar,
+                    lot.lot_number,
+                );
+                wrap_mvmt_and_push(mvmt, &outgoing_ar, lot, &settings.home_currency, raw_acct);
+            }
+            realloc_ars.push(outgoing_ar);
+
+            let incoming_ar = ActionRecord {
+                account_key: acct.raw_key,
+                amount: balance,
+                tx_key: anchor_txn_num,
+                self_ar_key: first_ar_key + realloc_ars.len() as u32,
+                movements: RefCell::new([].to_vec()),
+            };
+            for allocation in new_lots.iter().filter(|allocation| allocation.account_num == acct.raw_key) {
+                let lot = Rc::new(
+                    Lot {
+                        date_as_string: date.to_string(),
+                        date_of_first_mvmt_in_lot: date,
+                        date_for_basis_purposes: allocation.basis_date,
+                        lot_number: acct.list_of_lots.borrow().len() as u32 + 1,
+                        account_key: acct.raw_key,
+                        movements: RefCell::new([].to_vec()),
+                    }
+                );
+                let mvmt = get_realloc_mvmt(allocation.amount, allocation.basis, date, &incoming_ar, lot.lot_number);
+                wrap_mvmt_and_push(mvmt, &incoming_ar, &lot, &settings.home_currency, raw_acct);
+                acct.list_of_lots.borrow_mut().push(lot);
+            }
+            realloc_ars.push(incoming_ar);
+        }
+    }
+
+    Ok(realloc_ars)
+}
+
+fn get_realloc_mvmt(
+    amount: Decimal,
+    cost_basis: Decimal,
+    date: NaiveDate,
+    ar: &ActionRecord,
+    lot_num: u32,
+) -> Movement {
+    Movement {
+        amount,
+        date_as_string: date.to_string(),
+        date,
+        transaction_key: ar.tx_key,
+        action_record_key: ar.self_ar_key,
+        cost_basis: Cell::new(cost_basis),
+        ratio_of_amt_to_incoming_mvmts_in_a_r: dec!(1.0),
+        ratio_of_amt_to_outgoing_mvmts_in_a_r: Cell::new(dec!(1.0)),
+        lot_num,
+        proceeds: Cell::new(-cost_basis),
+        proceeds_lk: Cell::new(-cost_basis),
+        cost_basis_lk: Cell::new(cost_basis),
+        wash_sale_adjustment: Cell::new(dec!(0.0)),
+        fee_expense: Cell::new(dec!(0)),
+        is_reallocation: true,
+        carried_to_lot_num: Cell::new(None),
+    }
+}
+
+/// The global-to-wallet rule.  The last piece taken from a `lot` gets whatever basis is left in it, so no basis is
+/// lost to rounding.  With the tax-optimized method there is no disposal to optimize, so `lot`s are ordered as if
+/// disposed of for nothing on the reallocation date (long-term first, highest basis first).
+fn allocate_by_global_rule(
+    date: NaiveDate,
+    open_lots: &[Rc<Lot>],
+    accts_with_ticker: &[&Account],
+    settings: &ImportProcessParameters,
+) -> Vec<LotAllocation> {
+
+    let ordered_idxs = get_universal_order(open_lots, settings.costing_method_on(date), dec!(0), date, &settings.holding_period_rule);
+    let mut remaining: Vec<(Decimal, Decimal)> = open_lots.iter()
+        .map(|lot| (lot.get_sum_of_amts_in_lot(), lot.get_sum_of_orig_basis_in_lot()))
+        .collect();
+
+    let mut new_lots: Vec<LotAllocation> = [].to_vec();
+    let mut position = 0;
+
+    for acct in accts_with_ticker.iter() {
+
+        let mut amt_needed: Decimal = open_lots.iter()
+            .filter(|lot| lot.account_key == acct.raw_key)
+            .map(|lot| lot.get_sum_of_amts_in_lot())
+            .sum();
+
+        while amt_needed > dec!(0) {
+
+            let idx = ordered_idxs[position];
+            let (lot_amt, lot_basis) = remaining[idx];
+            let amount = lot_amt.min(amt_needed);
+            let basis = if amount == lot_amt {
+                lot_basis
+            } else {
+                round_d128_generalized(&(lot_basis * amount / lot_amt), settings.home_currency_minor_units)
+            };
+
+            remaining[idx] = (lot_amt - amount, lot_basis - basis);
+            if remaining[idx].0 == dec!(0) { position += 1 }
+            amt_needed -= amount;
+
+            new_lots.push(LotAllocation {
+                account_num: acct.raw_key,
+                basis_date: open_lots[idx].date_for_basis_purposes,
+                amount,
+                basis,
+            });
+        }
+    }
+
+    new_lots
+}
+
+fn check_allocations(
+    ticker: &str,
+    date: NaiveDate,
+    ticker_allocations: &[&LotAllocation],
+    open_lots: &[Rc<Lot>],
+    accts_with_ticker: &[&Account],
+    raw_acct_map: &HashMap<u16, RawAccount>,
+) -> Result<(), CrptlsError> {
+
+    for acct in accts_with_ticker.iter() {
+
+        let raw_acct = raw_acct_map.get(&acct.raw_key).unwrap();
+        let balance: Decimal = open_lots.iter()
+            .filter(|lot| lot.account_key == acct.raw_key)
+            .map(|lot| lot.get_sum_of_amts_in_lot())
+            .sum();
+        let allocated: Decimal = ticker_allocations.iter()
+            .filter(|allocation| allocation.account_num == acct.raw_key)
+            .map(|allocation| allocation.amount)
+            .sum();
+
+        if allocated != balance {
+            return Err(CrptlsError::BasisAllocationFile {
+                row: None,
+                reason: format!("{} allocated to account {} ({}) totals {}, but the account holds {} on {}.",
+                    ticker, raw_acct.account_num, raw_acct.name, allocated, balance, date)
+            })
+        }
+    }
+
+    let unused_basis: Decimal = open_lots.iter().map(|lot| lot.get_sum_of_orig_basis_in_lot()).sum();
+    let allocated_basis: Decimal = ticker_allocations.iter().map(|allocation| allocation.basis).sum();
+
+    if allocated_basis != unused_basis {
+        return Err(CrptlsError::BasisAllocationFile {
+            row: None,
+            reason: format!("{} basis allocated totals {}, but the unused basis on {} is {}.",
+                ticker, allocated_basis, date, unused_basis)
+        })
+    }
+
+    Ok(())
+}
+
+/// Finds the `lot`s closed out and opened by `reallocate_basis()`, for the allocation report.  For each ticker, the
+/// closed `lot`s are listed first, then the opened ones.
+pub fn get_reallocated_lots(
+    home_currency: &str,
+    raw_acct_map: &HashMap<u16, RawAccount>,
+    acct_map: &HashMap<u16, Account>,
+) -> Vec<ReallocatedLot> {
+
+    let mut raw_accts: Vec<&RawAccount> = raw_acct_map.values()
+        .filter(|raw_acct| !raw_acct.is_margin && !raw_acct.is_home_currency(home_currency))
+        .collect();
+    raw_accts.sort_by_key(|raw_acct| raw_acct.account_num);
+
+    let mut tickers: Vec<String> = [].to_vec();
+    let mut closed_by_ticker: HashMap<String, Vec<ReallocatedLot>> = HashMap::new();
+    let mut opened_by_ticker: HashMap<String, Vec<ReallocatedLot>> = HashMap::new();
+
+    for raw_acct in raw_accts.iter() {
+
+        let acct = acct_map.get(&raw_acct.account_num).unwrap();
+
+        for lot in acct.list_of_lots.borrow().iter() {
+
+            for mvmt in lot.movements.borrow().iter() {
+
+                if !mvmt.is_reallocation { continue }
+
+                if !tickers.contains(&raw_acct.ticker) { tickers.push(raw_acct.ticker.clone()) }
+
+                let reallocated_lot = ReallocatedLot {
+                    ticker: raw_acct.ticker.clone(),
+                    account_num: raw_acct.account_num,
+                    lot_num: lot.lot_number,
+                    closed: mvmt.amount < dec!(0),
+                    basis_date: lot.date_for_basis_purposes,
+                    amount: mvmt.amount.abs(),
+                    basis: mvmt.cost_basis.get().abs(),
+                };
+
+                if reallocated_lot.closed {
+                    closed_by_ticker.entry(raw_acct.ticker.clone()).or_default().push(reallocated_lot);
+                } else {
+                    opened_by_ticker.entry(raw_acct.ticker.clone()).or_default().push(reallocated_lot);
+                }
+            }
+        }
+    }
+
+    let mut reallocated_lots: Vec<ReallocatedLot> = [].to_vec();
+
+    for ticker in tickers.iter() {
+        reallocated_lots.extend(closed_by_ticker.remove(ticker).unwrap_or_default());
+        reallocated_lots.extend(opened_by_ticker.remove(ticker).unwrap_or_default());
+    }
+
+    reallocated_lots
+}
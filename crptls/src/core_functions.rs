@@ -2,18 +2,25 @@
 // Redistributions must include the license: https://github.com/scoobybejesus/cryptools/blob/master/LEGAL.txt
 
 use std::path::PathBuf;
-use std::error::Error;
 
 use std::collections::HashMap;
 
 use chrono::NaiveDate;
+use rust_decimal_macros::dec;
 
 use crate::account::{Account, RawAccount, Lot};
-use crate::transaction::{Transaction, ActionRecord};
+use crate::transaction::{Transaction, ActionRecord, TxType, TxHasMargin};
 use crate::csv_import_accts_txns;
 use crate::import_cost_proceeds_etc;
 use crate::create_lots_mvmts;
-use crate::costing_method::InventoryCostingMethod;
+use crate::lot_selection;
+use crate::basis_reallocation;
+use crate::average_cost;
+use crate::wash_sale;
+use crate::balance_check;
+use crate::costing_method::{InventoryCostingMethod, AverageCostPool, LotReliefScope, FeeTreatment};
+use crate::holding_period::HoldingPeriodRule;
+use crate::errors::CrptlsError;
 
 
 /// `ImportProcessParameters` are determined from command-line args, environment variables, and/or wizard input from the user.
@@ -24,7 +31,48 @@ pub struct ImportProcessParameters {
     pub input_file_date_separator: String,
     pub input_file_uses_iso_date_style: bool,
     pub home_currency: String,
+    /// Decimal places of the home currency's minor unit (e.g., 0 for JPY), which home currency values are rounded to.
+    /// See `currency::iso_4217_minor_units()`.
+    pub home_currency_minor_units: u32,
+    /// Decimal places that amounts of each listed ticker (uppercased) are rounded to.  Unlisted tickers use 8.
+    pub ticker_precision: HashMap<String, u32>,
     pub costing_method: InventoryCostingMethod,
+    /// `(effective_date, method)` pairs, sorted by date.  Each method replaces `costing_method` for `transaction`s dated on or after its date.
+    pub costing_method_schedule: Vec<(NaiveDate, InventoryCostingMethod)>,
+    /// Methods keyed by `RawAccount` number, used for that `account` regardless of `costing_method` and the schedule
+    pub costing_method_overrides: HashMap<u16, InventoryCostingMethod>,
+    /// Whether `costing_method` chooses from the `account`'s own `lot`s or from those of every non-margin `account` with the ticker
+    pub lot_relief_scope: LotReliefScope,
+    /// Optional CSV file of specific lot selections, consumed before falling back to `costing_method`
+    pub lot_selection_file: Option<PathBuf>,
+    /// When set, the unused basis of each ticker is reallocated across its non-margin `Account`s as of this date
+    pub basis_reallocation_date: Option<NaiveDate>,
+    /// Optional CSV file of the `Lot`s each `Account` holds after the reallocation, instead of the global-to-wallet rule
+    pub basis_allocation_file: Option<PathBuf>,
+    /// When set, dispositions take cost basis at the pool's running average instead of from their `lot`s
+    pub average_cost_pool: Option<AverageCostPool>,
+    /// Adds a report matching each disposal under the UK share-pooling rules.  Doesn't change any `Movement`s, so the
+    /// reports that take basis and gains from `lot`s aren't exported alongside it.
+    pub uk_share_pooling: bool,
+    /// Sorts disposals into `Term`s by how long the units were held
+    pub holding_period_rule: HoldingPeriodRule,
+    /// When set, only `transaction`s dated on or before this date are processed, and unsold `lot`s' holding periods
+    /// are judged as of this date rather than today
+    pub as_of_date: Option<NaiveDate>,
+    /// When set, `(first_date, last_date)` of the period the disposal, income and expense reports are limited to.
+    /// All `transaction`s are still processed.
+    pub report_period: Option<(NaiveDate, NaiveDate)>,
+    /// Optional CSV file of home currency prices by date and ticker, which adds the unrealized gain/loss report
+    pub price_file: Option<PathBuf>,
+    /// Date the unrealized gain/loss report values open `lot`s at.  Without one, the `as_of_date` or else today.
+    pub valuation_date: Option<NaiveDate>,
+    /// What becomes of trade fees: added to acquisition basis, subtracted from proceeds, or booked as an expense
+    pub fee_treatment: FeeTreatment,
+    /// Disallows losses on disposals of a ticker that was also acquired within 30 days before or after
+    pub wash_sale_rule: bool,
+    /// Realizes margin profit and loss on every trade that reduces a margin position, rather than when the quote
+    /// `account` settles with a spot `account`
+    pub realize_margin_pnl: bool,
     pub lk_treatment_enabled: bool,
     /// NaiveDate either from "1-1-1" (default and not to be used) or the actual date chosen (or passed in via env var)
     pub lk_cutoff_date: NaiveDate,
@@ -34,6 +82,32 @@ pub struct ImportProcessParameters {
     pub journal_entry_export: bool,
 }
 
+impl ImportProcessParameters {
+
+    /// Returns the `InventoryCostingMethod` in force on `date`, per the `costing_method_schedule`.
+    pub fn costing_method_on(&self, date: NaiveDate) -> &InventoryCostingMethod {
+        self.costing_method_schedule.iter()
+            .rev()
+            .find(|(effective_date, _method)| *effective_date <= date)
+            .map(|(_effective_date, method)| method)
+            .unwrap_or(&self.costing_method)
+    }
+
+    /// Whether `date` falls in the `report_period`.  Without one, every date does.
+    pub fn is_in_report_period(&self, date: NaiveDate) -> bool {
+        match self.report_period {
+            Some((first_date, last_date)) => first_date <= date && date <= last_date,
+            None => true,
+        }
+    }
+
+    /// Returns the `InventoryCostingMethod` used for the `account` on `date`, honoring any per-`account` override.
+    pub fn costing_method_for(&self, acct_key: u16, date: NaiveDate) -> &InventoryCostingMethod {
+        self.costing_method_overrides.get(&acct_key)
+            .unwrap_or_else(|| self.costing_method_on(date))
+    }
+}
+
 pub fn import_and_process_final(
     input_file_path: PathBuf,
     settings: &ImportProcessParameters,
@@ -42,7 +116,49 @@ pub fn import_and_process_final(
     HashMap<u16, Account>,
     HashMap<u32, ActionRecord>,
     HashMap<u32, Transaction>,
-), Box<dyn Error>> {
+), CrptlsError> {
+
+    if settings.average_cost_pool.is_some() && settings.lk_treatment_enabled {
+        return Err(CrptlsError::InvalidSettings("Average cost pooling can't be combined with like-kind treatment.".to_string()))
+    }
+
+    if settings.basis_allocation_file.is_some() && settings.basis_reallocation_date.is_none() {
+        return Err(CrptlsError::InvalidSettings("A basis allocation file requires a basis reallocation date.".to_string()))
+    }
+
+    if settings.basis_reallocation_date.is_some() && settings.lk_treatment_enabled {
+        return Err(CrptlsError::InvalidSettings("Basis reallocation can't be combined with like-kind treatment.".to_string()))
+    }
+
+    if settings.lot_relief_scope == LotReliefScope::Universal {
+        if settings.lk_treatment_enabled {
+            return Err(CrptlsError::InvalidSettings("Universal lot relief can't be combined with like-kind treatment.".to_string()))
+        }
+        if settings.average_cost_pool == Some(AverageCostPool::Account) {
+            return Err(CrptlsError::InvalidSettings("Universal lot relief can't be combined with per-account average cost pooling.".to_string()))
+        }
+    }
+
+    if settings.wash_sale_rule {
+        if settings.lk_treatment_enabled {
+            return Err(CrptlsError::InvalidSettings("The wash sale rule can't be combined with like-kind treatment.".to_string()))
+        }
+        if settings.average_cost_pool.is_some() {
+            return Err(CrptlsError::InvalidSettings("The wash sale rule can't be combined with average cost pooling.".to_string()))
+        }
+        if settings.lot_relief_scope == LotReliefScope::Universal || settings.basis_reallocation_date.is_some() {
+            return Err(CrptlsError::InvalidSettings("The wash sale rule can't be combined with universal lot relief or basis reallocation.".to_string()))
+        }
+    }
+
+    if settings.uk_share_pooling && settings.journal_entry_export {
+        return Err(CrptlsError::InvalidSettings("UK share pooling can't be combined with the journal entries, whose \
+            gains come from the costing method's lots rather than the UK matching rules.".to_string()))
+    }
+
+    if settings.realize_margin_pnl && settings.lk_treatment_enabled {
+        return Err(CrptlsError::InvalidSettings("Realizing margin profit and loss as positions change can't be combined with like-kind treatment.".to_string()))
+    }
 
     let mut transactions_map: HashMap<u32, Transaction> = HashMap::new();
     let mut action_records_map: HashMap<u32, ActionRecord> = HashMap::new();
@@ -52,8 +168,7 @@ pub fn import_and_process_final(
 
     csv_import_accts_txns::import_from_csv(
         input_file_path,
-        settings.input_file_uses_iso_date_style,
-        &settings.input_file_date_separator,
+        settings,
         &mut raw_account_map,
         &mut account_map,
         &mut action_records_map,
@@ -61,30 +176,193 @@ pub fn import_and_process_final(
     )?;
 
     println!("  Successfully imported CSV Input File.");
-    println!("Processing the data...");
 
-    transactions_map = create_lots_mvmts::create_lots_and_movements(
-        &settings,
-        &raw_account_map,
-        &account_map,
-        &action_records_map,
-        transactions_map,
-        // &mut lot_map,
-    )?;
+    for acct_key in settings.costing_method_overrides.keys() {
+        match raw_account_map.get(acct_key) {
+            None => return Err(CrptlsError::InvalidSettings(format!("Costing method override given for account {}, which doesn't exist.", acct_key))),
+            Some(raw_acct) if raw_acct.is_margin => {
+                return Err(CrptlsError::InvalidSettings(format!("Costing method override given for account {}, which is a margin account.", acct_key)))
+            }
+            Some(_raw_acct) => {}
+        }
+    }
 
-    println!("  Created lots and movements.");
+    for num in 1..=transactions_map.len() {
+        let txn = transactions_map.get(&(num as u32)).unwrap();
+        if txn.fee == dec!(0) && txn.fee_leg.is_none() { continue }
+        if settings.lk_treatment_enabled {
+            return Err(CrptlsError::invalid_txn(txn,
+                "The txn has a fee, which can't be combined with like-kind treatment.".to_string()))
+        }
+        if let Some(fee_leg) = &txn.fee_leg {
+            let fee_ar = action_records_map.get(&fee_leg.action_record_key).unwrap();
+            let raw_fee_acct = raw_account_map.get(&fee_ar.account_key).unwrap();
+            if raw_fee_acct.is_margin {
+                return Err(CrptlsError::invalid_txn(txn, "The txn pays its fee from a margin account.".to_string()))
+            }
+        }
+        if txn.transaction_type(&action_records_map, &raw_account_map, &account_map)? != TxType::Exchange
+            || txn.marginness(&action_records_map, &raw_account_map, &account_map) != TxHasMargin::NoARs {
+            return Err(CrptlsError::invalid_txn(txn,
+                "The txn has a fee, but only non-margin exchange transactions may.".to_string()))
+        }
+    }
+
+    for num in 1..=transactions_map.len() {
+        let txn = transactions_map.get(&(num as u32)).unwrap();
+        if txn.margin_charge.is_none() { continue }
+        if settings.lk_treatment_enabled {
+            return Err(CrptlsError::invalid_txn(txn,
+                "The txn has a margin charge, which can't be combined with like-kind treatment.".to_string()))
+        }
+        let charge_ar = action_records_map.get(&txn.action_record_idx_vec[0]).unwrap();
+        let raw_charge_acct = raw_account_map.get(&charge_ar.account_key).unwrap();
+        if txn.action_record_idx_vec.len() != 1 || txn.fee_leg.is_some() || !raw_charge_acct.is_margin_quote() {
+            return Err(CrptlsError::invalid_txn(txn, "The txn has a margin charge, but only a single outgoing \
+                amount from a margin quote account may.".to_string()))
+        }
+    }
+
+    let lot_selections = match &settings.lot_selection_file {
+        Some(path) => {
+            let selections = lot_selection::import_lot_selections(
+                path,
+                &settings.home_currency,
+                &raw_account_map,
+                &account_map,
+                &action_records_map,
+                &transactions_map,
+            )?;
+            println!("  Successfully imported lot selection file.");
+            selections
+        }
+        None => HashMap::new(),
+    };
 
-    import_cost_proceeds_etc::add_cost_basis_to_movements(
+    // Later `transaction`s are dropped only now, so the whole input file (and lot selection file) is still checked.
+    if let Some(as_of_date) = settings.as_of_date {
+        drop_txns_after_as_of_date(as_of_date, &mut action_records_map, &mut transactions_map)?;
+    }
+
+    // Every spot of the input file where an account spends more than it holds is reported at once, rather than
+    // stopping at the first `account` found to have run out of `lot`s.
+    let shortfalls = balance_check::get_balance_shortfalls(
         &settings.home_currency,
         &raw_account_map,
-        &account_map,
         &action_records_map,
-        &transactions_map
-    )?;
+        &transactions_map,
+    );
 
+    if !shortfalls.is_empty() {
+        println!("\nBalance shortfalls (non-margin accounts can't spend more than they hold):");
+        for shortfall in shortfalls.iter() {
+            let raw_acct = raw_account_map.get(&shortfall.acct_key).unwrap();
+            println!("  Account {} ({} {}), line {} of the input file (txn {}) on {}: missing {} {}; running balance {} {}.",
+                raw_acct.account_num,
+                raw_acct.name,
+                raw_acct.ticker,
+                shortfall.csv_line,
+                shortfall.tx_number,
+                shortfall.date,
+                shortfall.amount_missing,
+                raw_acct.ticker,
+                shortfall.running_balance,
+                raw_acct.ticker,
+            );
+        }
+        return Err(CrptlsError::BalanceShortfalls(shortfalls))
+    }
+
+    if settings.realize_margin_pnl {
+        for txn in transactions_map.values_mut() {
+            txn.settles_realized_margin_pnl = txn.action_record_idx_vec.len() == 2
+                && txn.marginness(&action_records_map, &raw_account_map, &account_map) == TxHasMargin::OneAR;
+        }
+    }
+
+    let lot_allocations = match &settings.basis_allocation_file {
+        Some(path) => {
+            let allocations = basis_reallocation::import_lot_allocations(
+                path,
+                &settings.home_currency,
+                settings.home_currency_minor_units,
+                &raw_account_map,
+            )?;
+            println!("  Successfully imported basis allocation file.");
+            allocations
+        }
+        None => [].to_vec(),
+    };
+
+    // The reallocation happens just before the first `transaction` dated on or after the reallocation date.  If there
+    // is none, it happens after the last `transaction` (and is tied to it).  A reallocation date after the as-of date
+    // never arrives.
+    let reallocate_before_txn_num = match (settings.basis_reallocation_date, settings.as_of_date) {
+        (Some(date), Some(as_of_date)) if date > as_of_date => 0,
+        (Some(date), _) => (1..=transactions_map.len() as u32)
+            .find(|txn_num| transactions_map.get(txn_num).unwrap().date >= date)
+            .unwrap_or(transactions_map.len() as u32 + 1),
+        (None, _) => 0,
+    };
+
+    println!("Processing the data...");
+
+    for num in 1..=transactions_map.len() {
+
+        let txn_num = num as u32;
+
+        // The `action record`s of this `transaction`, plus those of any reallocation or swap tied to it
+        let mut ar_keys = transactions_map.get(&txn_num).unwrap().ar_keys_incl_fee_leg();
+
+        if txn_num == reallocate_before_txn_num {
+            ar_keys.extend(reallocate_basis_as_of_date(txn_num, settings, &lot_allocations, &raw_account_map, &account_map, &mut action_records_map)?);
+        }
+
+        let swap_ars = create_lots_mvmts::create_lots_and_movements(
+            txn_num,
+            &settings,
+            &raw_account_map,
+            &account_map,
+            &action_records_map,
+            &transactions_map,
+            &lot_selections,
+            // &mut lot_map,
+        )?;
+
+        // With universal lot relief, `lot`s may have been swapped between `account`s.  Those `movement`s belong
+        // to `action record`s that aren't part of any `transaction`, but they must be found like any other.
+        for swap_ar in swap_ars {
+            ar_keys.push(swap_ar.self_ar_key);
+            action_records_map.insert(swap_ar.self_ar_key, swap_ar);
+        }
+
+        import_cost_proceeds_etc::add_cost_basis_to_movements(
+            txn_num,
+            settings,
+            &raw_account_map,
+            &account_map,
+            &action_records_map,
+            &transactions_map
+        )?;
+
+        if let Some(pool) = &settings.average_cost_pool {
+            record_pool_totals_of_ars(pool, txn_num, &ar_keys, settings, &raw_account_map, &account_map, &action_records_map);
+        }
+    }
+
+    if reallocate_before_txn_num > transactions_map.len() as u32 && !transactions_map.is_empty() {
+        let last_txn_num = transactions_map.len() as u32;
+        let ar_keys = reallocate_basis_as_of_date(last_txn_num, settings, &lot_allocations, &raw_account_map, &account_map, &mut action_records_map)?;
+        if let Some(pool) = &settings.average_cost_pool {
+            record_pool_totals_of_ars(pool, last_txn_num, &ar_keys, settings, &raw_account_map, &account_map, &action_records_map);
+        }
+    }
+
+    println!("  Created lots and movements.");
     println!("  Added cost basis to movements.");
 
     import_cost_proceeds_etc::add_proceeds_to_movements(
+        settings,
         &raw_account_map,
         &account_map,
         &action_records_map,
@@ -93,12 +371,27 @@ pub fn import_and_process_final(
 
     println!("  Added proceeds to movements.");
 
+    if settings.wash_sale_rule {
+
+        wash_sale::apply_wash_sale_treatment(
+            &settings.home_currency,
+            settings.home_currency_minor_units,
+            &raw_account_map,
+            &account_map,
+            &action_records_map,
+            &transactions_map
+        )?;
+
+        println!("  Applied wash sale rule.");
+    }
+
     if settings.lk_treatment_enabled {
 
         println!(" Applying like-kind treatment through cut-off date: {}.", settings.lk_cutoff_date);
 
         import_cost_proceeds_etc::apply_like_kind_treatment(
             &settings.home_currency,
+            settings.home_currency_minor_units,
             settings.lk_cutoff_date,
             &raw_account_map,
             &account_map,
@@ -111,3 +404,87 @@ pub fn import_and_process_final(
 
     Ok((raw_account_map, account_map, action_records_map, transactions_map))
 }
+
+fn reallocate_basis_as_of_date(
+    anchor_txn_num: u32,
+    settings: &ImportProcessParameters,
+    lot_allocations: &[basis_reallocation::LotAllocation],
+    raw_account_map: &HashMap<u16, RawAccount>,
+    account_map: &HashMap<u16, Account>,
+    action_records_map: &mut HashMap<u32, ActionRecord>,
+) -> Result<Vec<u32>, CrptlsError> {
+
+    let date = settings.basis_reallocation_date.unwrap();
+
+    let realloc_ars = basis_reallocation::reallocate_basis(
+        date,
+        anchor_txn_num,
+        settings,
+        lot_allocations,
+        raw_account_map,
+        account_map,
+        action_records_map.keys().max().unwrap() + 1,
+    )?;
+
+    let mut realloc_ar_keys = Vec::with_capacity(realloc_ars.len());
+
+    for realloc_ar in realloc_ars {
+        realloc_ar_keys.push(realloc_ar.self_ar_key);
+        action_records_map.insert(realloc_ar.self_ar_key, realloc_ar);
+    }
+
+    println!("  Reallocated basis across accounts as of {}.", date);
+
+    Ok(realloc_ar_keys)
+}
+
+fn record_pool_totals_of_ars(
+    pool: &AverageCostPool,
+    txn_num: u32,
+    ar_keys: &[u32],
+    settings: &ImportProcessParameters,
+    raw_account_map: &HashMap<u16, RawAccount>,
+    account_map: &HashMap<u16, Account>,
+    action_records_map: &HashMap<u32, ActionRecord>,
+) {
+    let ars: Vec<&ActionRecord> = ar_keys.iter().map(|ar_key| action_records_map.get(ar_key).unwrap()).collect();
+    average_cost::record_pool_totals(pool, txn_num, &ars, &settings.home_currency, raw_account_map, account_map);
+}
+
+/// Removes every `transaction` dated after `as_of_date`, along with its `action record`s.  Since `transaction`s are
+/// numbered in file order, the ones kept must come first, so the input file must be in date order.
+fn drop_txns_after_as_of_date(
+    as_of_date: NaiveDate,
+    action_records_map: &mut HashMap<u32, ActionRecord>,
+    transactions_map: &mut HashMap<u32, Transaction>,
+) -> Result<(), CrptlsError> {
+
+    let txns_count = transactions_map.len() as u32;
+
+    let first_later_txn_num = match (1..=txns_count).find(|num| transactions_map.get(num).unwrap().date > as_of_date) {
+        Some(num) => num,
+        None => return Ok(()),
+    };
+
+    for num in first_later_txn_num..=txns_count {
+        let txn = transactions_map.get(&num).unwrap();
+        if txn.date <= as_of_date {
+            return Err(CrptlsError::invalid_txn(txn, format!(
+                "The txn is dated {}, on or before the as-of date ({}), but it comes after txn {}, which is dated {}. \
+                The input file must be in date order to use an as-of date.",
+                txn.date, as_of_date, first_later_txn_num, transactions_map.get(&first_later_txn_num).unwrap().date,
+            )))
+        }
+    }
+
+    for num in first_later_txn_num..=txns_count {
+        let txn = transactions_map.remove(&num).unwrap();
+        for ar_num in txn.ar_keys_incl_fee_leg().iter() {
+            action_records_map.remove(ar_num);
+        }
+    }
+
+    println!("  Left out the {} txn(s) dated after the as-of date, {}.", txns_count - first_later_txn_num + 1, as_of_date);
+
+    Ok(())
+}
@@ -15,6 +15,12 @@ pub enum InventoryCostingMethod {
     FIFObyLotCreationDate,
     /// 4. FIFO according to the basis date of the lot.
     FIFObyLotBasisDate,
+    /// 5. HIFO according to the per-unit cost basis of the lot.
+    HIFObyLotUnitBasis,
+    /// 6. LOFO according to the per-unit cost basis of the lot.
+    LOFObyLotUnitBasis,
+    /// 7. Tax-optimized: long-term losses first, then short-term losses, then the smallest gains.
+    TaxOptimized,
 }
 
 impl fmt::Display for InventoryCostingMethod {
@@ -25,6 +31,75 @@ impl fmt::Display for InventoryCostingMethod {
            InventoryCostingMethod::LIFObyLotBasisDate => write!(f, "LIFO by lot basis date"),
            InventoryCostingMethod::FIFObyLotCreationDate => write!(f, "FIFO by lot creation date"),
            InventoryCostingMethod::FIFObyLotBasisDate => write!(f, "FIFO by lot basis date"),
+           InventoryCostingMethod::HIFObyLotUnitBasis => write!(f, "HIFO by lot per-unit cost basis"),
+           InventoryCostingMethod::LOFObyLotUnitBasis => write!(f, "LOFO by lot per-unit cost basis"),
+           InventoryCostingMethod::TaxOptimized => write!(f, "Tax-optimized (long-term losses, short-term losses, then smallest gains)"),
        }
     }
-}
\ No newline at end of file
+}
+
+/// An `AverageCostPool` replaces lot-by-lot relief of cost basis with a single pooled (average) cost basis,
+/// as is required for adjusted cost base (ACB) reporting.  `Lot`s are still created and chosen by the
+/// `InventoryCostingMethod`, which continues to determine holding periods.  Since basis is relieved at
+/// the average, the remaining basis of an individual `Lot` is no longer meaningful; the pool's total is.
+#[derive(Clone, Debug, PartialEq)]
+pub enum AverageCostPool {
+    /// Each non-margin `Account` keeps its own pool.
+    Account,
+    /// Every non-margin `Account` with the same ticker shares one pool.
+    Ticker,
+}
+
+impl fmt::Display for AverageCostPool {
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+           AverageCostPool::Account => write!(f, "per account"),
+           AverageCostPool::Ticker => write!(f, "per ticker"),
+       }
+    }
+}
+
+/// The `FeeTreatment` determines what becomes of a trade's fee, whether from the input file's `fee` column or a fee leg.
+/// When the chosen treatment doesn't fit the trade (e.g., there is no acquisition basis to add to in a sale for home
+/// currency, or no disposal to take it from in a purchase with home currency), the fee goes to whichever of basis or
+/// proceeds does fit.  An expense fits any trade.
+#[derive(Clone, Debug, PartialEq)]
+pub enum FeeTreatment {
+    /// The fee is added to the cost basis of what was acquired.
+    AddToBasis,
+    /// The fee is subtracted from the proceeds of what was disposed of.
+    SubtractFromProceeds,
+    /// The fee is booked as an expense, leaving basis and proceeds as they are.
+    Expense,
+}
+
+impl fmt::Display for FeeTreatment {
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+           FeeTreatment::AddToBasis => write!(f, "added to acquisition basis"),
+           FeeTreatment::SubtractFromProceeds => write!(f, "subtracted from proceeds"),
+           FeeTreatment::Expense => write!(f, "booked as an expense"),
+       }
+    }
+}
+
+/// The `LotReliefScope` determines which `Lot`s the `InventoryCostingMethod` chooses from.
+#[derive(Clone, Debug, PartialEq)]
+pub enum LotReliefScope {
+    /// Only the `Lot`s of the `Account` being spent from (i.e., per-wallet tracking).
+    PerAccount,
+    /// The `Lot`s of every non-margin `Account` with the same ticker, treated as one inventory.
+    Universal,
+}
+
+impl fmt::Display for LotReliefScope {
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+           LotReliefScope::PerAccount => write!(f, "per account"),
+           LotReliefScope::Universal => write!(f, "universal (all non-margin accounts with the same ticker)"),
+       }
+    }
+}
@@ -4,16 +4,20 @@
 use std::rc::Rc;
 use std::cell::{RefCell, Ref, Cell};
 use std::collections::HashMap;
-use std::error::Error;
 
+use chrono::NaiveDate;
 use rust_decimal::Decimal;
 use rust_decimal_macros::dec;
 
 use crate::core_functions::ImportProcessParameters;
 use crate::transaction::{Transaction, ActionRecord, TxType, Polarity, TxHasMargin};
 use crate::account::{Account, RawAccount, Lot, Movement};
-use crate::costing_method::InventoryCostingMethod;
-use crate::decimal_utils::round_d128_1e8;
+use crate::costing_method::{InventoryCostingMethod, LotReliefScope};
+use crate::holding_period::HoldingPeriodRule;
+use crate::lot_selection::{LotSelection, LotSelections};
+use crate::decimal_utils::{round_d128_generalized, round_ratio};
+use crate::margin_pnl;
+use crate::errors::CrptlsError;
 
 /// This is probably the most important function in the whole program.  Based on the data in the CSV Input File,
 /// the `account`s and `transaction`s will be created.  Once the `account`s and `transaction`s have been created, both
@@ -46,17 +50,25 @@ use crate::decimal_utils::round_d128_1e8;
 /// Fourth, this function does not contemplate any income/expense/gain/loss at all.  It is solely an exercise in determining
 /// and solidifying how to split (if needed) the amount in each `action record` into `movement`s that post to the appropriate
 /// `lot`s.  Conceptually, each `account` has a list of `lot`s, and each `lot` has a list of `movement`s.
+///
+/// Fifth, this function handles a single `transaction`.  The caller iterates through the `transaction`s in order and adds
+/// cost basis to each `transaction`'s `movement`s before moving onto the next, so every prior `lot` already knows its basis
+/// by the time it is considered here.  The cost-ordered `InventoryCostingMethod`s (HIFO and LOFO) depend on that.
+///
+/// Sixth, with universal `LotReliefScope`, `lot`s may be swapped between `account`s before an outgoing `action record` is
+/// fit into `lot`s.  The `action record`s created for those swaps are returned, and the caller adds them to the map.
 pub(crate) fn create_lots_and_movements(
+    txn_num: u32,
     settings: &ImportProcessParameters,
     raw_acct_map: &HashMap<u16, RawAccount>,
     acct_map: &HashMap<u16, Account>,
     ar_map: &HashMap<u32, ActionRecord>,
-    txns_map: HashMap<u32, Transaction>,
+    txns_map: &HashMap<u32, Transaction>,
+    lot_selections: &LotSelections,
     // lot_map: &HashMap<(RawAccount, u32), Lot>,
-) -> Result<HashMap<u32,Transaction>, Box<dyn Error>> {
+) -> Result<Vec<ActionRecord>, CrptlsError> {
 
     let chosen_home_currency = &settings.home_currency;
-    let chosen_costing_method = &settings.costing_method;
     let enable_lk_treatment = settings.lk_treatment_enabled;
     let like_kind_cutoff_date = settings.lk_cutoff_date;
     let lk_basis_date_preserved = settings.lk_basis_date_preserved;
@@ -64,444 +76,597 @@ pub(crate) fn create_lots_and_movements(
     // This is set automatically based on how like-kind `exchange` `transaction`s work, but it could be left to user choice, in theory.
     let multiple_incoming_mvmts_per_ar_due_to_lk = lk_basis_date_preserved;
 
-    let length = txns_map.len();
-
-    // Transactions are stored in a HashMap, and they are ordered sequentially starting at 1, so we iterate through
-    // that range and use the corresponding `num` to get each transaction.
-    for num in 1..=length {
-
-        let txn_num = num as u32;
-        let txn = txns_map.get(&(txn_num)).expect("Couldn't get txn. Tx num invalid?");
-
-        // The first type of transaction we consider are those where both `action record`s have an `account` that
-        // is a margin `account`.  If so, it is an `exchange` `transaction`.  `Exchange` `transaction`s for margin
-        // `account`s don't create a new lot for every increase.  Rather, it keeps one lot per "close," which is
-        // to say that pair of margin `account` `lot`s will be used until a profit or loss is realized as a result
-        // of zeroing out both the margin `account`s by both closing the margin position AND making a transfer
-        // between the margin quote `account` and the corresponding spot `account` such that both margin `account`s
-        // now have a zero balance.
-        if txn.marginness(&ar_map, &raw_acct_map, &acct_map) == TxHasMargin::TwoARs {
-            assert_eq!(txn.transaction_type(&ar_map, &raw_acct_map, &acct_map)?, TxType::Exchange);
-            assert_eq!(txn.action_record_idx_vec.len(), 2);
-
-            let the_raw_pair_keys = txn.get_base_and_quote_raw_acct_keys(&ar_map, &raw_acct_map, &acct_map)?;
-            let base_acct = acct_map.get(&the_raw_pair_keys.0).expect("Couldn't get acct. Raw pair keys invalid?");
-            let quote_acct = acct_map.get(&the_raw_pair_keys.1).expect("Couldn't get acct. Raw pair keys invalid?");
-
-            // This seems trivial, but there can be a series of buys and sells within a margin trade before the
-            // trade is closed for a profit or loss, so this ensures we know which `action record` is which.
-            let (base_ar_idx, quote_ar_idx) = get_base_and_quote_ar_idxs(
-                the_raw_pair_keys,
-                &txn,
-                &ar_map,
-                &raw_acct_map,
-                &acct_map
-            );
-
-            // Unlike all logic following this `TxHasMargin::TwoARs` section, both `action record`s are handled at once.
-            let base_ar = ar_map.get(&base_ar_idx).unwrap();
-            let quote_ar = ar_map.get(&quote_ar_idx).unwrap();
-
-            let mut base_acct_lot_list = base_acct.list_of_lots.borrow_mut();
-            let mut quote_acct_lot_list = quote_acct.list_of_lots.borrow_mut();
-
-            // The number of `lot`s between the quote and base `account`s should always be equal, and there is
-            // an error in the code if they are not, so it is meant to panic if so.
-            let base_number_of_lots = base_acct_lot_list.len() as u32;
-            let quote_number_of_lots = quote_acct_lot_list.len() as u32;
-            assert_eq!(base_number_of_lots, quote_number_of_lots, "");
-
-            // The value is set just below.  We use this to determine whether to create a new `lot` for each `account`.
-            let acct_balances_are_zero: bool;
-
-            // Though checking both is redundant, we keep the redundancy for code clarity sake.  If true, the implication
-            // is that there has already been activity in the margin `account`s in this `transaction`.
-            if !base_acct_lot_list.is_empty() && !quote_acct_lot_list.is_empty() {
-                // Since we know there has been activity, we set the bool variable above according to whether the `account`
-                // balances are both zero.
-                let base_balance_is_zero = base_acct_lot_list.last().unwrap().get_sum_of_amts_in_lot() == dec!(0);
-                let quote_balance_is_zero = quote_acct_lot_list.last().unwrap().get_sum_of_amts_in_lot() == dec!(0);
-                if base_balance_is_zero && quote_balance_is_zero {
-                    acct_balances_are_zero = true
-                } else {
-                    acct_balances_are_zero = false
-                }
+    let txn = get_txn(txns_map, txn_num)?;
+    let mut swap_ars: Vec<ActionRecord> = [].to_vec();
+
+    // The first type of transaction we consider are those where both `action record`s have an `account` that
+    // is a margin `account`.  If so, it is an `exchange` `transaction`.  `Exchange` `transaction`s for margin
+    // `account`s don't create a new lot for every increase.  Rather, it keeps one lot per "close," which is
+    // to say that pair of margin `account` `lot`s will be used until a profit or loss is realized as a result
+    // of zeroing out both the margin `account`s by both closing the margin position AND making a transfer
+    // between the margin quote `account` and the corresponding spot `account` such that both margin `account`s
+    // now have a zero balance.
+    if txn.marginness(&ar_map, &raw_acct_map, &acct_map) == TxHasMargin::TwoARs {
+        let the_raw_pair_keys = txn.get_base_and_quote_raw_acct_keys(&ar_map, &raw_acct_map, &acct_map)?;
+        let base_acct = get_margin_acct(txn, acct_map, the_raw_pair_keys.0)?;
+        let quote_acct = get_margin_acct(txn, acct_map, the_raw_pair_keys.1)?;
+
+        // This seems trivial, but there can be a series of buys and sells within a margin trade before the
+        // trade is closed for a profit or loss, so this ensures we know which `action record` is which.
+        let (base_ar_idx, quote_ar_idx) = get_base_and_quote_ar_idxs(
+            the_raw_pair_keys,
+            &txn,
+            &ar_map,
+            &raw_acct_map,
+            &acct_map
+        );
 
-            // If there has supposedly been no activity in the two margin `account`s in this `transaction`, we double
-            // check that there are no `lot`s associated with either `account`, and then set the bool variable accordingly.
-            } else {
-                assert_eq!(true, base_acct_lot_list.is_empty(),
-                    "One margin account's list_of_lots is empty, but its pair's isn't.");
-                assert_eq!(true, quote_acct_lot_list.is_empty(),
-                    "One margin account's list_of_lots is empty, but its pair's isn't.");
-                acct_balances_are_zero = true
+        // Unlike all logic following this `TxHasMargin::TwoARs` section, both `action record`s are handled at once.
+        let base_ar = ar_map.get(&base_ar_idx).unwrap();
+        let quote_ar = ar_map.get(&quote_ar_idx).unwrap();
+
+        let mut base_acct_lot_list = base_acct.list_of_lots.borrow_mut();
+        let mut quote_acct_lot_list = quote_acct.list_of_lots.borrow_mut();
+
+        // A quote `account` trades only with its base `account`, but a base `account` may trade with several quote
+        // `account`s.  The base `lot` in use is the one paired with the quote `account`'s latest `lot`.
+        let base_number_of_lots = base_acct_lot_list.len() as u32;
+        let quote_number_of_lots = quote_acct_lot_list.len() as u32;
+        let latest_lot_pair = match quote_acct_lot_list.last() {
+            None => None,
+            Some(quote_lot) => {
+                let base_lot = match base_acct_lot_list.iter().rev()
+                    .find(|base_lot| margin_pnl::lots_are_paired(base_lot, quote_lot)) {
+                    Some(x) => x,
+                    None => return Err(CrptlsError::bad_margin_pair(txn, format!("Margin quote account {} has \
+                        a position that wasn't opened against base account {}.", quote_acct.raw_key, base_acct.raw_key)))
+                };
+                Some((base_lot.clone(), quote_lot.clone()))
             }
+        };
 
-            // The `lot` for each `account` is allocated here, and their values are set within other scopes underneath
-            let base_lot: Rc<Lot>;
-            let quote_lot: Rc<Lot>;
-
-            // If both `account`s have zero balances, new `lot`s are created.  The variables created above will take
-            // the assignment.
-            if acct_balances_are_zero {
-                base_lot = Rc::new(
-                    Lot {
-                        date_as_string: txn.date_as_string.clone(),
-                        date_of_first_mvmt_in_lot: txn.date,
-                        date_for_basis_purposes: txn.date,
-                        lot_number: base_number_of_lots + 1,
-                        account_key: the_raw_pair_keys.0,
-                        movements: RefCell::new([].to_vec()),
-                    }
-                );
-                quote_lot = Rc::new(
-                    Lot {
-                        date_as_string: txn.date_as_string.clone(),
-                        date_of_first_mvmt_in_lot: txn.date,
-                        date_for_basis_purposes: txn.date,
-                        lot_number: quote_number_of_lots + 1,
-                        account_key: the_raw_pair_keys.1,
-                        movements: RefCell::new([].to_vec()),
-                    }
-                );
+        // The value is set just below.  We use this to determine whether to create a new `lot` for each `account`.
+        let acct_balances_are_zero: bool;
 
-            // If at least one `account` has a balance, then each must have at least one `lot`, and those are the `lot`s
-            // that will be assigned to the variables above.  If a `lot` can't be found, the data is wrong or the code is
-            // wrong, and the program should panic.
+        // If true, the implication is that there has already been activity in this pair of margin `account`s.
+        if let Some((base_lot, quote_lot)) = &latest_lot_pair {
+            // Since we know there has been activity, we set the bool variable above according to whether the `lot`
+            // balances are both zero.
+            let base_balance_is_zero = base_lot.get_sum_of_amts_in_lot() == dec!(0);
+            let quote_balance_is_zero = quote_lot.get_sum_of_amts_in_lot() == dec!(0);
+            if base_balance_is_zero && quote_balance_is_zero {
+                acct_balances_are_zero = true
             } else {
-                base_lot = base_acct_lot_list.last().expect("Couldn't get lot. Base acct lot list empty?").clone();
-                quote_lot = quote_acct_lot_list.last().expect("Couldn't get lot. Quote acct lot list empty?").clone();
+                acct_balances_are_zero = false
             }
 
-            // Now that each of the `lot`s is chosen, the `movement`s can be created (which contain the `lot` number)
-            // and pushed onto their respective `lot`s.
-            let base_mvmt = Movement {
-                amount: base_ar.amount,
-                date_as_string: txn.date_as_string.clone(),
-                date: txn.date,
-                transaction_key: txn_num,
-                action_record_key: base_ar_idx,
-                cost_basis: Cell::new(dec!(0.0)),
-                ratio_of_amt_to_incoming_mvmts_in_a_r: dec!(1.0),
-                ratio_of_amt_to_outgoing_mvmts_in_a_r: Cell::new(dec!(1.0)),
-                lot_num: base_lot.lot_number,
-                proceeds: Cell::new(dec!(0.0)),
-                proceeds_lk: Cell::new(dec!(0.0)),
-                cost_basis_lk: Cell::new(dec!(0.0)),
-            };
-            let raw_base_acct = raw_acct_map.get(&base_acct.raw_key).unwrap();
-            wrap_mvmt_and_push(
-                base_mvmt,
-                &base_ar,
-                &base_lot,
-                &chosen_home_currency,
-                &raw_base_acct,
-            );
+        // If there has been no activity in this pair of margin `account`s, new `lot`s are needed.
+        } else {
+            acct_balances_are_zero = true
+        }
 
-            let quote_mvmt = Movement {
-                amount: quote_ar.amount,
-                date_as_string: txn.date_as_string.clone(),
-                date: txn.date,
-                transaction_key: txn_num,
-                action_record_key: quote_ar_idx,
-                cost_basis: Cell::new(dec!(0.0)),
-                ratio_of_amt_to_incoming_mvmts_in_a_r: dec!(1.0),
-                ratio_of_amt_to_outgoing_mvmts_in_a_r: Cell::new(dec!(1.0)),
-                lot_num: quote_lot.lot_number,
-                proceeds: Cell::new(dec!(0.0)),
-                proceeds_lk: Cell::new(dec!(0.0)),
-                cost_basis_lk: Cell::new(dec!(0.0)),
-            };
-            let raw_quote_acct = raw_acct_map.get(&quote_acct.raw_key).unwrap();
-            wrap_mvmt_and_push(
-                quote_mvmt,
-                &quote_ar,
-                &quote_lot,
-                &chosen_home_currency,
-                &raw_quote_acct,
+        // The `lot` for each `account` is allocated here, and their values are set within other scopes underneath
+        let base_lot: Rc<Lot>;
+        let quote_lot: Rc<Lot>;
+
+        // If both `account`s have zero balances, new `lot`s are created.  The variables created above will take
+        // the assignment.
+        if acct_balances_are_zero {
+            base_lot = Rc::new(
+                Lot {
+                    date_as_string: txn.date_as_string.clone(),
+                    date_of_first_mvmt_in_lot: txn.date,
+                    date_for_basis_purposes: txn.date,
+                    lot_number: base_number_of_lots + 1,
+                    account_key: the_raw_pair_keys.0,
+                    movements: RefCell::new([].to_vec()),
+                }
+            );
+            quote_lot = Rc::new(
+                Lot {
+                    date_as_string: txn.date_as_string.clone(),
+                    date_of_first_mvmt_in_lot: txn.date,
+                    date_for_basis_purposes: txn.date,
+                    lot_number: quote_number_of_lots + 1,
+                    account_key: the_raw_pair_keys.1,
+                    movements: RefCell::new([].to_vec()),
+                }
             );
 
-            // Self-explanatory.  If new `lot`s were created, those `lot`s need to be pushed onto the `account`s.
-            if acct_balances_are_zero {
-                base_acct_lot_list.push(base_lot);
-                quote_acct_lot_list.push(quote_lot);
-            }
+        // If at least one `account` has a balance, the `lot` pair found above is still open, and those are the `lot`s
+        // that will be assigned to the variables above.
+        } else {
+            (base_lot, quote_lot) = latest_lot_pair.ok_or_else(|| CrptlsError::bad_margin_pair(txn,
+                "The margin accounts have balances, but no open lot pair holds them.".to_string()))?;
+        }
 
-            // Once the `movement`s have been created and pushed to the appropriate `lot` (and the `lot` pushed to the appropriate
-            // `account` if need be), then the transaction has been processed, and it can move onto the next.
-            continue
+        // Now that each of the `lot`s is chosen, the `movement`s can be created (which contain the `lot` number)
+        // and pushed onto their respective `lot`s.
+        let base_mvmt = Movement {
+            amount: base_ar.amount,
+            date_as_string: txn.date_as_string.clone(),
+            date: txn.date,
+            transaction_key: txn_num,
+            action_record_key: base_ar_idx,
+            cost_basis: Cell::new(dec!(0.0)),
+            ratio_of_amt_to_incoming_mvmts_in_a_r: dec!(1.0),
+            ratio_of_amt_to_outgoing_mvmts_in_a_r: Cell::new(dec!(1.0)),
+            lot_num: base_lot.lot_number,
+            proceeds: Cell::new(dec!(0.0)),
+            proceeds_lk: Cell::new(dec!(0.0)),
+            cost_basis_lk: Cell::new(dec!(0.0)),
+            wash_sale_adjustment: Cell::new(dec!(0.0)),
+            fee_expense: Cell::new(dec!(0)),
+            is_reallocation: false,
+            carried_to_lot_num: Cell::new(None),
+        };
+        let raw_base_acct = raw_acct_map.get(&base_acct.raw_key).unwrap();
+        wrap_mvmt_and_push(
+            base_mvmt,
+            &base_ar,
+            &base_lot,
+            &chosen_home_currency,
+            &raw_base_acct,
+        );
 
-        // If this isn't a margin `exchange` `transaction`, then the lot rules are different, and it continues below.
-        } else {
-            // Unlike all logic above, in the `TxHasMargin::TwoARs` section, each `action record` is handled one at a time.
-            for ar_num in txn.action_record_idx_vec.iter() {
-                let ar = ar_map.get(ar_num).unwrap();
-
-                let acct = acct_map.get(&ar.account_key).unwrap();
-                let raw_acct = raw_acct_map.get(&acct.raw_key).unwrap();
-                let length_of_list_of_lots = acct.list_of_lots.borrow().len();
-
-                // Each home currency `account` contains a single `lot`.  There is no restriction on its balance, unlike for
-                // crypto `account`s which must always have a non-negative balance (except for margin `account`s, where one `account`
-                // must necessarily go negative as the other goes postive).
-                if raw_acct.is_home_currency(&chosen_home_currency) {
-                    let lot;
-                    let new_lot_created;
-
-                    // If there is no `lot`, create a new one.  If there is one, use it.
-                    if length_of_list_of_lots == 0 {
-                        lot = Rc::new(
-                            Lot {
-                                date_as_string: txn.date_as_string.clone(),
-                                date_of_first_mvmt_in_lot: txn.date,
-                                date_for_basis_purposes: txn.date,
-                                lot_number: 1,
-                                account_key: acct.raw_key,
-                                movements: RefCell::new([].to_vec()),
-                            }
-                        );
-                        new_lot_created = true;
-                    }
-                    else {
-                        assert_eq!(1, length_of_list_of_lots); //  Only true for home currency
-                        lot = acct.list_of_lots.borrow_mut()[0 as usize].clone();
-                        new_lot_created = false;
-                    }
+        let quote_mvmt = Movement {
+            amount: quote_ar.amount,
+            date_as_string: txn.date_as_string.clone(),
+            date: txn.date,
+            transaction_key: txn_num,
+            action_record_key: quote_ar_idx,
+            cost_basis: Cell::new(dec!(0.0)),
+            ratio_of_amt_to_incoming_mvmts_in_a_r: dec!(1.0),
+            ratio_of_amt_to_outgoing_mvmts_in_a_r: Cell::new(dec!(1.0)),
+            lot_num: quote_lot.lot_number,
+            proceeds: Cell::new(dec!(0.0)),
+            proceeds_lk: Cell::new(dec!(0.0)),
+            cost_basis_lk: Cell::new(dec!(0.0)),
+            wash_sale_adjustment: Cell::new(dec!(0.0)),
+            fee_expense: Cell::new(dec!(0)),
+            is_reallocation: false,
+            carried_to_lot_num: Cell::new(None),
+        };
+        let raw_quote_acct = raw_acct_map.get(&quote_acct.raw_key).unwrap();
+        wrap_mvmt_and_push(
+            quote_mvmt,
+            &quote_ar,
+            &quote_lot,
+            &chosen_home_currency,
+            &raw_quote_acct,
+        );
 
-                    // Then create the movement and push it.
-                    let whole_mvmt = Movement {
-                        amount: ar.amount,
-                        date_as_string: txn.date_as_string.clone(),
-                        date: txn.date,
-                        transaction_key: txn_num,
-                        action_record_key: *ar_num,
-                        cost_basis: Cell::new(dec!(0.0)),
-                        ratio_of_amt_to_incoming_mvmts_in_a_r: dec!(1.0),
-                        ratio_of_amt_to_outgoing_mvmts_in_a_r: Cell::new(dec!(1.0)),
-                        lot_num: lot.lot_number,
-                        proceeds: Cell::new(dec!(0.0)),
-                        proceeds_lk: Cell::new(dec!(0.0)),
-                        cost_basis_lk: Cell::new(dec!(0.0)),
-                    };
-                    wrap_mvmt_and_push(
-                        whole_mvmt,
-                        &ar,
-                        &lot,
-                        &chosen_home_currency,
-                        &raw_acct,
-                    );
+        // Self-explanatory.  If new `lot`s were created, those `lot`s need to be pushed onto the `account`s.
+        if acct_balances_are_zero {
+            base_acct_lot_list.push(base_lot);
+            quote_acct_lot_list.push(quote_lot);
+        }
 
-                    // If there is a new `lot`, push it onto the `account`
-                    if new_lot_created { acct.list_of_lots.borrow_mut().push(lot); }
+        // Once the `movement`s have been created and pushed to the appropriate `lot` (and the `lot` pushed to the appropriate
+        // `account` if need be), then the transaction has been processed, and it can move onto the next.
+        check_mvmt_amounts_are_at_precision(txn, &swap_ars, ar_map, raw_acct_map)?;
+        return Ok(swap_ars)
 
-                    // Whether incoming or outgoing, the home currency `action record` is now recorded correctly, and then
-                    // onto the next `action record` or `transaction`.
-                    continue
+    // If this isn't a margin `exchange` `transaction`, then the lot rules are different, and it continues below.
+    } else {
+        // Unlike all logic above, in the `TxHasMargin::TwoARs` section, each `action record` is handled one at a time.
+        // A fee leg comes last, and it is spent from its `account`'s `lot`s like any other outgoing `action record`.
+        for ar_num in txn.ar_keys_incl_fee_leg().iter() {
+            let ar = ar_map.get(ar_num).unwrap();
+
+            let acct = acct_map.get(&ar.account_key).unwrap();
+            let raw_acct = raw_acct_map.get(&acct.raw_key).unwrap();
+            let length_of_list_of_lots = acct.list_of_lots.borrow().len();
+
+            // Each home currency `account` contains a single `lot`.  There is no restriction on its balance, unlike for
+            // crypto `account`s which must always have a non-negative balance (except for margin `account`s, where one `account`
+            // must necessarily go negative as the other goes postive).
+            if raw_acct.is_home_currency(&chosen_home_currency) {
+                let lot;
+                let new_lot_created;
+
+                // If there is no `lot`, create a new one.  If there is one, use it.
+                if length_of_list_of_lots == 0 {
+                    lot = Rc::new(
+                        Lot {
+                            date_as_string: txn.date_as_string.clone(),
+                            date_of_first_mvmt_in_lot: txn.date,
+                            date_for_basis_purposes: txn.date,
+                            lot_number: 1,
+                            account_key: acct.raw_key,
+                            movements: RefCell::new([].to_vec()),
+                        }
+                    );
+                    new_lot_created = true;
+                }
+                else {
+                    //  Only true for home currency
+                    if length_of_list_of_lots != 1 {
+                        return Err(CrptlsError::invalid_txn(txn, format!("Home currency account {} \
+                            should hold a single lot, but holds {}.", raw_acct.account_num, length_of_list_of_lots)))
+                    }
+                    lot = acct.list_of_lots.borrow_mut()[0 as usize].clone();
+                    new_lot_created = false;
                 }
-                // Below here, every `action record`'s `account` is not home currency, so the program must know whether
-                // `action record` is incoming/outgoing and whether the `transaction` `TxType` is `Exchange`/`ToSelf`/`Flow`.
-                let polarity = ar.direction();
-                let tx_type = txn.transaction_type(&ar_map, &raw_acct_map, &acct_map)?;
-
-                // The `action record` handling is different depending on whether it's incoming or outgoing.
-                match polarity {
-                    Polarity::Outgoing => {
-                        // println!("Txn: {}, outgoing {:?}-type of {} {}",
-                            // txn.tx_number, txn.transaction_type(), ar.amount, acct.ticker);
-                        //
-                        // For an outgoing `action record` with a margin `account`, it can be deduced that there is a corresponding
-                        // incoming `action record` with a non-margin `account.`  This setup (two `action record`s where one's `account`
-                        // is home currency and the other's isn't) is referred to in this context as a dual-`action record` `flow`
-                        // `transaction`. In this case (with an outgoing `action record` with the margin `account`), it is a margin
-                        // profit `transaction` since the corresponding incoming `action record` increases a non-margin `account` balance.
-                        //
-                        // In order to withdraw margin profits, the margin base `account` must have a zero balance, and the margin quote
-                        // `account` must have a positive balance. We know, therefore, that the `account` of this `action record` is the
-                        // quote `account`, and the `lot` treatment is simple. A single `movement` posts to the active `lot` in this
-                        // `account`, presumably (but not definitely) zeroing it out.
-                        if raw_acct.is_margin {
-                            let this_acct = acct_map.get(&ar.account_key).unwrap();
-                            let lot = this_acct.list_of_lots.borrow().last()
-                                .expect("Couldn't get lot. Acct lot list empty?").clone();
-                            let whole_mvmt = Movement {
-                                amount: ar.amount,
-                                date_as_string: txn.date_as_string.clone(),
-                                date: txn.date,
-                                transaction_key: txn_num,
-                                action_record_key: *ar_num,
-                                cost_basis: Cell::new(dec!(0.0)),
-                                ratio_of_amt_to_incoming_mvmts_in_a_r: dec!(1.0),
-                                ratio_of_amt_to_outgoing_mvmts_in_a_r: Cell::new(dec!(1.0)),
-                                lot_num: lot.lot_number,
-                                proceeds: Cell::new(dec!(0.0)),
-                                proceeds_lk: Cell::new(dec!(0.0)),
-                                cost_basis_lk: Cell::new(dec!(0.0)),
-                            };
-                            wrap_mvmt_and_push(
-                                whole_mvmt,
-                                &ar,
-                                &lot,
-                                &chosen_home_currency,
-                                &raw_acct,
-                            );
-                            continue
 
-                        // For an outgoing `action record` with a non-margin `account`, this is where it is determined how to split
-                        // the amount (if needed) into `movements` that "fit into" `lot`s.
-                        } else {
+                // Then create the movement and push it.
+                let whole_mvmt = Movement {
+                    amount: ar.amount,
+                    date_as_string: txn.date_as_string.clone(),
+                    date: txn.date,
+                    transaction_key: txn_num,
+                    action_record_key: *ar_num,
+                    cost_basis: Cell::new(dec!(0.0)),
+                    ratio_of_amt_to_incoming_mvmts_in_a_r: dec!(1.0),
+                    ratio_of_amt_to_outgoing_mvmts_in_a_r: Cell::new(dec!(1.0)),
+                    lot_num: lot.lot_number,
+                    proceeds: Cell::new(dec!(0.0)),
+                    proceeds_lk: Cell::new(dec!(0.0)),
+                    cost_basis_lk: Cell::new(dec!(0.0)),
+                    wash_sale_adjustment: Cell::new(dec!(0.0)),
+                    fee_expense: Cell::new(dec!(0)),
+                    is_reallocation: false,
+                    carried_to_lot_num: Cell::new(None),
+                };
+                wrap_mvmt_and_push(
+                    whole_mvmt,
+                    &ar,
+                    &lot,
+                    &chosen_home_currency,
+                    &raw_acct,
+                );
 
-                            if acct.list_of_lots.borrow().len() == 0 {
-                                println!("FATAL: There are zero lots to spend from in transaction:\n{:#?}",txn);
-                                std::process::exit(1);
+                // If there is a new `lot`, push it onto the `account`
+                if new_lot_created { acct.list_of_lots.borrow_mut().push(lot); }
+
+                // Whether incoming or outgoing, the home currency `action record` is now recorded correctly, and then
+                // onto the next `action record` or `transaction`.
+                continue
+            }
+            // Below here, every `action record`'s `account` is not home currency, so the program must know whether
+            // `action record` is incoming/outgoing and whether the `transaction` `TxType` is `Exchange`/`ToSelf`/`Flow`.
+            let polarity = ar.direction();
+            let tx_type = txn.transaction_type(&ar_map, &raw_acct_map, &acct_map)?;
+
+            // The `action record` handling is different depending on whether it's incoming or outgoing.
+            match polarity {
+                Polarity::Outgoing => {
+                    // println!("Txn: {}, outgoing {:?}-type of {} {}",
+                        // txn.tx_number, txn.transaction_type(), ar.amount, acct.ticker);
+                    //
+                    // For an outgoing `action record` with a margin `account`, it can be deduced that there is a corresponding
+                    // incoming `action record` with a non-margin `account.`  This setup (two `action record`s where one's `account`
+                    // is home currency and the other's isn't) is referred to in this context as a dual-`action record` `flow`
+                    // `transaction`. In this case (with an outgoing `action record` with the margin `account`), it is a margin
+                    // profit `transaction` since the corresponding incoming `action record` increases a non-margin `account` balance.
+                    //
+                    // In order to withdraw margin profits, the margin base `account` must have a zero balance, and the margin quote
+                    // `account` must have a positive balance. We know, therefore, that the `account` of this `action record` is the
+                    // quote `account`, and the `lot` treatment is simple. A single `movement` posts to the active `lot` in this
+                    // `account`, presumably (but not definitely) zeroing it out.
+                    if raw_acct.is_margin {
+                        let this_acct = acct_map.get(&ar.account_key).unwrap();
+                        let lot = match this_acct.list_of_lots.borrow().last() {
+                            Some(x) => x.clone(),
+                            None => return Err(CrptlsError::invalid_txn(txn, format!("The txn spends from margin \
+                                account {}, which has no position yet.", raw_acct.account_num)))
+                        };
+                        let whole_mvmt = Movement {
+                            amount: ar.amount,
+                            date_as_string: txn.date_as_string.clone(),
+                            date: txn.date,
+                            transaction_key: txn_num,
+                            action_record_key: *ar_num,
+                            cost_basis: Cell::new(dec!(0.0)),
+                            ratio_of_amt_to_incoming_mvmts_in_a_r: dec!(1.0),
+                            ratio_of_amt_to_outgoing_mvmts_in_a_r: Cell::new(dec!(1.0)),
+                            lot_num: lot.lot_number,
+                            proceeds: Cell::new(dec!(0.0)),
+                            proceeds_lk: Cell::new(dec!(0.0)),
+                            cost_basis_lk: Cell::new(dec!(0.0)),
+                            wash_sale_adjustment: Cell::new(dec!(0.0)),
+                            fee_expense: Cell::new(dec!(0)),
+                            is_reallocation: false,
+                            carried_to_lot_num: Cell::new(None),
+                        };
+                        wrap_mvmt_and_push(
+                            whole_mvmt,
+                            &ar,
+                            &lot,
+                            &chosen_home_currency,
+                            &raw_acct,
+                        );
+                        continue
+
+                    // For an outgoing `action record` with a non-margin `account`, this is where it is determined how to split
+                    // the amount (if needed) into `movements` that "fit into" `lot`s.
+                    } else {
+
+                        if acct.list_of_lots.borrow().len() == 0 {
+                            return Err(CrptlsError::InsufficientLots {
+                                tx_number: txn_num,
+                                csv_line: txn.csv_line,
+                                acct_key: raw_acct.account_num,
+                                ticker: raw_acct.ticker.clone(),
+                                amount: -ar.amount,
+                                balance: dec!(0),
+                            })
+                        }
+
+                        // If the user specifically identified `lot`s for this `action record` in the lot selection file,
+                        // those are consumed first, in the order given, before the chosen costing method takes over.
+                        let selected_lots = match lot_selections.get(ar_num) {
+                            Some(selections) => selections.as_slice(),
+                            None => &[],
+                        };
+
+                        // With universal lot relief, `lot`s chosen from other `account`s are swapped into this one first,
+                        // and the order in which to spend from this `account`'s `lot`s comes back with the swap.  A `ToSelf`
+                        // `transaction` isn't a disposal, so it always spends from the `account`'s own `lot`s.  Once basis
+                        // has been reallocated across `account`s, relief is per account from then on.
+                        let before_reallocation = match settings.basis_reallocation_date {
+                            Some(date) => txn.date < date,
+                            None => true,
+                        };
+                        let unit_proceeds = get_unit_proceeds(txn, ar, settings, ar_map, raw_acct_map, acct_map);
+                        let mut universal_index_values = None;
+                        if settings.lot_relief_scope == LotReliefScope::Universal
+                            && tx_type != TxType::ToSelf
+                            && before_reallocation {
+                            let first_swap_ar_key = ar_map.keys().max().unwrap() + swap_ars.len() as u32 + 1;
+                            if let Some((index_values, ars_for_swap)) = swap_in_lots_from_other_accts(
+                                txn,
+                                ar,
+                                selected_lots,
+                                unit_proceeds,
+                                &UniversalRelief { settings, raw_acct_map, acct_map },
+                                first_swap_ar_key,
+                            ) {
+                                universal_index_values = Some(index_values);
+                                swap_ars.extend(ars_for_swap);
                             }
+                        }
 
-                            let list_of_lots_to_use = acct.list_of_lots.clone();
-
-                            //  The following returns a Vec to be iterated from beginning to end. It provides the index for the desired `lot`.
-                            let vec_of_ordered_index_values = match chosen_costing_method {
-                                InventoryCostingMethod::LIFObyLotCreationDate => {
-                                    get_lifo_by_creation_date(&list_of_lots_to_use.borrow())}
-                                InventoryCostingMethod::LIFObyLotBasisDate => {
-                                    get_lifo_by_lot_basis_date(&list_of_lots_to_use.borrow())}
-                                InventoryCostingMethod::FIFObyLotCreationDate => {
-                                    get_fifo_by_creation_date(&list_of_lots_to_use.borrow())}
-                                InventoryCostingMethod::FIFObyLotBasisDate => {
-                                    get_fifo_by_lot_basis_date(&list_of_lots_to_use.borrow())}
-                            };
-
-                            assert_eq!(vec_of_ordered_index_values.len(), list_of_lots_to_use.borrow().len());
-
-                            fn get_lifo_by_creation_date(list_of_lots: &Ref<Vec<Rc<Lot>>>) -> Vec<usize> {
-                                let mut vec_of_indexes = [].to_vec(); // TODO: Add with_capacity()
-                                for (idx, _lot) in list_of_lots.iter().enumerate() {
-                                    vec_of_indexes.insert(0, idx)
-                                }
-                                vec_of_indexes
+                        let list_of_lots_to_use = acct.list_of_lots.clone();
+
+                        //  The following returns a Vec to be iterated from beginning to end. It provides the index for the desired `lot`.
+                        let vec_of_ordered_index_values = match settings.costing_method_for(acct.raw_key, txn.date) {
+                            InventoryCostingMethod::LIFObyLotCreationDate => {
+                                get_lifo_by_creation_date(&list_of_lots_to_use.borrow())}
+                            InventoryCostingMethod::LIFObyLotBasisDate => {
+                                get_lifo_by_lot_basis_date(&list_of_lots_to_use.borrow())}
+                            InventoryCostingMethod::FIFObyLotCreationDate => {
+                                get_fifo_by_creation_date(&list_of_lots_to_use.borrow())}
+                            InventoryCostingMethod::FIFObyLotBasisDate => {
+                                get_fifo_by_lot_basis_date(&list_of_lots_to_use.borrow())}
+                            InventoryCostingMethod::HIFObyLotUnitBasis => {
+                                get_hifo_by_lot_unit_basis(&list_of_lots_to_use.borrow())}
+                            InventoryCostingMethod::LOFObyLotUnitBasis => {
+                                get_lofo_by_lot_unit_basis(&list_of_lots_to_use.borrow())}
+                            InventoryCostingMethod::TaxOptimized => {
+                                get_tax_optimized_order(&list_of_lots_to_use.borrow(), unit_proceeds, txn.date, &settings.holding_period_rule)}
+                        };
+                        let vec_of_ordered_index_values = universal_index_values.unwrap_or(vec_of_ordered_index_values);
+
+                        if vec_of_ordered_index_values.len() != list_of_lots_to_use.borrow().len() {
+                            return Err(CrptlsError::invalid_txn(txn, "The costing method didn't order every lot \
+                                in the account.".to_string()))
+                        }
+
+                        fn get_lifo_by_creation_date(list_of_lots: &Ref<Vec<Rc<Lot>>>) -> Vec<usize> {
+                            let mut vec_of_indexes = [].to_vec(); // TODO: Add with_capacity()
+                            for (idx, _lot) in list_of_lots.iter().enumerate() {
+                                vec_of_indexes.insert(0, idx)
                             }
+                            vec_of_indexes
+                        }
 
-                            #[allow(suspicious_double_ref_op)]
-                            fn get_lifo_by_lot_basis_date(list_of_lots: &Ref<Vec<Rc<Lot>>>) -> Vec<usize> {
-                                let mut reordered_vec = list_of_lots.clone().to_vec();
-                                let length = reordered_vec.len();
-                                for _ in 0..length {
-                                    for j in 0..length-1 {
-                                        if reordered_vec[j].date_for_basis_purposes > reordered_vec[j+1].date_for_basis_purposes {
-                                            reordered_vec.swap(j, j+1)
-                                        }
+                        #[allow(suspicious_double_ref_op)]
+                        fn get_lifo_by_lot_basis_date(list_of_lots: &Ref<Vec<Rc<Lot>>>) -> Vec<usize> {
+                            let mut reordered_vec = list_of_lots.clone().to_vec();
+                            let length = reordered_vec.len();
+                            for _ in 0..length {
+                                for j in 0..length-1 {
+                                    if reordered_vec[j].date_for_basis_purposes > reordered_vec[j+1].date_for_basis_purposes {
+                                        reordered_vec.swap(j, j+1)
                                     }
                                 }
-                                let mut vec_of_indexes = [].to_vec();
-                                for (idx, _lot) in reordered_vec.iter().enumerate() {
-                                    vec_of_indexes.insert(0, idx)
-                                }
-                                vec_of_indexes
                             }
+                            let mut vec_of_indexes = [].to_vec();
+                            for (idx, _lot) in reordered_vec.iter().enumerate() {
+                                vec_of_indexes.insert(0, idx)
+                            }
+                            vec_of_indexes
+                        }
 
-                            fn get_fifo_by_creation_date(list_of_lots: &Ref<Vec<Rc<Lot>>>) -> Vec<usize> {
-                                let mut vec_of_indexes = [].to_vec();
-                                for (idx, _lot) in list_of_lots.iter().enumerate() {
-                                    vec_of_indexes.push(idx)
-                                }
-                                vec_of_indexes
+                        fn get_fifo_by_creation_date(list_of_lots: &Ref<Vec<Rc<Lot>>>) -> Vec<usize> {
+                            let mut vec_of_indexes = [].to_vec();
+                            for (idx, _lot) in list_of_lots.iter().enumerate() {
+                                vec_of_indexes.push(idx)
                             }
+                            vec_of_indexes
+                        }
 
-                            #[allow(suspicious_double_ref_op)]
-                            fn get_fifo_by_lot_basis_date(list_of_lots: &Ref<Vec<Rc<Lot>>>) -> Vec<usize> {
-                                let mut reordered_vec = list_of_lots.clone().to_vec();
-                                let length = reordered_vec.len();
-                                for _ in 0..length {
-                                    for j in 0..length-1 {
-                                        if reordered_vec[j].date_for_basis_purposes > reordered_vec[j+1].date_for_basis_purposes {
-                                            reordered_vec.swap(j, j+1)
-                                        }
+                        #[allow(suspicious_double_ref_op)]
+                        fn get_fifo_by_lot_basis_date(list_of_lots: &Ref<Vec<Rc<Lot>>>) -> Vec<usize> {
+                            let mut reordered_vec = list_of_lots.clone().to_vec();
+                            let length = reordered_vec.len();
+                            for _ in 0..length {
+                                for j in 0..length-1 {
+                                    if reordered_vec[j].date_for_basis_purposes > reordered_vec[j+1].date_for_basis_purposes {
+                                        reordered_vec.swap(j, j+1)
                                     }
                                 }
-                                let mut vec_of_indexes = [].to_vec();
-                                for (idx, _lot) in reordered_vec.iter().enumerate() {
-                                    vec_of_indexes.push(idx)
-                                }
-                                vec_of_indexes
                             }
+                            let mut vec_of_indexes = [].to_vec();
+                            for (idx, _lot) in reordered_vec.iter().enumerate() {
+                                vec_of_indexes.push(idx)
+                            }
+                            vec_of_indexes
+                        }
+
+                        // The per-unit basis of every prior `lot` is already known, because basis is added to each
+                        // `transaction`'s `movement`s before the next `transaction` is processed.  Ties keep creation order.
+                        fn get_hifo_by_lot_unit_basis(list_of_lots: &Ref<Vec<Rc<Lot>>>) -> Vec<usize> {
+                            let mut vec_of_indexes: Vec<usize> = (0..list_of_lots.len()).collect();
+                            vec_of_indexes.sort_by(|a, b| {
+                                list_of_lots[*b].get_unit_cost_basis().cmp(&list_of_lots[*a].get_unit_cost_basis())
+                            });
+                            vec_of_indexes
+                        }
 
-                            // TODO: Consider whether a for-loop can track the index more cleanly
-                            // Now that the index values of each `lot` are in the appropriate order, the starting point (index 0)
-                            // and the starting lot_index can be chosen in preparation for the recursive `fit_into_lots` function.
-                            // If the tentative `movement` must be reduced to fit into the tentative `lot`, a revised `movement` will be created
-                            // using an amount that will be reduced to the exact amount to fit into the `lot`.  After the revised `movement`
-                            // is pushed to the `lot`, the index position will be incremented to provide a new `lot_index`, a new tentative
-                            // `lot` will be chosen, and the remainder of the amount will be used in a new tentative `movement`, and so on
-                            // until the entire `action record` amount has been put into a `movement` and posted to a `lot`.
-                            let index_position: usize = 0;
-                            let lot_index = vec_of_ordered_index_values[index_position];
-
-                            // Now that the tentative `lot` can be chosen, it is, and a tentative `movement` is created.
-                            let lot_to_use = list_of_lots_to_use.borrow()[lot_index].clone();
-                            let whole_mvmt = Movement {
-                                amount: ar.amount,
-                                date_as_string: txn.date_as_string.clone(),
-                                date: txn.date,
-                                transaction_key: txn_num,
-                                action_record_key: *ar_num,
-                                cost_basis: Cell::new(dec!(0.0)),
-                                ratio_of_amt_to_incoming_mvmts_in_a_r: dec!(1.0),
-                                ratio_of_amt_to_outgoing_mvmts_in_a_r: Cell::new(dec!(1.0)),
-                                lot_num: lot_to_use.lot_number,
-                                proceeds: Cell::new(dec!(0.0)),
-                                proceeds_lk: Cell::new(dec!(0.0)),
-                                cost_basis_lk: Cell::new(dec!(0.0)),
-                            };
-
-                            // Just a last minute check that a home currency `action record` isn't being handled here
-                            assert_eq!(raw_acct.is_home_currency(&chosen_home_currency), false);
-
-                            // Beginning here, it will recursively attempt to fit the outgoing amount into `lot`s.
+                        fn get_lofo_by_lot_unit_basis(list_of_lots: &Ref<Vec<Rc<Lot>>>) -> Vec<usize> {
+                            let mut vec_of_indexes: Vec<usize> = (0..list_of_lots.len()).collect();
+                            vec_of_indexes.sort_by(|a, b| {
+                                list_of_lots[*a].get_unit_cost_basis().cmp(&list_of_lots[*b].get_unit_cost_basis())
+                            });
+                            vec_of_indexes
+                        }
+
+                        // TODO: Consider whether a for-loop can track the index more cleanly
+                        // Now that the index values of each `lot` are in the appropriate order, the starting point (index 0)
+                        // and the starting lot_index can be chosen in preparation for the recursive `fit_into_lots` function.
+                        // If the tentative `movement` must be reduced to fit into the tentative `lot`, a revised `movement` will be created
+                        // using an amount that will be reduced to the exact amount to fit into the `lot`.  After the revised `movement`
+                        // is pushed to the `lot`, the index position will be incremented to provide a new `lot_index`, a new tentative
+                        // `lot` will be chosen, and the remainder of the amount will be used in a new tentative `movement`, and so on
+                        // until the entire `action record` amount has been put into a `movement` and posted to a `lot`.
+                        let index_position: usize = 0;
+                        let lot_index = vec_of_ordered_index_values[index_position];
+
+                        // Now that the tentative `lot` can be chosen, it is, and a tentative `movement` is created.
+                        let lot_to_use = list_of_lots_to_use.borrow()[lot_index].clone();
+                        let whole_mvmt = Movement {
+                            amount: ar.amount,
+                            date_as_string: txn.date_as_string.clone(),
+                            date: txn.date,
+                            transaction_key: txn_num,
+                            action_record_key: *ar_num,
+                            cost_basis: Cell::new(dec!(0.0)),
+                            ratio_of_amt_to_incoming_mvmts_in_a_r: dec!(1.0),
+                            ratio_of_amt_to_outgoing_mvmts_in_a_r: Cell::new(dec!(1.0)),
+                            lot_num: lot_to_use.lot_number,
+                            proceeds: Cell::new(dec!(0.0)),
+                            proceeds_lk: Cell::new(dec!(0.0)),
+                            cost_basis_lk: Cell::new(dec!(0.0)),
+                            wash_sale_adjustment: Cell::new(dec!(0.0)),
+                            fee_expense: Cell::new(dec!(0)),
+                            is_reallocation: false,
+                            carried_to_lot_num: Cell::new(None),
+                        };
+
+                        // Just a last minute check that a home currency `action record` isn't being handled here
+                        if raw_acct.is_home_currency(&chosen_home_currency) {
+                            return Err(CrptlsError::invalid_txn(txn, "A home currency action record \
+                                can't be fit into lots.".to_string()))
+                        }
+
+                        let fit = FitContext { txn, ar, raw_acct, acct, chosen_home_currency: &chosen_home_currency };
+
+                        // The specifically identified `lot`s come first, and the lot selection file may fully account
+                        // for the `action record`'s amount.
+                        let amt_to_fit = fit_into_selected_lots(&fit, &whole_mvmt, ar.amount, selected_lots)?;
+
+                        // Beginning here, it will recursively attempt to fit the outgoing amount into `lot`s.
+                        if amt_to_fit != dec!(0.0) {
                             fit_into_lots(
+                                &fit,
                                 whole_mvmt,
-                                ar.amount,
-                                list_of_lots_to_use,
+                                amt_to_fit,
                                 vec_of_ordered_index_values,
                                 index_position,
-                                &chosen_home_currency,
-                                &ar,
-                                &raw_acct,
-                                &acct,
-                            );
-
-                            // Once the `action record`'s outgoing amount has been "consumed", the recording of this
-                            // `action record` is complete.
-                            continue
+                            )?;
                         }
+
+                        // Once the `action record`'s outgoing amount has been "consumed", the recording of this
+                        // `action record` is complete.
+                        continue
                     }
+                }
+
+                // Incoming `action records` have different requirements for posting to `lot`s.  Unlike for outgoing
+                // `action records`, there is often no need to consider how to fit these into lots because in most cases
+                // the amount of an incoming `action record` will be in a single movement that posts to a new `lot`.
+                // There are three exceptions to this which add many lines of code that aren't terribly easy to read. :)
+                // Exception #1: `ToSelf` transactions.  Cost basis and basis date must be preserved for currency
+                // owned by the user and transferred to another one of their accounts.
+                // Exception #2: Like-kind `exchange` `transaction`s must preserve the basis and the basis date
+                // of the corresponding outgoing `action record`.
+                // Exception #3: Dual-`action record` `flow` `transaction`s that occur during a period of like-kind
+                // `exchange` will also inherit an implied/imputed basis date based on the date of the 'buys' in the
+                // base margin `account`.  The special treatment occurs for an incoming `flow` `action record` whose
+                // `account` is non-margin.
+                Polarity::Incoming => {
+                    // println!("Txn: {}, Incoming {:?}-type of {} {}",
+                    //     txn.tx_number, txn.transaction_type(), ar.amount, acct.ticker);
+                    match tx_type {
+                        TxType::Flow => {
+                            let lot: Rc<Lot>;
+                            let mvmt: Movement;
+                            // For an incoming `flow` `action record` with a margin account, the implication is that
+                            // this is a margin loss `transaction`.  The corresponding outgoing `flow` `action record`
+                            // is where the loss is reflected.  This `action record` is simply reflecting the transfer
+                            // of funds into the quote margin account, presumably paying off the loan and bringing it
+                            // to a zero balance.
+                            if raw_acct.is_margin {
+                                let this_acct = acct_map.get(&ar.account_key).unwrap();
+                                let lot_list = this_acct.list_of_lots.borrow_mut();
+                                lot = match lot_list.last() {
+                                    Some(lot) => lot.clone(),
+                                    None => return Err(CrptlsError::invalid_txn(txn, format!("The txn flows into \
+                                        margin account {}, which has no position.", raw_acct.account_num)))
+                                };
+
+                                mvmt = Movement {
+                                    amount: ar.amount,
+                                    date_as_string: txn.date_as_string.clone(),
+                                    date: txn.date,
+                                    transaction_key: txn_num,
+                                    action_record_key: *ar_num,
+                                    cost_basis: Cell::new(dec!(0.0)),
+                                    ratio_of_amt_to_incoming_mvmts_in_a_r: dec!(1.0),
+                                    ratio_of_amt_to_outgoing_mvmts_in_a_r: Cell::new(dec!(1.0)),
+                                    lot_num: lot.lot_number,
+                                    proceeds: Cell::new(dec!(0.0)),
+                                    proceeds_lk: Cell::new(dec!(0.0)),
+                                    cost_basis_lk: Cell::new(dec!(0.0)),
+                                    wash_sale_adjustment: Cell::new(dec!(0.0)),
+                                    fee_expense: Cell::new(dec!(0)),
+                                    is_reallocation: false,
+                                    carried_to_lot_num: Cell::new(None),
+                                };
+                                wrap_mvmt_and_push(
+                                    mvmt,
+                                    &ar,
+                                    &lot,
+                                    &chosen_home_currency,
+                                    &raw_acct,
+                                );
+
+                                // Since a margin account is being posted new, a new lot is not created.  Once the `movement`
+                                // has been pushed to the `lot`, the recording of the `action record` is complete, and it's
+                                // onto the next
+                                continue
 
-                    // Incoming `action records` have different requirements for posting to `lot`s.  Unlike for outgoing
-                    // `action records`, there is often no need to consider how to fit these into lots because in most cases
-                    // the amount of an incoming `action record` will be in a single movement that posts to a new `lot`.
-                    // There are three exceptions to this which add many lines of code that aren't terribly easy to read. :)
-                    // Exception #1: `ToSelf` transactions.  Cost basis and basis date must be preserved for currency
-                    // owned by the user and transferred to another one of their accounts.
-                    // Exception #2: Like-kind `exchange` `transaction`s must preserve the basis and the basis date
-                    // of the corresponding outgoing `action record`.
-                    // Exception #3: Dual-`action record` `flow` `transaction`s that occur during a period of like-kind
-                    // `exchange` will also inherit an implied/imputed basis date based on the date of the 'buys' in the
-                    // base margin `account`.  The special treatment occurs for an incoming `flow` `action record` whose
-                    // `account` is non-margin.
-                    Polarity::Incoming => {
-                        // println!("Txn: {}, Incoming {:?}-type of {} {}",
-                        //     txn.tx_number, txn.transaction_type(), ar.amount, acct.ticker);
-                        match tx_type {
-                            TxType::Flow => {
-                                let lot: Rc<Lot>;
-                                let mvmt: Movement;
-                                // For an incoming `flow` `action record` with a margin account, the implication is that
-                                // this is a margin loss `transaction`.  The corresponding outgoing `flow` `action record`
-                                // is where the loss is reflected.  This `action record` is simply reflecting the transfer
-                                // of funds into the quote margin account, presumably paying off the loan and bringing it
-                                // to a zero balance.
-                                if raw_acct.is_margin {
-                                    let this_acct = acct_map.get(&ar.account_key).unwrap();
-                                    let lot_list = this_acct.list_of_lots.borrow_mut();
-                                    lot = lot_list.last().unwrap().clone();
+                            // Now the incoming `flow` `action record`s with a non-margin account are handled.
+                            } else {
 
+                                // The base case is a single-`action record` `flow` `transaction` where a `lot` is created (assigned),
+                                // a `movement` is created (assigned), and the `movement` is pushed to the `lot`.  Note that the `lot` variable
+                                // was allocated above, and this `if` section of code merely assigns this `lot` to that variable.
+                                // The `lot` isn't pushed to the `account` until after this whole `if/else` section.
+                                if txn.action_record_idx_vec.len() == 1 {
+                                    lot = Rc::new(
+                                        Lot {
+                                            date_as_string: txn.date_as_string.clone(),
+                                            date_of_first_mvmt_in_lot: txn.date,
+                                            date_for_basis_purposes: txn.date,
+
+                                            lot_number: length_of_list_of_lots as u32 + 1,
+                                            account_key: acct.raw_key,
+                                            movements: RefCell::new([].to_vec()),
+                                        }
+                                    );
                                     mvmt = Movement {
                                         amount: ar.amount,
                                         date_as_string: txn.date_as_string.clone(),
@@ -515,256 +680,150 @@ pub(crate) fn create_lots_and_movements(
                                         proceeds: Cell::new(dec!(0.0)),
                                         proceeds_lk: Cell::new(dec!(0.0)),
                                         cost_basis_lk: Cell::new(dec!(0.0)),
+                                        wash_sale_adjustment: Cell::new(dec!(0.0)),
+                                        fee_expense: Cell::new(dec!(0)),
+                                        is_reallocation: false,
+                                        carried_to_lot_num: Cell::new(None),
                                     };
-                                    wrap_mvmt_and_push(
-                                        mvmt,
-                                        &ar,
-                                        &lot,
-                                        &chosen_home_currency,
-                                        &raw_acct,
-                                    );
 
-                                    // Since a margin account is being posted new, a new lot is not created.  Once the `movement`
-                                    // has been pushed to the `lot`, the recording of the `action record` is complete, and it's
-                                    // onto the next
-                                    continue
-
-                                // Now the incoming `flow` `action record`s with a non-margin account are handled.
+                                // The more complicated case is the dual-`action record` `flow` `transaction`.
                                 } else {
 
-                                    // The base case is a single-`action record` `flow` `transaction` where a `lot` is created (assigned),
-                                    // a `movement` is created (assigned), and the `movement` is pushed to the `lot`.  Note that the `lot` variable
-                                    // was allocated above, and this `if` section of code merely assigns this `lot` to that variable.
-                                    // The `lot` isn't pushed to the `account` until after this whole `if/else` section.
-                                    if txn.action_record_idx_vec.len() == 1 {
-                                        lot = Rc::new(
-                                            Lot {
-                                                date_as_string: txn.date_as_string.clone(),
-                                                date_of_first_mvmt_in_lot: txn.date,
-                                                date_for_basis_purposes: txn.date,
-
-                                                lot_number: length_of_list_of_lots as u32 + 1,
-                                                account_key: acct.raw_key,
-                                                movements: RefCell::new([].to_vec()),
-                                            }
-                                        );
-                                        mvmt = Movement {
-                                            amount: ar.amount,
-                                            date_as_string: txn.date_as_string.clone(),
-                                            date: txn.date,
-                                            transaction_key: txn_num,
-                                            action_record_key: *ar_num,
-                                            cost_basis: Cell::new(dec!(0.0)),
-                                            ratio_of_amt_to_incoming_mvmts_in_a_r: dec!(1.0),
-                                            ratio_of_amt_to_outgoing_mvmts_in_a_r: Cell::new(dec!(1.0)),
-                                            lot_num: lot.lot_number,
-                                            proceeds: Cell::new(dec!(0.0)),
-                                            proceeds_lk: Cell::new(dec!(0.0)),
-                                            cost_basis_lk: Cell::new(dec!(0.0)),
-                                        };
-
-                                    // The more complicated case is the dual-`action record` `flow` `transaction`.
-                                    } else {
-
-                                        // A `flow` `transaction` usually has 1 `action record`.  In this special case, it'll have 2, but no more.
-                                        assert_eq!(txn.action_record_idx_vec.len(), 2);
-
-                                        // The theory in this `if` block is that a series of margin trades culminating in a margin profit during
-                                        // a period of like-kind exchange treatment should/could carry their basis and basis date, just like a traditional
-                                        // trade would.  The software allocates the size of the new `movement`s proportionally based on the size of every
-                                        // margin buy in the `lot` in relation to all the margin buys in the the `lot`; and for each `movement` that it
-                                        // creates, that new `movement` is given the basis date of the respective margin-buy's `movement`.
-                                        // (For those savvy, you noted that since margin trades produce no gain/loss, there is no basis to inherit.)
-                                        if multiple_incoming_mvmts_per_ar_due_to_lk && txn.date <= like_kind_cutoff_date {
-                                            
-                                            // First, two variables are allocated to hold some intermediate results that will be used to determine the
-                                            // size of `movement`(s) and how many `lot`s are needed.
-                                            // The `positive_mvmt_list` is for accumulating the margin-buy `movement`(s) that occurred during the course
-                                            // of the margin trade that is now ending in a profit.  And `total_positive_amounts` accounts for the total
-                                            // amount of those margin-buys.
-                                            let mut positive_mvmt_list: Vec<Rc<Movement>> = [].to_vec();
-                                            let mut total_positive_amounts = dec!(0);
-
-                                            // This is necessary to find the base account, because the margin-buys are reflected in this account.
-                                            let (base_acct_key, quote_acct_key) = get_base_and_quote_acct_for_dual_actionrecord_flow_tx(
-                                                txn_num,
-                                                &ar_map,
-                                                &raw_acct_map,
-                                                &acct_map,
-                                                &txns_map,
-                                            )?;
-
-                                            let base_acct = acct_map.get(&base_acct_key).unwrap();
-                                            let base_acct_lot = base_acct.list_of_lots.borrow().last().unwrap().clone();
-                                            // It should be apparent that the relevant `lot` has been selected, and its `movement` are now iterated
-                                            // over for capturing its `movement`s (for their date) and adding up their amounts.
-                                            for base_acct_mvmt in base_acct_lot.movements.borrow().iter() {
-                                                if base_acct_mvmt.amount > dec!(0) {
-                                                    // println!("In lot# {}, positive mvmt amount: {} {},",
-                                                    //     base_acct_lot.lot_number,
-                                                    //     mvmt.borrow().amount,
-                                                    //     base_acct_lot.account.raw.ticker);
-                                                    total_positive_amounts += base_acct_mvmt.amount;
-                                                    positive_mvmt_list.push(base_acct_mvmt.clone())
-                                                }
-                                            }
+                                    // A `flow` `transaction` usually has 1 `action record`.  In this special case, it'll have 2, but no more.
+                                    if txn.action_record_idx_vec.len() != 2 {
+                                        return Err(CrptlsError::invalid_txn(txn, "A flow txn into a margin \
+                                            account should have exactly 2 action records.".to_string()))
+                                    }
 
-                                            // These variables track relevant usage in the following for-loop.  These are used after the for-loop
-                                            // when creating the final `movement`.
-                                            let mut amounts_used = dec!(0);
-                                            let mut percentages_used = dec!(0);
-
-                                            // Here, the margin-buys are iterated over while creating proportionally-sized new `movement`s.
-                                            // Note that this for-loop excludes the final positive `movement` because rounding must be taken into
-                                            // account (the effect of rounding must be eliminated) when determining the amount of the final `movement`.
-                                            // The `inner_lot` and `inner_mvmt` were named this was to reflect they are created and wrapped/pushed
-                                            // only inside this iteration of `positive_mvmt_list`.
-                                            for pos_mvmt in positive_mvmt_list.iter().take(positive_mvmt_list.len()-1) {
-                                                let inner_lot = Rc::new(
-                                                    Lot {
-                                                        date_as_string: txn.date_as_string.clone(),
-                                                        date_of_first_mvmt_in_lot: txn.date,
-                                                        date_for_basis_purposes: pos_mvmt.date,
-                                                        lot_number: acct.list_of_lots.borrow().len() as u32 + 1,
-                                                        account_key: acct.raw_key,
-                                                        movements: RefCell::new([].to_vec()),
-                                                    }
-                                                );
-                                                let percentage_used = round_d128_1e8(&(pos_mvmt.amount/&total_positive_amounts));
-                                                let amount_used = round_d128_1e8(&(ar.amount*percentage_used));
-                                                let inner_mvmt = Movement {
-                                                    amount: amount_used,
-                                                    date_as_string: txn.date_as_string.clone(),
-                                                    date: txn.date,
-                                                    transaction_key: txn_num,
-                                                    action_record_key: *ar_num,
-                                                    cost_basis: Cell::new(dec!(0.0)),
-                                                    ratio_of_amt_to_incoming_mvmts_in_a_r: percentage_used,
-                                                    ratio_of_amt_to_outgoing_mvmts_in_a_r: Cell::new(dec!(1.0)),
-                                                    lot_num: inner_lot.lot_number,
-                                                    proceeds: Cell::new(dec!(0.0)),
-                                                    proceeds_lk: Cell::new(dec!(0.0)),
-                                                    cost_basis_lk: Cell::new(dec!(0.0)),
-                                                };
-                                                wrap_mvmt_and_push(
-                                                    inner_mvmt,
-                                                    &ar,
-                                                    &inner_lot,
-                                                    &chosen_home_currency,
-                                                    &raw_acct,
-                                                );
-                                                acct.list_of_lots.borrow_mut().push(inner_lot);
-                                                amounts_used += amount_used;
-                                                percentages_used += percentage_used;
+                                    // The theory in this `if` block is that a series of margin trades culminating in a margin profit during
+                                    // a period of like-kind exchange treatment should/could carry their basis and basis date, just like a traditional
+                                    // trade would.  The software allocates the size of the new `movement`s proportionally based on the size of every
+                                    // margin buy in the `lot` in relation to all the margin buys in the the `lot`; and for each `movement` that it
+                                    // creates, that new `movement` is given the basis date of the respective margin-buy's `movement`.
+                                    // (For those savvy, you noted that since margin trades produce no gain/loss, there is no basis to inherit.)
+                                    if multiple_incoming_mvmts_per_ar_due_to_lk && txn.date <= like_kind_cutoff_date {
+                                        
+                                        // First, two variables are allocated to hold some intermediate results that will be used to determine the
+                                        // size of `movement`(s) and how many `lot`s are needed.
+                                        // The `positive_mvmt_list` is for accumulating the margin-buy `movement`(s) that occurred during the course
+                                        // of the margin trade that is now ending in a profit.  And `total_positive_amounts` accounts for the total
+                                        // amount of those margin-buys.
+                                        let mut positive_mvmt_list: Vec<Rc<Movement>> = [].to_vec();
+                                        let mut total_positive_amounts = dec!(0);
+
+                                        // This is necessary to find the base account, because the margin-buys are reflected in this account.
+                                        let (base_acct_key, quote_acct_key) = get_base_and_quote_acct_for_dual_actionrecord_flow_tx(
+                                            txn_num,
+                                            &ar_map,
+                                            &raw_acct_map,
+                                            &acct_map,
+                                            txns_map,
+                                        )?;
+
+                                        let base_acct = acct_map.get(&base_acct_key).unwrap();
+                                        let base_acct_lot = base_acct.list_of_lots.borrow().last().unwrap().clone();
+                                        // It should be apparent that the relevant `lot` has been selected, and its `movement` are now iterated
+                                        // over for capturing its `movement`s (for their date) and adding up their amounts.
+                                        for base_acct_mvmt in base_acct_lot.movements.borrow().iter() {
+                                            if base_acct_mvmt.amount > dec!(0) {
+                                                // println!("In lot# {}, positive mvmt amount: {} {},",
+                                                //     base_acct_lot.lot_number,
+                                                //     mvmt.borrow().amount,
+                                                //     base_acct_lot.account.raw.ticker);
+                                                total_positive_amounts += base_acct_mvmt.amount;
+                                                positive_mvmt_list.push(base_acct_mvmt.clone())
                                             }
+                                        }
 
-                                            // Now that the intermediate `lot`s and `movement`s have been taken care of, the `lot` and `movement` that were
-                                            // allocated after matching on `flow` can be assigned the following values, and which will be wrapped and
-                                            // pushed further down.
-                                            let final_pos_mvmt = positive_mvmt_list.last().expect("After exluding last mvmt from for-loop above, expected last mvmt.");
-                                            lot = Rc::new(
+                                        // These variables track relevant usage in the following for-loop.  These are used after the for-loop
+                                        // when creating the final `movement`.
+                                        let mut amounts_used = dec!(0);
+                                        let mut percentages_used = dec!(0);
+
+                                        // Here, the margin-buys are iterated over while creating proportionally-sized new `movement`s.
+                                        // Note that this for-loop excludes the final positive `movement` because rounding must be taken into
+                                        // account (the effect of rounding must be eliminated) when determining the amount of the final `movement`.
+                                        // The `inner_lot` and `inner_mvmt` were named this was to reflect they are created and wrapped/pushed
+                                        // only inside this iteration of `positive_mvmt_list`.
+                                        for pos_mvmt in positive_mvmt_list.iter().take(positive_mvmt_list.len()-1) {
+                                            let inner_lot = Rc::new(
                                                 Lot {
                                                     date_as_string: txn.date_as_string.clone(),
                                                     date_of_first_mvmt_in_lot: txn.date,
-                                                    date_for_basis_purposes: final_pos_mvmt.date,
+                                                    date_for_basis_purposes: pos_mvmt.date,
                                                     lot_number: acct.list_of_lots.borrow().len() as u32 + 1,
                                                     account_key: acct.raw_key,
                                                     movements: RefCell::new([].to_vec()),
                                                 }
                                             );
-                                            mvmt = Movement {
-                                                amount: round_d128_1e8(&(ar.amount - amounts_used)),
+                                            let percentage_used = round_ratio(&(pos_mvmt.amount/total_positive_amounts), raw_acct.precision);
+                                            let amount_used = round_d128_generalized(&(ar.amount*percentage_used), raw_acct.precision);
+                                            let inner_mvmt = Movement {
+                                                amount: amount_used,
                                                 date_as_string: txn.date_as_string.clone(),
                                                 date: txn.date,
                                                 transaction_key: txn_num,
                                                 action_record_key: *ar_num,
                                                 cost_basis: Cell::new(dec!(0.0)),
-                                                ratio_of_amt_to_incoming_mvmts_in_a_r: round_d128_1e8(&(dec!(1.0) - percentages_used)),
+                                                ratio_of_amt_to_incoming_mvmts_in_a_r: percentage_used,
                                                 ratio_of_amt_to_outgoing_mvmts_in_a_r: Cell::new(dec!(1.0)),
-                                                lot_num: lot.lot_number,
+                                                lot_num: inner_lot.lot_number,
                                                 proceeds: Cell::new(dec!(0.0)),
                                                 proceeds_lk: Cell::new(dec!(0.0)),
                                                 cost_basis_lk: Cell::new(dec!(0.0)),
+                                                wash_sale_adjustment: Cell::new(dec!(0.0)),
+                                                fee_expense: Cell::new(dec!(0)),
+                                                is_reallocation: false,
+                                                carried_to_lot_num: Cell::new(None),
                                             };
-
-                                        // Back to "base case" style treatment, if this is an incoming dual-`action record` `flow` `transaction`, but either
-                                        // (a) like-kind `exchange` treatment was not elected or (b) the `transaction` date is after the like-kind treatment period,
-                                        // then just a single `movement` is created for eventual pushing into a single new `lot`.
-                                        } else {
-                                            lot = Rc::new(
-                                                Lot {
-                                                    date_as_string: txn.date_as_string.clone(),
-                                                    date_of_first_mvmt_in_lot: txn.date,
-                                                    date_for_basis_purposes: txn.date,
-                                                    lot_number: length_of_list_of_lots as u32 + 1,
-                                                    account_key: acct.raw_key,
-                                                    movements: RefCell::new([].to_vec()),
-                                                }
+                                            wrap_mvmt_and_push(
+                                                inner_mvmt,
+                                                &ar,
+                                                &inner_lot,
+                                                &chosen_home_currency,
+                                                &raw_acct,
                                             );
-                                            mvmt = Movement {
-                                                amount: ar.amount,
-                                                date_as_string: txn.date_as_string.clone(),
-                                                date: txn.date,
-                                                transaction_key: txn_num,
-                                                action_record_key: *ar_num,
-                                                cost_basis: Cell::new(dec!(0.0)),
-                                                ratio_of_amt_to_incoming_mvmts_in_a_r: dec!(1.0),
-                                                ratio_of_amt_to_outgoing_mvmts_in_a_r: Cell::new(dec!(1.0)),
-                                                lot_num: lot.lot_number,
-                                                proceeds: Cell::new(dec!(0.0)),
-                                                proceeds_lk: Cell::new(dec!(0.0)),
-                                                cost_basis_lk: Cell::new(dec!(0.0)),
-                                            };
+                                            acct.list_of_lots.borrow_mut().push(inner_lot);
+                                            amounts_used += amount_used;
+                                            percentages_used += percentage_used;
                                         }
-                                    }
 
-                                    // Here, finally, the lot and movement allocated at the top of `match TxType::Flow` have been set
-                                    // and can be wrapped/pushed, at which point this `action record` is complete and it's onto the next.
-                                    wrap_mvmt_and_push(
-                                        mvmt,
-                                        &ar,
-                                        &lot,
-                                        &chosen_home_currency,
-                                        &raw_acct,
-                                    );
-                                    acct.list_of_lots.borrow_mut().push(lot);
-                                    continue
-                                }
-                            }
-                            TxType::Exchange => {
-
-                                // These will only initialize if the outer `if` or inner `if` resolve to false
-                                let whole_mvmt;
-                                let lot;
-
-                                // The first check is for like-kind exchange treatment is applicable to the `transaction`:
-                                if multiple_incoming_mvmts_per_ar_due_to_lk && (txn.date <= like_kind_cutoff_date) {
-
-                                    // If lk is applicable, determine whether to `process_multiple..`,
-                                    // based on if each `action record` has a home currency `account`.
-                                    let both_are_non_home_curr: bool;
-                                    let og_ar = ar_map.get(txn.action_record_idx_vec.first().unwrap()).unwrap();
-                                    let og_acct = acct_map.get(&og_ar.account_key).unwrap();
-                                    let og_raw_acct = raw_acct_map.get(&og_acct.raw_key).unwrap();
-                                    let ic_ar = ar;
-                                    let ic_raw_acct = raw_acct;
-                                    both_are_non_home_curr = !og_raw_acct.is_home_currency(&chosen_home_currency)
-                                    && !ic_raw_acct.is_home_currency(&chosen_home_currency);
-
-                                    if both_are_non_home_curr {
-                                        process_multiple_incoming_lots_and_mvmts(
-                                            txn_num,
-                                            &og_ar,
-                                            &ic_ar,
-                                            &chosen_home_currency,
-                                            &acct_map,
-                                            &txns_map,
-                                            &ar_map,
-                                            &raw_acct,
+                                        // Now that the intermediate `lot`s and `movement`s have been taken care of, the `lot` and `movement` that were
+                                        // allocated after matching on `flow` can be assigned the following values, and which will be wrapped and
+                                        // pushed further down.
+                                        let final_pos_mvmt = positive_mvmt_list.last().expect("After exluding last mvmt from for-loop above, expected last mvmt.");
+                                        lot = Rc::new(
+                                            Lot {
+                                                date_as_string: txn.date_as_string.clone(),
+                                                date_of_first_mvmt_in_lot: txn.date,
+                                                date_for_basis_purposes: final_pos_mvmt.date,
+                                                lot_number: acct.list_of_lots.borrow().len() as u32 + 1,
+                                                account_key: acct.raw_key,
+                                                movements: RefCell::new([].to_vec()),
+                                            }
                                         );
-                                        continue
+                                        mvmt = Movement {
+                                            amount: round_d128_generalized(&(ar.amount - amounts_used), raw_acct.precision),
+                                            date_as_string: txn.date_as_string.clone(),
+                                            date: txn.date,
+                                            transaction_key: txn_num,
+                                            action_record_key: *ar_num,
+                                            cost_basis: Cell::new(dec!(0.0)),
+                                            ratio_of_amt_to_incoming_mvmts_in_a_r: round_ratio(&(dec!(1.0) - percentages_used), raw_acct.precision),
+                                            ratio_of_amt_to_outgoing_mvmts_in_a_r: Cell::new(dec!(1.0)),
+                                            lot_num: lot.lot_number,
+                                            proceeds: Cell::new(dec!(0.0)),
+                                            proceeds_lk: Cell::new(dec!(0.0)),
+                                            cost_basis_lk: Cell::new(dec!(0.0)),
+                                            wash_sale_adjustment: Cell::new(dec!(0.0)),
+                                            fee_expense: Cell::new(dec!(0)),
+                                            is_reallocation: false,
+                                            carried_to_lot_num: Cell::new(None),
+                                        };
 
-                                    // If lk treatment is applicable but one `account` is home currency, then use a single `lot` and `movement`
+                                    // Back to "base case" style treatment, if this is an incoming dual-`action record` `flow` `transaction`, but either
+                                    // (a) like-kind `exchange` treatment was not elected or (b) the `transaction` date is after the like-kind treatment period,
+                                    // then just a single `movement` is created for eventual pushing into a single new `lot`.
                                     } else {
                                         lot = Rc::new(
                                             Lot {
@@ -776,7 +835,7 @@ pub(crate) fn create_lots_and_movements(
                                                 movements: RefCell::new([].to_vec()),
                                             }
                                         );
-                                        whole_mvmt = Movement {
+                                        mvmt = Movement {
                                             amount: ar.amount,
                                             date_as_string: txn.date_as_string.clone(),
                                             date: txn.date,
@@ -789,13 +848,62 @@ pub(crate) fn create_lots_and_movements(
                                             proceeds: Cell::new(dec!(0.0)),
                                             proceeds_lk: Cell::new(dec!(0.0)),
                                             cost_basis_lk: Cell::new(dec!(0.0)),
+                                            wash_sale_adjustment: Cell::new(dec!(0.0)),
+                                            fee_expense: Cell::new(dec!(0)),
+                                            is_reallocation: false,
+                                            carried_to_lot_num: Cell::new(None),
                                         };
                                     }
                                 }
 
-                                // For an incoming `action record` in an `exchange` `transaction` where there's no like-kind
-                                // treatment, simply create a new `lot`, create a new `movement`, and wrap/push.
-                                else {
+                                // Here, finally, the lot and movement allocated at the top of `match TxType::Flow` have been set
+                                // and can be wrapped/pushed, at which point this `action record` is complete and it's onto the next.
+                                wrap_mvmt_and_push(
+                                    mvmt,
+                                    &ar,
+                                    &lot,
+                                    &chosen_home_currency,
+                                    &raw_acct,
+                                );
+                                acct.list_of_lots.borrow_mut().push(lot);
+                                continue
+                            }
+                        }
+                        TxType::Exchange => {
+
+                            // These will only initialize if the outer `if` or inner `if` resolve to false
+                            let whole_mvmt;
+                            let lot;
+
+                            // The first check is for like-kind exchange treatment is applicable to the `transaction`:
+                            if multiple_incoming_mvmts_per_ar_due_to_lk && (txn.date <= like_kind_cutoff_date) {
+
+                                // If lk is applicable, determine whether to `process_multiple..`,
+                                // based on if each `action record` has a home currency `account`.
+                                let both_are_non_home_curr: bool;
+                                let og_ar = ar_map.get(txn.action_record_idx_vec.first().unwrap()).unwrap();
+                                let og_acct = acct_map.get(&og_ar.account_key).unwrap();
+                                let og_raw_acct = raw_acct_map.get(&og_acct.raw_key).unwrap();
+                                let ic_ar = ar;
+                                let ic_raw_acct = raw_acct;
+                                both_are_non_home_curr = !og_raw_acct.is_home_currency(&chosen_home_currency)
+                                && !ic_raw_acct.is_home_currency(&chosen_home_currency);
+
+                                if both_are_non_home_curr {
+                                    process_multiple_incoming_lots_and_mvmts(
+                                        txn_num,
+                                        &og_ar,
+                                        &ic_ar,
+                                        &chosen_home_currency,
+                                        &acct_map,
+                                        txns_map,
+                                        &ar_map,
+                                        &raw_acct,
+                                    )?;
+                                    continue
+
+                                // If lk treatment is applicable but one `account` is home currency, then use a single `lot` and `movement`
+                                } else {
                                     lot = Rc::new(
                                         Lot {
                                             date_as_string: txn.date_as_string.clone(),
@@ -819,50 +927,123 @@ pub(crate) fn create_lots_and_movements(
                                         proceeds: Cell::new(dec!(0.0)),
                                         proceeds_lk: Cell::new(dec!(0.0)),
                                         cost_basis_lk: Cell::new(dec!(0.0)),
+                                        wash_sale_adjustment: Cell::new(dec!(0.0)),
+                                        fee_expense: Cell::new(dec!(0)),
+                                        is_reallocation: false,
+                                        carried_to_lot_num: Cell::new(None),
                                     };
                                 }
-                                // The `lot` and `whole_mvmt` variables have been initialized/assigned
-                                wrap_mvmt_and_push(
-                                    whole_mvmt,
-                                    &ar,
-                                    &lot,
+                            }
+
+                            // For an incoming `action record` in an `exchange` `transaction` where there's no like-kind
+                            // treatment, simply create a new `lot`, create a new `movement`, and wrap/push.
+                            else {
+                                lot = Rc::new(
+                                    Lot {
+                                        date_as_string: txn.date_as_string.clone(),
+                                        date_of_first_mvmt_in_lot: txn.date,
+                                        date_for_basis_purposes: txn.date,
+                                        lot_number: length_of_list_of_lots as u32 + 1,
+                                        account_key: acct.raw_key,
+                                        movements: RefCell::new([].to_vec()),
+                                    }
+                                );
+                                whole_mvmt = Movement {
+                                    amount: ar.amount,
+                                    date_as_string: txn.date_as_string.clone(),
+                                    date: txn.date,
+                                    transaction_key: txn_num,
+                                    action_record_key: *ar_num,
+                                    cost_basis: Cell::new(dec!(0.0)),
+                                    ratio_of_amt_to_incoming_mvmts_in_a_r: dec!(1.0),
+                                    ratio_of_amt_to_outgoing_mvmts_in_a_r: Cell::new(dec!(1.0)),
+                                    lot_num: lot.lot_number,
+                                    proceeds: Cell::new(dec!(0.0)),
+                                    proceeds_lk: Cell::new(dec!(0.0)),
+                                    cost_basis_lk: Cell::new(dec!(0.0)),
+                                    wash_sale_adjustment: Cell::new(dec!(0.0)),
+                                    fee_expense: Cell::new(dec!(0)),
+                                    is_reallocation: false,
+                                    carried_to_lot_num: Cell::new(None),
+                                };
+                            }
+                            // The `lot` and `whole_mvmt` variables have been initialized/assigned
+                            wrap_mvmt_and_push(
+                                whole_mvmt,
+                                &ar,
+                                &lot,
+                                &chosen_home_currency,
+                                &raw_acct,
+                            );
+                            acct.list_of_lots.borrow_mut().push(lot);
+                            continue
+                        }
+                        TxType::ToSelf => {
+
+                            // Based on experience, and considering how `transaction`s are constructed, this should never happen.
+                            if raw_acct.is_margin {
+                                return Err(CrptlsError::invalid_txn(txn, "Margin accounts can't transfer to \
+                                    other accounts of the same ticker.".to_string()))
+
+                            // When transferring to oneself, the amounts should carry over proportionally (considering the incoming `movement`
+                            // is likely to be less than the outgoing `movement` due to transaction fees), as should the basis date of each of the
+                            // outgoing `movement`s.
+                            } else {
+                                process_multiple_incoming_lots_and_mvmts(
+                                    txn_num,
+                                    &ar_map.get(txn.action_record_idx_vec.first().unwrap()).unwrap(), // outgoing
+                                    &ar, // incoming
                                     &chosen_home_currency,
+                                    &acct_map,
+                                    txns_map,
+                                    &ar_map,
                                     &raw_acct,
-                                );
-                                acct.list_of_lots.borrow_mut().push(lot);
-                                continue
+                                )?;
                             }
-                            TxType::ToSelf => {
+                            continue
+                        }
+                    }   // end for match::TxType
+                }   // end for Polarity::Incoming
+            }   // end for match::Polarity
+        }   //  end for ar in txn.actionrecords (ar_num in tx.ar_idx_vec)
+    }   //  end of tx does not have marginness of TwoARs
+    check_mvmt_amounts_are_at_precision(txn, &swap_ars, ar_map, raw_acct_map)?;
+    Ok(swap_ars)
+}
 
-                                // Based on experience, and considering how `transaction`s are constructed, this should never happen.
-                                if raw_acct.is_margin {
-                                    println!("FATAL: Consult developer. Found margin actionrecord in ToSelf transaction:\n{:#?}", txn);
-                                    std::process::exit(1);
+/// Looks up `transaction` `txn_num`.  The numbers run from 1 through the number of `transaction`s, so it is always there.
+fn get_txn(txns_map: &HashMap<u32, Transaction>, txn_num: u32) -> Result<&Transaction, CrptlsError> {
+    txns_map.get(&txn_num).ok_or_else(|| CrptlsError::invalid_row(txn_num, 0, "There's no txn with this number.".to_string()))
+}
 
-                                // When transferring to oneself, the amounts should carry over proportionally (considering the incoming `movement`
-                                // is likely to be less than the outgoing `movement` due to transaction fees), as should the basis date of each of the
-                                // outgoing `movement`s.
-                                } else {
-                                    process_multiple_incoming_lots_and_mvmts(
-                                        txn_num,
-                                        &ar_map.get(txn.action_record_idx_vec.first().unwrap()).unwrap(), // outgoing
-                                        &ar, // incoming
-                                        &chosen_home_currency,
-                                        &acct_map,
-                                        &txns_map,
-                                        &ar_map,
-                                        &raw_acct,
-                                    );
-                                }
-                                continue
-                            }
-                        }   // end for match::TxType
-                    }   // end for Polarity::Incoming
-                }   // end for match::Polarity
-            }   //  end for ar in txn.actionrecords (ar_num in tx.ar_idx_vec)
-        }   //  end of tx does not have marginness of TwoARs
-    }   //  end for txn in transactions (txn_num in txn_map.len())
-    Ok(txns_map)
+/// Looks up a margin `account` found from a trade's margin pair.
+fn get_margin_acct<'a>(txn: &Transaction, acct_map: &'a HashMap<u16, Account>, acct_key: u16) -> Result<&'a Account, CrptlsError> {
+    acct_map.get(&acct_key).ok_or_else(|| CrptlsError::bad_margin_pair(txn,
+        format!("The margin pair includes account {}, which doesn't exist.", acct_key)))
+}
+
+/// Every `movement` amount must already be rounded to its `account`'s precision.  The `action record` amounts are
+/// rounded on import, and amounts split across `lot`s are rounded as they're split, so this only double-checks that.
+fn check_mvmt_amounts_are_at_precision(
+    txn: &Transaction,
+    swap_ars: &[ActionRecord],
+    ar_map: &HashMap<u32, ActionRecord>,
+    raw_acct_map: &HashMap<u16, RawAccount>,
+) -> Result<(), CrptlsError> {
+
+    let txn_ars = txn.ar_keys_incl_fee_leg().into_iter().map(|ar_num| ar_map.get(&ar_num).unwrap());
+
+    for ar in txn_ars.chain(swap_ars.iter()) {
+        let raw_acct = raw_acct_map.get(&ar.account_key).unwrap();
+        for mvmt in ar.movements.borrow().iter() {
+            if mvmt.amount != round_d128_generalized(&mvmt.amount, raw_acct.precision) {
+                return Err(CrptlsError::invalid_txn(txn, format!("The amount {} in account {} has more than its \
+                    {} decimal places.", mvmt.amount, ar.account_key, raw_acct.precision)))
+            }
+        }
+    }
+
+    Ok(())
 }
 
 /// Preface: this ONLY works for a dual-`action record` `transaction` when the `account` of the incoming
@@ -883,13 +1064,17 @@ fn get_base_and_quote_acct_for_dual_actionrecord_flow_tx(
     raw_acct_map: &HashMap<u16, RawAccount>,
     acct_map: &HashMap<u16, Account>,
     txns_map: &HashMap<u32, Transaction>,
-) -> Result<(u16, u16), Box<dyn Error>> {
+) -> Result<(u16, u16), CrptlsError> {
 
-    let txn = txns_map.get(&txn_num).expect("Couldn't get txn. Tx num invalid?");
+    let txn = get_txn(txns_map, txn_num)?;
 
     let og_flow_ar = ar_map.get(txn.action_record_idx_vec.first().unwrap()).unwrap();
     let og_flow_ar_acct = acct_map.get(&og_flow_ar.account_key).unwrap();
-    let og_flow_ar_acct_first_lot = &og_flow_ar_acct.list_of_lots.borrow()[0];
+    let og_flow_ar_acct_first_lot = match og_flow_ar_acct.list_of_lots.borrow().first() {
+        Some(x) => x.clone(),
+        None => return Err(CrptlsError::invalid_txn(txn, format!("The txn spends from margin account {}, \
+            which has no position yet.", og_flow_ar_acct.raw_key)))
+    };
     let first_lot_mvmts = og_flow_ar_acct_first_lot.movements.borrow();
     let first_lot_mvmts_first_mvmt = &first_lot_mvmts.first().unwrap();
     let txn_of_first_lot_mvmts_first_mvmt = txns_map.get(&first_lot_mvmts_first_mvmt.transaction_key).unwrap();
@@ -924,7 +1109,7 @@ fn get_base_and_quote_ar_idxs(
 
 /// Every time a new `movement` is created, it must be wrapped in an `Rc` because it is owned both by the
 /// `lot` (which itself is owned by an `account`) and by the `action record` from which it was derived.
-fn wrap_mvmt_and_push(
+pub(crate) fn wrap_mvmt_and_push(
     this_mvmt: Movement,
     ar: &ActionRecord,
     lot: &Lot,
@@ -938,51 +1123,104 @@ fn wrap_mvmt_and_push(
     // TODO: Consider commenting or deleting this code block (and the two vars above).
     if ar.direction() == Polarity::Outgoing && !raw_acct.is_home_currency(chosen_home_currency) {
         let ratio = this_mvmt.amount / ar.amount;
-        this_mvmt.ratio_of_amt_to_outgoing_mvmts_in_a_r.set(round_d128_1e8(&ratio));
+        this_mvmt.ratio_of_amt_to_outgoing_mvmts_in_a_r.set(round_ratio(&ratio, raw_acct.precision));
     }
 
-    // This is the type of thing that should probably be changed into a test or an operation that is only
-    // run under certain circumstances, since it is double-checking something that prior code should have
-    // already taken care of, which is that the value has been rounded to 8 digits of precision already.
-    // TODO: Consider deleting this assertion.
-    let amt = this_mvmt.amount;
-    let amt2 = round_d128_1e8(&amt);
-    assert_eq!(amt, amt2);
+    // That the amount is rounded to the `account`'s precision is checked once the whole `transaction` has its
+    // `movement`s (see `check_mvmt_amounts_are_at_precision()`).
 
     let mvmt = Rc::from(this_mvmt);
     lot.movements.borrow_mut().push(mvmt.clone());
     ar.movements.borrow_mut().push(mvmt);
 }
 
+/// What stays the same while an outgoing `action record` is fit into `lot`s.
+struct FitContext<'a> {
+    txn: &'a Transaction,
+    ar: &'a ActionRecord,
+    raw_acct: &'a RawAccount,
+    acct: &'a Account,
+    chosen_home_currency: &'a str,
+}
+
+/// Gives each of the `selected_lots` (from the lot selection file) a `movement` for exactly the selected amount, and
+/// returns what is left of `amt_to_fit`.  It is an error for a selected `lot` not to exist or not to hold enough.
+fn fit_into_selected_lots(
+    fit: &FitContext,
+    mvmt_to_fit: &Movement,
+    amt_to_fit: Decimal,
+    selected_lots: &[LotSelection],
+) -> Result<Decimal, CrptlsError> {
+
+    let (txn, raw_acct) = (fit.txn, fit.raw_acct);
+    let mut remaining_amt = amt_to_fit;
+
+    for selection in selected_lots.iter() {
+
+        let lot = match fit.acct.list_of_lots.borrow().get(selection.lot_num as usize - 1) {
+            Some(lot) => lot.clone(),
+            None => {
+                return Err(CrptlsError::invalid_txn(txn, format!("Selected lot {} \
+                    doesn't exist in account {} ({}).", selection.lot_num, raw_acct.account_num, raw_acct.name)))
+            }
+        };
+
+        let sum_of_mvmts_in_lot = lot.get_sum_of_amts_in_lot();
+        if sum_of_mvmts_in_lot < selection.amount {
+            return Err(CrptlsError::invalid_txn(txn, format!("Selected lot {} in account {} \
+                ({}) holds {} {}, which is less than the {} selected.", selection.lot_num, raw_acct.account_num,
+                raw_acct.name, sum_of_mvmts_in_lot, raw_acct.ticker, selection.amount)))
+        }
+
+        let selected_mvmt: Movement = Movement {
+            amount: -selection.amount,
+            lot_num: lot.lot_number,
+            ..mvmt_to_fit.clone()
+        };
+        wrap_mvmt_and_push(
+            selected_mvmt,
+            fit.ar,
+            &lot,
+            fit.chosen_home_currency,
+            raw_acct
+        );
+
+        remaining_amt = (remaining_amt + selection.amount).round_dp(raw_acct.precision);
+    }
+
+    Ok(remaining_amt)
+}
+
 /// Recursively check the balance in a `lot`, and if not zero then create a `movement` that is the lesser of
 /// the `mvmt_to_fit` or the balance of the `lot`; and if the `lot` balance is smaller than the amount of
 /// the `mvmt_to_fit`, then create a `movement` that will fit into that `lot` and push it to that `lot`; and
 /// then select the next `lot` and replace the `mvmt_to_fit` with a new `mvmt_to_fit` (reduced by the one
 /// that was pushed to the previous `lot`), and recursively check...
 fn fit_into_lots(
+    fit: &FitContext,
     mvmt_to_fit: Movement,
     amt_to_fit: Decimal,
-    list_of_lots_to_use: RefCell<Vec<Rc<Lot>>>,
     vec_of_ordered_index_values: Vec<usize>,
     index_position: usize,
-    chosen_home_currency: &str,
-    ar: &ActionRecord,
-    raw_acct: &RawAccount,
-    acct: &Account,
-) {
+) -> Result<(), CrptlsError> {
+
+    let (txn, ar, raw_acct, acct) = (fit.txn, fit.ar, fit.raw_acct, fit.acct);
+    let chosen_home_currency = fit.chosen_home_currency;
 
     let mut current_index_position = index_position;
 
     // Here is a check to make sure the `lot` will exist. If it won't, then there will be an index
     // out of bounds error. The account balance should be zero in that case, but it is checked
-    // anyway before printing the error message for the user and exiting.
+    // anyway for the error returned to the user.
     if vec_of_ordered_index_values.len() == current_index_position {
-        println!("FATAL: Txn {} on {} spending {} {} has run out of lots to spend from.",
-            mvmt_to_fit.transaction_key, mvmt_to_fit.date_as_string, ar.amount, raw_acct.ticker);
-        let bal = if acct.get_sum_of_amts_in_lots() == dec!(0) { "0.00000000".to_string() }
-            else { acct.get_sum_of_amts_in_lots().to_string() };
-        println!("Account balance is only: {}", bal);
-        std::process::exit(1);
+        return Err(CrptlsError::InsufficientLots {
+            tx_number: txn.tx_number,
+            csv_line: txn.csv_line,
+            acct_key: raw_acct.account_num,
+            ticker: raw_acct.ticker.clone(),
+            amount: -ar.amount,
+            balance: acct.get_sum_of_amts_in_lots(),
+        })
     }
 
     // Get the `lot`, and then get its balance to see how much room there is
@@ -993,33 +1231,29 @@ fn fit_into_lots(
         sum_of_mvmts_in_lot += movement.amount;
     }
 
-    assert!(sum_of_mvmts_in_lot >= dec!(0.0));
+    if sum_of_mvmts_in_lot < dec!(0.0) {
+        return Err(CrptlsError::invalid_txn(txn, format!("Lot {} in account {} has a negative balance.",
+            lot.lot_number, raw_acct.account_num)))
+    }
 
     //  If the `lot` is "full", try the next.
     if sum_of_mvmts_in_lot == dec!(0.0) {
 
         current_index_position += 1;
 
-        fit_into_lots(
+        return fit_into_lots(
+            fit,
             mvmt_to_fit,
             amt_to_fit,
-            list_of_lots_to_use,
             vec_of_ordered_index_values,
             current_index_position,
-            chosen_home_currency,
-            ar,
-            raw_acct,
-            acct,
-        );
-        return;
+        )
     }
 
-    assert!(sum_of_mvmts_in_lot > dec!(0.0));
-
     // If `remainder_amt_to_recurse` is positive, it means the `lot` balance exceeded `amt_to_fit`,
     // therefore, the amount completely fits in the `lot`.  If negative, it is passed as the `amt_to_fit`
     // for the next round of recursion.
-    let remainder_amt_to_recurse = (amt_to_fit + sum_of_mvmts_in_lot).round_dp(8);
+    let remainder_amt_to_recurse = (amt_to_fit + sum_of_mvmts_in_lot).round_dp(raw_acct.precision);
 
     // If the remainder fits, the `movement` is wrapped/pushed, and the recursion is complete.
     if remainder_amt_to_recurse >= dec!(0.0) {
@@ -1031,42 +1265,381 @@ fn fit_into_lots(
         };
         wrap_mvmt_and_push(
             remainder_mvmt_that_fits,
-            &ar,
+            ar,
             &lot,
-            &chosen_home_currency,
-            &raw_acct
+            chosen_home_currency,
+            raw_acct
         );
-        return
+        return Ok(())
     }
 
     // The amt_to_fit doesn't completely fit in the present `lot`, but some does. Create a `movement` that will fit.
     let mvmt_that_fits_in_lot: Movement = Movement {
-        amount: (-sum_of_mvmts_in_lot).round_dp(8),
+        amount: (-sum_of_mvmts_in_lot).round_dp(raw_acct.precision),
         lot_num: lot.lot_number,
         ..mvmt_to_fit.clone()
     };
     wrap_mvmt_and_push(
         mvmt_that_fits_in_lot,
-        &ar,
+        ar,
         &lot,
-        &chosen_home_currency,
-        &raw_acct
+        chosen_home_currency,
+        raw_acct
     );
 
     current_index_position += 1;
 
     // After applying some of the `amt_to_fit` to the `lot`, increment the index, take the remainder, and recurse
     fit_into_lots(
+        fit,
         mvmt_to_fit,
-        remainder_amt_to_recurse.round_dp(8),  //  This was updated before recursing
-        list_of_lots_to_use,
+        remainder_amt_to_recurse.round_dp(raw_acct.precision),  //  This was updated before recursing
         vec_of_ordered_index_values,
         current_index_position,     //  This was updated before recursing
+    )
+}
+
+/// The disposal's proceeds per unit spent, which only the tax-optimized `InventoryCostingMethod` looks at.  These are
+/// the proceeds the `movement`s will get, net of any fee taken from them, or zero if `ar` spends nothing.
+fn get_unit_proceeds(
+    txn: &Transaction,
+    ar: &ActionRecord,
+    settings: &ImportProcessParameters,
+    ar_map: &HashMap<u32, ActionRecord>,
+    raw_acct_map: &HashMap<u16, RawAccount>,
+    acct_map: &HashMap<u16, Account>,
+) -> Decimal {
+
+    if ar.amount == dec!(0) { return dec!(0) }
+
+    let proceeds = match &txn.fee_leg {
+        Some(fee_leg) if fee_leg.action_record_key == ar.self_ar_key => fee_leg.value,
+        _ => {
+            let fee_allocation = txn.get_fee_allocation(
+                &settings.fee_treatment,
+                &settings.home_currency,
+                ar_map,
+                raw_acct_map,
+                acct_map,
+            );
+            txn.proceeds - fee_allocation.taken_from_proceeds
+        }
+    };
+    proceeds / -ar.amount
+}
+
+/// Orders `lot`s to minimize the gain on disposing of units at `unit_proceeds` each on `disposal_date`.  Losses come
+/// first, from the longest `Term` to the shortest, largest per-unit loss first within each.  Then come gains, smallest
+/// per-unit gain first, with the longer `Term` ahead on a tie.  Any other ties keep the order of `lots`.  The holding
+/// period is judged with `HoldingPeriodRule::term()`, since the `movement`s don't exist yet.
+pub(crate) fn get_tax_optimized_order(
+    lots: &[Rc<Lot>],
+    unit_proceeds: Decimal,
+    disposal_date: NaiveDate,
+    rule: &HoldingPeriodRule,
+) -> Vec<usize> {
+
+    let sort_key = |lot: &Rc<Lot>| {
+        let unit_gain = unit_proceeds - lot.get_unit_cost_basis();
+        let term_rank = std::cmp::Reverse(rule.term(lot.date_for_basis_purposes, disposal_date).rank);
+        let is_gain = unit_gain >= dec!(0);
+        let loss_group = if is_gain { std::cmp::Reverse(0) } else { term_rank };
+        (is_gain, loss_group, unit_gain, term_rank)
+    };
+
+    let mut vec_of_indexes: Vec<usize> = (0..lots.len()).collect();
+    vec_of_indexes.sort_by_key(|idx| sort_key(&lots[*idx]));
+    vec_of_indexes
+}
+
+/// Orders `lot`s that may belong to different `account`s, for universal lot relief.  Creation order is taken
+/// from the `transaction` that created each `lot`.  Ties keep the order of `lots`.  `unit_proceeds` and
+/// `disposal_date` are only used by the tax-optimized method.
+pub(crate) fn get_universal_order(
+    lots: &[Rc<Lot>],
+    costing_method: &InventoryCostingMethod,
+    unit_proceeds: Decimal,
+    disposal_date: NaiveDate,
+    rule: &HoldingPeriodRule,
+) -> Vec<usize> {
+
+    let creation_key = |lot: &Rc<Lot>| {
+        (lot.movements.borrow().first().unwrap().transaction_key, lot.lot_number)
+    };
+
+    let mut vec_of_indexes: Vec<usize> = (0..lots.len()).collect();
+
+    match costing_method {
+        InventoryCostingMethod::LIFObyLotCreationDate => {
+            vec_of_indexes.sort_by_key(|idx| std::cmp::Reverse(creation_key(&lots[*idx])))}
+        InventoryCostingMethod::LIFObyLotBasisDate => {
+            vec_of_indexes.sort_by_key(|idx| std::cmp::Reverse((lots[*idx].date_for_basis_purposes, creation_key(&lots[*idx]))))}
+        InventoryCostingMethod::FIFObyLotCreationDate => {
+            vec_of_indexes.sort_by_key(|idx| creation_key(&lots[*idx]))}
+        InventoryCostingMethod::FIFObyLotBasisDate => {
+            vec_of_indexes.sort_by_key(|idx| (lots[*idx].date_for_basis_purposes, creation_key(&lots[*idx])))}
+        InventoryCostingMethod::HIFObyLotUnitBasis => {
+            vec_of_indexes.sort_by(|a, b| lots[*b].get_unit_cost_basis().cmp(&lots[*a].get_unit_cost_basis()))}
+        InventoryCostingMethod::LOFObyLotUnitBasis => {
+            vec_of_indexes.sort_by(|a, b| lots[*a].get_unit_cost_basis().cmp(&lots[*b].get_unit_cost_basis()))}
+        InventoryCostingMethod::TaxOptimized => {
+            vec_of_indexes = get_tax_optimized_order(lots, unit_proceeds, disposal_date, rule)}
+    }
+
+    vec_of_indexes
+}
+
+/// The settings and `account` maps that universal lot relief reads while swapping `lot`s between `account`s.
+struct UniversalRelief<'a> {
+    settings: &'a ImportProcessParameters,
+    raw_acct_map: &'a HashMap<u16, RawAccount>,
+    acct_map: &'a HashMap<u16, Account>,
+}
+
+/// For universal lot relief, the `lot`s of every non-margin `account` with the ticker are ordered together by the
+/// `InventoryCostingMethod`, and the outgoing amount (less anything from the lot selection file) is matched against
+/// them.  A `lot` of another `account` can't simply be spent from, because each `account`'s balance must still match
+/// the wallet it represents.  Instead, the units are swapped: the matched units of the other `account`'s `lot` move
+/// into a new `lot` in this `account`, and the same number of this `account`'s own unmatched units (next in line
+/// first) move into new `lot`s in the other `account`.  Each new `lot` keeps the basis date and per-unit basis of the
+/// `lot` it came from.  The swap `movement`s belong to new `action record`s, keyed from `first_swap_ar_key`, which
+/// aren't part of the `transaction`'s list of `action record`s, so no proceeds, income or expense are added to them.
+///
+/// Returns the order in which to spend from this `account`'s `lot`s and the swap `action record`s, or `None` if the
+/// `account` doesn't hold enough, in which case `fit_into_lots()` reports the problem as it normally would.
+fn swap_in_lots_from_other_accts(
+    txn: &Transaction,
+    ar: &ActionRecord,
+    selected_lots: &[LotSelection],
+    unit_proceeds: Decimal,
+    relief: &UniversalRelief,
+    first_swap_ar_key: u32,
+) -> Option<(Vec<usize>, Vec<ActionRecord>)> {
+
+    let (settings, raw_acct_map, acct_map) = (relief.settings, relief.raw_acct_map, relief.acct_map);
+    let acct = acct_map.get(&ar.account_key).unwrap();
+    let raw_acct = raw_acct_map.get(&acct.raw_key).unwrap();
+
+    // Units already claimed by the lot selection file aren't available to be matched here.
+    let available_in_lot = |lot: &Rc<Lot>| -> Decimal {
+        let mut available = lot.get_sum_of_amts_in_lot();
+        if lot.account_key == acct.raw_key {
+            for selection in selected_lots.iter().filter(|s| s.lot_num == lot.lot_number) {
+                available -= selection.amount;
+            }
+        }
+        available.max(dec!(0))
+    };
+
+    let own_lots: Vec<Rc<Lot>> = acct.list_of_lots.borrow().clone();
+    let amt_selected: Decimal = selected_lots.iter().map(|s| s.amount).sum();
+    let amt_to_match = -ar.amount - amt_selected;
+    let own_amt_available: Decimal = own_lots.iter().map(available_in_lot).sum();
+
+    if amt_to_match <= dec!(0) || own_amt_available < amt_to_match { return None }
+
+    let mut pool_acct_keys: Vec<u16> = raw_acct_map.values()
+        .filter(|other| other.ticker == raw_acct.ticker && !other.is_margin)
+        .map(|other| other.account_num)
+        .collect();
+    pool_acct_keys.sort();
+
+    let mut pooled_lots: Vec<Rc<Lot>> = [].to_vec();
+    for acct_key in pool_acct_keys.iter() {
+        let pool_acct = acct_map.get(acct_key).unwrap();
+        for lot in pool_acct.list_of_lots.borrow().iter() {
+            if available_in_lot(lot) > dec!(0) { pooled_lots.push(lot.clone()) }
+        }
+    }
+
+    let mut own_picks: Vec<(Rc<Lot>, Decimal)> = [].to_vec();
+    let mut other_picks: Vec<(Rc<Lot>, Decimal)> = [].to_vec();
+    let mut amt_remaining = amt_to_match;
+
+    for idx in get_universal_order(&pooled_lots, settings.costing_method_for(acct.raw_key, txn.date), unit_proceeds, txn.date, &settings.holding_period_rule) {
+        if amt_remaining == dec!(0) { break }
+        let lot = &pooled_lots[idx];
+        let amt = available_in_lot(lot).min(amt_remaining);
+        amt_remaining -= amt;
+        if lot.account_key == acct.raw_key {
+            own_picks.push((lot.clone(), amt))
+        } else {
+            other_picks.push((lot.clone(), amt))
+        }
+    }
+
+    // Whatever of this `account`'s own units weren't matched are what it can give up in exchange.
+    let mut own_units_to_give: Vec<(Rc<Lot>, Decimal)> = [].to_vec();
+    for idx in get_universal_order(&own_lots, settings.costing_method_for(acct.raw_key, txn.date), unit_proceeds, txn.date, &settings.holding_period_rule) {
+        let lot = &own_lots[idx];
+        let amt_matched: Decimal = own_picks.iter()
+            .filter(|(picked, _)| picked.lot_number == lot.lot_number)
+            .map(|(_, amt)| *amt)
+            .sum();
+        let leftover = available_in_lot(lot) - amt_matched;
+        if leftover > dec!(0) { own_units_to_give.push((lot.clone(), leftover)) }
+    }
+
+    let mut swap_ars: Vec<ActionRecord> = [].to_vec();
+    let mut vec_of_ordered_index_values: Vec<usize> = [].to_vec();
+    let mut give_idx = 0;
+
+    for (other_lot, amt) in other_picks.iter() {
+
+        let other_acct = acct_map.get(&other_lot.account_key).unwrap();
+        let mut amt_to_give = *amt;
+
+        while amt_to_give > dec!(0) {
+            let (own_lot, own_amt) = &mut own_units_to_give[give_idx];
+            let amt_given = (*own_amt).min(amt_to_give);
+            let (outgoing_ar, incoming_ar) = move_units_to_new_lot(
+                own_lot,
+                amt_given,
+                other_acct,
+                txn,
+                raw_acct,
+                settings,
+                first_swap_ar_key + swap_ars.len() as u32,
+            );
+            swap_ars.push(outgoing_ar);
+            swap_ars.push(incoming_ar);
+            *own_amt -= amt_given;
+            amt_to_give -= amt_given;
+            if *own_amt == dec!(0) { give_idx += 1 }
+        }
+
+        let (outgoing_ar, incoming_ar) = move_units_to_new_lot(
+            other_lot,
+            *amt,
+            acct,
+            txn,
+            raw_acct,
+            settings,
+            first_swap_ar_key + swap_ars.len() as u32,
+        );
+        swap_ars.push(outgoing_ar);
+        swap_ars.push(incoming_ar);
+        vec_of_ordered_index_values.push(acct.list_of_lots.borrow().len() - 1);
+    }
+
+    // Spend the swapped-in `lot`s first, then this `account`'s own matched `lot`s, then anything else in order.
+    for (lot, _amt) in own_picks.iter() {
+        vec_of_ordered_index_values.push(lot.lot_number as usize - 1);
+    }
+    for idx in get_universal_order(&acct.list_of_lots.borrow(), settings.costing_method_for(acct.raw_key, txn.date), unit_proceeds, txn.date, &settings.holding_period_rule) {
+        if !vec_of_ordered_index_values.contains(&idx) { vec_of_ordered_index_values.push(idx) }
+    }
+
+    Some((vec_of_ordered_index_values, swap_ars))
+}
+
+/// Moves `amount` units out of `from_lot` and into a new `lot` in `to_acct`, carrying the basis date and per-unit
+/// basis along.  Cost basis is set here, since these `movement`s aren't part of any `transaction`'s `action record`s.
+/// Returns the outgoing and incoming `action record`s, keyed `first_ar_key` and `first_ar_key + 1`.
+fn move_units_to_new_lot(
+    from_lot: &Lot,
+    amount: Decimal,
+    to_acct: &Account,
+    txn: &Transaction,
+    raw_acct: &RawAccount,
+    settings: &ImportProcessParameters,
+    first_ar_key: u32,
+) -> (ActionRecord, ActionRecord) {
+
+    let chosen_home_currency = &settings.home_currency;
+
+    // Once the last units leave the `lot`, they take whatever basis is left, so no cents stay behind without units.
+    // If the `transaction` itself has already spent from the `lot`, what's left isn't known yet (its `movement`s get
+    // their basis in `add_cost_basis_to_movements()`), so the piece is figured at the per-unit basis instead.
+    let txn_ar_keys = txn.ar_keys_incl_fee_leg();
+    let is_last_piece = amount == from_lot.get_sum_of_amts_in_lot()
+        && from_lot.movements.borrow().iter().all(|mvmt| !txn_ar_keys.contains(&mvmt.action_record_key));
+
+    let basis = if is_last_piece {
+        from_lot.get_sum_of_orig_basis_in_lot()
+    } else {
+        let first_mvmt_in_lot = from_lot.movements.borrow().first().unwrap().clone();
+        round_d128_generalized(&(first_mvmt_in_lot.cost_basis.get() * amount / first_mvmt_in_lot.amount), settings.home_currency_minor_units)
+    };
+
+    let outgoing_ar = ActionRecord {
+        account_key: from_lot.account_key,
+        amount: -amount,
+        tx_key: txn.tx_number,
+        self_ar_key: first_ar_key,
+        movements: RefCell::new([].to_vec()),
+    };
+    let outgoing_mvmt = Movement {
+        amount: -amount,
+        date_as_string: txn.date_as_string.clone(),
+        date: txn.date,
+        transaction_key: txn.tx_number,
+        action_record_key: outgoing_ar.self_ar_key,
+        cost_basis: Cell::new(-basis),
+        ratio_of_amt_to_incoming_mvmts_in_a_r: dec!(1.0),
+        ratio_of_amt_to_outgoing_mvmts_in_a_r: Cell::new(dec!(1.0)),
+        lot_num: from_lot.lot_number,
+        proceeds: Cell::new(basis),
+        proceeds_lk: Cell::new(basis),
+        cost_basis_lk: Cell::new(-basis),
+        wash_sale_adjustment: Cell::new(dec!(0.0)),
+        fee_expense: Cell::new(dec!(0)),
+        is_reallocation: false,
+        carried_to_lot_num: Cell::new(None),
+    };
+    wrap_mvmt_and_push(
+        outgoing_mvmt,
+        &outgoing_ar,
+        from_lot,
         chosen_home_currency,
-        ar,
         raw_acct,
-        acct,
     );
+
+    let incoming_ar = ActionRecord {
+        account_key: to_acct.raw_key,
+        amount,
+        tx_key: txn.tx_number,
+        self_ar_key: first_ar_key + 1,
+        movements: RefCell::new([].to_vec()),
+    };
+    let lot = Rc::new(
+        Lot {
+            date_as_string: txn.date_as_string.clone(),
+            date_of_first_mvmt_in_lot: txn.date,
+            date_for_basis_purposes: from_lot.date_for_basis_purposes,
+            lot_number: to_acct.list_of_lots.borrow().len() as u32 + 1,
+            account_key: to_acct.raw_key,
+            movements: RefCell::new([].to_vec()),
+        }
+    );
+    let incoming_mvmt = Movement {
+        amount,
+        date_as_string: txn.date_as_string.clone(),
+        date: txn.date,
+        transaction_key: txn.tx_number,
+        action_record_key: incoming_ar.self_ar_key,
+        cost_basis: Cell::new(basis),
+        ratio_of_amt_to_incoming_mvmts_in_a_r: dec!(1.0),
+        ratio_of_amt_to_outgoing_mvmts_in_a_r: Cell::new(dec!(1.0)),
+        lot_num: lot.lot_number,
+        proceeds: Cell::new(-basis),
+        proceeds_lk: Cell::new(-basis),
+        cost_basis_lk: Cell::new(basis),
+        wash_sale_adjustment: Cell::new(dec!(0.0)),
+        fee_expense: Cell::new(dec!(0)),
+        is_reallocation: false,
+        carried_to_lot_num: Cell::new(None),
+    };
+    wrap_mvmt_and_push(
+        incoming_mvmt,
+        &incoming_ar,
+        &lot,
+        chosen_home_currency,
+        raw_acct,
+    );
+    to_acct.list_of_lots.borrow_mut().push(lot);
+
+    (outgoing_ar, incoming_ar)
 }
 
 /// This is for the surprisingly common occasion (not surprising once you think about it) when an
@@ -1081,9 +1654,9 @@ fn process_multiple_incoming_lots_and_mvmts(
     txns_map: &HashMap<u32, Transaction>,
     ar_map: &HashMap<u32, ActionRecord>,
     raw_acct: &RawAccount,
-) {
+) -> Result<(), CrptlsError> {
 
-    let txn = txns_map.get(&txn_num).expect("Couldn't get txn. Tx num invalid?");
+    let txn = get_txn(txns_map, txn_num)?;
 
     let acct_of_incoming_ar = acct_map.get(&incoming_ar.account_key).unwrap();
 
@@ -1099,10 +1672,13 @@ fn process_multiple_incoming_lots_and_mvmts(
         // println!("Ratio of outgoing amt to total actionrecord amt: {:.8}", ratio_of_outgoing_to_total_ar);
         let tentative_incoming_amt = ratio_of_outgoing_mvmt_to_total_ar * incoming_ar.amount;
         // println!("Unrounded incoming amt: {}", tentative_incoming_amt);
-        let corresponding_incoming_amt = tentative_incoming_amt.round_dp(8);
+        let corresponding_incoming_amt = tentative_incoming_amt.round_dp(raw_acct.precision);
         // println!("Rounded incoming amt: {}", corresponding_incoming_amt);
-        if corresponding_incoming_amt == dec!(0) { continue }  //  Due to rounding, this could be zero.
-        assert!(corresponding_incoming_amt > dec!(0.0));
+        //  Due to rounding, this could be zero.  Its units are then carried into the final `lot`, below.
+        if corresponding_incoming_amt == dec!(0) { continue }
+        if corresponding_incoming_amt < dec!(0.0) {
+            return Err(CrptlsError::invalid_txn(txn, "A transfer can't carry a negative amount.".to_string()))
+        }
         let this_acct = acct_of_incoming_ar;
         let length_of_list_of_lots: usize = this_acct.list_of_lots.borrow().len();
         let inherited_date = outgoing_mvmt.get_lot(acct_map, ar_map).date_of_first_mvmt_in_lot;
@@ -1119,22 +1695,26 @@ fn process_multiple_incoming_lots_and_mvmts(
         )
         ;
         let incoming_mvmt = Movement {
-            amount: corresponding_incoming_amt.round_dp(8),
+            amount: corresponding_incoming_amt.round_dp(raw_acct.precision),
             date_as_string: txn.date_as_string.clone(),
             date: txn.date,
             transaction_key: txn_num,
             action_record_key: incoming_ar.self_ar_key,
             cost_basis: Cell::new(dec!(0.0)),
-            ratio_of_amt_to_incoming_mvmts_in_a_r: round_d128_1e8(&ratio_of_outgoing_mvmt_to_total_ar),
+            ratio_of_amt_to_incoming_mvmts_in_a_r: round_ratio(&ratio_of_outgoing_mvmt_to_total_ar, raw_acct.precision),
             ratio_of_amt_to_outgoing_mvmts_in_a_r: Cell::new(dec!(1.0)),
             lot_num: inner_lot.lot_number,
             proceeds: Cell::new(dec!(0.0)),
             proceeds_lk: Cell::new(dec!(0.0)),
             cost_basis_lk: Cell::new(dec!(0.0)),
+            wash_sale_adjustment: Cell::new(dec!(0.0)),
+            fee_expense: Cell::new(dec!(0)),
+            is_reallocation: false,
+            carried_to_lot_num: Cell::new(None),
         };
         // println!("From first set of incoming movements, amount: {} {} to account: {}",
         //     incoming_mvmt.amount, acct_incoming_ar.ticker, acct_incoming_ar.account_num);
-        all_but_last_incoming_mvmt_ratio += round_d128_1e8(&ratio_of_outgoing_mvmt_to_total_ar);
+        all_but_last_incoming_mvmt_ratio += round_ratio(&ratio_of_outgoing_mvmt_to_total_ar, raw_acct.precision);
         all_but_last_incoming_mvmt_amt += incoming_mvmt.amount;
         wrap_mvmt_and_push(
             incoming_mvmt,
@@ -1143,11 +1723,14 @@ fn process_multiple_incoming_lots_and_mvmts(
             &chosen_home_currency,
             &raw_acct,
         );
+        outgoing_mvmt.carried_to_lot_num.set(Some(inner_lot.lot_number));
         this_acct.list_of_lots.borrow_mut().push(inner_lot);
     }
     //  Second iteration, for final movement
     let corresponding_incoming_amt = incoming_ar.amount - all_but_last_incoming_mvmt_amt;
-    assert!(corresponding_incoming_amt > dec!(0.0));
+    if corresponding_incoming_amt <= dec!(0.0) {
+        return Err(CrptlsError::invalid_txn(txn, "A transfer must carry a positive amount into its final lot.".to_string()))
+    }
     let this_acct = acct_of_incoming_ar;
     let length_of_list_of_lots = this_acct.list_of_lots.borrow().len();
     let inherited_date = final_og_mvmt.get_lot(acct_map, ar_map).date_of_first_mvmt_in_lot;
@@ -1164,7 +1747,7 @@ fn process_multiple_incoming_lots_and_mvmts(
     )
     ;
     let incoming_mvmt = Movement {
-        amount: corresponding_incoming_amt.round_dp(8),
+        amount: corresponding_incoming_amt.round_dp(raw_acct.precision),
         date_as_string: txn.date_as_string.clone(),
         date: txn.date,
         transaction_key: txn_num,
@@ -1176,6 +1759,10 @@ fn process_multiple_incoming_lots_and_mvmts(
         proceeds: Cell::new(dec!(0.0)),
         proceeds_lk: Cell::new(dec!(0.0)),
         cost_basis_lk: Cell::new(dec!(0.0)),
+        wash_sale_adjustment: Cell::new(dec!(0.0)),
+        fee_expense: Cell::new(dec!(0)),
+        is_reallocation: false,
+        carried_to_lot_num: Cell::new(None),
     };
     // println!("Final incoming mvmt for this actionrecord, amount: {} {} to account: {}",
     //     incoming_mvmt.amount, acct_incoming_ar.ticker, acct_incoming_ar.account_num);
@@ -1186,5 +1773,10 @@ fn process_multiple_incoming_lots_and_mvmts(
         &chosen_home_currency,
         &raw_acct,
     );
+    for outgoing_mvmt in list_of_mvmts_of_outgoing_ar.iter() {
+        if outgoing_mvmt.carried_to_lot_num.get().is_none() { outgoing_mvmt.carried_to_lot_num.set(Some(lot.lot_number)) }
+    }
     this_acct.list_of_lots.borrow_mut().push(lot);
+
+    Ok(())
 }
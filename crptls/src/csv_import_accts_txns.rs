@@ -1,54 +1,57 @@
 // Copyright (c) 2017-2023, scoobybejesus
 // Redistributions must include the license: https://github.com/scoobybejesus/cryptools/blob/master/LEGAL.txt
 
-use std::error::Error;
-use std::process;
 use std::fs::File;
 use std::cell::RefCell;
-use std::collections::HashMap;
+use std::collections::{HashMap, BTreeMap};
 use std::path::PathBuf;
 
 use chrono::NaiveDate;
 use rust_decimal::Decimal;
 use rust_decimal_macros::dec;
 
-use crate::transaction::{Transaction, ActionRecord};
-use crate::account::{Account, RawAccount};
-use crate::decimal_utils::round_d128_1e8;
+use crate::transaction::{Transaction, ActionRecord, FeeLeg, MarginCharge, RoundedAmount};
+use crate::account::{Account, RawAccount, MarginPairDeclaration};
+use crate::decimal_utils::{round_d128_generalized, DEFAULT_QUANTITY_PRECISION};
+use crate::core_functions::ImportProcessParameters;
+use crate::errors::CrptlsError;
 
 
 pub fn import_from_csv(
     import_file_path: PathBuf,
-    iso_date_style: bool,
-    separator: &String,
+    settings: &ImportProcessParameters,
     raw_acct_map: &mut HashMap<u16, RawAccount>,
     acct_map: &mut HashMap<u16, Account>,
     action_records: &mut HashMap<u32, ActionRecord>,
     transactions_map: &mut HashMap<u32, Transaction>,
-) -> Result<(), Box<dyn Error>> {
+) -> Result<(), CrptlsError> {
 
-    let file = match File::open(import_file_path) {
+    let file = match File::open(&import_file_path) {
         Ok(x) => {
             // println!("\nCSV ledger file opened successfully.\n");
             x
         },
-        Err(e) => {
-            println!("Invalid import_file_path");
-            eprintln!("System error: {}", e);
-            std::process::exit(1);
-        }
+        Err(e) => return Err(CrptlsError::FileOpen { path: import_file_path, source: e })
     };
 
     let mut rdr = csv::ReaderBuilder::new()
         .has_headers(true)
         .from_reader(file);
 
-    import_accounts(&mut rdr, raw_acct_map, acct_map)?;
+    //  An optional `fee` column may follow the memo, in which case the account columns start one column later.
+    let has_fee_column = match rdr.headers()?.get(3) {
+        Some(field) => field.trim().eq_ignore_ascii_case("fee"),
+        None => false,
+    };
+    let first_acct_col = if has_fee_column { 4 } else { 3 };
+
+    import_accounts(&mut rdr, first_acct_col, settings, raw_acct_map, acct_map)?;
 
     import_transactions(
         &mut rdr,
-        iso_date_style,
-        &separator,
+        first_acct_col,
+        settings,
+        raw_acct_map,
         action_records,
         transactions_map,
     )?;
@@ -58,9 +61,11 @@ pub fn import_from_csv(
 
 fn import_accounts(
     rdr: &mut csv::Reader<File>,
+    first_acct_col: usize,
+    settings: &ImportProcessParameters,
     raw_acct_map: &mut HashMap<u16, RawAccount>,
     acct_map: &mut HashMap<u16, Account>,
-) -> Result<(), Box<dyn Error>> {
+) -> Result<(), CrptlsError> {
 
     let header1 = rdr.headers()?.clone();   //  account_num
     let mut header2: csv::StringRecord = csv::StringRecord::new();  //  name
@@ -95,78 +100,171 @@ The next column's value should be 2, then 3, etc, until the final account).";
 
             let length = &headerstrings.len();
 
-            for (idx, field) in headerstrings[3..*length].iter().enumerate() {
+            for (idx, field) in headerstrings[first_acct_col..*length].iter().enumerate() {
+
+                let ind = idx + first_acct_col; // Add the 'key' columns back, since the idx skips them
 
                 // Parse account numbers.
-                let account_num = field.trim().parse::<u16>().expect(&format!("Header row account number should parse into u16: {}", field));
+                let account_num = match field.trim().parse::<u16>() {
+                    Ok(x) => x,
+                    Err(_) => return Err(CrptlsError::InvalidAccountHeader {
+                        column: ind + 1,
+                        reason: format!("Account number `{}` should parse into u16.", field)
+                    })
+                };
                 // For now, their columns aren't remembered.  Instead, they must have a particular index. 0th idx is the 1st account, and so on.
                 if account_num != ((idx + 1) as u16) {
-                    println!("FATAL: CSV Import: {}", acct_num_warn);
-                    std::process::exit(1);
+                    return Err(CrptlsError::InvalidAccountHeader { column: ind + 1, reason: acct_num_warn.to_string() })
                 }
 
-                let ind = idx+3; // Add three because the idx skips the first three 'key' columns
                 let name:String = header2[ind].trim().to_string();
                 let ticker:String = header3[ind].trim().to_string();   //  no .to_uppercase() b/c margin...
-                let margin_string = &header4.clone()[ind];
+                let margin_string = header4[ind].to_lowercase();
+                // A margin account may declare its pair after the margin value (i.e., `margin:pair1:base`).
+                let margin_spec: Vec<&str> = margin_string.trim().split(':').collect();
 
-                let is_margin:bool = match margin_string.to_lowercase().trim() {
+                let is_margin:bool = match margin_spec[0] {
                     "no" | "non" | "false" => false,
                     "yes" | "margin" | "true" => true,
-                    _ => {
-                        println!("\n FATAL: CSV Import: Couldn't parse margin value for account {} {} \n",account_num, name);
-                        process::exit(1)
+                    _ => return Err(CrptlsError::InvalidAccountHeader {
+                        column: ind + 1,
+                        reason: format!("Couldn't parse margin value for account {} {}.", account_num, name)
+                    })
+                };
+
+                let margin_pair = if margin_spec.len() == 1 {
+                    None
+                } else {
+                    match parse_margin_pair_declaration(is_margin, &margin_spec) {
+                        Some(declaration) => Some(declaration),
+                        None => return Err(CrptlsError::InvalidAccountHeader {
+                            column: ind + 1,
+                            reason: format!("Couldn't parse margin pair declaration for account {} {}. \
+                                It should look like `margin:pair1:base` or `margin:pair1:quote`.", account_num, name)
+                        })
                     }
                 };
 
-                let just_account: RawAccount = RawAccount {
+                let mut just_account: RawAccount = RawAccount {
                     account_num,
                     name,
                     ticker,
                     is_margin,
+                    margin_pair,
+                    precision: DEFAULT_QUANTITY_PRECISION,
                 };
+                if let Some(precision) = settings.ticker_precision.get(&just_account.underlying_ticker().to_uppercase()) {
+                    just_account.precision = *precision;
+                } else if just_account.is_home_currency(&settings.home_currency) {
+                    just_account.precision = settings.home_currency_minor_units;
+                }
 
                 raw_acct_map.insert(account_num, just_account);
 
                 let account: Account = Account {
                     raw_key: account_num,
-                    list_of_lots: RefCell::new([].to_vec())
+                    list_of_lots: RefCell::new([].to_vec()),
+                    pool_totals: RefCell::new(BTreeMap::new()),
                 };
 
                 acct_map.insert(account_num, account);
             }
+
+            validate_margin_pair_declarations(first_acct_col, raw_acct_map)?;
+
             break    //  This `break` exits this scope so `accounts` can be accessed in `import_transactions`. The rdr stays put.
         }
     };
     Ok(())
 }
 
+/// Parses the parts of a margin header row value that declares a margin pair (i.e., `margin:pair1:base`).
+/// A base `account` may list several pair IDs separated by `+`.
+fn parse_margin_pair_declaration(is_margin: bool, margin_spec: &[&str]) -> Option<MarginPairDeclaration> {
+
+    if !is_margin || margin_spec.len() != 3 { return None }
+
+    let pair_ids: Vec<String> = margin_spec[1].split('+').map(|id| id.trim().to_string()).collect();
+    if pair_ids.iter().any(|id| id.is_empty()) { return None }
+
+    let is_quote = match margin_spec[2].trim() {
+        "base" => false,
+        "quote" if pair_ids.len() == 1 => true,
+        _ => return None,
+    };
+
+    Some(MarginPairDeclaration { pair_ids, is_quote })
+}
+
+/// Ensures every declared margin pair ID has exactly one base `account` and one quote `account`.
+fn validate_margin_pair_declarations(
+    first_acct_col: usize,
+    raw_acct_map: &HashMap<u16, RawAccount>,
+) -> Result<(), CrptlsError> {
+
+    // For each pair ID, the base and quote `account` numbers that declared it.
+    let mut pairs: HashMap<&str, (Vec<u16>, Vec<u16>)> = HashMap::new();
+
+    for raw_acct in raw_acct_map.values() {
+        if let Some(declaration) = &raw_acct.margin_pair {
+            for pair_id in declaration.pair_ids.iter() {
+                let (bases, quotes) = pairs.entry(pair_id.as_str()).or_default();
+                if declaration.is_quote {
+                    quotes.push(raw_acct.account_num)
+                } else {
+                    bases.push(raw_acct.account_num)
+                }
+            }
+        }
+    }
+
+    let mut pair_ids: Vec<&&str> = pairs.keys().collect();
+    pair_ids.sort();
+
+    for pair_id in pair_ids {
+        let (bases, quotes) = &pairs[*pair_id];
+        if bases.len() != 1 || quotes.len() != 1 {
+            let first_acct_num = bases.iter().chain(quotes.iter()).min().unwrap();
+            return Err(CrptlsError::InvalidAccountHeader {
+                column: first_acct_col + *first_acct_num as usize,
+                reason: format!("Margin pair `{}` must be declared by exactly one base account and one quote account, \
+                    but it has {} base and {} quote accounts.", pair_id, bases.len(), quotes.len())
+            })
+        }
+    }
+
+    Ok(())
+}
+
 fn import_transactions(
     rdr: &mut csv::Reader<File>,
-    iso_date_style: bool,
-    separator: &String,
+    first_acct_col: usize,
+    settings: &ImportProcessParameters,
+    raw_acct_map: &HashMap<u16, RawAccount>,
     action_records: &mut HashMap<u32, ActionRecord>,
     txns_map: &mut HashMap<u32, Transaction>,
-) -> Result<(), Box<dyn Error>> {
+) -> Result<(), CrptlsError> {
 
+    let iso_date_style = settings.input_file_uses_iso_date_style;
+    let separator = &settings.input_file_date_separator;
     let mut this_tx_number = 0;
     let mut this_ar_number = 0;
-    let mut changed_action_records = 0;
-    let mut changed_txn_num = Vec::new();
 
     println!("Creating transactions...");
 
     for result in rdr.records() {
 
-        //  rdr's cursor is at row 5, which is the first transaction row
         let record = result?;
         this_tx_number += 1;
+        //  Blank lines are skipped, so the line is taken from the reader rather than counted from the txn number.
+        let this_csv_line = record.position().map_or(0, |position| position.line() as u32);
 
         //  First, initialize metadata fields.
         let mut this_tx_date: &str = "";
         let mut this_proceeds: &str;
         let mut this_memo: &str = "";
-        let mut proceeds_parsed = 0f32;
+        let mut proceeds_parsed = dec!(0);
+        let mut fee_parsed = dec!(0);
 
         //  Next, create action_records.
         let mut action_records_map_keys_vec: Vec<u32> = Vec::with_capacity(2);
@@ -174,6 +272,9 @@ fn import_transactions(
         let mut incoming_ar: Option<ActionRecord> = None;
         let mut outgoing_ar_num: Option<u32> = None;
         let mut incoming_ar_num: Option<u32> = None;
+        let mut fee_leg: Option<FeeLeg> = None;
+        let mut margin_charge: Option<MarginCharge> = None;
+        let mut rounded_amounts: Vec<RoundedAmount> = Vec::new();
 
         for (idx, field) in record.iter().enumerate() {
 
@@ -181,25 +282,74 @@ fn import_transactions(
             if idx == 0 { this_tx_date = field; }
             else if idx == 1 {
                 let no_comma_string = field.replace(",", "");
-                proceeds_parsed = no_comma_string.parse::<f32>()?;
+                proceeds_parsed = match no_comma_string.parse::<Decimal>() {
+                    Ok(x) => x.normalize(),   //  Trailing zeros dropped, as when proceeds were parsed as an f32
+                    _ => return Err(CrptlsError::parse(this_tx_number, this_csv_line, field, "Proceeds should be a number."))
+                };
             }
 
             else if idx == 2 { this_memo = field; }
 
+            else if idx < first_acct_col {
+                let no_comma_string = field.replace(",", "");
+                if !no_comma_string.trim().is_empty() {
+                    fee_parsed = match no_comma_string.trim().parse::<Decimal>() {
+                        Ok(x) => round_d128_generalized(&x, settings.home_currency_minor_units),
+                        Err(_) => return Err(CrptlsError::parse(this_tx_number, this_csv_line, field, "The fee should be a number."))
+                    };
+                }
+                if fee_parsed < dec!(0) {
+                    return Err(CrptlsError::invalid_row(this_tx_number, this_csv_line,
+                        "The txn has a negative fee. Fees are entered as positive values.".to_string()))
+                }
+                //  The fee is the part of the proceeds that went to the exchange, so it can't be more than all of them.
+                if fee_parsed > proceeds_parsed {
+                    return Err(CrptlsError::invalid_row(this_tx_number, this_csv_line, format!("The txn's fee \
+                        ({}) is more than its proceeds ({}).", fee_parsed, proceeds_parsed)))
+                }
+            }
+
             //  Check for empty strings. If not empty, it's a value for an action_record.
             else if field != "" {
                 this_ar_number += 1;
-                let ind = idx;  //  starts at 3, which is the fourth field
-                let acct_idx = ind - 2; //  acct_num and acct_key would be idx + 1, so subtract 2 from ind to get 1
+                let ind = idx;  //  starts at first_acct_col, which is the fourth (or, with a fee column, fifth) field
+                let acct_idx = ind + 1 - first_acct_col; //  acct_num and acct_key start at 1
                 let account_key = acct_idx as u16;
+                let raw_acct = match raw_acct_map.get(&account_key) {
+                    Some(x) => x,
+                    None => return Err(CrptlsError::invalid_row(this_tx_number, this_csv_line,
+                        format!("The txn has an amount in column {}, which has no account.", idx + 1)))
+                };
+
+                //  A fee leg is written as its amount, then `fee`, then its value in home currency (e.g. `-0.01 fee 3.50`).
+                //  A margin charge is written as its amount, then `interest` or `funding` (e.g. `-0.0005 interest`).
+                let mut parts = field.split_whitespace();
+                let amount_part = parts.next().unwrap_or("");
+                let mut charge: Option<MarginCharge> = None;
+                let fee_value = match (parts.next(), parts.next(), parts.next()) {
+                    (None, _, _) => None,
+                    (Some(tag), None, _) if tag.eq_ignore_ascii_case("interest") => {
+                        charge = Some(MarginCharge::Interest);
+                        None
+                    }
+                    (Some(tag), None, _) if tag.eq_ignore_ascii_case("funding") => {
+                        charge = Some(MarginCharge::Funding);
+                        None
+                    }
+                    (Some(tag), Some(value), None) if tag.eq_ignore_ascii_case("fee") => {
+                        match value.replace(",", "").parse::<Decimal>() {
+                            Ok(x) => Some(round_d128_generalized(&x, settings.home_currency_minor_units)),
+                            Err(_) => return Err(CrptlsError::parse(this_tx_number, this_csv_line, value, "The fee leg's value should be a number."))
+                        }
+                    }
+                    _ => return Err(CrptlsError::parse(this_tx_number, this_csv_line, field, "A fee leg is written as, e.g., \
+                        `-0.01 fee 3.50`, and a margin charge as, e.g., `-0.0005 interest`."))
+                };
 
-                let amount_str = field.replace(",", "");
+                let amount_str = amount_part.replace(",", "");
                 let amount = match amount_str.parse::<Decimal>() {
                     Ok(x) => x,
-                    Err(e) => {
-                        println!("FATAL: Couldn't convert amount to d128 for transaction:\n{:#?}", record);
-                        println!("Error: {}", e);
-                        std::process::exit(1);}
+                    Err(_) => return Err(CrptlsError::parse(this_tx_number, this_csv_line, field, "The amount should be a number."))
                 };
 
                 // When parsing to a d128, it won't error; rather it'll return a NaN. It must now check for NaN,
@@ -219,8 +369,10 @@ fn import_transactions(
                 //     std::process::exit(1);
                 // }
 
-                let amount_rounded = round_d128_1e8(&amount);
-                if amount != amount_rounded { changed_action_records += 1; changed_txn_num.push(this_tx_number); }
+                let amount_rounded = round_d128_generalized(&amount, raw_acct.precision);
+                if amount != amount_rounded {
+                    rounded_amounts.push(RoundedAmount { action_record_key: this_ar_number, amount_as_entered: amount });
+                }
 
                 let action_record = ActionRecord {
                     account_key,
@@ -230,6 +382,29 @@ fn import_transactions(
                     movements: RefCell::new([].to_vec()),
                 };
 
+                if let Some(value) = fee_value {
+                    if amount >= dec!(0.0) || value < dec!(0.0) || fee_leg.is_some() {
+                        return Err(CrptlsError::invalid_row(this_tx_number, this_csv_line, "The txn may have one fee leg, and it must \
+                            be a negative amount with a non-negative value.".to_string()))
+                    }
+                    fee_leg = Some(FeeLeg { action_record_key: this_ar_number, value });
+                    action_records.insert(this_ar_number, action_record);
+                    continue
+                }
+
+                if charge.is_some() {
+                    if amount >= dec!(0.0) {
+                        return Err(CrptlsError::invalid_row(this_tx_number, this_csv_line,
+                            "The txn has a margin charge, which must be a negative amount.".to_string()))
+                    }
+                    margin_charge = charge;
+                }
+
+                if (amount > dec!(0.0) && incoming_ar.is_some()) || (amount <= dec!(0.0) && outgoing_ar.is_some()) {
+                    return Err(CrptlsError::invalid_row(this_tx_number, this_csv_line, "The txn has more than one incoming or outgoing \
+                        amount. Any third amount must be designated as the fee leg (e.g., `-0.01 fee 3.50`).".to_string()))
+                }
+
                 if amount > dec!(0.0) {
                     incoming_ar = Some(action_record);
                     incoming_ar_num = Some(this_ar_number);
@@ -330,28 +505,60 @@ fn import_transactions(
             format_yy = "%m".to_owned() + separator + "%d" + separator + "%y";
         }
 
-        let tx_date = NaiveDate::parse_from_str(this_tx_date, &format_yy)
-            .unwrap_or_else(|_| NaiveDate::parse_from_str(this_tx_date, &format_yyyy)
-            .expect("
-FATAL: Transaction date parsing failed. You must tell the program the format of the date in your CSV Input File. The date separator \
+        let tx_date = match NaiveDate::parse_from_str(this_tx_date, &format_yy)
+            .or_else(|_| NaiveDate::parse_from_str(this_tx_date, &format_yyyy)) {
+            Ok(x) => x,
+            Err(_) => return Err(CrptlsError::parse(this_tx_number, this_csv_line, this_tx_date, "\
+You must tell the program the format of the date in your CSV Input File. The date separator \
 is expected to be a hyphen. The dating format is expected to be \"American\" (%m-%d-%y), not ISO 8601 (%y-%m-%d). You may set different \
-date format options via command line flag, environment variable or .env file. Perhaps first run with `--help` or see `.env.example.`\n")
-        );
+date format options via command line flag, environment variable or .env file. Perhaps first run with `--help` or see `.env.example.`"))
+        };
+
+        if action_records_map_keys_vec.is_empty() {
+            return Err(CrptlsError::invalid_row(this_tx_number, this_csv_line,
+                "The txn has no incoming or outgoing amount.".to_string()))
+        }
 
         let transaction = Transaction {
             tx_number: this_tx_number,
+            csv_line: this_csv_line,
             date_as_string: this_tx_date.to_string(),
             date: tx_date,
             user_memo: this_memo.to_string(),
             proceeds: proceeds_parsed,
             action_record_idx_vec: action_records_map_keys_vec,
+            fee: fee_parsed,
+            fee_leg,
+            settles_realized_margin_pnl: false,
+            margin_charge,
+            rounded_amounts,
         };
 
         txns_map.insert(this_tx_number, transaction);
     };
 
-    if changed_action_records > 0 {
-        println!("  Changed actionrecord amounts due to rounding precision: {}. Changed txn numbers: {:?}.", changed_action_records, changed_txn_num);
+    let rounded_count: usize = txns_map.values().map(|txn| txn.rounded_amounts.len()).sum();
+
+    if rounded_count > 0 {
+        println!("  Changed actionrecord amounts due to rounding precision: {}.", rounded_count);
+        for txn_num in 1..=txns_map.len() as u32 {
+            let txn = txns_map.get(&txn_num).unwrap();
+            for rounded in txn.rounded_amounts.iter() {
+                let ar = action_records.get(&rounded.action_record_key).unwrap();
+                let raw_acct = raw_acct_map.get(&ar.account_key).unwrap();
+                println!("    Txn {} (line {}), account {} ({} {}, {} decimal places): {} became {}, a change of {}.",
+                    txn.tx_number,
+                    txn.csv_line,
+                    ar.account_key,
+                    raw_acct.name,
+                    raw_acct.ticker,
+                    raw_acct.precision,
+                    rounded.amount_as_entered,
+                    ar.amount,
+                    ar.amount - rounded.amount_as_entered,
+                );
+            }
+        }
     }
 
     Ok(())
@@ -0,0 +1,22 @@
+// Copyright (c) 2017-2023, scoobybejesus
+// Redistributions must include the license: https://github.com/scoobybejesus/cryptools/blob/master/LEGAL.txt
+
+/// Decimal places of a currency's minor unit when the ISO 4217 table has no entry for it (i.e., cents).
+pub const DEFAULT_MINOR_UNITS: u32 = 2;
+
+/// Returns the decimal places of the currency's minor unit per ISO 4217 (0 for JPY, 2 for USD, 3 for KWD), which is
+/// what home currency values are rounded to.  Currencies not in the table (including crypto tickers) use 2.
+pub fn iso_4217_minor_units(currency: &str) -> u32 {
+
+    match currency.to_uppercase().as_str() {
+
+        "BIF" | "CLP" | "DJF" | "GNF" | "ISK" | "JPY" | "KMF" | "KRW" | "PYG" | "RWF" | "UGX" | "UYI" | "VND"
+        | "VUV" | "XAF" | "XOF" | "XPF" => 0,
+
+        "BHD" | "IQD" | "JOD" | "KWD" | "LYD" | "OMR" | "TND" => 3,
+
+        "CLF" | "UYW" => 4,
+
+        _ => DEFAULT_MINOR_UNITS,
+    }
+}
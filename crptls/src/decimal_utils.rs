@@ -3,6 +3,9 @@
 
 use rust_decimal::Decimal;
 
+/// Decimal places amounts are rounded to, unless their ticker is given another precision.
+pub const DEFAULT_QUANTITY_PRECISION: u32 = 8;
+
 pub fn round_d128_generalized(to_round: &Decimal, places_past_decimal: u32) -> Decimal {
     let rounded: Decimal = to_round.round_dp(places_past_decimal);
     rounded//.reduce()
@@ -13,6 +16,25 @@ pub fn round_d128_1e2(to_round: &Decimal) -> Decimal {
     rounded//.reduce()
 }
 
+/// Splits `total` into parts by `shares` (each part's fraction of the whole, so they add up to 1) and rounds each part
+/// to `places_past_decimal`.  The last part takes whatever the rounding left over, so the parts add back up to `total`
+/// exactly (once `total` itself is rounded).
+pub fn allocate_rounded(total: &Decimal, shares: &[Decimal], places_past_decimal: u32) -> Vec<Decimal> {
+    let mut parts: Vec<Decimal> = shares.iter()
+        .map(|share| round_d128_generalized(&(total * share), places_past_decimal))
+        .collect();
+    let allocated: Decimal = parts.iter().sum();
+    if let Some(last) = parts.last_mut() {
+        *last += round_d128_generalized(total, places_past_decimal) - allocated;
+    }
+    parts
+}
+
+/// Ratios of one amount to another keep at least 8 places, and more for tickers with finer amounts.
+pub fn round_ratio(to_round: &Decimal, precision: u32) -> Decimal {
+    to_round.round_dp(precision.max(DEFAULT_QUANTITY_PRECISION))
+}
+
 pub fn round_d128_1e8(to_round: &Decimal) -> Decimal {
     let rounded: Decimal = to_round.round_dp(8);
     rounded//.reduce()
@@ -0,0 +1,116 @@
+// Copyright (c) 2017-2023, scoobybejesus
+// Redistributions must include the license: https://github.com/scoobybejesus/cryptools/blob/master/LEGAL.txt
+
+use std::fmt;
+use std::error::Error;
+use std::path::PathBuf;
+
+use rust_decimal::Decimal;
+
+use crate::balance_check::BalanceShortfall;
+use crate::transaction::Transaction;
+
+/// Everything that can go wrong while importing and processing the input file (and the optional lot selection,
+/// basis allocation and price files).  Errors in a `transaction` carry its number and its line in the input file.
+#[derive(Debug)]
+pub enum CrptlsError {
+    /// The settings can't be combined, or they don't fit the input file.
+    InvalidSettings(String),
+    /// A file couldn't be opened.
+    FileOpen { path: PathBuf, source: std::io::Error },
+    /// A file couldn't be read as CSV.
+    Csv(csv::Error),
+    /// An `account` column in the header rows is malformed.  `column` counts from 1.
+    InvalidAccountHeader { column: usize, reason: String },
+    /// A value in a `transaction` row couldn't be parsed.
+    Parse { tx_number: u32, csv_line: u32, value: String, reason: String },
+    /// A `transaction` row parsed, but it doesn't make a valid `transaction`.
+    InvalidTransaction { tx_number: u32, csv_line: u32, reason: String },
+    /// Margin `account`s traded with each other, but they don't make a margin pair.
+    BadMarginPair { tx_number: u32, csv_line: u32, reason: String },
+    /// A `transaction` spends more from an `account` than its `lot`s hold.
+    InsufficientLots { tx_number: u32, csv_line: u32, acct_key: u16, ticker: String, amount: Decimal, balance: Decimal },
+    /// Non-margin `account`s spend more than they hold.  Found before any `lot`s are created.
+    BalanceShortfalls(Vec<BalanceShortfall>),
+    /// A row of the lot selection file is invalid.
+    LotSelectionFile { row: usize, reason: String },
+    /// The basis allocation file is invalid.  `row` is `None` when the problem spans rows.
+    BasisAllocationFile { row: Option<usize>, reason: String },
+    /// A row of the price file is invalid.
+    PriceFile { row: usize, reason: String },
+}
+
+impl CrptlsError {
+
+    /// A value of the input file row at `csv_line`, which would have been `transaction` `tx_number`, couldn't be parsed.
+    pub(crate) fn parse(tx_number: u32, csv_line: u32, value: &str, reason: &str) -> CrptlsError {
+        CrptlsError::Parse {
+            tx_number,
+            csv_line,
+            value: value.to_string(),
+            reason: reason.to_string(),
+        }
+    }
+
+    /// The input file row at `csv_line`, which would have been `transaction` `tx_number`, isn't a valid `transaction`.
+    pub(crate) fn invalid_row(tx_number: u32, csv_line: u32, reason: String) -> CrptlsError {
+        CrptlsError::InvalidTransaction { tx_number, csv_line, reason }
+    }
+
+    pub(crate) fn invalid_txn(txn: &Transaction, reason: String) -> CrptlsError {
+        CrptlsError::InvalidTransaction { tx_number: txn.tx_number, csv_line: txn.csv_line, reason }
+    }
+
+    pub(crate) fn bad_margin_pair(txn: &Transaction, reason: String) -> CrptlsError {
+        CrptlsError::BadMarginPair { tx_number: txn.tx_number, csv_line: txn.csv_line, reason }
+    }
+}
+
+impl fmt::Display for CrptlsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CrptlsError::InvalidSettings(reason) => write!(f, "{}", reason),
+            CrptlsError::FileOpen { path, source } => write!(f, "Couldn't open {}: {}", path.display(), source),
+            CrptlsError::Csv(e) => write!(f, "Couldn't read CSV: {}", e),
+            CrptlsError::InvalidAccountHeader { column, reason } => {
+                write!(f, "Header rows, column {}: {}", column, reason)
+            }
+            CrptlsError::Parse { tx_number, csv_line, value, reason } => {
+                write!(f, "Txn {} (line {}): couldn't parse `{}`. {}", tx_number, csv_line, value, reason)
+            }
+            CrptlsError::InvalidTransaction { tx_number, csv_line, reason }
+            | CrptlsError::BadMarginPair { tx_number, csv_line, reason } => {
+                write!(f, "Txn {} (line {}): {}", tx_number, csv_line, reason)
+            }
+            CrptlsError::InsufficientLots { tx_number, csv_line, acct_key, ticker, amount, balance } => {
+                write!(f, "Txn {} (line {}) spends {} {} from account {}, which has run out of lots to spend from. \
+                    Account balance is only: {}", tx_number, csv_line, amount, ticker, acct_key, balance)
+            }
+            CrptlsError::BalanceShortfalls(shortfalls) => {
+                write!(f, "Found {} balance shortfall(s) in the input file.", shortfalls.len())
+            }
+            CrptlsError::LotSelectionFile { row, reason } => write!(f, "Lot selection file row {}: {}", row, reason),
+            CrptlsError::BasisAllocationFile { row: Some(row), reason } => {
+                write!(f, "Basis allocation file row {}: {}", row, reason)
+            }
+            CrptlsError::BasisAllocationFile { row: None, reason } => write!(f, "Basis allocation file: {}", reason),
+            CrptlsError::PriceFile { row, reason } => write!(f, "Price file row {}: {}", row, reason),
+        }
+    }
+}
+
+impl Error for CrptlsError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            CrptlsError::FileOpen { source, .. } => Some(source),
+            CrptlsError::Csv(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<csv::Error> for CrptlsError {
+    fn from(e: csv::Error) -> CrptlsError {
+        CrptlsError::Csv(e)
+    }
+}
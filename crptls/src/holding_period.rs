@@ -0,0 +1,142 @@
+// Copyright (c) 2017-2023, scoobybejesus
+// Redistributions must include the license: https://github.com/scoobybejesus/cryptools/blob/master/LEGAL.txt
+
+use std::fmt;
+
+use chrono::{Months, NaiveDate};
+
+use crate::account::Term;
+use crate::errors::CrptlsError;
+
+/// A holding period a unit must outlast to move into the next `Term`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum HoldingPeriod {
+    /// Disposed of after the basis date's anniversary this many years on (the US "more than one year" rule).
+    /// A February 29 basis date has its anniversary on February 28.
+    Years(u32),
+    /// Disposed of more than this many days after the basis date.
+    Days(u32),
+}
+
+impl HoldingPeriod {
+
+    fn is_outlasted(&self, basis_date: NaiveDate, disposal_date: NaiveDate) -> bool {
+        match *self {
+            HoldingPeriod::Years(years) => {
+                match basis_date.checked_add_months(Months::new(years * 12)) {
+                    Some(anniversary) => disposal_date > anniversary,
+                    None => false,
+                }
+            }
+            HoldingPeriod::Days(days) => {
+                disposal_date.signed_duration_since(basis_date) > chrono::Duration::days(days as i64)
+            }
+        }
+    }
+
+    /// Roughly the number of days, only for checking that a rule's periods increase.
+    fn approx_days(&self) -> u32 {
+        match *self {
+            HoldingPeriod::Years(years) => years * 365,
+            HoldingPeriod::Days(days) => days,
+        }
+    }
+}
+
+impl fmt::Display for HoldingPeriod {
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+           HoldingPeriod::Years(1) => write!(f, "1 year (calendar)"),
+           HoldingPeriod::Years(years) => write!(f, "{} years (calendar)", years),
+           HoldingPeriod::Days(days) => write!(f, "{} days", days),
+       }
+    }
+}
+
+/// The `HoldingPeriodRule` sorts disposals into `Term`s by how long the units were held.  Each of its increasing
+/// `HoldingPeriod`s that a unit outlasts moves it into the next `Term`, so there is always one more `Term` than
+/// there are periods.  The default is short-term and long-term, split at more than 365 days.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HoldingPeriodRule {
+    periods: Vec<HoldingPeriod>,
+    /// `(abbreviation, name)` of each `Term`, shortest holding period first
+    labels: Vec<(String, String)>,
+}
+
+impl HoldingPeriodRule {
+
+    /// Builds a rule from increasing `periods`.  `labels` name the `Term`s, shortest holding period first, and there
+    /// must be one more of them than there are periods.  Without `labels`, a rule of one period has short-term and
+    /// long-term, and a rule of no periods has a single, unnamed `Term`.
+    pub fn new(periods: Vec<HoldingPeriod>, labels: Option<Vec<String>>) -> Result<HoldingPeriodRule, CrptlsError> {
+
+        if periods.windows(2).any(|pair| pair[0].approx_days() >= pair[1].approx_days()) {
+            return Err(CrptlsError::InvalidSettings("Holding periods must be listed from shortest to longest.".to_string()))
+        }
+
+        let labels = match labels {
+            Some(labels) => {
+                if labels.len() != periods.len() + 1 {
+                    return Err(CrptlsError::InvalidSettings(format!(
+                        "A holding period rule with {} period(s) needs {} term label(s), not {}.",
+                        periods.len(), periods.len() + 1, labels.len(),
+                    )))
+                }
+                labels.into_iter().map(|label| (label.clone(), label)).collect()
+            }
+            None => match periods.len() {
+                0 => vec![("".to_string(), "Realized".to_string())],
+                1 => vec![
+                    ("ST".to_string(), "Short-term".to_string()),
+                    ("LT".to_string(), "Long-term".to_string()),
+                ],
+                _ => return Err(CrptlsError::InvalidSettings(
+                    "A holding period rule with more than one period needs term labels.".to_string()
+                )),
+            },
+        };
+
+        Ok(HoldingPeriodRule { periods, labels })
+    }
+
+    /// Returns the `Term` of units with the basis date that are disposed of on `disposal_date`.
+    pub fn term(&self, basis_date: NaiveDate, disposal_date: NaiveDate) -> Term {
+
+        let rank = self.periods.iter()
+            .take_while(|period| period.is_outlasted(basis_date, disposal_date))
+            .count();
+        let (abbr, name) = &self.labels[rank];
+
+        Term { rank, abbr: abbr.clone(), name: name.clone() }
+    }
+
+    /// Returns every `Term` of the rule, shortest holding period first.
+    pub fn terms(&self) -> Vec<Term> {
+        self.labels.iter()
+            .enumerate()
+            .map(|(rank, (abbr, name))| Term { rank, abbr: abbr.clone(), name: name.clone() })
+            .collect()
+    }
+}
+
+impl Default for HoldingPeriodRule {
+
+    fn default() -> HoldingPeriodRule {
+        HoldingPeriodRule::new(vec![HoldingPeriod::Days(365)], None).unwrap()
+    }
+}
+
+impl fmt::Display for HoldingPeriodRule {
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.periods.is_empty() {
+            return write!(f, "no distinction by holding period")
+        }
+        write!(f, "{}", self.labels[0].1)?;
+        for (period, (_abbr, name)) in self.periods.iter().zip(self.labels.iter().skip(1)) {
+            write!(f, "; {} when held more than {}", name, period)?;
+        }
+        Ok(())
+    }
+}
@@ -2,7 +2,6 @@
 // Redistributions must include the license: https://github.com/scoobybejesus/cryptools/blob/master/LEGAL.txt
 
 use std::collections::HashMap;
-use std::error::Error;
 
 use chrono::NaiveDate;
 use rust_decimal::Decimal;
@@ -10,203 +9,260 @@ use rust_decimal_macros::dec;
 
 use crate::transaction::{Transaction, TxType, ActionRecord, Polarity};
 use crate::account::{Account, RawAccount};
-use crate::decimal_utils::round_d128_1e2;
-
+use crate::core_functions::ImportProcessParameters;
+use crate::average_cost;
+use crate::margin_pnl;
+use crate::decimal_utils::{round_d128_generalized, allocate_rounded};
+use crate::errors::CrptlsError;
+
+/// Adds cost basis to the `movement`s of a single `transaction`.  This is called for each `transaction` right after
+/// its `lot`s and `movement`s are created, so the basis of every earlier `lot` is known when later `lot`s are chosen.
 pub(crate) fn add_cost_basis_to_movements(
-    home_currency: &String,
+    txn_num: u32,
+    settings: &ImportProcessParameters,
     raw_acct_map: &HashMap<u16, RawAccount>,
     acct_map: &HashMap<u16, Account>,
     ars: &HashMap<u32, ActionRecord>,
     txns_map: &HashMap<u32, Transaction>,
-) -> Result<(), Box<dyn Error>> {
-
-    let length = txns_map.len();
+) -> Result<(), CrptlsError> {
 
-    for txn_num in 1..=length {
+    let home_currency = &settings.home_currency;
+    let minor_units = settings.home_currency_minor_units;
+    let txn = txns_map.get(&(txn_num)).unwrap();
+    let fee_allocation = txn.get_fee_allocation(&settings.fee_treatment, home_currency, ars, raw_acct_map, acct_map);
 
-        let txn_num = txn_num as u32;
-        let txn = txns_map.get(&(txn_num)).unwrap();
+    for ar_num in txn.ar_keys_incl_fee_leg().iter() {
 
-        for ar_num in txn.action_record_idx_vec.iter() {
-
-            let ar = ars.get(ar_num).unwrap();
-            let acct = acct_map.get(&ar.account_key).unwrap();
-            let raw_acct = raw_acct_map.get(&acct.raw_key).unwrap();
-            let movements = ar.get_mvmts_in_ar_in_lot_date_order(acct_map, txns_map);
+        let ar = ars.get(ar_num).unwrap();
+        let acct = acct_map.get(&ar.account_key).unwrap();
+        let raw_acct = raw_acct_map.get(&acct.raw_key).unwrap();
+        let movements = ar.get_mvmts_in_ar_in_lot_date_order(acct_map, txns_map);
 
-            for (idx, mvmt) in movements.iter().enumerate() {
+        // With average cost pooling, an outgoing `action record` takes its basis at the pool's running average
+        // rather than from the `lot`s its `movement`s happen to post to.
+        let pooled_bases = match &settings.average_cost_pool {
+            Some(pool) if !raw_acct.is_margin
+                && !raw_acct.is_home_currency(home_currency)
+                && ar.direction() == Polarity::Outgoing => {
+                Some(average_cost::get_pooled_basis_of_outgoing_mvmts(
+                    pool,
+                    ar,
+                    txn_num,
+                    &movements,
+                    minor_units,
+                    raw_acct_map,
+                    acct_map,
+                ))
+            }
+            _ => None,
+        };
 
-                let polarity = ar.direction();
-                let tx_type = txn.transaction_type(ars, raw_acct_map, acct_map)?;
-                let is_home_curr = raw_acct.is_home_currency(home_currency);
-                let mvmt_copy = mvmt.clone();
-                let borrowed_mvmt = mvmt_copy.clone();
-                // println!("Txn: {} on {} of type: {:?}",
-                //     txn.tx_number,txn.date, txn.transaction_type(ars, raw_acct_map, acct_map));
+        // What an incoming `action record` is worth is split among its `movement`s by these.
+        let incoming_shares: Vec<Decimal> = movements.iter().map(|mvmt| mvmt.ratio_of_amt_to_incoming_mvmts_in_a_r).collect();
 
-                if !raw_acct.is_margin {
+        for (idx, mvmt) in movements.iter().enumerate() {
 
-                    match polarity {
+            let polarity = ar.direction();
+            let tx_type = txn.transaction_type(ars, raw_acct_map, acct_map)?;
+            let is_home_curr = raw_acct.is_home_currency(home_currency);
+            let mvmt_copy = mvmt.clone();
+            let borrowed_mvmt = mvmt_copy.clone();
+            // println!("Txn: {} on {} of type: {:?}",
+            //     txn.tx_number,txn.date, txn.transaction_type(ars, raw_acct_map, acct_map));
 
-                        Polarity::Outgoing => {
+            if !raw_acct.is_margin {
 
-                            if is_home_curr {
+                match polarity {
 
-                                let mvmts_amt = mvmt_copy.amount;
+                    Polarity::Outgoing => {
 
-                                mvmt.cost_basis.set(mvmts_amt);
-                                mvmt.cost_basis_lk.set(mvmts_amt);
+                        if is_home_curr {
 
-                            } else {
+                            let mvmts_amt = mvmt_copy.amount;
 
-                                let cb_of_lots_first_mvmt = mvmt_copy.get_cost_basis_of_lots_first_mvmt(acct_map, ars);
-                                let ratio_of_amt_to_lots_first_mvmt = borrowed_mvmt.ratio_of_amt_to_lots_first_mvmt(acct_map, ars);
-                                let unrounded_basis = -(cb_of_lots_first_mvmt * ratio_of_amt_to_lots_first_mvmt);
-                                let rounded_basis = round_d128_1e2(&unrounded_basis);
+                            mvmt.cost_basis.set(mvmts_amt);
+                            mvmt.cost_basis_lk.set(mvmts_amt);
 
-                                mvmt.cost_basis.set(rounded_basis);
-                                mvmt.cost_basis_lk.set(rounded_basis);
-                            }
-                            assert!(mvmt.cost_basis.get() <= dec!(0));
-                            // assert!(mvmt.cost_basis_lk.get() <= dec!(0));   //  Same as above assert.
-                            continue
-                        }
+                        } else if let Some(bases) = &pooled_bases {
 
-                        Polarity::Incoming => {
+                            mvmt.cost_basis.set(bases[idx]);
+                            mvmt.cost_basis_lk.set(bases[idx]);
 
-                            if is_home_curr {
+                        } else {
 
-                                let mvmts_amt = mvmt_copy.amount;
+                            let cb_of_lots_first_mvmt = mvmt_copy.get_cost_basis_of_lots_first_mvmt(acct_map, ars);
+                            let ratio_of_amt_to_lots_first_mvmt = borrowed_mvmt.ratio_of_amt_to_lots_first_mvmt(acct_map, ars);
+                            let unrounded_basis = -(cb_of_lots_first_mvmt * ratio_of_amt_to_lots_first_mvmt);
+                            let rounded_basis = round_d128_generalized(&unrounded_basis, minor_units);
 
-                                mvmt.cost_basis.set(mvmts_amt);
-                                mvmt.cost_basis_lk.set(mvmts_amt);
+                            mvmt.cost_basis.set(rounded_basis);
+                            mvmt.cost_basis_lk.set(rounded_basis);
+                        }
+                        if mvmt.cost_basis.get() > dec!(0) {
+                            return Err(CrptlsError::invalid_txn(txn, format!("The amount out of account {} was given \
+                                a positive cost basis ({}).", ar.account_key, mvmt.cost_basis.get())))
+                        }
+                        continue
+                    }
 
-                            } else {
+                    Polarity::Incoming => {
 
-                                match tx_type {
+                        if is_home_curr {
 
-                                    TxType::Exchange => {
+                            let mvmts_amt = mvmt_copy.amount;
 
-                                        let other_ar = ars.get(&txn.action_record_idx_vec[0]).unwrap();
-                                        let other_acct = acct_map.get(&other_ar.account_key).unwrap();
-                                        let raw_other_acct = raw_acct_map.get(&other_acct.raw_key).unwrap();
-                                        assert_eq!(other_ar.direction(), Polarity::Outgoing);
-                                        let other_ar_is_home_curr = raw_other_acct.is_home_currency(home_currency);
+                            mvmt.cost_basis.set(mvmts_amt);
+                            mvmt.cost_basis_lk.set(mvmts_amt);
 
-                                        if other_ar_is_home_curr {
-                                            mvmt.cost_basis.set(-(other_ar.amount));
-                                            mvmt.cost_basis_lk.set(-(other_ar.amount));
+                        } else {
 
-                                        } else {
+                            match tx_type {
 
-                                            let ratio_of_amt_to_incoming_mvmts_in_a_r =
-                                                borrowed_mvmt.ratio_of_amt_to_incoming_mvmts_in_a_r;
-                                            let txn_proceeds = txn.proceeds
-                                                .to_string()
-                                                .parse::<Decimal>()
-                                                .unwrap();
-                                            let unrounded_basis = txn_proceeds * ratio_of_amt_to_incoming_mvmts_in_a_r;
-                                            let rounded_basis = round_d128_1e2(&unrounded_basis);
+                                TxType::Exchange => {
 
-                                            mvmt.cost_basis.set(rounded_basis);
-                                            mvmt.cost_basis_lk.set(rounded_basis);
-                                        }
+                                    let other_ar = ars.get(&txn.action_record_idx_vec[0]).unwrap();
+                                    let other_acct = acct_map.get(&other_ar.account_key).unwrap();
+                                    let raw_other_acct = raw_acct_map.get(&other_acct.raw_key).unwrap();
+                                    if other_ar.direction() != Polarity::Outgoing {
+                                        return Err(CrptlsError::invalid_txn(txn,
+                                            "The exchange's first amount must be the one going out.".to_string()))
                                     }
+                                    let other_ar_is_home_curr = raw_other_acct.is_home_currency(home_currency);
 
-                                    TxType::ToSelf => {
+                                    // A `fee` was netted out of what was received, so it is worth that much less than was paid.
+                                    let fee_adjustment = fee_allocation.added_to_basis - txn.fee;
 
-                                        let cb_vec_outgoing_ar = retrieve_cb_vec_from_corresponding_outgoing_toself(
-                                            txn_num,
-                                            &ars,
-                                            txns_map,
-                                            acct_map
-                                        );
+                                    if other_ar_is_home_curr {
+                                        mvmt.cost_basis.set(-(other_ar.amount) + fee_adjustment);
+                                        mvmt.cost_basis_lk.set(-(other_ar.amount) + fee_adjustment);
 
-                                        assert!(idx <= cb_vec_outgoing_ar.len(),
-                                            "ToSelf txn had different # of in- and out- mvmts (more outs than ins).");
+                                    } else {
 
-                                        let unrounded_basis = cb_vec_outgoing_ar[idx];
-                                        let rounded_basis = round_d128_1e2(&unrounded_basis);
+                                        let rounded_basis = allocate_rounded(&(txn.proceeds + fee_adjustment), &incoming_shares, minor_units)[idx];
 
-                                        mvmt.cost_basis.set(-rounded_basis);
-                                        mvmt.cost_basis_lk.set(-rounded_basis);
+                                        mvmt.cost_basis.set(rounded_basis);
+                                        mvmt.cost_basis_lk.set(rounded_basis);
                                     }
+                                }
 
-                                    TxType::Flow => {
+                                TxType::ToSelf => {
 
-                                        let txn_proceeds = txn.proceeds.to_string().parse::<Decimal>().unwrap();
-                                        let mvmt_proceeds = round_d128_1e2(
-                                            &(txn_proceeds *
-                                            borrowed_mvmt.ratio_of_amt_to_incoming_mvmts_in_a_r)
-                                        );  //  Ratio should always be 1.0, but we do the calc anyway, for future-proofing.
+                                    let unrounded_basis = get_basis_carried_into_lot_by_toself(
+                                        txn_num,
+                                        mvmt.lot_num,
+                                        &ars,
+                                        txns_map,
+                                        acct_map
+                                    )?;
+                                    let rounded_basis = round_d128_generalized(&unrounded_basis, minor_units);
 
-                                        mvmt.cost_basis.set(mvmt_proceeds);
-                                        mvmt.cost_basis_lk.set(mvmt_proceeds);
-                                    }
+                                    mvmt.cost_basis.set(-rounded_basis);
+                                    mvmt.cost_basis_lk.set(-rounded_basis);
+                                }
+
+                                TxType::Flow => {
+
+                                    //  Ratio should always be 1.0, but we do the calc anyway, for future-proofing.
+                                    let mvmt_proceeds = allocate_rounded(&txn.proceeds, &incoming_shares, minor_units)[idx];
+
+                                    mvmt.cost_basis.set(mvmt_proceeds);
+                                    mvmt.cost_basis_lk.set(mvmt_proceeds);
                                 }
                             }
-                            assert!(mvmt.cost_basis.get() >= dec!(0));
-                            // assert!(mvmt.cost_basis_lk.get() >= dec!(0));   //  Same as above assert.
-                            continue
                         }
+                        if mvmt.cost_basis.get() < dec!(0) {
+                            return Err(CrptlsError::invalid_txn(txn, format!("The amount into account {} was given \
+                                a negative cost basis ({}).  Are the txn's proceeds negative?", ar.account_key, mvmt.cost_basis.get())))
+                        }
+                        continue
                     }
-                } else {
-                    // Do nothing. Future changes can add a code path where margin txns "settle"
-                    // as they happen, though, if desired. Just need to write the code.
                 }
+            } else {
+                // Margin `movement`s have no basis of their own.  If margin profit and loss are realized as
+                // positions change, that basis is added below, once every `movement` of the `transaction` has its own.
             }
         }
     }
 
-    fn retrieve_cb_vec_from_corresponding_outgoing_toself(
+    if txns_map.get(&txn_num).unwrap().margin_charge.is_some() {
+        margin_pnl::add_margin_charge_basis(txn_num, minor_units, acct_map, ars, txns_map)?;
+    }
+
+    if settings.realize_margin_pnl {
+        margin_pnl::add_realized_margin_basis(txn_num, minor_units, raw_acct_map, acct_map, ars, txns_map)?;
+    } else {
+        margin_pnl::add_margin_settlement_basis(txn_num, minor_units, raw_acct_map, acct_map, ars, txns_map)?;
+    }
+
+    /// The outgoing `movement`s record which new `lot` took their units.  Rounding can leave a `lot` with the units
+    /// of more than one of them, so this sums the basis of every one carried into `lot_num`.
+    fn get_basis_carried_into_lot_by_toself(
         txn_num: u32,
+        lot_num: u32,
         ars: &HashMap<u32, ActionRecord>,
         txns_map: &HashMap<u32, Transaction>,
         acct_map: &HashMap<u16, Account>,
-    ) -> Vec<Decimal> {
+    ) -> Result<Decimal, CrptlsError> {
 
         let txn = txns_map.get(&txn_num).unwrap();
         let other_ar_borrowed = &ars.get(&txn.action_record_idx_vec[0]).unwrap();
 
-        assert_eq!(other_ar_borrowed.direction(), Polarity::Outgoing);
+        if other_ar_borrowed.direction() != Polarity::Outgoing {
+            return Err(CrptlsError::invalid_txn(txn, "The transfer's first amount must be the one going out.".to_string()))
+        }
 
         let movements = other_ar_borrowed.get_mvmts_in_ar_in_lot_date_order(acct_map, txns_map);
-        let mut vec = Vec::new();
 
-        for mvmt in movements.iter() {
-            vec.push(mvmt.cost_basis.get());
-        }
-
-        vec
+        Ok(movements.iter()
+            .filter(|mvmt| mvmt.carried_to_lot_num.get() == Some(lot_num))
+            .map(|mvmt| mvmt.cost_basis.get())
+            .sum())
     }
 
     Ok(())
 }
 
 pub(crate) fn add_proceeds_to_movements(
+    settings: &ImportProcessParameters,
     raw_acct_map: &HashMap<u16, RawAccount>,
     acct_map: &HashMap<u16, Account>,
     ars: &HashMap<u32, ActionRecord>,
     txns_map: &HashMap<u32, Transaction>,
-) -> Result<(), Box<dyn Error>> {
+) -> Result<(), CrptlsError> {
 
     let length = txns_map.len();
+    let minor_units = settings.home_currency_minor_units;
 
     for txn_num in 1..=length {
 
         let txn_num = txn_num as u32;
         let txn = txns_map.get(&(txn_num)).unwrap();
+        let fee_allocation = txn.get_fee_allocation(&settings.fee_treatment, &settings.home_currency, ars, raw_acct_map, acct_map);
 
-        for ar_num in txn.action_record_idx_vec.iter() {
+        for ar_num in txn.ar_keys_incl_fee_leg().iter() {
 
             let ar = ars.get(ar_num).unwrap();
             let acct = acct_map.get(&ar.account_key).unwrap();
             let raw_acct = raw_acct_map.get(&acct.raw_key).unwrap();
             let movements = ar.get_mvmts_in_ar_in_lot_date_order(acct_map, txns_map);
 
+            // The fee leg's own proceeds are the fee's value, and it bears none of the trade's fees.
+            let (ar_proceeds, ar_fee_expense) = match &txn.fee_leg {
+                Some(fee_leg) if fee_leg.action_record_key == *ar_num => (fee_leg.value, dec!(0)),
+                _ => (
+                    txn.proceeds - fee_allocation.taken_from_proceeds,
+                    fee_allocation.expensed,
+                ),
+            };
+
             if !raw_acct.is_margin {
 
-                for mvmt in movements.iter() {
+                // An outgoing `action record`'s proceeds (and fee expense) are split among its `movement`s by amount.
+                let outgoing_shares: Vec<Decimal> = movements.iter().map(|mvmt| mvmt.amount / ar.amount).collect();
+                let mvmt_proceeds = allocate_rounded(&ar_proceeds, &outgoing_shares, minor_units);
+                let mvmt_fee_expenses = allocate_rounded(&ar_fee_expense, &outgoing_shares, minor_units);
+
+                for (idx, mvmt) in movements.iter().enumerate() {
 
                     let polarity = ar.direction();
                     let tx_type = txn.transaction_type(ars, raw_acct_map, acct_map)?;
@@ -223,16 +279,28 @@ pub(crate) fn add_proceeds_to_movements(
 
                                     if (tx_type == TxType::Flow) && (txn.action_record_idx_vec.len() == 2) {
 
-                                        // Keep at 0.00 proceeds for margin loss
+                                        // Keep at 0.00 proceeds for margin loss, unless the loss was already realized (or
+                                        // part of it already expensed as margin charges).  Then the spot funds covering it
+                                        // are disposed of for the basis carried into the quote `account`.
+                                        let margin_basis: Decimal = txn.action_record_idx_vec.iter()
+                                            .filter(|other_ar_num| *other_ar_num != ar_num)
+                                            .map(|other_ar_num| ars.get(other_ar_num).unwrap().cost_basis_in_ar())
+                                            .sum();
+                                        if settings.realize_margin_pnl || margin_basis != dec!(0) {
+                                            let proceeds_rounded = allocate_rounded(&margin_basis, &outgoing_shares, minor_units)[idx];
+
+                                            mvmt.proceeds.set(proceeds_rounded);
+                                            mvmt.proceeds_lk.set(proceeds_rounded);
+                                        }
                                         continue
                                     }
 
-                                    let ratio = borrowed_mvmt.amount / ar.amount;
-                                    let proceeds_unrounded = txn.proceeds.to_string().parse::<Decimal>().unwrap() * ratio;
-                                    let proceeds_rounded = round_d128_1e2(&proceeds_unrounded);
+                                    mvmt.proceeds.set(mvmt_proceeds[idx]);
+                                    mvmt.proceeds_lk.set(mvmt_proceeds[idx]);
 
-                                    mvmt.proceeds.set(proceeds_rounded);
-                                    mvmt.proceeds_lk.set(proceeds_rounded);
+                                    if ar_fee_expense != dec!(0) {
+                                        mvmt.fee_expense.set(-mvmt_fee_expenses[idx]);
+                                    }
 
                                 }
 
@@ -255,9 +323,18 @@ pub(crate) fn add_proceeds_to_movements(
                         }
                     }
                 }
+            } else if settings.realize_margin_pnl {
+                for mvmt in movements.iter() {
+                    margin_pnl::add_realized_margin_proceeds(mvmt, txn, raw_acct_map, acct_map, ars, txns_map)?;
+                }
             } else {
-                // Do nothing. Future changes can add a code path where margin txns "settle"
-                // as they happen, though, if desired. Just need to write the code.
+                // Unless margin profit and loss are realized as positions change, margin txns don't "settle" until the
+                // quote `account`'s balance is moved to (or covered from) a spot `account`.  Only margin charges, and
+                // the basis they leave for the settlement to carry, net to zero gain or loss here.
+                for mvmt in movements.iter().filter(|mvmt| mvmt.cost_basis.get() != dec!(0)) {
+                    mvmt.proceeds.set(-mvmt.cost_basis.get());
+                    mvmt.proceeds_lk.set(-mvmt.cost_basis_lk.get());
+                }
             }
         }
     }
@@ -267,12 +344,13 @@ pub(crate) fn add_proceeds_to_movements(
 
 pub(crate) fn apply_like_kind_treatment(
     home_currency: &String,
+    minor_units: u32,
     cutoff_date: NaiveDate,
     raw_acct_map: &HashMap<u16, RawAccount>,
     acct_map: &HashMap<u16, Account>,
     ars: &HashMap<u32, ActionRecord>,
     txns_map: &HashMap<u32, Transaction>,
-) -> Result<(), Box<dyn Error>> {
+) -> Result<(), CrptlsError> {
 
     let length = txns_map.len();
 
@@ -281,10 +359,10 @@ pub(crate) fn apply_like_kind_treatment(
         let txn_num = txn_num as u32;
         let txn = txns_map.get(&(txn_num)).unwrap();
 
-        update_current_txn_for_prior_likekind_treatment(txn_num, home_currency, &raw_acct_map, &acct_map, &ars, &txns_map)?;
+        update_current_txn_for_prior_likekind_treatment(txn_num, home_currency, minor_units, &raw_acct_map, &acct_map, &ars, &txns_map)?;
 
         if txn.date <= cutoff_date {
-            perform_likekind_treatment_on_txn(txn_num, home_currency, &raw_acct_map, &acct_map, &ars, &txns_map)?;
+            perform_likekind_treatment_on_txn(txn_num, home_currency, minor_units, &raw_acct_map, &acct_map, &ars, &txns_map)?;
         }
     }
 
@@ -294,11 +372,12 @@ pub(crate) fn apply_like_kind_treatment(
 fn update_current_txn_for_prior_likekind_treatment(
     txn_num: u32,
     home_currency: &String,
+    minor_units: u32,
     raw_acct_map: &HashMap<u16, RawAccount>,
     acct_map: &HashMap<u16, Account>,
     ars: &HashMap<u32, ActionRecord>,
     txns_map: &HashMap<u32, Transaction>,
-) -> Result<(), Box<dyn Error>> {
+) -> Result<(), CrptlsError> {
 
     let mut sum_of_outgoing_lk_cost_basis_in_ar = dec!(0);
     let txn = txns_map.get(&txn_num).unwrap();
@@ -330,7 +409,7 @@ fn update_current_txn_for_prior_likekind_treatment(
                             let lk_cb_of_lots_first_mvmt = borrowed_mvmt.get_lk_cost_basis_of_lots_first_mvmt(acct_map, ars);
                             let ratio_of_amt_to_lots_first_mvmt = borrowed_mvmt.ratio_of_amt_to_lots_first_mvmt(acct_map, ars);
                             let unrounded_lk_basis = -(lk_cb_of_lots_first_mvmt * ratio_of_amt_to_lots_first_mvmt);
-                            let rounded_lk_basis = round_d128_1e2(&unrounded_lk_basis);
+                            let rounded_lk_basis = round_d128_generalized(&unrounded_lk_basis, minor_units);
 
                             mvmt.cost_basis_lk.set(rounded_lk_basis);
 
@@ -365,7 +444,7 @@ fn update_current_txn_for_prior_likekind_treatment(
                                         borrowed_mvmt.ratio_of_amt_to_incoming_mvmts_in_a_r;
                                     let unrounded_lk_basis = sum_of_outgoing_lk_cost_basis_in_ar *
                                         ratio_of_amt_to_incoming_mvmts_in_a_r;
-                                    let rounded_lk_basis = round_d128_1e2(&unrounded_lk_basis);
+                                    let rounded_lk_basis = round_d128_generalized(&unrounded_lk_basis, minor_units);
 
                                     mvmt.cost_basis_lk.set(-rounded_lk_basis);
                                     mvmt.proceeds_lk.set(rounded_lk_basis);
@@ -384,11 +463,12 @@ fn update_current_txn_for_prior_likekind_treatment(
 fn perform_likekind_treatment_on_txn(
     txn_num: u32,
     home_currency: &String,
+    minor_units: u32,
     raw_acct_map: &HashMap<u16, RawAccount>,
     acct_map: &HashMap<u16, Account>,
     ars: &HashMap<u32, ActionRecord>,
     txns_map: &HashMap<u32, Transaction>,
-) -> Result<(), Box<dyn Error>> {
+) -> Result<(), CrptlsError> {
 
     let txn = txns_map.get(&txn_num).unwrap();
     let tx_type = txn.transaction_type(ars, raw_acct_map, acct_map)?;
@@ -429,7 +509,7 @@ fn perform_likekind_treatment_on_txn(
                                     borrowed_mvmt.ratio_of_amt_to_incoming_mvmts_in_a_r;
                                 let unrounded_basis = sum_of_outgoing_lk_cost_basis_in_ar *
                                     ratio_of_amt_to_incoming_mvmts_in_a_r;
-                                let rounded_basis = round_d128_1e2(&unrounded_basis);
+                                let rounded_basis = round_d128_generalized(&unrounded_basis, minor_units);
 
                                 mvmt.cost_basis_lk.set(-rounded_basis);
                                 mvmt.proceeds_lk.set(rounded_basis);
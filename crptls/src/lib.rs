@@ -11,6 +11,17 @@ pub mod core_functions;
 pub mod costing_method;
 pub mod csv_import_accts_txns;
 pub mod create_lots_mvmts;
+pub mod lot_selection;
+pub mod average_cost;
+pub mod uk_share_pooling;
+pub mod basis_reallocation;
+pub mod wash_sale;
+pub mod margin_pnl;
+pub mod balance_check;
+pub mod errors;
+pub mod currency;
+pub mod holding_period;
+pub mod prices;
 
 mod decimal_utils;
 mod import_cost_proceeds_etc;
@@ -0,0 +1,197 @@
+// Copyright (c) 2017-2023, scoobybejesus
+// Redistributions must include the license: https://github.com/scoobybejesus/cryptools/blob/master/LEGAL.txt
+
+use std::fs::File;
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use rust_decimal::Decimal;
+use rust_decimal_macros::dec;
+
+use crate::transaction::{Transaction, ActionRecord, Polarity};
+use crate::account::{Account, RawAccount};
+use crate::decimal_utils::round_d128_generalized;
+use crate::errors::CrptlsError;
+
+/// A single specific-identification instruction: spend `amount` (a positive number of units) out of
+/// `lot` number `lot_num` of the `account` in question.  For any given `transaction` and `account`,
+/// these are applied in the order they appear in the lot-selection file.
+#[derive(Clone, Debug)]
+pub struct LotSelection {
+    pub lot_num: u32,
+    pub amount: Decimal,
+}
+
+/// `LotSelection`s keyed by the outgoing `action record` they are spent by.
+pub type LotSelections = HashMap<u32, Vec<LotSelection>>;
+
+/// Imports the optional lot-selection (specific identification) file.  The file is a CSV with a header row and
+/// four columns: `txn`, `account`, `lot`, and `amount`.  The `txn` column holds either the `transaction` number
+/// (i.e., the row number of the `transaction` in the CSV Input File, starting at 1) or an external ID, which is
+/// matched against the `transaction`'s memo.  Either may be prefixed (`txn:12` or `id:12`) to say which it is, and
+/// a bare key that could be both is an error.  The `account` column holds the account number from the header row
+/// of the CSV Input File.
+///
+/// A row selects `lot`s for the `transaction`'s outgoing `action record` in the `account`, whether the trade's or
+/// its fee leg's, and the selections are keyed by that `action record`.
+///
+/// Each value in the returned map is the ordered list of `LotSelection`s to be consumed before falling back
+/// to the chosen `InventoryCostingMethod`.
+pub fn import_lot_selections(
+    lot_selection_file_path: &PathBuf,
+    home_currency: &str,
+    raw_acct_map: &HashMap<u16, RawAccount>,
+    acct_map: &HashMap<u16, Account>,
+    ar_map: &HashMap<u32, ActionRecord>,
+    txns_map: &HashMap<u32, Transaction>,
+) -> Result<LotSelections, CrptlsError> {
+
+    let file = match File::open(lot_selection_file_path) {
+        Ok(x) => x,
+        Err(e) => {
+            return Err(CrptlsError::FileOpen { path: lot_selection_file_path.clone(), source: e })
+        }
+    };
+
+    let mut rdr = csv::ReaderBuilder::new()
+        .has_headers(true)
+        .trim(csv::Trim::All)
+        .from_reader(file);
+
+    let mut lot_selections: LotSelections = HashMap::new();
+
+    for (idx, result) in rdr.records().enumerate() {
+
+        let record = result?;
+        let row = idx + 2;  //  The header is row 1
+
+        if record.len() != 4 {
+            return Err(CrptlsError::LotSelectionFile {
+                row,
+                reason: format!("expected 4 fields (txn, account, lot, amount), found {}.", record.len())
+            })
+        }
+
+        let txn_num = get_txn_num_from_key(&record[0], txns_map)
+            .map_err(|e| CrptlsError::LotSelectionFile { row, reason: e })?;
+
+        let acct_num = record[1].parse::<u16>()
+            .map_err(|_| CrptlsError::LotSelectionFile {
+                row,
+                reason: format!("couldn't parse account number: {}", &record[1])
+            })?;
+
+        let lot_num = record[2].parse::<u32>()
+            .map_err(|_| CrptlsError::LotSelectionFile {
+                row,
+                reason: format!("couldn't parse lot number: {}", &record[2])
+            })?;
+
+        let amount_str = record[3].replace(",", "");
+        let amount = amount_str.parse::<Decimal>()
+            .map_err(|_| CrptlsError::LotSelectionFile {
+                row,
+                reason: format!("couldn't parse amount: {}", &record[3])
+            })?;
+
+        if lot_num == 0 {
+            return Err(CrptlsError::LotSelectionFile { row, reason: "lot numbers start at 1.".to_string() })
+        }
+
+        let raw_acct = match raw_acct_map.get(&acct_num) {
+            Some(x) => x,
+            None => return Err(CrptlsError::LotSelectionFile {
+                row,
+                reason: format!("account {} doesn't exist.", acct_num)
+            })
+        };
+
+        let amount = round_d128_generalized(&amount.abs(), raw_acct.precision);
+        if amount == dec!(0) {
+            return Err(CrptlsError::LotSelectionFile { row, reason: "amount must not be zero.".to_string() })
+        }
+
+        if raw_acct.is_margin || raw_acct.is_home_currency(home_currency) {
+            return Err(CrptlsError::LotSelectionFile {
+                row,
+                reason: format!("account {} ({}) is a margin or home currency account, so its lots can't be selected.",
+                    acct_num, raw_acct.name)
+            })
+        }
+
+        // The `account` must be spent from in this `transaction`.
+        let txn = txns_map.get(&txn_num).unwrap();
+        let outgoing_ar = txn.ar_keys_incl_fee_leg().iter()
+            .map(|ar_num| ar_map.get(ar_num).unwrap())
+            .find(|ar| {
+                let acct = acct_map.get(&ar.account_key).unwrap();
+                acct.raw_key == acct_num && ar.direction() == Polarity::Outgoing
+            });
+
+        let outgoing_ar = match outgoing_ar {
+            Some(x) => x,
+            None => return Err(CrptlsError::LotSelectionFile {
+                row,
+                reason: format!("txn {} doesn't spend from account {} ({}).", txn_num, acct_num, raw_acct.name)
+            })
+        };
+
+        let selections = lot_selections.entry(outgoing_ar.self_ar_key).or_default();
+        selections.push(LotSelection { lot_num, amount });
+
+        let total_selected: Decimal = selections.iter().map(|s| s.amount).sum();
+        if total_selected > -outgoing_ar.amount {
+            return Err(CrptlsError::LotSelectionFile {
+                row,
+                reason: format!("lots selected for txn {} total {} {}, which is more than the {} spent.", txn_num, total_selected, raw_acct.ticker, -outgoing_ar.amount)
+            })
+        }
+    }
+
+    Ok(lot_selections)
+}
+
+/// A key prefixed `txn:` is a `transaction` number, and one prefixed `id:` is an external ID, which must match the
+/// memo of exactly one `transaction`.  A bare numeric key is a `transaction` number unless it is also some memo, in
+/// which case it is ambiguous.  Any other bare key is an external ID.
+fn get_txn_num_from_key(key: &str, txns_map: &HashMap<u32, Transaction>) -> Result<u32, String> {
+
+    if let Some(num_str) = key.strip_prefix("txn:") {
+        return match num_str.trim().parse::<u32>() {
+            Ok(txn_num) if txns_map.contains_key(&txn_num) => Ok(txn_num),
+            Ok(txn_num) => Err(format!("txn {} doesn't exist.", txn_num)),
+            Err(_) => Err(format!("couldn't parse txn number: {}", num_str)),
+        }
+    }
+
+    if let Some(external_id) = key.strip_prefix("id:") {
+        return get_txn_num_from_external_id(external_id.trim(), txns_map)
+    }
+
+    if let Ok(txn_num) = key.parse::<u32>() {
+        if txns_map.values().any(|txn| txn.user_memo.trim() == key) {
+            return Err(format!("\"{}\" is both a txn number and an external ID. Write txn:{} or id:{} instead.", key, key, key))
+        }
+        if txns_map.contains_key(&txn_num) {
+            return Ok(txn_num)
+        }
+        return Err(format!("txn {} doesn't exist.", txn_num))
+    }
+
+    get_txn_num_from_external_id(key, txns_map)
+}
+
+fn get_txn_num_from_external_id(external_id: &str, txns_map: &HashMap<u32, Transaction>) -> Result<u32, String> {
+
+    let mut matches: Vec<u32> = txns_map.values()
+        .filter(|txn| txn.user_memo.trim() == external_id)
+        .map(|txn| txn.tx_number)
+        .collect();
+    matches.sort();
+
+    match matches.len() {
+        1 => Ok(matches[0]),
+        0 => Err(format!("no txn has a memo matching external ID \"{}\".", external_id)),
+        _ => Err(format!("external ID \"{}\" matches the memo of more than one txn: {:?}.", external_id, matches)),
+    }
+}
@@ -0,0 +1,472 @@
+// Copyright (c) 2017-2023, scoobybejesus
+// Redistributions must include the license: https://github.com/scoobybejesus/cryptools/blob/master/LEGAL.txt
+
+use std::rc::Rc;
+use std::collections::HashMap;
+
+use chrono::NaiveDate;
+use rust_decimal::Decimal;
+use rust_decimal_macros::dec;
+
+use crate::account::{Account, RawAccount, Lot, Movement};
+use crate::transaction::{Transaction, ActionRecord, TxType, TxHasMargin};
+use crate::decimal_utils::round_d128_generalized;
+use crate::errors::CrptlsError;
+
+/// The state of a margin position (one base/quote `lot` pair) after a trade.
+#[derive(Clone, Debug)]
+pub struct MarginPositionStep {
+    pub transaction_key: u32,
+    pub date: NaiveDate,
+    /// Change in the base `account`
+    pub base_amount: Decimal,
+    /// Change in the quote `account`
+    pub quote_amount: Decimal,
+    /// Base units held (or, if negative, owed) after the trade
+    pub position: Decimal,
+    /// Quote units paid (negative) or received (positive) for the `position` still open after the trade
+    pub open_quote: Decimal,
+    /// Profit (or, if negative, loss) in quote units from the part of the `position` the trade closed
+    pub realized_quote: Decimal,
+}
+
+/// Replays the trades of a margin `lot` pair in order.  A trade adding to the position (or opening one) adds its
+/// quote amount to the position's cost.  A trade reducing the position releases the average cost of the part it
+/// closes, and the difference from the quote amount it gets for that part is realized.  A trade that overshoots
+/// opens a position the other way with what's left.  Once the position is back to zero, the quote `account` holds
+/// exactly the sum of what was realized.
+///
+/// Quote `movement`s without a trade in the base `lot` (i.e., the dual-`action record` `flow`s moving profit out
+/// or funds in) are not trades, so they are skipped.
+pub fn get_margin_position_steps(base_lot: &Lot, quote_lot: &Lot) -> Vec<MarginPositionStep> {
+
+    let mut steps: Vec<MarginPositionStep> = [].to_vec();
+
+    let mut position = dec!(0);
+    let mut open_quote = dec!(0);
+
+    for base_mvmt in base_lot.movements.borrow().iter() {
+
+        let quote_amount = quote_lot.movements.borrow().iter()
+            .find(|quote_mvmt| quote_mvmt.transaction_key == base_mvmt.transaction_key)
+            .map(|quote_mvmt| quote_mvmt.amount)
+            .unwrap_or(dec!(0));
+        let base_amount = base_mvmt.amount;
+        let mut realized_quote = dec!(0);
+
+        if position == dec!(0) || position.is_sign_positive() == base_amount.is_sign_positive() {
+
+            position += base_amount;
+            open_quote += quote_amount;
+
+        } else {
+
+            let closed = base_amount.abs().min(position.abs());
+            let released_quote = open_quote * closed / position.abs();
+            let closing_quote = quote_amount * closed / base_amount.abs();
+
+            realized_quote = closing_quote + released_quote;
+            open_quote -= released_quote;
+            position += base_amount;
+
+            // Overshooting the close opens a position the other way.
+            if base_amount.abs() > closed {
+                open_quote = quote_amount - closing_quote;
+            }
+        }
+
+        steps.push(MarginPositionStep {
+            transaction_key: base_mvmt.transaction_key,
+            date: base_mvmt.date,
+            base_amount,
+            quote_amount,
+            position,
+            open_quote,
+            realized_quote,
+        });
+    }
+
+    steps
+}
+
+/// A dual-`action record` `flow` moving profit out of a quote `lot` (or funds in to cover a loss).
+#[derive(Clone, Debug)]
+pub struct MarginSettlement {
+    pub transaction_key: u32,
+    pub date: NaiveDate,
+    /// Change in the quote `account`
+    pub quote_amount: Decimal,
+    /// The `flow`'s value in home currency, positive for profit moved out and negative for a loss covered
+    pub value: Decimal,
+}
+
+/// The history of one margin position, i.e., one pair of base and quote `lot`s, for the margin position report.
+#[derive(Clone, Debug)]
+pub struct MarginLotPairHistory {
+    pub base_acct_key: u16,
+    pub quote_acct_key: u16,
+    pub lot_num: u32,
+    pub open_date: NaiveDate,
+    pub steps: Vec<MarginPositionStep>,
+    /// Date of the trade that last brought the position back to zero, if it is at zero
+    pub close_date: Option<NaiveDate>,
+    pub settlements: Vec<MarginSettlement>,
+    /// Home currency value realized by the trades (see `ImportProcessParameters::realize_margin_pnl`)
+    pub realized_by_trades: Decimal,
+    /// Home currency value of the margin interest and funding paid (see `Transaction::margin_charge`)
+    pub charges: Decimal,
+}
+
+impl MarginLotPairHistory {
+
+    /// Base units still held (or owed) at the end of the input file.
+    pub fn open_position(&self) -> Decimal {
+        self.steps.last().map(|step| step.position).unwrap_or(dec!(0))
+    }
+
+    /// Quote units paid (or received) for the `open_position()`.
+    pub fn open_quote(&self) -> Decimal {
+        self.steps.last().map(|step| step.open_quote).unwrap_or(dec!(0))
+    }
+
+    /// Profit (or, if negative, loss) in quote units realized by the trades.
+    pub fn realized_quote(&self) -> Decimal {
+        self.steps.iter().map(|step| step.realized_quote).sum()
+    }
+
+    /// Home currency value of the profit (or loss) moved through dual-`action record` `flow`s.
+    pub fn settled_value(&self) -> Decimal {
+        self.settlements.iter().map(|settlement| settlement.value).sum()
+    }
+}
+
+/// Finds every margin position, for the margin position report.  Positions are grouped by base/quote `account` pair,
+/// in the order each pair first traded, and then by `lot` pair.
+pub fn get_margin_lot_pair_histories(
+    raw_acct_map: &HashMap<u16, RawAccount>,
+    acct_map: &HashMap<u16, Account>,
+    ars: &HashMap<u32, ActionRecord>,
+    txns_map: &HashMap<u32, Transaction>,
+) -> Result<Vec<MarginLotPairHistory>, CrptlsError> {
+
+    let mut acct_pairs: Vec<(u16, u16)> = [].to_vec();
+
+    for txn_num in 1..=txns_map.len() as u32 {
+        let txn = txns_map.get(&txn_num).unwrap();
+        if txn.marginness(ars, raw_acct_map, acct_map) != TxHasMargin::TwoARs { continue }
+        let acct_pair = txn.get_base_and_quote_raw_acct_keys(ars, raw_acct_map, acct_map)?;
+        if !acct_pairs.contains(&acct_pair) { acct_pairs.push(acct_pair) }
+    }
+
+    let mut histories: Vec<MarginLotPairHistory> = [].to_vec();
+
+    for (base_acct_key, quote_acct_key) in acct_pairs {
+
+        let base_acct = acct_map.get(&base_acct_key).unwrap();
+        let quote_acct = acct_map.get(&quote_acct_key).unwrap();
+
+        for base_lot in base_acct.list_of_lots.borrow().iter() {
+
+            let quote_lot = match get_paired_quote_lot(base_lot, quote_acct) {
+                Some(quote_lot) => quote_lot,
+                None => continue,   //  Opened against another quote `account`
+            };
+            let steps = get_margin_position_steps(base_lot, &quote_lot);
+
+            let close_date = match steps.last() {
+                Some(last_step) if last_step.position == dec!(0) => Some(last_step.date),
+                _ => None,
+            };
+
+            let mut settlements: Vec<MarginSettlement> = [].to_vec();
+            let mut realized_by_trades = dec!(0);
+            let mut charges = dec!(0);
+
+            for quote_mvmt in quote_lot.movements.borrow().iter() {
+
+                let txn = txns_map.get(&quote_mvmt.transaction_key).unwrap();
+
+                if txn.margin_charge.is_some() {
+                    charges += txn.proceeds;
+                } else if txn.transaction_type(ars, raw_acct_map, acct_map)? == TxType::Flow {
+                    let flow_value = txn.proceeds;
+                    settlements.push(MarginSettlement {
+                        transaction_key: quote_mvmt.transaction_key,
+                        date: quote_mvmt.date,
+                        quote_amount: quote_mvmt.amount,
+                        value: if quote_mvmt.amount < dec!(0) { flow_value } else { -flow_value },
+                    });
+                } else {
+                    realized_by_trades += quote_mvmt.cost_basis_lk.get();
+                }
+            }
+
+            histories.push(MarginLotPairHistory {
+                base_acct_key,
+                quote_acct_key,
+                lot_num: base_lot.lot_number,
+                open_date: base_lot.date_of_first_mvmt_in_lot,
+                steps,
+                close_date,
+                settlements,
+                realized_by_trades,
+                charges,
+            });
+        }
+    }
+
+    Ok(histories)
+}
+
+/// Returns the `lot` in a quote `account` paired with a base `lot`, if that base `lot` was opened against that quote
+/// `account`.  A base `account` may be paired with several quote `account`s, so its `lot`s may be paired with
+/// `lot`s in any of them.
+pub fn get_paired_quote_lot(base_lot: &Lot, quote_acct: &Account) -> Option<Rc<Lot>> {
+    quote_acct.list_of_lots.borrow().iter()
+        .find(|quote_lot| lots_are_paired(base_lot, quote_lot))
+        .cloned()
+}
+
+/// Margin `account`s create their `lot`s in pairs, with the trade that opens the position as the first `movement`
+/// of each, so two margin `lot`s are paired when their first `movement`s come from the same `transaction`.
+pub(crate) fn lots_are_paired(base_lot: &Lot, quote_lot: &Lot) -> bool {
+    match (base_lot.movements.borrow().first(), quote_lot.movements.borrow().first()) {
+        (Some(base_mvmt), Some(quote_mvmt)) => base_mvmt.transaction_key == quote_mvmt.transaction_key,
+        _ => false,
+    }
+}
+
+/// With margin profit and loss realized as positions change, sets the cost basis of the margin `movement`s of a
+/// `transaction`.  This is called for each `transaction` right after its non-margin cost basis is set.
+///
+/// For a trade between the two margin `account`s, the profit or loss realized in quote units is valued at the trade's
+/// own price.  It becomes the cost basis of the quote `movement`, so the quote `lot` carries what was realized, and a
+/// loss is also the cost basis of the base `movement` (see `add_realized_margin_proceeds()` for a profit).
+///
+/// For a dual-`action record` `flow` moving profit out of the quote `account` (or funds in to cover a loss), a
+/// proportional share of the quote `lot`'s basis goes with it.  Since that profit was already realized, the spot
+/// `account` takes this share as basis instead of the `flow`'s value.
+pub(crate) fn add_realized_margin_basis(
+    txn_num: u32,
+    minor_units: u32,
+    raw_acct_map: &HashMap<u16, RawAccount>,
+    acct_map: &HashMap<u16, Account>,
+    ars: &HashMap<u32, ActionRecord>,
+    txns_map: &HashMap<u32, Transaction>,
+) -> Result<(), CrptlsError> {
+
+    let txn = txns_map.get(&txn_num).unwrap();
+
+    match txn.marginness(ars, raw_acct_map, acct_map) {
+
+        TxHasMargin::TwoARs => {
+
+            let (base_mvmt, quote_mvmt) = get_base_and_quote_mvmts(txn, raw_acct_map, acct_map, ars, txns_map)?;
+            let base_lot = base_mvmt.get_lot(acct_map, ars);
+            let quote_acct = acct_map.get(&quote_mvmt.get_lot(acct_map, ars).account_key).unwrap();
+            let quote_lot = get_paired_quote_lot(&base_lot, quote_acct).ok_or_else(|| CrptlsError::bad_margin_pair(txn,
+                format!("The trade's base lot isn't paired with a lot in margin quote account {}.", quote_acct.raw_key)))?;
+
+            let step = get_margin_position_steps(&base_lot, &quote_lot).into_iter()
+                .find(|step| step.transaction_key == txn_num)
+                .ok_or_else(|| CrptlsError::invalid_txn(txn, "The margin trade isn't in its lots.".to_string()))?;
+
+            if step.realized_quote == dec!(0) { return Ok(()) }
+
+            // The trade's `proceeds` are the home currency value of its quote amount.
+            let realized = round_d128_generalized(&(step.realized_quote * txn.proceeds / step.quote_amount.abs()), minor_units);
+
+            quote_mvmt.cost_basis.set(realized);
+            quote_mvmt.cost_basis_lk.set(realized);
+
+            if realized < dec!(0) {
+                base_mvmt.cost_basis.set(realized);
+                base_mvmt.cost_basis_lk.set(realized);
+            }
+        }
+
+        TxHasMargin::OneAR => {
+
+            // A margin charge's basis is set by `add_margin_charge_basis()`, and any other lone margin `flow` has none.
+            if txn.action_record_idx_vec.len() == 1 { return Ok(()) }
+
+            let (margin_ar, spot_ar) = get_margin_and_spot_ars(txn, raw_acct_map, acct_map, ars);
+            let margin_mvmts = margin_ar.get_mvmts_in_ar_in_lot_date_order(acct_map, txns_map);
+            let margin_mvmt = margin_mvmts.first().unwrap();
+            let carried_basis = get_carried_basis(margin_ar, margin_mvmt, minor_units, acct_map, ars);
+
+            margin_mvmt.cost_basis.set(carried_basis);
+            margin_mvmt.cost_basis_lk.set(carried_basis);
+
+            // Profit moved into a spot `account` takes the realized basis.  Spot funds covering a loss keep the
+            // basis of the `lot`s they came from, and the carried basis becomes their proceeds instead.
+            if spot_ar.amount > dec!(0) {
+                for mvmt in spot_ar.get_mvmts_in_ar_in_lot_date_order(acct_map, txns_map).iter() {
+                    let basis = round_d128_generalized(&(-carried_basis * mvmt.ratio_of_amt_to_incoming_mvmts_in_a_r), minor_units);
+                    mvmt.cost_basis.set(basis);
+                    mvmt.cost_basis_lk.set(basis);
+                }
+            }
+        }
+
+        TxHasMargin::NoARs => {}
+    }
+
+    Ok(())
+}
+
+/// Sets the cost basis of a margin charge's `movement` (see `Transaction::margin_charge`) to minus the charge's value,
+/// so the charge comes out of the quote `lot`'s basis.  The basis later carried out with the quote `account`'s profit
+/// (or in with funds covering its loss) then reflects what was already expensed.
+pub(crate) fn add_margin_charge_basis(
+    txn_num: u32,
+    minor_units: u32,
+    acct_map: &HashMap<u16, Account>,
+    ars: &HashMap<u32, ActionRecord>,
+    txns_map: &HashMap<u32, Transaction>,
+) -> Result<(), CrptlsError> {
+
+    let txn = txns_map.get(&txn_num).unwrap();
+    let value = round_d128_generalized(&txn.proceeds, minor_units);
+    let charge_ar = ars.get(&txn.action_record_idx_vec[0]).unwrap();
+
+    for mvmt in charge_ar.get_mvmts_in_ar_in_lot_date_order(acct_map, txns_map).iter() {
+        mvmt.cost_basis.set(-value);
+        mvmt.cost_basis_lk.set(-value);
+    }
+
+    Ok(())
+}
+
+/// Unless margin profit and loss are realized as positions change, the only basis in a quote `lot` is from margin
+/// charges.  This sets the cost basis of the margin `movement` of a dual-`action record` `flow` to its share of it.
+pub(crate) fn add_margin_settlement_basis(
+    txn_num: u32,
+    minor_units: u32,
+    raw_acct_map: &HashMap<u16, RawAccount>,
+    acct_map: &HashMap<u16, Account>,
+    ars: &HashMap<u32, ActionRecord>,
+    txns_map: &HashMap<u32, Transaction>,
+) -> Result<(), CrptlsError> {
+
+    let txn = txns_map.get(&txn_num).unwrap();
+
+    if txn.action_record_idx_vec.len() != 2 || txn.marginness(ars, raw_acct_map, acct_map) != TxHasMargin::OneAR {
+        return Ok(())
+    }
+
+    let (margin_ar, _spot_ar) = get_margin_and_spot_ars(txn, raw_acct_map, acct_map, ars);
+    let margin_mvmts = margin_ar.get_mvmts_in_ar_in_lot_date_order(acct_map, txns_map);
+    let margin_mvmt = margin_mvmts.first().unwrap();
+    let carried_basis = get_carried_basis(margin_ar, margin_mvmt, minor_units, acct_map, ars);
+
+    if carried_basis != dec!(0) {
+        margin_mvmt.cost_basis.set(carried_basis);
+        margin_mvmt.cost_basis_lk.set(carried_basis);
+    }
+
+    Ok(())
+}
+
+/// Returns the share of its `lot`'s basis that a margin `flow`'s `movement` carries, in proportion to the share of the
+/// `lot`'s balance that it moves.
+fn get_carried_basis(
+    margin_ar: &ActionRecord,
+    margin_mvmt: &Movement,
+    minor_units: u32,
+    acct_map: &HashMap<u16, Account>,
+    ars: &HashMap<u32, ActionRecord>,
+) -> Decimal {
+
+    let margin_lot = margin_mvmt.get_lot(acct_map, ars);
+
+    let mut basis_before = dec!(0);
+    let mut balance_before = dec!(0);
+
+    for mvmt in margin_lot.movements.borrow().iter().filter(|mvmt| mvmt.transaction_key != margin_mvmt.transaction_key) {
+        basis_before += mvmt.cost_basis.get();
+        balance_before += mvmt.amount;
+    }
+
+    if balance_before == dec!(0) {
+        dec!(0)
+    } else {
+        round_d128_generalized(&(basis_before * margin_ar.amount / balance_before), minor_units)
+    }
+}
+
+/// With margin profit and loss realized as positions change, sets the proceeds of a margin `movement`.  A base
+/// `movement` realizing a profit takes the profit as proceeds (with no cost basis), just as margin profit is reported
+/// when it is realized upon leaving the quote `account`.  Every other margin `movement` nets to zero gain or loss.
+pub(crate) fn add_realized_margin_proceeds(
+    mvmt: &Movement,
+    txn: &Transaction,
+    raw_acct_map: &HashMap<u16, RawAccount>,
+    acct_map: &HashMap<u16, Account>,
+    ars: &HashMap<u32, ActionRecord>,
+    txns_map: &HashMap<u32, Transaction>,
+) -> Result<(), CrptlsError> {
+
+    let proceeds = if txn.marginness(ars, raw_acct_map, acct_map) == TxHasMargin::TwoARs {
+
+        let (base_mvmt, quote_mvmt) = get_base_and_quote_mvmts(txn, raw_acct_map, acct_map, ars, txns_map)?;
+
+        if base_mvmt.action_record_key == mvmt.action_record_key {
+            quote_mvmt.cost_basis.get().max(dec!(0))
+        } else {
+            -mvmt.cost_basis.get()
+        }
+    } else {
+        -mvmt.cost_basis.get()
+    };
+
+    if proceeds != dec!(0) {
+        mvmt.proceeds.set(proceeds);
+        mvmt.proceeds_lk.set(proceeds);
+    }
+
+    Ok(())
+}
+
+/// Returns the base and quote `movement`s of a trade between two margin `account`s.  Each is the only `movement`
+/// of its `action record`.
+fn get_base_and_quote_mvmts(
+    txn: &Transaction,
+    raw_acct_map: &HashMap<u16, RawAccount>,
+    acct_map: &HashMap<u16, Account>,
+    ars: &HashMap<u32, ActionRecord>,
+    txns_map: &HashMap<u32, Transaction>,
+) -> Result<(Rc<Movement>, Rc<Movement>), CrptlsError> {
+
+    let (base_acct_key, _quote_acct_key) = txn.get_base_and_quote_raw_acct_keys(ars, raw_acct_map, acct_map)?;
+
+    let mut base_mvmt = None;
+    let mut quote_mvmt = None;
+
+    for ar_num in txn.action_record_idx_vec.iter() {
+        let ar = ars.get(ar_num).unwrap();
+        let mvmt = ar.get_mvmts_in_ar_in_lot_date_order(acct_map, txns_map).first().unwrap().clone();
+        if ar.account_key == base_acct_key { base_mvmt = Some(mvmt) } else { quote_mvmt = Some(mvmt) }
+    }
+
+    Ok((base_mvmt.unwrap(), quote_mvmt.unwrap()))
+}
+
+/// Returns the margin and non-margin `action record`s of a dual-`action record` `flow`.
+fn get_margin_and_spot_ars<'a>(
+    txn: &Transaction,
+    raw_acct_map: &HashMap<u16, RawAccount>,
+    acct_map: &HashMap<u16, Account>,
+    ars: &'a HashMap<u32, ActionRecord>,
+) -> (&'a ActionRecord, &'a ActionRecord) {
+
+    let first_ar = ars.get(&txn.action_record_idx_vec[0]).unwrap();
+    let second_ar = ars.get(&txn.action_record_idx_vec[1]).unwrap();
+    let first_acct = acct_map.get(&first_ar.account_key).unwrap();
+
+    if raw_acct_map.get(&first_acct.raw_key).unwrap().is_margin {
+        (first_ar, second_ar)
+    } else {
+        (second_ar, first_ar)
+    }
+}
@@ -0,0 +1,170 @@
+// Copyright (c) 2017-2023, scoobybejesus
+// Redistributions must include the license: https://github.com/scoobybejesus/cryptools/blob/master/LEGAL.txt
+
+use std::fs::File;
+use std::path::PathBuf;
+use std::collections::{HashMap, BTreeMap};
+
+use chrono::NaiveDate;
+use rust_decimal::Decimal;
+use rust_decimal_macros::dec;
+
+use crate::account::{Account, RawAccount, Term};
+use crate::errors::CrptlsError;
+use crate::holding_period::HoldingPeriodRule;
+
+/// Home currency prices of each ticker (uppercased), by date, from the price file.
+#[derive(Clone, Debug, Default)]
+pub struct PriceTable {
+    prices: HashMap<String, BTreeMap<NaiveDate, Decimal>>,
+}
+
+impl PriceTable {
+
+    /// Returns the ticker's price on `date` or, failing that, its latest price before `date`.
+    pub fn price_on(&self, ticker: &str, date: NaiveDate) -> Option<Decimal> {
+        self.prices.get(&ticker.to_uppercase())?
+            .range(..=date)
+            .next_back()
+            .map(|(_date, price)| *price)
+    }
+}
+
+/// An open `lot` valued at the price on the valuation date, for the unrealized gain/loss report.
+#[derive(Clone, Debug)]
+pub struct LotValuation {
+    pub account_num: u16,
+    pub ticker: String,
+    pub lot_num: u32,
+    pub basis_date: NaiveDate,
+    /// The `Term` the `lot` would have if disposed of on the valuation date
+    pub term: Term,
+    pub amount: Decimal,
+    pub cost_basis: Decimal,
+    /// `None` when the price file has no price for the ticker on or before the valuation date
+    pub price: Option<Decimal>,
+}
+
+impl LotValuation {
+
+    /// Returns the market value, rounded to `minor_units` decimal places, or `None` without a price.
+    pub fn market_value(&self, minor_units: u32) -> Option<Decimal> {
+        self.price.map(|price| (price * self.amount).round_dp(minor_units))
+    }
+
+    /// Returns the market value less the cost basis, or `None` without a price.
+    pub fn unrealized_gain_loss(&self, minor_units: u32) -> Option<Decimal> {
+        self.market_value(minor_units).map(|value| value - self.cost_basis.round_dp(minor_units))
+    }
+}
+
+/// Values every non-margin, non-home currency `lot` with a balance at the end of `date`, counting only `movement`s
+/// dated on or before it.  Sorted by `account` number, then `lot` number.
+pub fn get_open_lot_valuations(
+    date: NaiveDate,
+    prices: &PriceTable,
+    rule: &HoldingPeriodRule,
+    home_currency: &str,
+    raw_acct_map: &HashMap<u16, RawAccount>,
+    acct_map: &HashMap<u16, Account>,
+) -> Vec<LotValuation> {
+
+    let mut raw_accts: Vec<&RawAccount> = raw_acct_map.values()
+        .filter(|raw_acct| !raw_acct.is_margin && !raw_acct.is_home_currency(home_currency))
+        .collect();
+    raw_accts.sort_by_key(|raw_acct| raw_acct.account_num);
+
+    let mut valuations: Vec<LotValuation> = [].to_vec();
+
+    for raw_acct in raw_accts.iter() {
+
+        let acct = acct_map.get(&raw_acct.account_num).unwrap();
+
+        for lot in acct.list_of_lots.borrow().iter() {
+
+            let mut amount = dec!(0);
+            let mut cost_basis = dec!(0);
+            for mvmt in lot.movements.borrow().iter().filter(|mvmt| mvmt.date <= date) {
+                amount += mvmt.amount;
+                cost_basis += mvmt.cost_basis_lk.get();
+            }
+
+            if amount == dec!(0) { continue }
+
+            valuations.push(LotValuation {
+                account_num: raw_acct.account_num,
+                ticker: raw_acct.ticker.clone(),
+                lot_num: lot.lot_number,
+                basis_date: lot.date_for_basis_purposes,
+                term: rule.term(lot.date_for_basis_purposes, date),
+                amount,
+                cost_basis,
+                price: prices.price_on(&raw_acct.ticker, date),
+            });
+        }
+    }
+
+    valuations
+}
+
+/// Imports the price file.  The file is a CSV with a header row and three columns: `date` (%Y-%m-%d), `ticker`, and
+/// `price` (of one unit, in the home currency).  A ticker may have only one price on any date.
+pub fn import_prices(price_file_path: &PathBuf) -> Result<PriceTable, CrptlsError> {
+
+    let file = match File::open(price_file_path) {
+        Ok(x) => x,
+        Err(e) => {
+            return Err(CrptlsError::FileOpen { path: price_file_path.clone(), source: e })
+        }
+    };
+
+    let mut rdr = csv::ReaderBuilder::new()
+        .has_headers(true)
+        .trim(csv::Trim::All)
+        .from_reader(file);
+
+    let mut table = PriceTable::default();
+
+    for (idx, result) in rdr.records().enumerate() {
+
+        let record = result?;
+        let row = idx + 2;  //  The header is row 1
+
+        if record.len() != 3 {
+            return Err(CrptlsError::PriceFile {
+                row,
+                reason: format!("expected 3 fields (date, ticker, price), found {}.", record.len())
+            })
+        }
+
+        let date = NaiveDate::parse_from_str(&record[0], "%Y-%m-%d")
+            .map_err(|_| CrptlsError::PriceFile {
+                row,
+                reason: format!("couldn't parse date (use %Y-%m-%d): {}", &record[0])
+            })?;
+
+        let ticker = record[1].to_uppercase();
+        if ticker.is_empty() {
+            return Err(CrptlsError::PriceFile { row, reason: "ticker must not be blank.".to_string() })
+        }
+
+        let price = record[2].replace(",", "").parse::<Decimal>()
+            .map_err(|_| CrptlsError::PriceFile {
+                row,
+                reason: format!("couldn't parse price: {}", &record[2])
+            })?;
+
+        if price < dec!(0) {
+            return Err(CrptlsError::PriceFile { row, reason: "price must not be negative.".to_string() })
+        }
+
+        if table.prices.entry(ticker.clone()).or_default().insert(date, price).is_some() {
+            return Err(CrptlsError::PriceFile {
+                row,
+                reason: format!("{} has more than one price on {}.", ticker, date)
+            })
+        }
+    }
+
+    Ok(table)
+}
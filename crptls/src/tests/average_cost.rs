@@ -0,0 +1,54 @@
+// Copyright (c) 2017-2023, scoobybejesus
+// Redistributions must include the license: https://github.com/scoobybejesus/cryptools/blob/master/LEGAL.txt
+
+use rust_decimal::Decimal;
+use rust_decimal_macros::dec;
+
+use crate::average_cost;
+use crate::core_functions::ImportProcessParameters;
+use crate::costing_method::AverageCostPool;
+use crate::tests::helpers::{settings, process};
+
+/// BTC is held in two accounts, bought at 100 and 300, partly sold, and then bought again at 100.
+const CSV: &str = "\
+txDate,proceeds,memo,1,2,3
+,,,Bank,Exchange,Wallet
+,,,USD,BTC,BTC
+,,,non,non,non
+1-1-20,100,BUY1,-100,1,
+2-1-20,300,BUY2,-300,,1
+3-1-20,500,SELL,500,-1,
+4-1-20,200,BUY3,-200,2,
+";
+
+#[test]
+fn ticker_pool_relieves_basis_at_the_running_average() {
+
+    let settings = ImportProcessParameters { average_cost_pool: Some(AverageCostPool::Ticker), ..settings() };
+    let (raw_accts, acct_map, ars, txns_map) = process("avg_ticker", CSV, &settings).unwrap();
+
+    let sale = txns_map.get(&3).unwrap();
+    let btc_ar = sale.action_record_idx_vec.iter()
+        .map(|ar_num| ars.get(ar_num).unwrap())
+        .find(|ar| ar.account_key == 2)
+        .unwrap();
+    let mvmts = btc_ar.get_mvmts_in_ar_in_lot_date_order(&acct_map, &txns_map);
+    assert_eq!(mvmts.iter().map(|mvmt| mvmt.cost_basis.get()).sum::<Decimal>(), dec!(-200));
+
+    let pool = AverageCostPool::Ticker;
+    assert_eq!(average_cost::get_pool_totals(&pool, 3, 3, &raw_accts, &acct_map), (dec!(2), dec!(400)));
+    assert_eq!(average_cost::get_pool_totals(&pool, 2, 4, &raw_accts, &acct_map), (dec!(1), dec!(200)));
+    assert_eq!(average_cost::get_pool_totals(&pool, 2, u32::MAX, &raw_accts, &acct_map), (dec!(3), dec!(400)));
+    assert_eq!(average_cost::get_pool_totals(&pool, 2, 1, &raw_accts, &acct_map), (dec!(0), dec!(0)));
+}
+
+#[test]
+fn account_pools_are_kept_apart() {
+
+    let settings = ImportProcessParameters { average_cost_pool: Some(AverageCostPool::Account), ..settings() };
+    let (raw_accts, acct_map, _ars, _txns_map) = process("avg_account", CSV, &settings).unwrap();
+
+    let pool = AverageCostPool::Account;
+    assert_eq!(average_cost::get_pool_totals(&pool, 2, u32::MAX, &raw_accts, &acct_map), (dec!(2), dec!(200)));
+    assert_eq!(average_cost::get_pool_totals(&pool, 3, u32::MAX, &raw_accts, &acct_map), (dec!(1), dec!(300)));
+}
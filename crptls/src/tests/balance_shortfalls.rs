@@ -0,0 +1,44 @@
+// Copyright (c) 2017-2023, scoobybejesus
+// Redistributions must include the license: https://github.com/scoobybejesus/cryptools/blob/master/LEGAL.txt
+
+use rust_decimal_macros::dec;
+
+use crate::errors::CrptlsError;
+use crate::tests::helpers::{settings, process, date};
+
+/// The BTC account sells more than it bought and then sells again while still short, and the ETH account, in
+/// between, sells what it never had.
+const CSV: &str = "\
+txDate,proceeds,memo,1,2,3
+,,,Bank,Exchange,Exchange
+,,,USD,BTC,ETH
+,,,non,non,non
+1-1-20,100,BUY BTC,-100,1,
+2-1-20,300,SELL BTC,300,-1.5,
+3-1-20,50,SELL ETH,50,,-2
+4-1-20,100,SELL BTC AGAIN,100,-0.25,
+";
+
+#[test]
+fn every_shortfall_is_reported_before_processing_stops() {
+
+    let result = process("balance_shortfalls", CSV, &settings());
+    let shortfalls = match result {
+        Err(CrptlsError::BalanceShortfalls(shortfalls)) => shortfalls,
+        other => panic!("Expected balance shortfalls, got {:?}", other.err()),
+    };
+    assert_eq!(shortfalls.len(), 3);
+
+    let btc = &shortfalls[0];
+    assert_eq!((btc.acct_key, btc.tx_number, btc.csv_line, btc.date), (2, 2, 6, date("2020-02-01")));
+    assert_eq!((btc.amount_missing, btc.running_balance), (dec!(0.5), dec!(-0.5)));
+
+    let eth = &shortfalls[1];
+    assert_eq!((eth.acct_key, eth.tx_number, eth.csv_line), (3, 3, 7));
+    assert_eq!((eth.amount_missing, eth.running_balance), (dec!(2), dec!(-2)));
+
+    // Still short, the account is missing all of the second sale.
+    let btc_again = &shortfalls[2];
+    assert_eq!((btc_again.acct_key, btc_again.tx_number, btc_again.csv_line), (2, 4, 8));
+    assert_eq!((btc_again.amount_missing, btc_again.running_balance), (dec!(0.25), dec!(-0.75)));
+}
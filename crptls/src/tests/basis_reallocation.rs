@@ -0,0 +1,52 @@
+// Copyright (c) 2017-2023, scoobybejesus
+// Redistributions must include the license: https://github.com/scoobybejesus/cryptools/blob/master/LEGAL.txt
+
+use rust_decimal_macros::dec;
+
+use crate::basis_reallocation::get_reallocated_lots;
+use crate::core_functions::ImportProcessParameters;
+use crate::tests::helpers::{settings, process, date};
+
+/// The exchange holds the older, cheaper lot, so the global-to-wallet rule gives it to the wallet (account 2) and
+/// the wallet's lot to the exchange.  The sale is dated the reallocation date, so it comes right after it.
+const CSV: &str = "\
+txDate,proceeds,memo,1,2,3
+,,,Bank,Wallet,Exchange
+,,,USD,BTC,BTC
+,,,non,non,non
+1-1-20,100,EXCHANGE BUY,-100,,1
+2-1-20,300,WALLET BUY,-300,1,
+3-1-20,250,SELL,250,,-1
+";
+
+#[test]
+fn global_rule_reallocates_oldest_lots_to_lowest_account_first() {
+
+    let settings = ImportProcessParameters { basis_reallocation_date: Some(date("2020-03-01")), ..settings() };
+    let (raw_accts, acct_map, _ars, _txns_map) = process("realloc_global", CSV, &settings).unwrap();
+
+    let wallet = acct_map.get(&2).unwrap();
+    assert_eq!(wallet.get_sum_of_amts_in_lots(), dec!(1));
+    assert_eq!(wallet.get_sum_of_orig_basis_in_lots(), dec!(100));
+    assert_eq!(wallet.list_of_lots.borrow().last().unwrap().date_for_basis_purposes, date("2020-01-01"));
+
+    // The exchange sells the wallet's former lot, at a loss.
+    let exchange = acct_map.get(&3).unwrap();
+    assert_eq!(exchange.get_sum_of_amts_in_lots(), dec!(0));
+    let sold_lot = exchange.list_of_lots.borrow().last().unwrap().clone();
+    assert_eq!(sold_lot.date_for_basis_purposes, date("2020-02-01"));
+    let sale_mvmt = sold_lot.movements.borrow().iter().find(|mvmt| !mvmt.is_reallocation).unwrap().clone();
+    assert_eq!(sale_mvmt.cost_basis.get(), dec!(-300));
+    assert_eq!(sale_mvmt.proceeds.get(), dec!(250));
+
+    // Only the reallocation's own movements are reported, not the sale's on the same date.
+    let reallocated: Vec<(u16, u32, bool, _, _)> = get_reallocated_lots("USD", &raw_accts, &acct_map).iter()
+        .map(|lot| (lot.account_num, lot.lot_num, lot.closed, lot.amount, lot.basis))
+        .collect();
+    assert_eq!(reallocated, vec![
+        (2, 1, true, dec!(1), dec!(300)),
+        (3, 1, true, dec!(1), dec!(100)),
+        (2, 2, false, dec!(1), dec!(100)),
+        (3, 2, false, dec!(1), dec!(300)),
+    ]);
+}
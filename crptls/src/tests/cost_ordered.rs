@@ -0,0 +1,69 @@
+// Copyright (c) 2017-2023, scoobybejesus
+// Redistributions must include the license: https://github.com/scoobybejesus/cryptools/blob/master/LEGAL.txt
+
+use rust_decimal::Decimal;
+use rust_decimal_macros::dec;
+
+use crate::core_functions::ImportProcessParameters;
+use crate::costing_method::InventoryCostingMethod;
+use crate::tests::helpers::{settings, process, Processed};
+
+/// Returns the `lot` numbers the sale in `txn_num` spent from, in order, and the sale's total cost basis.
+fn lots_and_basis_of_sale(processed: &Processed, txn_num: u32) -> (Vec<u32>, Decimal) {
+    let (_raw_accts, _acct_map, ars, txns_map) = processed;
+    let sale = txns_map.get(&txn_num).unwrap();
+    let btc_ar = ars.get(&sale.action_record_idx_vec[0]).unwrap();
+    let mvmts = btc_ar.movements.borrow();
+    (mvmts.iter().map(|mvmt| mvmt.lot_num).collect(), mvmts.iter().map(|mvmt| mvmt.cost_basis.get()).sum())
+}
+
+fn with_method(costing_method: InventoryCostingMethod) -> ImportProcessParameters {
+    ImportProcessParameters { costing_method, ..settings() }
+}
+
+/// Per-unit bases of 100, 300, 200, 300 and 100, so both the highest and the lowest cost are tied between two `lot`s.
+const CSV: &str = "\
+txDate,proceeds,memo,1,2
+,,,Bank,Exchange
+,,,USD,BTC
+,,,non,non
+1-1-20,100,BUY1,-100,1
+2-1-20,300,BUY2,-300,1
+3-1-20,200,BUY3,-200,1
+4-1-20,300,BUY4,-300,1
+5-1-20,100,BUY5,-100,1
+6-1-20,375,SELL,375,-1.5
+";
+
+#[test]
+fn hifo_relieves_the_highest_unit_cost_first_and_ties_in_creation_order() {
+    let processed = process("hifo", CSV, &with_method(InventoryCostingMethod::HIFObyLotUnitBasis)).unwrap();
+    assert_eq!(lots_and_basis_of_sale(&processed, 6), (vec![2, 4], dec!(-450)));
+}
+
+#[test]
+fn lofo_relieves_the_lowest_unit_cost_first_and_ties_in_creation_order() {
+    let processed = process("lofo", CSV, &with_method(InventoryCostingMethod::LOFObyLotUnitBasis)).unwrap();
+    assert_eq!(lots_and_basis_of_sale(&processed, 6), (vec![1, 5], dec!(-150)));
+}
+
+/// At 300 per unit, lot 1 is a long-term loss of 100 and the newer lot 2 a short-term gain of 50.
+const TERM_CSV: &str = "\
+txDate,proceeds,memo,1,2
+,,,Bank,Exchange
+,,,USD,BTC
+,,,non,non
+1-1-18,400,BUY1,-400,1
+1-1-20,250,BUY2,-250,1
+6-1-20,300,SELL,300,-1
+";
+
+#[test]
+fn tax_optimized_takes_a_long_term_loss_over_a_short_term_gain() {
+    let processed = process("tax_optimized_terms", TERM_CSV, &with_method(InventoryCostingMethod::TaxOptimized)).unwrap();
+    assert_eq!(lots_and_basis_of_sale(&processed, 3), (vec![1], dec!(-400)));
+
+    // Spending the newest lot first would have taken the gain.
+    let processed = process("lifo_terms", TERM_CSV, &with_method(InventoryCostingMethod::LIFObyLotCreationDate)).unwrap();
+    assert_eq!(lots_and_basis_of_sale(&processed, 3), (vec![2], dec!(-250)));
+}
@@ -0,0 +1,85 @@
+// Copyright (c) 2017-2023, scoobybejesus
+// Redistributions must include the license: https://github.com/scoobybejesus/cryptools/blob/master/LEGAL.txt
+
+use std::collections::HashMap;
+
+use crate::core_functions::ImportProcessParameters;
+use crate::costing_method::InventoryCostingMethod;
+use crate::errors::CrptlsError;
+use crate::tests::helpers::{settings, process, date, Processed};
+
+/// Returns the `lot` numbers the sale in `txn_num` spent from.
+fn lots_spent_by(processed: &Processed, txn_num: u32) -> Vec<u32> {
+    let (_raw_accts, _acct_map, ars, txns_map) = processed;
+    let sale = txns_map.get(&txn_num).unwrap();
+    let btc_ar = ars.get(&sale.action_record_idx_vec[0]).unwrap();
+    let lot_nums = btc_ar.movements.borrow().iter().map(|mvmt| mvmt.lot_num).collect();
+    lot_nums
+}
+
+/// One sale before the schedule's switch to LIFO on 2020-06-01, and one on that date.
+const CSV: &str = "\
+txDate,proceeds,memo,1,2
+,,,Bank,Exchange
+,,,USD,BTC
+,,,non,non
+1-1-20,100,BUY1,-100,1
+2-1-20,200,BUY2,-200,1
+3-1-20,300,BUY3,-300,1
+4-1-20,400,SELL1,400,-1
+6-1-20,400,SELL2,400,-1
+";
+
+#[test]
+fn each_sale_uses_the_method_in_force_on_its_date() {
+
+    let settings = ImportProcessParameters {
+        costing_method: InventoryCostingMethod::FIFObyLotCreationDate,
+        costing_method_schedule: vec![(date("2020-06-01"), InventoryCostingMethod::LIFObyLotCreationDate)],
+        ..settings()
+    };
+    let processed = process("costing_method_schedule", CSV, &settings).unwrap();
+
+    assert_eq!(lots_spent_by(&processed, 4), vec![1]);
+    assert_eq!(lots_spent_by(&processed, 5), vec![3]);
+}
+
+/// The exchange and the wallet each buy two lots and then sell one unit on the schedule's switch date.
+const TWO_ACCOUNTS_CSV: &str = "\
+txDate,proceeds,memo,1,2,3
+,,,Bank,Exchange,Wallet
+,,,USD,BTC,BTC
+,,,non,non,non
+1-1-20,100,EXCHANGE BUY1,-100,1,
+2-1-20,200,EXCHANGE BUY2,-200,1,
+1-1-20,100,WALLET BUY1,-100,,1
+2-1-20,200,WALLET BUY2,-200,,1
+6-1-20,400,EXCHANGE SELL,400,-1,
+6-1-20,400,WALLET SELL,400,,-1
+";
+
+#[test]
+fn an_account_override_takes_precedence_over_the_schedule() {
+
+    let settings = ImportProcessParameters {
+        costing_method: InventoryCostingMethod::FIFObyLotCreationDate,
+        costing_method_schedule: vec![(date("2020-06-01"), InventoryCostingMethod::LIFObyLotCreationDate)],
+        costing_method_overrides: HashMap::from([(3, InventoryCostingMethod::FIFObyLotCreationDate)]),
+        ..settings()
+    };
+    let processed = process("costing_method_override", TWO_ACCOUNTS_CSV, &settings).unwrap();
+
+    assert_eq!(lots_spent_by(&processed, 5), vec![2]);
+    assert_eq!(lots_spent_by(&processed, 6), vec![1]);
+}
+
+#[test]
+fn an_override_for_an_account_that_does_not_exist_is_an_error() {
+
+    let settings = ImportProcessParameters {
+        costing_method_overrides: HashMap::from([(9, InventoryCostingMethod::LIFObyLotCreationDate)]),
+        ..settings()
+    };
+    let result = process("costing_method_override_missing", TWO_ACCOUNTS_CSV, &settings);
+    assert!(matches!(result, Err(CrptlsError::InvalidSettings(reason)) if reason.contains("account 9, which doesn't exist")));
+}
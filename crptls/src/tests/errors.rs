@@ -0,0 +1,105 @@
+// Copyright (c) 2017-2023, scoobybejesus
+// Redistributions must include the license: https://github.com/scoobybejesus/cryptools/blob/master/LEGAL.txt
+
+use crate::core_functions::ImportProcessParameters;
+use crate::costing_method::FeeTreatment;
+use crate::errors::CrptlsError;
+use crate::tests::helpers::{settings, process, date};
+
+/// The blank row is skipped by the reader, so the bad third txn is on line 8 of the file rather than line 7.
+const CSV_WITH_BLANK_ROW: &str = "\
+txDate,proceeds,memo,1,2
+,,,Bank,Exchange
+,,,USD,BTC
+,,,non,non
+1-1-20,1000,BUY,-1000,1
+
+2-1-20,600,SELL,600,-0.5
+3-1-20,lots,SELL AGAIN,600,-0.5
+";
+
+#[test]
+fn errors_report_the_line_of_the_file_past_blank_rows() {
+    let result = process("blank_row", CSV_WITH_BLANK_ROW, &settings());
+    assert!(matches!(&result, Err(CrptlsError::Parse { tx_number: 3, csv_line: 8, .. })));
+    assert!(result.err().unwrap().to_string().contains("line 8"));
+}
+
+/// Nothing has been traded on margin yet, so there's no position for the inflow to pay into.
+const CSV_MARGIN_INFLOW: &str = "\
+txDate,proceeds,memo,1,2,3
+,,,Exchange,Exchange,Exchange
+,,,BTC,XMR,BTC_xmr
+,,,non,margin,margin
+1-1-16,0,DEPOSIT,1,,
+2-1-16,0,PAY MARGIN,-0.2,,0.2
+";
+
+#[test]
+fn inflow_to_a_margin_account_with_no_position_is_an_error() {
+    let result = process("margin_no_position", CSV_MARGIN_INFLOW, &settings());
+    assert!(matches!(result, Err(CrptlsError::InvalidTransaction { tx_number: 2, csv_line: 6, reason })
+        if reason.contains("no position")));
+}
+
+/// The second txn is after the as-of date, but the third, on or before it, comes later in the file.
+const CSV_OUT_OF_ORDER: &str = "\
+txDate,proceeds,memo,1,2
+,,,Bank,Exchange
+,,,USD,BTC
+,,,non,non
+1-1-20,1000,BUY,-1000,1
+3-1-20,600,SELL,600,-0.5
+2-1-20,600,SELL EARLIER,600,-0.5
+";
+
+#[test]
+fn txn_out_of_date_order_around_the_as_of_date_is_an_error() {
+    let settings = ImportProcessParameters { as_of_date: Some(date("2020-02-15")), ..settings() };
+    let result = process("as_of_out_of_order", CSV_OUT_OF_ORDER, &settings);
+    assert!(matches!(result, Err(CrptlsError::InvalidTransaction { tx_number: 3, csv_line: 7, reason })
+        if reason.contains("date order")));
+}
+
+/// The swap's fee of 600 is more than the 500 the BTC was worth.
+const CSV_FEE_OVER_PROCEEDS: &str = "\
+txDate,proceeds,memo,fee,1,2,3
+,,,,Bank,Exchange,Exchange
+,,,,USD,BTC,ETH
+,,,,non,non,non
+1-1-20,100,BUY,,-100,1,
+2-1-20,500,SWAP,600,,-1,5
+";
+
+#[test]
+fn fee_more_than_the_proceeds_is_an_error() {
+    let settings = ImportProcessParameters { fee_treatment: FeeTreatment::Expense, ..settings() };
+    let result = process("fee_over_proceeds", CSV_FEE_OVER_PROCEEDS, &settings);
+    assert!(matches!(result, Err(CrptlsError::InvalidTransaction { tx_number: 2, csv_line: 6, reason })
+        if reason.contains("(600) is more than its proceeds (500)")));
+}
+
+#[test]
+fn negative_fee_is_an_error() {
+    let csv = CSV_FEE_OVER_PROCEEDS.replace(",600,", ",-5,");
+    let result = process("negative_fee", &csv, &settings());
+    assert!(matches!(result, Err(CrptlsError::InvalidTransaction { tx_number: 2, csv_line: 6, reason })
+        if reason.contains("negative fee")));
+}
+
+/// The BTC received as income is given a negative value.
+const CSV_NEGATIVE_PROCEEDS: &str = "\
+txDate,proceeds,memo,1,2
+,,,Bank,Exchange
+,,,USD,BTC
+,,,non,non
+1-1-20,1000,BUY,-1000,1
+2-1-20,-100,INCOME,,0.1
+";
+
+#[test]
+fn incoming_flow_with_negative_proceeds_is_an_error() {
+    let result = process("negative_proceeds", CSV_NEGATIVE_PROCEEDS, &settings());
+    assert!(matches!(result, Err(CrptlsError::InvalidTransaction { tx_number: 2, csv_line: 6, reason })
+        if reason.contains("negative cost basis")));
+}
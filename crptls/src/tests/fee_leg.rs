@@ -0,0 +1,47 @@
+// Copyright (c) 2017-2023, scoobybejesus
+// Redistributions must include the license: https://github.com/scoobybejesus/cryptools/blob/master/LEGAL.txt
+
+use rust_decimal_macros::dec;
+
+use crate::tests::helpers::{settings, process};
+
+/// A BTC to ETH trade worth 500 pays its fee in BNB: 1 BNB, bought for 1 and worth 3.50.
+const CSV: &str = "\
+txDate,proceeds,memo,1,2,3,4
+,,,Bank,Exchange,Exchange,Exchange
+,,,USD,BTC,ETH,BNB
+,,,non,non,non,non
+1-1-20,100,BUY BTC,-100,1,,
+1-2-20,10,BUY BNB,-10,,,10
+2-1-20,500,BTC TO ETH,,-1,5,-1 fee 3.50
+";
+
+#[test]
+fn fee_leg_is_a_disposal_and_its_value_is_added_to_the_acquired_basis() {
+
+    let (_raw_accts, acct_map, ars, txns_map) = process("fee_leg", CSV, &settings()).unwrap();
+
+    let trade = txns_map.get(&3).unwrap();
+    let fee_leg = trade.fee_leg.as_ref().unwrap();
+    assert_eq!(fee_leg.value, dec!(3.50));
+
+    // The fee leg realizes its own gain, apart from the trade's.
+    let fee_ar = ars.get(&fee_leg.action_record_key).unwrap();
+    assert_eq!(fee_ar.amount, dec!(-1));
+    let fee_mvmt = fee_ar.movements.borrow()[0].clone();
+    assert_eq!(fee_mvmt.proceeds.get(), dec!(3.50));
+    assert_eq!(fee_mvmt.cost_basis.get(), dec!(-1));
+    assert_eq!(fee_mvmt.get_orig_gain_or_loss(), dec!(2.50));
+
+    let btc_ar = ars.get(&trade.action_record_idx_vec[0]).unwrap();
+    assert_eq!(btc_ar.movements.borrow()[0].get_orig_gain_or_loss(), dec!(400));
+
+    // The ETH acquired carries the trade's value plus the fee's.
+    let eth_lot = acct_map.get(&3).unwrap().list_of_lots.borrow()[0].clone();
+    assert_eq!(eth_lot.get_sum_of_amts_in_lot(), dec!(5));
+    assert_eq!(eth_lot.get_sum_of_lk_basis_in_lot(), dec!(503.50));
+
+    // The BNB account is left with the units the fee didn't spend.
+    let bnb_lot = acct_map.get(&4).unwrap().list_of_lots.borrow()[0].clone();
+    assert_eq!(bnb_lot.get_sum_of_amts_in_lot(), dec!(9));
+}
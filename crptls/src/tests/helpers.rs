@@ -0,0 +1,76 @@
+// Copyright (c) 2017-2023, scoobybejesus
+// Redistributions must include the license: https://github.com/scoobybejesus/cryptools/blob/master/LEGAL.txt
+
+use std::fs;
+use std::path::PathBuf;
+use std::collections::HashMap;
+
+use chrono::NaiveDate;
+
+use crate::account::{Account, RawAccount};
+use crate::transaction::{Transaction, ActionRecord};
+use crate::core_functions::{self, ImportProcessParameters};
+use crate::costing_method::{InventoryCostingMethod, LotReliefScope, FeeTreatment};
+use crate::holding_period::HoldingPeriodRule;
+use crate::errors::CrptlsError;
+
+pub type Processed = (
+    HashMap<u16, RawAccount>,
+    HashMap<u16, Account>,
+    HashMap<u32, ActionRecord>,
+    HashMap<u32, Transaction>,
+);
+
+/// FIFO, USD, per-account relief and every optional treatment off.  Tests change what they need with
+/// `ImportProcessParameters { .., ..settings() }`.
+pub fn settings() -> ImportProcessParameters {
+    ImportProcessParameters {
+        input_file_date_separator: "-".to_string(),
+        input_file_uses_iso_date_style: false,
+        home_currency: "USD".to_string(),
+        home_currency_minor_units: 2,
+        ticker_precision: HashMap::new(),
+        costing_method: InventoryCostingMethod::FIFObyLotCreationDate,
+        costing_method_schedule: Vec::new(),
+        costing_method_overrides: HashMap::new(),
+        lot_relief_scope: LotReliefScope::PerAccount,
+        lot_selection_file: None,
+        basis_reallocation_date: None,
+        basis_allocation_file: None,
+        average_cost_pool: None,
+        uk_share_pooling: false,
+        holding_period_rule: HoldingPeriodRule::default(),
+        as_of_date: None,
+        report_period: None,
+        price_file: None,
+        valuation_date: None,
+        fee_treatment: FeeTreatment::AddToBasis,
+        wash_sale_rule: false,
+        realize_margin_pnl: false,
+        lk_treatment_enabled: false,
+        lk_cutoff_date: NaiveDate::from_ymd_opt(1, 1, 1).unwrap(),
+        lk_basis_date_preserved: true,
+        should_export: false,
+        export_path: PathBuf::new(),
+        journal_entry_export: false,
+    }
+}
+
+/// Writes `contents` to a file in the temp directory that is unique to the test `name` and this process.
+pub fn write_file(name: &str, contents: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("crptls_{}_{}.csv", name, std::process::id()));
+    fs::write(&path, contents).unwrap();
+    path
+}
+
+/// Imports and processes `csv` as the input file.
+pub fn process(name: &str, csv: &str, settings: &ImportProcessParameters) -> Result<Processed, CrptlsError> {
+    let path = write_file(name, csv);
+    let processed = core_functions::import_and_process_final(path.clone(), settings);
+    fs::remove_file(&path).unwrap();
+    processed
+}
+
+pub fn date(ymd: &str) -> NaiveDate {
+    NaiveDate::parse_from_str(ymd, "%Y-%m-%d").unwrap()
+}
@@ -0,0 +1,36 @@
+// Copyright (c) 2017-2023, scoobybejesus
+// Redistributions must include the license: https://github.com/scoobybejesus/cryptools/blob/master/LEGAL.txt
+
+use crate::holding_period::{HoldingPeriod, HoldingPeriodRule};
+use crate::tests::helpers::date;
+
+/// The rank of the `Term` of units with the basis date that are disposed of on the disposal date.
+fn rank(period: HoldingPeriod, basis_date: &str, disposal_date: &str) -> usize {
+    let rule = HoldingPeriodRule::new(vec![period], None).unwrap();
+    rule.term(date(basis_date), date(disposal_date)).rank
+}
+
+#[test]
+fn day_and_year_rules_agree_outside_a_leap_year() {
+    for period in [HoldingPeriod::Days(365), HoldingPeriod::Years(1)] {
+        assert_eq!(rank(period.clone(), "2021-03-01", "2022-03-01"), 0, "{}", period);
+        assert_eq!(rank(period.clone(), "2021-03-01", "2022-03-02"), 1, "{}", period);
+    }
+}
+
+#[test]
+fn a_leap_day_in_the_holding_period_moves_only_the_day_rule_a_day_earlier() {
+    // The 366th day after the basis date is the anniversary itself, which the year rule doesn't count as more than a year.
+    assert_eq!(rank(HoldingPeriod::Days(365), "2019-03-01", "2020-02-29"), 0);
+    assert_eq!(rank(HoldingPeriod::Days(365), "2019-03-01", "2020-03-01"), 1);
+    assert_eq!(rank(HoldingPeriod::Years(1), "2019-03-01", "2020-03-01"), 0);
+    assert_eq!(rank(HoldingPeriod::Years(1), "2019-03-01", "2020-03-02"), 1);
+}
+
+#[test]
+fn a_leap_day_basis_date_has_its_anniversary_on_february_28() {
+    for period in [HoldingPeriod::Days(365), HoldingPeriod::Years(1)] {
+        assert_eq!(rank(period.clone(), "2020-02-29", "2021-02-28"), 0, "{}", period);
+        assert_eq!(rank(period.clone(), "2020-02-29", "2021-03-01"), 1, "{}", period);
+    }
+}
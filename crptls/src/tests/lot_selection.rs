@@ -0,0 +1,88 @@
+// Copyright (c) 2017-2023, scoobybejesus
+// Redistributions must include the license: https://github.com/scoobybejesus/cryptools/blob/master/LEGAL.txt
+
+use std::fs;
+
+use rust_decimal::Decimal;
+use rust_decimal_macros::dec;
+
+use crate::core_functions::ImportProcessParameters;
+use crate::errors::CrptlsError;
+use crate::tests::helpers::{settings, write_file, process, Processed};
+
+/// Txn 1's memo is "3", so a bare key of 3 could mean it or txn 3, the sale.
+const CSV: &str = "\
+txDate,proceeds,memo,1,2
+,,,Bank,Exchange
+,,,USD,BTC
+,,,non,non
+1-1-20,100,3,-100,1
+2-1-20,200,BUY2,-200,1
+3-1-20,300,SELL,300,-1
+";
+
+fn process_with_selection(name: &str, selection_csv: &str) -> Result<Processed, CrptlsError> {
+    process_csv_with_selection(name, CSV, selection_csv)
+}
+
+fn process_csv_with_selection(name: &str, csv: &str, selection_csv: &str) -> Result<Processed, CrptlsError> {
+    let selection_path = write_file(&format!("{}_selection", name), selection_csv);
+    let settings = ImportProcessParameters { lot_selection_file: Some(selection_path.clone()), ..settings() };
+    let processed = process(name, csv, &settings);
+    fs::remove_file(&selection_path).unwrap();
+    processed
+}
+
+fn sale_basis((_raw_accts, acct_map, ars, txns_map): &Processed) -> Decimal {
+    let sale = txns_map.get(&3).unwrap();
+    let btc_ar = sale.action_record_idx_vec.iter()
+        .map(|ar_num| ars.get(ar_num).unwrap())
+        .find(|ar| ar.account_key == 2)
+        .unwrap();
+    btc_ar.get_mvmts_in_ar_in_lot_date_order(acct_map, txns_map).iter().map(|mvmt| mvmt.cost_basis.get()).sum()
+}
+
+#[test]
+fn prefixed_txn_number_selects_that_txns_lots() {
+    let processed = process_with_selection("ls_txn", "txn,account,lot,amount\ntxn:3,2,2,1\n").unwrap();
+    assert_eq!(sale_basis(&processed), dec!(-200));
+}
+
+#[test]
+fn key_that_is_both_a_txn_number_and_a_memo_is_an_error() {
+    let result = process_with_selection("ls_ambiguous", "txn,account,lot,amount\n3,2,2,1\n");
+    assert!(matches!(result, Err(CrptlsError::LotSelectionFile { row: 2, reason }) if reason.contains("txn:3 or id:3")));
+}
+
+#[test]
+fn prefixed_external_id_is_matched_against_memos() {
+    // The memo "3" is txn 1, a purchase, which doesn't spend BTC.
+    let result = process_with_selection("ls_id", "txn,account,lot,amount\nid:3,2,2,1\n");
+    assert!(matches!(result, Err(CrptlsError::LotSelectionFile { row: 2, reason }) if reason.starts_with("txn 1 ")));
+}
+
+#[test]
+fn selecting_a_lot_that_does_not_exist_is_an_error() {
+    let result = process_with_selection("ls_missing_lot", "txn,account,lot,amount\ntxn:3,2,5,1\n");
+    assert!(matches!(result, Err(CrptlsError::InvalidTransaction { tx_number: 3, csv_line: 7, reason })
+        if reason.contains("Selected lot 5 doesn't exist")));
+}
+
+/// Lot 1 is half spent by the first sale before the second sale selects all of it.
+const PARTLY_SPENT_CSV: &str = "\
+txDate,proceeds,memo,1,2
+,,,Bank,Exchange
+,,,USD,BTC
+,,,non,non
+1-1-20,100,BUY1,-100,1
+2-1-20,200,BUY2,-200,1
+3-1-20,150,SELL1,150,-0.5
+4-1-20,300,SELL2,300,-1
+";
+
+#[test]
+fn selecting_more_than_a_lot_holds_is_an_error() {
+    let result = process_csv_with_selection("ls_short_lot", PARTLY_SPENT_CSV, "txn,account,lot,amount\ntxn:3,2,1,0.5\ntxn:4,2,1,1\n");
+    assert!(matches!(result, Err(CrptlsError::InvalidTransaction { tx_number: 4, csv_line: 8, reason })
+        if reason.contains("holds 0.5 BTC, which is less than the 1 selected")));
+}
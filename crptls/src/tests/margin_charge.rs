@@ -0,0 +1,50 @@
+// Copyright (c) 2017-2023, scoobybejesus
+// Redistributions must include the license: https://github.com/scoobybejesus/cryptools/blob/master/LEGAL.txt
+
+use rust_decimal_macros::dec;
+
+use crate::margin_pnl::get_margin_lot_pair_histories;
+use crate::tests::helpers::{settings, process};
+
+/// A long position pays 0.01 BTC of interest, worth 5, before it is closed for a profit of 0.2 BTC.  What's left of
+/// the profit after the interest, 0.19 BTC worth 95, is moved out.
+const CSV: &str = "\
+txDate,proceeds,memo,1,2,3,4
+,,,Bank,Exchange,Exchange,Exchange
+,,,USD,BTC,XMR,BTC_xmr
+,,,non,non,margin,margin
+1-1-16,0,BUY BTC,-1000,2,,
+2-1-16,500,MARGIN LONG,,,10,-1
+2-15-16,5,INTEREST,,,,-0.01 interest
+3-1-16,600,CLOSE,,,-10,1.2
+4-1-16,95,PROFIT WITHDRAW,,0.19,,-0.19
+";
+
+#[test]
+fn margin_interest_is_an_expense_and_added_back_to_the_profit_moved_out() {
+
+    let (raw_accts, acct_map, ars, txns_map) = process("margin_charge", CSV, &settings()).unwrap();
+
+    let interest = txns_map.get(&3).unwrap();
+    let interest_ar = ars.get(&interest.action_record_idx_vec[0]).unwrap();
+    let interest_mvmt = interest_ar.movements.borrow()[0].clone();
+    assert_eq!(interest_mvmt.get_expense(&ars, &raw_accts, &acct_map, &txns_map).unwrap(), dec!(-5));
+
+    // The history shows the charge on its own, apart from the settlement.
+    let histories = get_margin_lot_pair_histories(&raw_accts, &acct_map, &ars, &txns_map).unwrap();
+    assert_eq!(histories[0].charges, dec!(5));
+    assert_eq!(histories[0].settled_value(), dec!(95));
+
+    // The spot BTC takes its value as basis, but the interest already expensed is added back to the income, so
+    // income less expense is the 95 the position made.
+    let spot_btc = acct_map.get(&2).unwrap();
+    let withdrawn_lot = spot_btc.list_of_lots.borrow().last().unwrap().clone();
+    assert_eq!(withdrawn_lot.get_sum_of_orig_basis_in_lot(), dec!(95));
+    let withdraw = txns_map.get(&5).unwrap();
+    let spot_ar = withdraw.action_record_idx_vec.iter()
+        .map(|ar_num| ars.get(ar_num).unwrap())
+        .find(|ar| ar.account_key == 2)
+        .unwrap();
+    let spot_mvmt = spot_ar.movements.borrow()[0].clone();
+    assert_eq!(spot_mvmt.get_income(&ars, &raw_accts, &acct_map, &txns_map, 2).unwrap(), dec!(100));
+}
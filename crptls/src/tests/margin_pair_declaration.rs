@@ -0,0 +1,51 @@
+// Copyright (c) 2017-2023, scoobybejesus
+// Redistributions must include the license: https://github.com/scoobybejesus/cryptools/blob/master/LEGAL.txt
+
+use rust_decimal_macros::dec;
+
+use crate::errors::CrptlsError;
+use crate::margin_pnl::get_margin_lot_pair_histories;
+use crate::tests::helpers::{settings, process};
+
+/// The XMR base `account` is declared in two pairs, one with a quote ticker holding an underscore and one with
+/// a quote ticker holding none, so neither could be found from the ticker.
+const CSV: &str = "\
+txDate,proceeds,memo,1,2,3,4,5
+,,,Bank,Exchange,Exchange,Exchange,Exchange
+,,,USD,BTC,XMR,BTC_PERP,ETHQUOTE
+,,,non,non,margin:xmrbtc+xmreth:base,margin:xmrbtc:quote,margin:xmreth:quote
+1-1-16,0,BUY BTC,-1000,2,,,
+2-1-16,500,LONG VS BTC,,,10,-1,
+2-15-16,300,LONG VS ETH,,,5,,-20
+3-1-16,600,CLOSE VS BTC,,,-10,1.2,
+3-10-16,300,CLOSE VS ETH,,,-5,,22
+";
+
+#[test]
+fn a_declared_base_account_pairs_with_each_of_its_quote_accounts() {
+
+    let (raw_accts, acct_map, ars, txns_map) = process("margin_pair_declaration", CSV, &settings()).unwrap();
+
+    let trade = txns_map.get(&2).unwrap();
+    assert_eq!(trade.get_base_and_quote_raw_acct_keys(&ars, &raw_accts, &acct_map).unwrap(), (3, 4));
+    let trade = txns_map.get(&3).unwrap();
+    assert_eq!(trade.get_base_and_quote_raw_acct_keys(&ars, &raw_accts, &acct_map).unwrap(), (3, 5));
+
+    // Each base lot is paired with the lot its opening trade created in that trade's quote account.
+    let histories = get_margin_lot_pair_histories(&raw_accts, &acct_map, &ars, &txns_map).unwrap();
+    assert_eq!(histories.len(), 2);
+    assert_eq!((histories[0].quote_acct_key, histories[0].lot_num), (4, 1));
+    assert_eq!(histories[0].realized_quote(), dec!(0.2));
+    assert_eq!((histories[1].quote_acct_key, histories[1].lot_num), (5, 2));
+    assert_eq!(histories[1].realized_quote(), dec!(2));
+    assert!(histories.iter().all(|history| history.open_position() == dec!(0)));
+}
+
+#[test]
+fn an_undeclared_quote_ticker_without_an_underscore_is_an_error() {
+
+    // Undeclared, the ETH pair can't be told from its tickers.
+    let csv = CSV.replace("margin:xmrbtc+xmreth:base,margin:xmrbtc:quote,margin:xmreth:quote", "margin,margin,margin");
+    let result = process("margin_pair_undeclared", &csv, &settings());
+    assert!(matches!(result, Err(CrptlsError::BadMarginPair { tx_number: 3, csv_line: 7, .. })));
+}
@@ -0,0 +1,68 @@
+// Copyright (c) 2017-2023, scoobybejesus
+// Redistributions must include the license: https://github.com/scoobybejesus/cryptools/blob/master/LEGAL.txt
+
+use rust_decimal::Decimal;
+use rust_decimal_macros::dec;
+
+use crate::core_functions::ImportProcessParameters;
+use crate::tests::helpers::{settings, process, Processed};
+
+/// A long position of 15 XMR costs 1.6 BTC in all.  A third of it is sold for 0.7 BTC, realizing 1/6 BTC at that
+/// trade's 500 USD for 0.7 BTC, and the rest for 1.1 BTC, realizing 1/30 BTC at 600 USD for 1.1 BTC.
+const CSV: &str = "\
+txDate,proceeds,memo,1,2,3,4
+,,,Bank,Exchange,Exchange,Exchange
+,,,USD,BTC,XMR,BTC_xmr
+,,,non,non,margin,margin
+1-1-16,0,BUY BTC,-1000,2,,
+2-1-16,500,MARGIN LONG,,,10,-1
+2-15-16,300,MARGIN ADD,,,5,-0.6
+3-1-16,500,PARTIAL CLOSE,,,-5,0.7
+3-10-16,600,CLOSE,,,-10,1.1
+4-1-16,50,PROFIT WITHDRAW,,0.2,,-0.2
+";
+
+/// The gain or loss on the `movement`s of the `transaction`'s `action record` in `account_num`.
+fn gain_loss_in(processed: &Processed, txn_num: u32, account_num: u16) -> Decimal {
+    let (_raw_accts, acct_map, ars, txns_map) = processed;
+    let txn = txns_map.get(&txn_num).unwrap();
+    let ar = txn.action_record_idx_vec.iter()
+        .map(|ar_num| ars.get(ar_num).unwrap())
+        .find(|ar| ar.account_key == account_num)
+        .unwrap();
+    ar.get_mvmts_in_ar_in_lot_date_order(acct_map, txns_map).iter().map(|mvmt| mvmt.get_lk_gain_or_loss()).sum()
+}
+
+#[test]
+fn trades_reducing_the_position_realize_profit() {
+
+    let settings = ImportProcessParameters { realize_margin_pnl: true, ..settings() };
+    let processed = process("margin_realized", CSV, &settings).unwrap();
+
+    // Adding to the position realizes nothing.
+    assert_eq!(gain_loss_in(&processed, 3, 3), dec!(0));
+    assert_eq!(gain_loss_in(&processed, 4, 3), dec!(119.05));
+    assert_eq!(gain_loss_in(&processed, 5, 3), dec!(18.18));
+
+    // Moving the profit out carries what was realized as basis, so it is neither a gain nor income.
+    let (_raw_accts, acct_map, _ars, _txns_map) = &processed;
+    let spot_btc = acct_map.get(&2).unwrap();
+    let withdrawn_lot = spot_btc.list_of_lots.borrow().last().unwrap().clone();
+    assert_eq!(withdrawn_lot.get_sum_of_orig_basis_in_lot(), dec!(137.23));
+    assert_eq!(gain_loss_in(&processed, 6, 4), dec!(0));
+}
+
+#[test]
+fn without_realizing_the_settlement_carries_the_profit() {
+
+    let processed = process("margin_settled", CSV, &settings()).unwrap();
+
+    assert_eq!(gain_loss_in(&processed, 4, 3), dec!(0));
+    assert_eq!(gain_loss_in(&processed, 5, 3), dec!(0));
+
+    // The profit only takes a value once it is moved out, at the transfer's value.
+    let (_raw_accts, acct_map, _ars, _txns_map) = &processed;
+    let spot_btc = acct_map.get(&2).unwrap();
+    let withdrawn_lot = spot_btc.list_of_lots.borrow().last().unwrap().clone();
+    assert_eq!(withdrawn_lot.get_sum_of_orig_basis_in_lot(), dec!(50));
+}
@@ -0,0 +1,54 @@
+// Copyright (c) 2017-2023, scoobybejesus
+// Redistributions must include the license: https://github.com/scoobybejesus/cryptools/blob/master/LEGAL.txt
+
+use rust_decimal_macros::dec;
+
+use crate::margin_pnl::get_margin_lot_pair_histories;
+use crate::tests::helpers::{settings, process, date};
+
+/// A long XMR position is opened, added to and closed in two trades, and the profit is moved out.  A second
+/// position is then opened short and left open at the end of the file.
+const CSV: &str = "\
+txDate,proceeds,memo,1,2,3,4
+,,,Bank,Exchange,Exchange,Exchange
+,,,USD,BTC,XMR,BTC_xmr
+,,,non,non,margin,margin
+1-1-16,0,BUY BTC,-1000,2,,
+2-1-16,500,MARGIN LONG,,,10,-1
+2-15-16,300,MARGIN ADD,,,5,-0.6
+3-1-16,500,PARTIAL CLOSE,,,-5,0.7
+3-10-16,600,CLOSE,,,-10,1.1
+4-1-16,50,PROFIT WITHDRAW,,0.2,,-0.2
+5-1-16,400,MARGIN SHORT,,,-4,0.5
+";
+
+#[test]
+fn each_lot_pair_lists_its_trades_close_and_settlements() {
+
+    let (raw_accts, acct_map, ars, txns_map) = process("margin_history", CSV, &settings()).unwrap();
+    let histories = get_margin_lot_pair_histories(&raw_accts, &acct_map, &ars, &txns_map).unwrap();
+    assert_eq!(histories.len(), 2);
+
+    let closed = &histories[0];
+    assert_eq!((closed.base_acct_key, closed.quote_acct_key), (3, 4));
+    assert_eq!(closed.open_date, date("2016-02-01"));
+    assert_eq!(closed.steps.iter().map(|step| step.transaction_key).collect::<Vec<u32>>(), vec![2, 3, 4, 5]);
+    assert_eq!(closed.steps[1].position, dec!(15));
+    assert_eq!(closed.steps[1].open_quote, dec!(-1.6));
+    assert_eq!(closed.close_date, Some(date("2016-03-10")));
+    assert_eq!(closed.open_position(), dec!(0));
+    assert_eq!(closed.realized_quote().round_dp(8), dec!(0.2));
+
+    // The profit moved out through the dual-action-record flow is its settlement.
+    assert_eq!(closed.settlements.len(), 1);
+    assert_eq!(closed.settlements[0].transaction_key, 6);
+    assert_eq!(closed.settlements[0].quote_amount, dec!(-0.2));
+    assert_eq!(closed.settled_value(), dec!(50));
+
+    let open = &histories[1];
+    assert_eq!(open.open_date, date("2016-05-01"));
+    assert_eq!(open.close_date, None);
+    assert_eq!(open.open_position(), dec!(-4));
+    assert_eq!(open.open_quote(), dec!(0.5));
+    assert!(open.settlements.is_empty());
+}
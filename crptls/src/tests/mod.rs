@@ -2,3 +2,46 @@
 // Redistributions must include the license: https://github.com/scoobybejesus/cryptools/blob/master/LEGAL.txt
 
 pub mod test;
+
+#[cfg(test)]
+mod helpers;
+#[cfg(test)]
+mod lot_selection;
+#[cfg(test)]
+mod average_cost;
+#[cfg(test)]
+mod uk_share_pooling;
+#[cfg(test)]
+mod universal_relief;
+#[cfg(test)]
+mod basis_reallocation;
+#[cfg(test)]
+mod tax_optimized;
+#[cfg(test)]
+mod wash_sale;
+#[cfg(test)]
+mod margin_pnl;
+#[cfg(test)]
+mod errors;
+#[cfg(test)]
+mod holding_period;
+#[cfg(test)]
+mod cost_ordered;
+#[cfg(test)]
+mod costing_method_schedule;
+#[cfg(test)]
+mod fee_leg;
+#[cfg(test)]
+mod margin_position_history;
+#[cfg(test)]
+mod margin_pair_declaration;
+#[cfg(test)]
+mod margin_charge;
+#[cfg(test)]
+mod balance_shortfalls;
+#[cfg(test)]
+mod quantity_precision;
+#[cfg(test)]
+mod report_period;
+#[cfg(test)]
+mod unrealized;
@@ -0,0 +1,42 @@
+// Copyright (c) 2017-2023, scoobybejesus
+// Redistributions must include the license: https://github.com/scoobybejesus/cryptools/blob/master/LEGAL.txt
+
+use std::collections::HashMap;
+
+use rust_decimal_macros::dec;
+
+use crate::core_functions::ImportProcessParameters;
+use crate::tests::helpers::{settings, process};
+
+/// BTC is kept to two decimal places, so the purchase of 1.006 is rounded, but the sale of 0.5 isn't.
+const CSV: &str = "\
+txDate,proceeds,memo,1,2
+,,,Bank,Exchange
+,,,USD,BTC
+,,,non,non
+1-1-20,1000,BUY,-1000,1.006
+2-1-20,300,SELL,300,-0.5
+";
+
+#[test]
+fn an_amount_with_too_many_decimal_places_is_rounded_and_listed() {
+
+    let settings = ImportProcessParameters {
+        ticker_precision: HashMap::from([("BTC".to_string(), 2)]),
+        ..settings()
+    };
+    let (_raw_accts, acct_map, ars, txns_map) = process("quantity_precision", CSV, &settings).unwrap();
+
+    let buy = txns_map.get(&1).unwrap();
+    assert_eq!(buy.rounded_amounts.len(), 1);
+    let rounded = &buy.rounded_amounts[0];
+    assert_eq!(rounded.amount_as_entered, dec!(1.006));
+    let btc_ar = ars.get(&rounded.action_record_key).unwrap();
+    assert_eq!((btc_ar.account_key, btc_ar.amount), (2, dec!(1.01)));
+
+    assert!(txns_map.get(&2).unwrap().rounded_amounts.is_empty());
+
+    // The lot holds what's left of the rounded amount.
+    let btc_lot = acct_map.get(&2).unwrap().list_of_lots.borrow()[0].clone();
+    assert_eq!(btc_lot.get_sum_of_amts_in_lot(), dec!(0.51));
+}
@@ -0,0 +1,86 @@
+// Copyright (c) 2017-2023, scoobybejesus
+// Redistributions must include the license: https://github.com/scoobybejesus/cryptools/blob/master/LEGAL.txt
+
+use chrono::NaiveDate;
+use rust_decimal_macros::dec;
+
+use crate::account::PeriodBalances;
+use crate::core_functions::ImportProcessParameters;
+use crate::tests::helpers::{settings, process, date, Processed};
+
+/// Half the first lot is sold in 2019.  In 2020, a second lot is bought and the rest of the first lot and half the
+/// second are sold (FIFO), and the last half is sold on the first day of 2021.
+const CSV: &str = "\
+txDate,proceeds,memo,1,2
+,,,Bank,Exchange
+,,,USD,BTC
+,,,non,non
+1-1-19,100,BUY1,-100,1
+6-1-19,300,SELL1,300,-0.5
+3-1-20,200,BUY2,-200,1
+9-1-20,500,SELL2,500,-1
+1-1-21,250,SELL3,250,-0.5
+";
+
+fn btc_period_balances(processed: &Processed, first_date: NaiveDate, last_date: NaiveDate) -> PeriodBalances {
+    let (raw_accts, acct_map, _ars, _txns_map) = processed;
+    acct_map.get(&2).unwrap().get_period_balances(raw_accts.get(&2).unwrap(), first_date, last_date)
+}
+
+#[test]
+fn a_period_starting_mid_history_opens_with_what_was_held_before_it() {
+
+    let processed = process("report_period_mid_history", CSV, &settings()).unwrap();
+
+    let year_2020 = btc_period_balances(&processed, date("2020-01-01"), date("2020-12-31"));
+    assert_eq!(year_2020, PeriodBalances {
+        opening_balance: dec!(0.5),
+        opening_basis: dec!(50),
+        closing_balance: dec!(0.5),
+        closing_basis: dec!(100),
+    });
+
+    // A txn on the first day of the period is in it, so it isn't part of the opening balance.
+    let one_day = btc_period_balances(&processed, date("2020-03-01"), date("2020-03-01"));
+    assert_eq!((one_day.opening_balance, one_day.closing_balance), (dec!(0.5), dec!(1.5)));
+    assert_eq!((one_day.opening_basis, one_day.closing_basis), (dec!(50), dec!(250)));
+
+    // Sold out, the closing balance is a plain zero rather than one with the movements' decimal places.
+    let year_2021 = btc_period_balances(&processed, date("2021-01-01"), date("2021-12-31"));
+    assert_eq!(year_2021.closing_balance.to_string(), "0");
+    assert_eq!(year_2021.closing_basis, dec!(0));
+}
+
+#[test]
+fn a_period_from_the_earliest_date_opens_with_nothing() {
+
+    let processed = process("report_period_from_min", CSV, &settings()).unwrap();
+
+    let all_time = btc_period_balances(&processed, NaiveDate::MIN, date("2019-12-31"));
+    assert_eq!(all_time, PeriodBalances {
+        opening_balance: dec!(0),
+        opening_basis: dec!(0),
+        closing_balance: dec!(0.5),
+        closing_basis: dec!(50),
+    });
+}
+
+#[test]
+fn only_txns_dated_in_the_period_are_reported() {
+
+    let settings = ImportProcessParameters {
+        report_period: Some((date("2020-03-01"), date("2021-01-01"))),
+        ..settings()
+    };
+    let (_raw_accts, _acct_map, _ars, txns_map) = process("report_period_filter", CSV, &settings).unwrap();
+
+    // Both ends of the period are in it.
+    let reported: Vec<u32> = (1..=txns_map.len() as u32)
+        .filter(|txn_num| settings.is_in_report_period(txns_map.get(txn_num).unwrap().date))
+        .collect();
+    assert_eq!(reported, vec![3, 4, 5]);
+
+    assert!(!settings.is_in_report_period(date("2020-02-29")));
+    assert!(!settings.is_in_report_period(date("2021-01-02")));
+    assert!(ImportProcessParameters { report_period: None, ..settings }.is_in_report_period(NaiveDate::MIN));
+}
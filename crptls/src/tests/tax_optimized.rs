@@ -0,0 +1,66 @@
+// Copyright (c) 2017-2023, scoobybejesus
+// Redistributions must include the license: https://github.com/scoobybejesus/cryptools/blob/master/LEGAL.txt
+
+use crate::core_functions::ImportProcessParameters;
+use crate::costing_method::{InventoryCostingMethod, FeeTreatment};
+use crate::tests::helpers::{settings, process, Processed};
+
+fn tax_optimized(fee_treatment: FeeTreatment) -> ImportProcessParameters {
+    ImportProcessParameters { costing_method: InventoryCostingMethod::TaxOptimized, fee_treatment, ..settings() }
+}
+
+/// Returns the `lot` numbers the sale in `txn_num` spent from, in the order it spent from them.
+fn lots_spent_by(processed: &Processed, txn_num: u32) -> Vec<u32> {
+    let (_raw_accts, _acct_map, ars, txns_map) = processed;
+    let sale = txns_map.get(&txn_num).unwrap();
+    let btc_ar = ars.get(&sale.action_record_idx_vec[0]).unwrap();
+    let lot_nums = btc_ar.movements.borrow().iter().map(|mvmt| mvmt.lot_num).collect();
+    lot_nums
+}
+
+/// At 300 per unit: lot 1 is a long-term loss of 50, lot 2 a short-term loss of 200, lots 3 and 6 are long-term gains of
+/// 200 and 100, and lots 4 and 5 are short-term gains of 50 and 100.
+const CSV: &str = "\
+txDate,proceeds,memo,1,2
+,,,Bank,Exchange
+,,,USD,BTC
+,,,non,non
+2-1-18,350,BUY1,-350,1
+1-1-20,500,BUY2,-500,1
+3-1-18,100,BUY3,-100,1
+2-1-20,250,BUY4,-250,1
+3-1-20,200,BUY5,-200,1
+4-1-18,200,BUY6,-200,1
+6-1-20,1800,SELL,1800,-6
+";
+
+#[test]
+fn losses_come_first_by_term_then_the_smallest_gains() {
+
+    let processed = process("tax_optimized", CSV, &tax_optimized(FeeTreatment::AddToBasis)).unwrap();
+
+    // The long-term loss goes first though it is the smaller loss, and the long-term gain wins the tie at 100.
+    assert_eq!(lots_spent_by(&processed, 7), vec![1, 2, 4, 6, 5, 3]);
+}
+
+/// At 300 per unit, lot 1 (long-term) is a gain of 5 and lot 2 (short-term) a loss of 5.  A fee taken from the
+/// proceeds brings them down to 290 per unit, which makes both losses.  An expensed fee leaves them at 300.
+const FEE_CSV: &str = "\
+txDate,proceeds,memo,fee,1,2
+,,,,Bank,Exchange
+,,,,USD,BTC
+,,,,non,non
+1-1-18,295,BUY1,,-295,1
+1-1-20,305,BUY2,,-305,1
+6-1-20,300,SELL,10,300,-1
+";
+
+#[test]
+fn fee_taken_from_proceeds_is_counted_in_the_ranking() {
+
+    let processed = process("tax_optimized_expense", FEE_CSV, &tax_optimized(FeeTreatment::Expense)).unwrap();
+    assert_eq!(lots_spent_by(&processed, 3), vec![2]);
+
+    let processed = process("tax_optimized_fee", FEE_CSV, &tax_optimized(FeeTreatment::SubtractFromProceeds)).unwrap();
+    assert_eq!(lots_spent_by(&processed, 3), vec![1]);
+}
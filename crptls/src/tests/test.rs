@@ -242,3 +242,120 @@ fn _test_dec_rounded_1e2(random_float_string: &str) {
     println!("String into dec: {:?}; dec rounded to 2 places: {:?}", amt, amt2);
     //  Results of this test suggest that quantize() is off by one.  round_dec_1e8() was adjusted accordingly.
 }
+
+#[cfg(test)]
+mod proceeds_allocation {
+
+    use super::*;
+    use crate::core_functions::{self, ImportProcessParameters};
+    use crate::costing_method::FeeTreatment;
+    use crate::tests::helpers::{self, write_file};
+
+    /// Three 1 BTC `lot`s bought for large sums, then all 3 BTC sold at once.  The sale's proceeds split three ways
+    /// leave a cent over, which must land on one of its `movement`s.
+    const LARGE_SALE_CSV: &str = "\
+txDate,proceeds,memo,1,2
+,,,Bank,Exchange
+,,,USD,BTC
+,,,non,non
+1-1-20,\"33,333,333.33\",BUY1,\"-33,333,333.33\",1
+2-1-20,\"44,444,444.44\",BUY2,\"-44,444,444.44\",1
+3-1-20,\"55,555,555.55\",BUY3,\"-55,555,555.55\",1
+4-1-20,\"123,456,789.01\",SELL,\"123,456,789.01\",-3
+";
+
+    fn settings(fee_treatment: FeeTreatment) -> ImportProcessParameters {
+        ImportProcessParameters { fee_treatment, ..helpers::settings() }
+    }
+
+    #[test]
+    fn allocated_parts_add_back_up_to_large_totals() {
+
+        let thirds = [dec!(1) / dec!(3), dec!(1) / dec!(3), dec!(1) - dec!(2) / dec!(3)];
+
+        for total in [dec!(123456789.01), dec!(99999999999.99), dec!(-70000000.02), dec!(100000.005)] {
+            let parts = allocate_rounded(&total, &thirds, 2);
+            assert_eq!(parts.len(), 3);
+            assert_eq!(parts.iter().sum::<Decimal>(), round_d128_1e2(&total));
+            assert!(parts.iter().all(|part| *part == round_d128_1e2(part)));
+        }
+
+        assert_eq!(allocate_rounded(&dec!(123456789.01), &[dec!(1)], 2), vec![dec!(123456789.01)]);
+        assert!(allocate_rounded(&dec!(123456789.01), &[], 2).is_empty());
+    }
+
+    #[test]
+    fn large_sale_proceeds_keep_their_cents_across_lots() {
+
+        let path = write_file("large_sale", LARGE_SALE_CSV);
+        let (raw_accts, acct_map, ars, txns_map) =
+            core_functions::import_and_process_final(path.clone(), &settings(FeeTreatment::AddToBasis)).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let sale = txns_map.get(&4).unwrap();
+        assert_eq!(sale.proceeds, dec!(123456789.01));
+
+        let btc_ar = ars.get(&sale.action_record_idx_vec[0]).unwrap();
+        let mvmts = btc_ar.get_mvmts_in_ar_in_lot_date_order(&acct_map, &txns_map);
+        assert_eq!(mvmts.len(), 3);
+
+        let proceeds: Decimal = mvmts.iter().map(|mvmt| mvmt.proceeds.get()).sum();
+        let proceeds_lk: Decimal = mvmts.iter().map(|mvmt| mvmt.proceeds_lk.get()).sum();
+        assert_eq!(proceeds, sale.proceeds);
+        assert_eq!(proceeds_lk, sale.proceeds);
+
+        let basis: Decimal = mvmts.iter().map(|mvmt| mvmt.cost_basis.get()).sum();
+        assert_eq!(basis, dec!(-133333333.32));
+
+        let memo = sale.get_auto_memo(&ars, &raw_accts, &acct_map, "USD", 2).unwrap();
+        assert_eq!(memo, "Paid -3 BTC for 123456789.01 USD, valued at 123456789.01 USD.");
+    }
+
+    #[test]
+    fn large_fee_expense_keeps_its_cents_across_lots() {
+
+        let csv = LARGE_SALE_CSV.replace("txDate,proceeds,memo,1,2", "txDate,proceeds,memo,fee,1,2")
+            .replace(",,,", ",,,,")
+            .replace("BUY1,", "BUY1,,")
+            .replace("BUY2,", "BUY2,,")
+            .replace("BUY3,", "BUY3,,")
+            .replace("SELL,", "SELL,\"1,000,000.01\",");
+        let path = write_file("large_fee", &csv);
+        let (_raw_accts, acct_map, ars, txns_map) =
+            core_functions::import_and_process_final(path.clone(), &settings(FeeTreatment::Expense)).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let sale = txns_map.get(&4).unwrap();
+        assert_eq!(sale.fee, dec!(1000000.01));
+        let btc_ar = ars.get(&sale.action_record_idx_vec[0]).unwrap();
+        let mvmts = btc_ar.get_mvmts_in_ar_in_lot_date_order(&acct_map, &txns_map);
+
+        let proceeds: Decimal = mvmts.iter().map(|mvmt| mvmt.proceeds.get()).sum();
+        let fee_expense: Decimal = mvmts.iter().map(|mvmt| mvmt.fee_expense.get()).sum();
+        assert_eq!(proceeds, sale.proceeds);
+        assert_eq!(fee_expense, -sale.fee);
+    }
+
+    #[test]
+    fn expensed_fee_on_a_home_currency_purchase_is_not_added_to_basis() {
+
+        let csv = "\
+txDate,proceeds,memo,fee,1,2
+,,,,Bank,Exchange
+,,,,USD,BTC
+,,,,non,non
+1-1-20,10010,BUY,10,-10010,1
+";
+        let settings = settings(FeeTreatment::Expense);
+        let (_raw_accts, acct_map, ars, txns_map) = helpers::process("purchase_fee", csv, &settings).unwrap();
+
+        let purchase = txns_map.get(&1).unwrap();
+        let usd_ar = ars.get(&purchase.action_record_idx_vec[0]).unwrap();
+        let usd_mvmts = usd_ar.get_mvmts_in_ar_in_lot_date_order(&acct_map, &txns_map);
+        let fee_expense: Decimal = usd_mvmts.iter().map(|mvmt| mvmt.fee_expense.get()).sum();
+        assert_eq!(fee_expense, dec!(-10));
+
+        let btc_ar = ars.get(&purchase.action_record_idx_vec[1]).unwrap();
+        assert_eq!(btc_ar.cost_basis_in_ar(), dec!(10000));
+    }
+}
@@ -0,0 +1,96 @@
+// Copyright (c) 2017-2023, scoobybejesus
+// Redistributions must include the license: https://github.com/scoobybejesus/cryptools/blob/master/LEGAL.txt
+
+use rust_decimal::Decimal;
+use rust_decimal_macros::dec;
+
+use crate::core_functions::ImportProcessParameters;
+use crate::errors::CrptlsError;
+use crate::uk_share_pooling::{get_uk_share_matches, UkDisposalMatch, UkMatchingRule};
+use crate::tests::helpers::{settings, process, date};
+
+fn uk_matches(name: &str, csv: &str) -> Vec<UkDisposalMatch> {
+    let (raw_accts, acct_map, ars, txns_map) = process(name, csv, &settings()).unwrap();
+    get_uk_share_matches("USD", 2, &raw_accts, &acct_map, &ars, &txns_map).unwrap()
+}
+
+fn cost_of_txn(matches: &[UkDisposalMatch], txn_num: u32) -> Decimal {
+    matches.iter().filter(|m| m.txn_num == txn_num).map(|m| m.cost).sum()
+}
+
+const HEADER: &str = "\
+txDate,proceeds,memo,1,2
+,,,Bank,Exchange
+,,,USD,BTC
+,,,non,non
+1-1-20,1000,POOL,-1000,10
+";
+
+/// Two sales on one day with a purchase between them.  As one disposal, they share the same-day purchase and
+/// the pool equally, whichever row comes first.
+#[test]
+fn same_day_disposals_are_matched_as_one() {
+
+    let sales_around_buy = format!("{}{}", HEADER, "\
+2-1-20,1000,SELL1,1000,-5
+2-1-20,600,BUY,-600,5
+2-1-20,1000,SELL2,1000,-5
+");
+    let buy_first = format!("{}{}", HEADER, "\
+2-1-20,600,BUY,-600,5
+2-1-20,1000,SELL1,1000,-5
+2-1-20,1000,SELL2,1000,-5
+");
+
+    for (name, csv, sale_txns) in [("uk_same_day_1", sales_around_buy, [2, 4]), ("uk_same_day_2", buy_first, [3, 4])] {
+
+        let matches = uk_matches(name, &csv);
+
+        for txn_num in sale_txns {
+            let sale_matches: Vec<&UkDisposalMatch> = matches.iter().filter(|m| m.txn_num == txn_num).collect();
+            assert_eq!(sale_matches.len(), 2);
+            assert_eq!(sale_matches[0].rule, UkMatchingRule::SameDay);
+            assert_eq!(sale_matches[0].amount, dec!(2.5));
+            assert_eq!(sale_matches[0].cost, dec!(300));
+            assert_eq!(sale_matches[0].acquisition_date, Some(date("2020-02-01")));
+            assert_eq!(sale_matches[1].rule, UkMatchingRule::Section104);
+            assert_eq!(sale_matches[1].amount, dec!(2.5));
+            assert_eq!(sale_matches[1].cost, dec!(250));
+            assert_eq!(cost_of_txn(&matches, txn_num), dec!(550));
+            assert_eq!(sale_matches.iter().map(|m| m.proceeds).sum::<Decimal>(), dec!(1000));
+        }
+    }
+}
+
+/// A purchase within 30 days after a sale is matched to it ahead of the pool, and one after 30 days isn't.
+#[test]
+fn purchases_within_30_days_are_matched_before_the_pool() {
+
+    let csv = format!("{}{}", HEADER, "\
+3-1-20,800,SELL1,800,-4
+3-31-20,1000,BUY1,-1000,4
+5-1-20,300,SELL2,300,-2
+6-1-20,900,BUY2,-900,2
+");
+    let matches = uk_matches("uk_30_day", &csv);
+
+    let first_sale: Vec<&UkDisposalMatch> = matches.iter().filter(|m| m.txn_num == 2).collect();
+    assert_eq!(first_sale.len(), 1);
+    assert_eq!(first_sale[0].rule, UkMatchingRule::BedAndBreakfast);
+    assert_eq!(first_sale[0].acquisition_date, Some(date("2020-03-31")));
+    assert_eq!(first_sale[0].cost, dec!(1000));
+    assert_eq!(first_sale[0].get_gain_or_loss(), dec!(-200));
+
+    // BUY2 is 31 days later, so the second sale comes from the pool, which BUY1 never joined: 10 units for 1000.
+    let second_sale: Vec<&UkDisposalMatch> = matches.iter().filter(|m| m.txn_num == 4).collect();
+    assert_eq!(second_sale.len(), 1);
+    assert_eq!(second_sale[0].rule, UkMatchingRule::Section104);
+    assert_eq!(second_sale[0].cost, dec!(200));
+}
+
+#[test]
+fn journal_entries_are_rejected_with_uk_share_pooling() {
+    let settings = ImportProcessParameters { uk_share_pooling: true, journal_entry_export: true, ..settings() };
+    let result = process("uk_journal_entries", HEADER, &settings);
+    assert!(matches!(result, Err(CrptlsError::InvalidSettings(reason)) if reason.starts_with("UK share pooling can't")));
+}
@@ -0,0 +1,41 @@
+// Copyright (c) 2017-2023, scoobybejesus
+// Redistributions must include the license: https://github.com/scoobybejesus/cryptools/blob/master/LEGAL.txt
+
+use rust_decimal_macros::dec;
+
+use crate::core_functions::ImportProcessParameters;
+use crate::costing_method::LotReliefScope;
+use crate::tests::helpers::{settings, process};
+
+/// The wallet's lot is older, so FIFO across both accounts matches each sale from the exchange against it, and
+/// the wallet's lot is swapped into the exchange a third at a time.
+const CSV: &str = "\
+txDate,proceeds,memo,1,2,3
+,,,Bank,Exchange,Wallet
+,,,USD,BTC,BTC
+,,,non,non,non
+1-1-20,100,WALLET BUY,-100,,3
+2-1-20,300,EXCHANGE BUY,-300,3,
+3-1-20,200,SELL1,200,-1,
+4-1-20,200,SELL2,200,-1,
+5-1-20,200,SELL3,200,-1,
+";
+
+#[test]
+fn swapped_out_lot_keeps_no_basis_once_empty() {
+
+    let settings = ImportProcessParameters { lot_relief_scope: LotReliefScope::Universal, ..settings() };
+    let (_raw_accts, acct_map, _ars, _txns_map) = process("universal_swaps", CSV, &settings).unwrap();
+
+    let wallet = acct_map.get(&3).unwrap();
+    let wallets_first_lot = wallet.list_of_lots.borrow()[0].clone();
+    assert_eq!(wallets_first_lot.get_sum_of_amts_in_lot(), dec!(0));
+    assert_eq!(wallets_first_lot.get_sum_of_orig_basis_in_lot(), dec!(0));
+
+    // The wallet's balance is unchanged, now held at the exchange lot's basis, and the exchange sold the wallet's.
+    assert_eq!(wallet.get_sum_of_amts_in_lots(), dec!(3));
+    assert_eq!(wallet.get_sum_of_orig_basis_in_lots(), dec!(300));
+    let exchange = acct_map.get(&2).unwrap();
+    assert_eq!(exchange.get_sum_of_amts_in_lots(), dec!(0));
+    assert_eq!(exchange.get_sum_of_orig_basis_in_lots(), dec!(0));
+}
@@ -0,0 +1,68 @@
+// Copyright (c) 2017-2023, scoobybejesus
+// Redistributions must include the license: https://github.com/scoobybejesus/cryptools/blob/master/LEGAL.txt
+
+use std::fs;
+
+use rust_decimal_macros::dec;
+
+use crate::holding_period::HoldingPeriodRule;
+use crate::prices::{import_prices, get_open_lot_valuations};
+use crate::tests::helpers::{settings, process, write_file, date};
+
+/// Two BTC lots, one held over a year by the end of 2020 and half sold, and one ETH lot.  The LTC has no price, and
+/// the last BTC sale comes after the valuation date.
+const CSV: &str = "\
+txDate,proceeds,memo,1,2,3,4
+,,,Bank,Exchange,Wallet,Wallet
+,,,USD,BTC,ETH,LTC
+,,,non,non,non,non
+1-1-19,3000,BUY BTC1,-3000,1,,
+6-1-20,9000,BUY BTC2,-9000,1,,
+7-1-20,2000,BUY ETH,-2000,,10,
+8-1-20,5000,SELL BTC,5000,-0.5,,
+9-1-20,100,BUY LTC,-100,,,2
+1-1-21,29000,SELL BTC AGAIN,29000,-1,,
+";
+
+/// The ETH price is from before the valuation date, which is the latest there is.
+const PRICES: &str = "\
+date,ticker,price
+2020-12-01,BTC,20000
+2020-12-31,BTC,29000
+2020-12-15,eth,730
+2021-01-15,ETH,1200
+";
+
+#[test]
+fn open_lots_are_valued_at_the_latest_price_on_or_before_the_date() {
+
+    let (raw_accts, acct_map, _ars, _txns_map) = process("unrealized", CSV, &settings()).unwrap();
+    let price_path = write_file("unrealized_prices", PRICES);
+    let prices = import_prices(&price_path).unwrap();
+    fs::remove_file(&price_path).unwrap();
+
+    let rule = HoldingPeriodRule::default();
+    let valuations = get_open_lot_valuations(date("2020-12-31"), &prices, &rule, "USD", &raw_accts, &acct_map);
+
+    // The bank's home currency isn't valued.
+    let lots: Vec<(u16, u32)> = valuations.iter().map(|valuation| (valuation.account_num, valuation.lot_num)).collect();
+    assert_eq!(lots, vec![(2, 1), (2, 2), (3, 1), (4, 1)]);
+
+    let half_sold = &valuations[0];
+    assert_eq!((half_sold.amount, half_sold.cost_basis), (dec!(0.5), dec!(1500)));
+    assert_eq!(half_sold.term.abbr, "LT");
+    assert_eq!(half_sold.market_value(2), Some(dec!(14500)));
+    assert_eq!(half_sold.unrealized_gain_loss(2), Some(dec!(13000)));
+
+    // The sale after the valuation date hasn't happened yet.
+    let newer = &valuations[1];
+    assert_eq!((newer.amount, newer.term.abbr.as_str()), (dec!(1), "ST"));
+    assert_eq!(newer.unrealized_gain_loss(2), Some(dec!(20000)));
+
+    let eth = &valuations[2];
+    assert_eq!(eth.price, Some(dec!(730)));
+    assert_eq!(eth.unrealized_gain_loss(2), Some(dec!(5300)));
+
+    let ltc = &valuations[3];
+    assert_eq!((ltc.price, ltc.market_value(2), ltc.unrealized_gain_loss(2)), (None, None, None));
+}
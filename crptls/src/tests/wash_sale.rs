@@ -0,0 +1,68 @@
+// Copyright (c) 2017-2023, scoobybejesus
+// Redistributions must include the license: https://github.com/scoobybejesus/cryptools/blob/master/LEGAL.txt
+
+use std::collections::HashMap;
+
+use rust_decimal_macros::dec;
+
+use crate::core_functions::ImportProcessParameters;
+use crate::tests::helpers::{settings, process, date};
+
+fn wash_sale_settings() -> ImportProcessParameters {
+    ImportProcessParameters {
+        wash_sale_rule: true,
+        ticker_precision: HashMap::from([("BTC".to_string(), 2)]),
+        ..settings()
+    }
+}
+
+/// The wallet sells at a loss of 400, and the exchange buys again nine days later.  The replacement units then move to
+/// the wallet along with a sliver of an old exchange lot, whose share of the transfer rounds away, so both outgoing
+/// `movement`s are carried into the wallet's one new `lot`.
+const CSV: &str = "\
+txDate,proceeds,memo,1,2,3
+,,,Bank,Exchange,Wallet
+,,,USD,BTC,BTC
+,,,non,non,non
+12-1-19,10,EXCHANGE BUY1,-10,0.01,
+1-1-20,1000,WALLET BUY,-1000,,1
+2-1-20,600,WALLET SELL,600,,-1
+2-10-20,700,EXCHANGE BUY2,-700,1,
+3-1-20,0,TRANSFER,,-1.01,0.4
+4-1-20,500,WALLET SELL AGAIN,500,,-0.4
+";
+
+#[test]
+fn disallowed_loss_follows_replacement_units_across_a_transfer() {
+
+    let (_raw_accts, acct_map, ars, txns_map) = process("wash_sale", CSV, &wash_sale_settings()).unwrap();
+
+    let first_sale = txns_map.get(&3).unwrap();
+    let sale_ar = ars.get(&first_sale.action_record_idx_vec[0]).unwrap();
+    let sale_mvmt = sale_ar.movements.borrow()[0].clone();
+    assert_eq!(sale_mvmt.get_orig_gain_or_loss(), dec!(-400));
+    assert_eq!(sale_mvmt.wash_sale_adjustment.get(), dec!(400));
+    assert_eq!(sale_mvmt.get_lk_gain_or_loss(), dec!(0));
+
+    // The replacement lot takes the loss as basis, and its basis date moves back by the 31 days the units were held.
+    let exchange = acct_map.get(&2).unwrap();
+    let replacement_lot = exchange.list_of_lots.borrow()[1].clone();
+    assert_eq!(replacement_lot.movements.borrow()[0].cost_basis_lk.get(), dec!(1100));
+    assert_eq!(replacement_lot.movements.borrow()[0].cost_basis.get(), dec!(700));
+    assert_eq!(replacement_lot.date_for_basis_purposes, date("2020-01-10"));
+
+    // The transfer carries the plain basis of both exchange lots and the deferred loss into the wallet's new lot.
+    let wallet = acct_map.get(&3).unwrap();
+    let received_lot = wallet.list_of_lots.borrow()[1].clone();
+    let received_mvmt = received_lot.movements.borrow()[0].clone();
+    assert_eq!(received_mvmt.amount, dec!(0.4));
+    assert_eq!(received_mvmt.cost_basis.get(), dec!(710));
+    assert_eq!(received_mvmt.cost_basis_lk.get(), dec!(1110));
+    assert_eq!(received_lot.date_for_basis_purposes, date("2020-01-10"));
+
+    let second_sale = txns_map.get(&6).unwrap();
+    let sale_ar = ars.get(&second_sale.action_record_idx_vec[0]).unwrap();
+    let sale_mvmt = sale_ar.movements.borrow()[0].clone();
+    assert_eq!(sale_mvmt.cost_basis_lk.get(), dec!(-1110));
+    assert_eq!(sale_mvmt.get_lk_gain_or_loss(), dec!(-610));
+}
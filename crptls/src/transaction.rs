@@ -3,10 +3,8 @@
 
 use std::rc::Rc;
 use std::cell::RefCell;
-use std::process;
 use std::fmt;
 use std::collections::HashMap;
-use std::error::Error;
 
 use rust_decimal::Decimal;
 use rust_decimal_macros::dec;
@@ -14,15 +12,57 @@ use chrono::NaiveDate;
 use serde_derive::{Serialize, Deserialize};
 
 use crate::account::{Account, Movement, RawAccount};
+use crate::costing_method::FeeTreatment;
+use crate::errors::CrptlsError;
+use crate::decimal_utils::round_d128_generalized;
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Transaction {
 	pub tx_number: u32,	//	Does NOT start at zero.  First txn is 1.
+	/// The line of the input file the `transaction` was read from, for error messages.
+	pub csv_line: u32,
 	pub date_as_string: String,
 	pub date: NaiveDate,
 	pub user_memo: String,
-	pub proceeds: f32,
+	pub proceeds: Decimal,
 	pub action_record_idx_vec: Vec<u32>,
+	/// The value, in home currency, of a fee already netted out of the row's amounts (the input file's optional `fee`
+	/// column).  `proceeds` is then the value of what was given up, fee included.
+	pub fee: Decimal,
+	pub fee_leg: Option<FeeLeg>,
+	/// Set for a dual-`action record` `flow` when margin profit and loss are realized as positions change.  What it
+	/// moves out of (or into) the quote `account` was already realized, so it is neither income nor expense.
+	pub settles_realized_margin_pnl: bool,
+	/// Set for a single-`action record` outgoing `flow` from a margin quote `account` paying interest or funding (i.e.,
+	/// `-0.0005 interest`).  Its value is expensed, and it comes back out of the margin profit or loss that later settles.
+	pub margin_charge: Option<MarginCharge>,
+	/// The row's amounts that had more decimal places than their `account`'s precision allows.  The `action record`s
+	/// hold them rounded.
+	pub rounded_amounts: Vec<RoundedAmount>,
+}
+
+/// How a trade's fees, in home currency, are split per the `FeeTreatment`.
+#[derive(Clone, Debug, Default)]
+pub struct FeeAllocation {
+	pub added_to_basis: Decimal,
+	pub taken_from_proceeds: Decimal,
+	pub expensed: Decimal,
+}
+
+/// A fee paid in a currency other than the two traded.  Its `action record` is kept out of `action_record_idx_vec`,
+/// so the `transaction` is still typed by its trade legs, but it is disposed of like any outgoing `action record`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct FeeLeg {
+	pub action_record_key: u32,
+	/// The fee's value in home currency.  This is the fee leg's proceeds, and it is then allocated per the `FeeTreatment`.
+	pub value: Decimal,
+}
+
+/// An amount in the input file rounded to its `account`'s precision (see `RawAccount::precision`).
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RoundedAmount {
+	pub action_record_key: u32,
+	pub amount_as_entered: Decimal,
 }
 
 impl Transaction {
@@ -32,29 +72,25 @@ impl Transaction {
 		ars: &HashMap<u32, ActionRecord>,
 		raw_acct_map: &HashMap<u16, RawAccount>,
 		acct_map: &HashMap<u16, Account>,
-	) -> Result<TxType, Box<dyn Error>> {
+	) -> Result<TxType, CrptlsError> {
 
 		if self.action_record_idx_vec.len() == 1 {
 			Ok(TxType::Flow)
 		}
 		else if self.action_record_idx_vec.len() == 2 {
-			//	This exercise of splitting the strings is because of margin accounts, where BTC borrowed to buy XMR would reflect as BTC_xmr
+			//	Underlying tickers are compared because of margin accounts, where BTC borrowed to buy XMR may reflect as BTC_xmr
 			let first_ar = ars.get(&self.action_record_idx_vec[0]).unwrap();
 			let second_ar = ars.get(&self.action_record_idx_vec[1]).unwrap();
 			let first_acct = acct_map.get(&first_ar.account_key).unwrap();
 			let second_acct = acct_map.get(&second_ar.account_key).unwrap();
 			let ar1_raw_acct = raw_acct_map.get(&first_acct.raw_key).unwrap();
 			let ar2_raw_acct = raw_acct_map.get(&second_acct.raw_key).unwrap();
-			let ar1_ticker_full = ar1_raw_acct.ticker.clone();
-			let ar2_ticker_full = ar2_raw_acct.ticker.clone();
-			let ar1_ticker_comp: Vec<&str> = ar1_ticker_full.split('_').collect();
-			let ar2_ticker_comp: Vec<&str> = ar2_ticker_full.split('_').collect();
-			let ar1_ticker = ar1_ticker_comp[0];
-			let ar2_ticker = ar2_ticker_comp[0];
+			let ar1_ticker = ar1_raw_acct.underlying_ticker();
+			let ar2_ticker = ar2_raw_acct.underlying_ticker();
 
 			if first_ar.direction() == second_ar.direction() {
-                println!("FATAL: TxType: Found transaction with two actionRecords with the same polarity: \n{:#?}", self);
-                process::exit(1);
+				return Err(CrptlsError::invalid_txn(self,
+					"The txn's two amounts are both incoming or both outgoing.".to_string()))
 			}
 			if ar1_ticker == ar2_ticker {
 				if ar1_raw_acct.is_margin != ar2_raw_acct.is_margin {
@@ -69,12 +105,11 @@ impl Transaction {
 			}
 		}
 		else if self.action_record_idx_vec.len() > 2 {
-            println!("FATAL: TxType: Found transaction with too many actionRecords: \n{:#?}", self);
-            process::exit(1);
+			Err(CrptlsError::invalid_txn(self, "The txn has more than two trade amounts. A third amount \
+				must be designated as the fee leg (e.g., `-0.01 fee 3.50`).".to_string()))
 		}
 		else {
-            println!("FATAL: TxType: Found transaction with no actionRecords: \n{:#?}", self);
-            process::exit(1);
+			Err(CrptlsError::invalid_txn(self, "The txn has no incoming or outgoing amount.".to_string()))
 		}
 	}
 
@@ -96,23 +131,9 @@ impl Transaction {
 			}
 		} else {
 
-            if self.action_record_idx_vec.len() != 2 {
-                println!("FATAL: Each transaction may have up to two actionrecords, and there are {} actionrecords in transaction:\n{:#?}",
-                self.action_record_idx_vec.len(), self);
-            }
-
-            let first_ar = match ars.get(&self.action_record_idx_vec[0]) {
-                Some(x) => x,
-                None => {
-                    println!("FATAL: ActionRecord not found for: \n{:#?}", self);
-                    process::exit(1)}
-            };
-			let second_ar = match ars.get(&self.action_record_idx_vec[1]) {
-                Some(x) => x,
-                None => {
-                    println!("FATAL: ActionRecord not found for: \n{:#?}", self);
-                    process::exit(1)}
-            };
+			//	The import guarantees every `transaction` has one or two `action record`s.
+			let first_ar = ars.get(&self.action_record_idx_vec[0]).unwrap();
+			let second_ar = ars.get(&self.action_record_idx_vec[1]).unwrap();
 
 			let first_acct = acct_map.get(&first_ar.account_key).unwrap();
 			let second_acct = acct_map.get(&second_ar.account_key).unwrap();
@@ -131,10 +152,12 @@ impl Transaction {
 		ars: &HashMap<u32, ActionRecord>,
 		raw_accts: &HashMap<u16, RawAccount>,
 		acct_map: &HashMap<u16, Account>
-	) -> Result<(u16, u16), Box<dyn Error>> {
+	) -> Result<(u16, u16), CrptlsError> {
 
-		assert_eq!(self.transaction_type(ars, raw_accts, acct_map)?, TxType::Exchange,
-			"This can only be called on exchange transactions.");
+		if self.transaction_type(ars, raw_accts, acct_map)? != TxType::Exchange {
+			return Err(CrptlsError::bad_margin_pair(self,
+				"Margin accounts may only exchange with each other, but this txn isn't an exchange.".to_string()))
+		}
 
 		let first_ar = ars.get(&self.action_record_idx_vec[0]).unwrap();
 		let second_ar = ars.get(&self.action_record_idx_vec[1]).unwrap();
@@ -145,34 +168,67 @@ impl Transaction {
 		let first_raw_acct = raw_accts.get(&first_acct_raw_key).unwrap();
 		let second_raw_acct = raw_accts.get(&second_acct_raw_key).unwrap();
 
-		assert_eq!(first_raw_acct.is_margin, true, "First actionrecord wasn't a margin account. Both must be.");
-		assert_eq!(second_raw_acct.is_margin, true, "Second actionrecord wasn't a margin account. Both must be.");
+		if !first_raw_acct.is_margin || !second_raw_acct.is_margin {
+			return Err(CrptlsError::bad_margin_pair(self,
+				"Only exchanges between two margin accounts have a base and quote account.".to_string()))
+		}
 
 		let quote: u16;
 		let base: u16;
 
-        if first_raw_acct.ticker.contains('_') {
-			quote = first_acct_raw_key;
-			base = second_acct_raw_key;
-			Ok((base, quote))
-		} else if second_raw_acct.ticker.contains('_') {
-			base = first_acct_raw_key;
-			quote = second_acct_raw_key;
-			Ok((base, quote))
-		} else {
-            println!("FATAL: {}", VariousErrors::MarginNoUnderbar);
-            std::process::exit(1);
+		match (&first_raw_acct.margin_pair, &second_raw_acct.margin_pair) {
+
+			// Declared margin pairs say which `account` is the quote `account`.
+			(Some(first_pair), Some(second_pair)) => {
+				if !first_pair.pair_ids.iter().any(|pair_id| second_pair.pair_ids.contains(pair_id)) {
+					return Err(CrptlsError::bad_margin_pair(self, format!("Margin accounts {} and {} traded \
+						with each other, but they weren't declared as the same margin pair.",
+						first_acct_raw_key, second_acct_raw_key)))
+				}
+				if first_pair.is_quote == second_pair.is_quote {
+					return Err(CrptlsError::bad_margin_pair(self, format!("Margin accounts {} and {} traded \
+						with each other, but they weren't declared as one base account and one quote account.",
+						first_acct_raw_key, second_acct_raw_key)))
+				}
+				if first_pair.is_quote {
+					quote = first_acct_raw_key;
+					base = second_acct_raw_key;
+				} else {
+					base = first_acct_raw_key;
+					quote = second_acct_raw_key;
+				}
+				Ok((base, quote))
+			}
+
+			(None, None) => {
+				if first_raw_acct.ticker.contains('_') {
+					quote = first_acct_raw_key;
+					base = second_acct_raw_key;
+					Ok((base, quote))
+				} else if second_raw_acct.ticker.contains('_') {
+					base = first_acct_raw_key;
+					quote = second_acct_raw_key;
+					Ok((base, quote))
+				} else {
+					Err(CrptlsError::bad_margin_pair(self, format!("{}", VariousErrors::MarginNoUnderbar)))
+				}
+			}
+
+			_ => Err(CrptlsError::bad_margin_pair(self, format!("Margin accounts {} and {} traded with \
+				each other, but only one of them was declared as part of a margin pair.",
+				first_acct_raw_key, second_acct_raw_key)))
 		}
 	}
 
 	pub fn get_outgoing_exchange_and_flow_mvmts(
         &self,
         user_home_currency: &str,
+        minor_units: u32,
         ars: &HashMap<u32, ActionRecord>,
         raw_acct_map: &HashMap<u16, RawAccount>,
         acct_map: &HashMap<u16, Account>,
         txns_map: &HashMap<u32, Transaction>,
-    ) -> Result<Vec<Rc<Movement>>, Box<dyn Error>> {
+    ) -> Result<Vec<Rc<Movement>>, CrptlsError> {
 
 		let mut flow_or_outgoing_exchange_movements = [].to_vec();
 
@@ -182,7 +238,29 @@ impl Transaction {
             let acct = acct_map.get(&ar.account_key).unwrap();
             let raw_acct = raw_acct_map.get(&acct.raw_key).unwrap();
 
-            if !raw_acct.is_home_currency(user_home_currency) & !raw_acct.is_margin {
+            // Only a margin `movement` that realized profit or loss (see `ImportProcessParameters::realize_margin_pnl`)
+            // has any gain or loss, and it is reported like a disposal.  One paying (or returning) a margin charge
+            // has an expense (or income) instead.
+            if raw_acct.is_margin {
+
+                let movements = ar.get_mvmts_in_ar_in_lot_date_order(acct_map, txns_map);
+
+                for mvmt in movements.iter() {
+                    if mvmt.get_lk_gain_or_loss() != dec!(0)
+                        || mvmt.get_income(ars, raw_acct_map, acct_map, txns_map, minor_units)? != dec!(0)
+                        || mvmt.get_expense(ars, raw_acct_map, acct_map, txns_map)? != dec!(0) {
+                        flow_or_outgoing_exchange_movements.push(mvmt.clone());
+                    }
+                }
+            } else if raw_acct.is_home_currency(user_home_currency) {
+
+                // Home currency spent has no gain or loss, but a purchase's expensed fee is booked against it.
+                let movements = ar.get_mvmts_in_ar_in_lot_date_order(acct_map, txns_map);
+
+                for mvmt in movements.iter().filter(|mvmt| mvmt.fee_expense.get() != dec!(0)) {
+                    flow_or_outgoing_exchange_movements.push(mvmt.clone());
+                }
+            } else {
 
                 let movements = ar.get_mvmts_in_ar_in_lot_date_order(acct_map, txns_map);
 
@@ -206,15 +284,113 @@ impl Transaction {
 		Ok(flow_or_outgoing_exchange_movements)
 	}
 
+	/// Returns the trade's `action record` keys, followed by the fee leg's, if there is one.
+	pub fn ar_keys_incl_fee_leg(&self) -> Vec<u32> {
+		let mut ar_keys = self.action_record_idx_vec.clone();
+		if let Some(fee_leg) = &self.fee_leg {
+			ar_keys.push(fee_leg.action_record_key)
+		}
+		ar_keys
+	}
+
+	/// Returns the `movement`s of a non-home currency fee leg.  These are disposals in their own right, but in a
+	/// different currency than the trade's, so reports show them apart from the trade's outgoing `movement`s.
+	pub fn get_fee_leg_mvmts(
+		&self,
+		home_currency: &str,
+		ars: &HashMap<u32, ActionRecord>,
+		raw_acct_map: &HashMap<u16, RawAccount>,
+		acct_map: &HashMap<u16, Account>,
+		txns_map: &HashMap<u32, Transaction>,
+	) -> Vec<Rc<Movement>> {
+
+		let fee_leg = match &self.fee_leg {
+			Some(x) => x,
+			None => return [].to_vec()
+		};
+
+		let ar = ars.get(&fee_leg.action_record_key).unwrap();
+		let acct = acct_map.get(&ar.account_key).unwrap();
+		let raw_acct = raw_acct_map.get(&acct.raw_key).unwrap();
+
+		if raw_acct.is_home_currency(home_currency) { return [].to_vec() }
+
+		ar.get_mvmts_in_ar_in_lot_date_order(acct_map, txns_map)
+	}
+
+	/// Returns the value of the trade's fees (its `fee` plus any fee leg) in home currency.
+	pub fn get_total_fee(&self) -> Decimal {
+		match &self.fee_leg {
+			Some(fee_leg) => self.fee + fee_leg.value,
+			None => self.fee,
+		}
+	}
+
+	/// Splits the trade's fees per the `FeeTreatment`.  When the treatment doesn't fit the trade, the fee is added to
+	/// the basis of what was acquired (a purchase with home currency has no proceeds to take it from) or, if the trade
+	/// is a sale for home currency, taken out of its proceeds.  An expensed fee fits any trade.
+	pub fn get_fee_allocation(
+		&self,
+		fee_treatment: &FeeTreatment,
+		home_currency: &str,
+		ars: &HashMap<u32, ActionRecord>,
+		raw_acct_map: &HashMap<u16, RawAccount>,
+		acct_map: &HashMap<u16, Account>,
+	) -> FeeAllocation {
+
+		let fee = self.get_total_fee();
+		if fee == dec!(0) { return FeeAllocation::default() }
+
+		let og_ar = ars.get(&self.action_record_idx_vec[0]).unwrap();
+		let ic_ar = ars.get(&self.action_record_idx_vec[1]).unwrap();
+		let raw_og_acct = raw_acct_map.get(&acct_map.get(&og_ar.account_key).unwrap().raw_key).unwrap();
+		let raw_ic_acct = raw_acct_map.get(&acct_map.get(&ic_ar.account_key).unwrap().raw_key).unwrap();
+
+		let acquires = !raw_ic_acct.is_home_currency(home_currency);
+		let disposes = !raw_og_acct.is_home_currency(home_currency);
+
+		let fee_treatment = match fee_treatment {
+			FeeTreatment::AddToBasis if !acquires => &FeeTreatment::SubtractFromProceeds,
+			FeeTreatment::SubtractFromProceeds if !disposes => &FeeTreatment::AddToBasis,
+			_ => fee_treatment,
+		};
+
+		match fee_treatment {
+			FeeTreatment::AddToBasis => FeeAllocation { added_to_basis: fee, ..Default::default() },
+			FeeTreatment::SubtractFromProceeds => FeeAllocation { taken_from_proceeds: fee, ..Default::default() },
+			FeeTreatment::Expense => FeeAllocation { expensed: fee, ..Default::default() },
+		}
+	}
+
+	pub fn get_fee_leg_memo(
+		&self,
+		ars: &HashMap<u32, ActionRecord>,
+		raw_accts: &HashMap<u16, RawAccount>,
+		acct_map: &HashMap<u16, Account>,
+		home_currency: &str,
+		minor_units: u32,
+	) -> Option<String> {
+
+		let fee_leg = self.fee_leg.as_ref()?;
+		let ar = ars.get(&fee_leg.action_record_key).unwrap();
+		let acct = acct_map.get(&ar.account_key).unwrap();
+		let raw_acct = raw_accts.get(&acct.raw_key).unwrap();
+
+		Some(format!("Paid a fee of {} {}, valued at {:.*} {}.", -ar.amount, raw_acct.ticker, minor_units as usize,
+			round_d128_generalized(&fee_leg.value, minor_units), home_currency))
+	}
+
     pub fn both_exch_ars_are_non_home_curr(
         &self,
         ars: &HashMap<u32, ActionRecord>,
         raw_acct_map: &HashMap<u16, RawAccount>,
         acct_map: &HashMap<u16, Account>,
         home_currency: &str,
-    ) -> Result<bool, Box<dyn Error>> {
+    ) -> Result<bool, CrptlsError> {
 
-        assert_eq!(self.action_record_idx_vec.len(), (2 as usize));
+        if self.action_record_idx_vec.len() != 2 {
+            return Err(CrptlsError::invalid_txn(self, "An exchange txn should have exactly 2 action records.".to_string()))
+        }
 
         let og_ar = ars.get(&self.action_record_idx_vec.first().unwrap()).unwrap();
         let ic_ar = ars.get(&self.action_record_idx_vec.last().unwrap()).unwrap();
@@ -236,7 +412,10 @@ impl Transaction {
 		raw_accts: &HashMap<u16, RawAccount>,
 		acct_map: &HashMap<u16, Account>,
         home_currency: &str,
-	) -> Result<String, Box<dyn Error>> {
+        minor_units: u32,
+	) -> Result<String, CrptlsError> {
+
+        let places = minor_units as usize;
 
         let auto_memo = if self.action_record_idx_vec.len() == 2 {
 
@@ -260,8 +439,8 @@ impl Transaction {
 
                 let og_amt_and_ticker;
                 if og_raw_acct.is_home_currency(home_currency) {
-                    og_amt_and_ticker = format!("{:.2} {}",
-                        og_amt.to_string().as_str().parse::<f32>()?, og_ticker
+                    og_amt_and_ticker = format!("{:.*} {}",
+                        places, round_d128_generalized(&og_amt, minor_units), og_ticker
                     );
                 } else {
                     og_amt_and_ticker = format!("{} {}", og_amt, og_ticker);
@@ -269,25 +448,25 @@ impl Transaction {
 
                 let ic_amt_and_ticker;
                 if ic_raw_acct.is_home_currency(home_currency) {
-                    ic_amt_and_ticker = format!("{:.2} {}",
-                        ic_amt.to_string().as_str().parse::<f32>()?, ic_ticker
+                    ic_amt_and_ticker = format!("{:.*} {}",
+                        places, round_d128_generalized(&ic_amt, minor_units), ic_ticker
                     );
                 } else {
                     ic_amt_and_ticker = format!("{} {}", ic_amt, ic_ticker);
                 }
 
                 if tx_type == TxType::Exchange {
-                    format!("Paid {} for {}, valued at {:.2} {}.",
+                    format!("Paid {} for {}, valued at {:.*} {}.",
                         og_amt_and_ticker, ic_amt_and_ticker,
-                        self.proceeds.to_string().as_str().parse::<f32>()?, home_currency)
+                        places, round_d128_generalized(&self.proceeds, minor_units), home_currency)
                 } else {
                     format!("Transferred {} to another account. Received {}, likely after a transaction fee.",
                         og_amt_and_ticker, ic_amt_and_ticker)
                 }
             } else {
 
-                format!("Margin profit or loss valued at {:.2} {}.",
-                self.proceeds.to_string().as_str().parse::<f32>()?, home_currency)
+                format!("Margin profit or loss valued at {:.*} {}.",
+                places, round_d128_generalized(&self.proceeds, minor_units), home_currency)
             }
 
         } else {
@@ -298,15 +477,20 @@ impl Transaction {
             let raw_acct = raw_accts.get(&acct.raw_key).unwrap();
             let ticker = &raw_acct.ticker;
 
-            if amt > dec!(0.0) {
+            if let Some(charge) = self.margin_charge {
+
+                format!("Paid {} {} of margin {}, valued at {:.*} {}.", -amt, ticker, charge,
+                places, round_d128_generalized(&self.proceeds, minor_units), home_currency)
 
-                format!("Received {} {} valued at {:.2} {}.", amt, ticker,
-                self.proceeds.to_string().as_str().parse::<f32>()?, home_currency)
+            } else if amt > dec!(0.0) {
+
+                format!("Received {} {} valued at {:.*} {}.", amt, ticker,
+                places, round_d128_generalized(&self.proceeds, minor_units), home_currency)
 
             } else {
 
-                format!("Spent {} {} valued at {:.2} {}.", amt, ticker,
-                self.proceeds.to_string().as_str().parse::<f32>()?, home_currency)
+                format!("Spent {} {} valued at {:.*} {}.", amt, ticker,
+                places, round_d128_generalized(&self.proceeds, minor_units), home_currency)
 
             }
         };
@@ -447,6 +631,25 @@ impl fmt::Display for Polarity {
     }
 }
 
+/// What a margin `account` paid for, per the tag after its amount in the input file.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum MarginCharge {
+	/// Interest on a margin loan
+	Interest,
+	/// A periodic funding payment, as on perpetual futures
+	Funding,
+}
+
+impl fmt::Display for MarginCharge {
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+       match *self {
+           MarginCharge::Interest => write!(f, "interest"),
+           MarginCharge::Funding => write!(f, "funding"),
+       }
+    }
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub enum VariousErrors {
 	MarginNoUnderbar,
@@ -459,7 +662,8 @@ impl fmt::Display for VariousErrors {
 			VariousErrors::MarginNoUnderbar => write!(f,
 			"Neither account ticker contained an underbar '_', so the quote account couldn't be determined.
 			For example, for the 'USD/EUR' pair, USD is the base account and EUR is the quote account.
-			In order for this software to function correctly, the quote ticker should be denoted as 'EUR_usd'.")
+			In order for this software to function correctly, the quote ticker should be denoted as 'EUR_usd',
+			or the pair should be declared in the margin header row (i.e., 'margin:pair1:base' and 'margin:pair1:quote').")
 		}
 	}
 }
@@ -0,0 +1,341 @@
+// Copyright (c) 2017-2023, scoobybejesus
+// Redistributions must include the license: https://github.com/scoobybejesus/cryptools/blob/master/LEGAL.txt
+
+use std::fmt;
+use std::collections::HashMap;
+
+use chrono::{Duration, NaiveDate};
+use rust_decimal::Decimal;
+use rust_decimal_macros::dec;
+
+use crate::transaction::{Transaction, ActionRecord, Polarity, TxType};
+use crate::account::{Account, RawAccount};
+use crate::decimal_utils::{round_d128_generalized, allocate_rounded};
+use crate::errors::CrptlsError;
+
+/// The HMRC share matching rules, in the order they are applied to a disposal.
+#[derive(Clone, Debug, PartialEq)]
+pub enum UkMatchingRule {
+    /// Acquisitions on the same day as the disposal.
+    SameDay,
+    /// Acquisitions in the 30 days following the disposal ("bed and breakfast").
+    BedAndBreakfast,
+    /// The Section 104 holding (the pool of everything else, at average cost).
+    Section104,
+}
+
+impl fmt::Display for UkMatchingRule {
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+           UkMatchingRule::SameDay => write!(f, "Same day"),
+           UkMatchingRule::BedAndBreakfast => write!(f, "30 day"),
+           UkMatchingRule::Section104 => write!(f, "Section 104"),
+       }
+    }
+}
+
+/// One part of a disposal, matched under a single rule.  A disposal has one `UkDisposalMatch` per
+/// acquisition day it is matched against, plus one for the Section 104 pool if it reaches the pool.
+#[derive(Clone, Debug)]
+pub struct UkDisposalMatch {
+    pub txn_num: u32,
+    pub date: NaiveDate,
+    pub ticker: String,
+    pub rule: UkMatchingRule,
+    /// Positive number of units matched
+    pub amount: Decimal,
+    pub proceeds: Decimal,
+    /// Positive allowable cost
+    pub cost: Decimal,
+    /// `None` for `Section104` matches
+    pub acquisition_date: Option<NaiveDate>,
+}
+
+impl UkDisposalMatch {
+    pub fn get_gain_or_loss(&self) -> Decimal {
+        self.proceeds - self.cost
+    }
+}
+
+enum UkEvent {
+    Acquisition { date: NaiveDate, amount: Decimal, cost: Decimal },
+    Disposal { txn_num: u32, date: NaiveDate, amount: Decimal, proceeds: Decimal, precision: u32 },
+    // Units moved between the user's own accounts.  Only a fee (the net) changes the pool, and the
+    // cost stays in the pool, consistent with how `ToSelf` `transaction`s carry basis elsewhere.
+    Transfer { amount: Decimal },
+}
+
+struct AcquisitionDay {
+    date: NaiveDate,
+    amount: Decimal,
+    cost: Decimal,
+    remaining: Decimal,
+}
+
+struct Disposal {
+    txn_num: u32,
+    date: NaiveDate,
+    amount: Decimal,
+    proceeds: Decimal,
+    /// Decimal places of the `account`'s amounts, for splitting the day's matches
+    precision: u32,
+    matches: Vec<(UkMatchingRule, Decimal, Decimal, Option<NaiveDate>)>,  //  (rule, amount, cost, acquisition date)
+}
+
+/// All disposals of the ticker on one day, which HMRC treats as a single disposal.
+struct DisposalDay {
+    date: NaiveDate,
+    amount: Decimal,
+    remaining: Decimal,
+    /// Indexes of the day's `Disposal`s
+    disposal_idxs: Vec<usize>,
+    drawn_from_pool: bool,
+    matches: Vec<(UkMatchingRule, Decimal, Decimal, Option<NaiveDate>)>,  //  (rule, amount, cost, acquisition date)
+}
+
+/// This is a separate costing engine from `create_lots_and_movements()`.  HMRC matching can't be done while
+/// walking forward through the `transaction`s, because a disposal can be matched against an acquisition up to
+/// 30 days after it.  So this runs after all `transaction`s have been processed, and it only reads the amounts,
+/// cost basis and proceeds that are already on the `movement`s; it doesn't change them.
+///
+/// Each ticker is one pool, shared across every non-margin `account` with that ticker.  All acquisitions on
+/// a given day are treated as a single acquisition, and so are all disposals.  Each day's disposal is matched, in
+/// order, against (1) acquisitions on the same day, (2) acquisitions in the following 30 days, earliest first, and
+/// (3) the Section 104 pool.  Its matches are then split among that day's disposals in proportion to their amounts.
+pub fn get_uk_share_matches(
+    home_currency: &str,
+    minor_units: u32,
+    raw_acct_map: &HashMap<u16, RawAccount>,
+    acct_map: &HashMap<u16, Account>,
+    ar_map: &HashMap<u32, ActionRecord>,
+    txns_map: &HashMap<u32, Transaction>,
+) -> Result<Vec<UkDisposalMatch>, CrptlsError> {
+
+    let mut events_by_ticker: HashMap<String, Vec<UkEvent>> = HashMap::new();
+    let mut tickers_in_order: Vec<String> = Vec::new();
+
+    for num in 1..=txns_map.len() {
+
+        let txn_num = num as u32;
+        let txn = txns_map.get(&txn_num).unwrap();
+        let tx_type = txn.transaction_type(ar_map, raw_acct_map, acct_map)?;
+
+        for ar_num in txn.ar_keys_incl_fee_leg().iter() {
+
+            let ar = ar_map.get(ar_num).unwrap();
+            let acct = acct_map.get(&ar.account_key).unwrap();
+            let raw_acct = raw_acct_map.get(&acct.raw_key).unwrap();
+
+            if raw_acct.is_margin || raw_acct.is_home_currency(home_currency) { continue }
+
+            if !events_by_ticker.contains_key(&raw_acct.ticker) {
+                tickers_in_order.push(raw_acct.ticker.clone());
+            }
+            let events = events_by_ticker.entry(raw_acct.ticker.clone()).or_default();
+
+            if tx_type == TxType::ToSelf {
+                events.push(UkEvent::Transfer { amount: ar.amount });
+                continue
+            }
+
+            match ar.direction() {
+                Polarity::Incoming => {
+                    let cost: Decimal = ar.movements.borrow().iter().map(|m| m.cost_basis.get()).sum();
+                    events.push(UkEvent::Acquisition { date: txn.date, amount: ar.amount, cost });
+                }
+                Polarity::Outgoing => {
+                    let proceeds: Decimal = ar.movements.borrow().iter().map(|m| m.proceeds.get()).sum();
+                    events.push(UkEvent::Disposal {
+                        txn_num, date: txn.date, amount: -ar.amount, proceeds, precision: raw_acct.precision
+                    });
+                }
+            }
+        }
+    }
+
+    let mut all_matches: Vec<UkDisposalMatch> = Vec::new();
+
+    for ticker in tickers_in_order.iter() {
+        let events = events_by_ticker.get(ticker).unwrap();
+        all_matches.extend(match_disposals_for_ticker(ticker, events, minor_units));
+    }
+
+    all_matches.sort_by_key(|m| m.txn_num);   //  Stable, so each disposal's matches stay in rule order
+
+    Ok(all_matches)
+}
+
+fn match_disposals_for_ticker(ticker: &str, events: &[UkEvent], minor_units: u32) -> Vec<UkDisposalMatch> {
+
+    let mut acq_days: Vec<AcquisitionDay> = Vec::new();
+    let mut disposals: Vec<Disposal> = Vec::new();
+    let mut disposal_days: Vec<DisposalDay> = Vec::new();
+
+    for event in events.iter() {
+        match event {
+            UkEvent::Acquisition { date, amount, cost } => {
+                match acq_days.iter_mut().find(|day| day.date == *date) {
+                    Some(day) => {
+                        day.amount += *amount;
+                        day.cost += *cost;
+                        day.remaining += *amount;
+                    }
+                    None => acq_days.push(AcquisitionDay {
+                        date: *date, amount: *amount, cost: *cost, remaining: *amount
+                    }),
+                }
+            }
+            UkEvent::Disposal { txn_num, date, amount, proceeds, precision } => {
+                match disposal_days.iter_mut().find(|day| day.date == *date) {
+                    Some(day) => {
+                        day.amount += *amount;
+                        day.remaining += *amount;
+                        day.disposal_idxs.push(disposals.len());
+                    }
+                    None => disposal_days.push(DisposalDay {
+                        date: *date,
+                        amount: *amount,
+                        remaining: *amount,
+                        disposal_idxs: vec![disposals.len()],
+                        drawn_from_pool: false,
+                        matches: Vec::new(),
+                    }),
+                }
+                disposals.push(Disposal {
+                    txn_num: *txn_num,
+                    date: *date,
+                    amount: *amount,
+                    proceeds: *proceeds,
+                    precision: *precision,
+                    matches: Vec::new(),
+                });
+            }
+            UkEvent::Transfer { .. } => {}
+        }
+    }
+
+    // First, the same-day rule.
+    for disposal_day in disposal_days.iter_mut() {
+        if let Some(day) = acq_days.iter_mut().find(|day| day.date == disposal_day.date) {
+            match_against_acquisition_day(disposal_day, day, UkMatchingRule::SameDay, minor_units);
+        }
+    }
+
+    // Second, the 30-day ("bed and breakfast") rule.  Earlier disposals get first claim on later acquisitions.
+    for disposal_day in disposal_days.iter_mut() {
+        let window_end = disposal_day.date + Duration::days(30);
+        for day in acq_days.iter_mut() {
+            if disposal_day.remaining == dec!(0) { break }
+            if day.date > disposal_day.date && day.date <= window_end {
+                match_against_acquisition_day(disposal_day, day, UkMatchingRule::BedAndBreakfast, minor_units);
+            }
+        }
+    }
+
+    // Last, the Section 104 pool, built chronologically from whatever wasn't matched by the rules above.  A day's
+    // disposal draws on the pool once, at the first of its disposals.
+    let mut pool_amount = dec!(0);
+    let mut pool_cost = dec!(0);
+
+    for event in events.iter() {
+        match event {
+            UkEvent::Acquisition { date, amount, cost } => {
+                // Every acquisition on a day shares proportionally in what's left of that day.
+                let day = acq_days.iter().find(|day| day.date == *date).unwrap();
+                let ratio = day.remaining / day.amount;
+                pool_amount += *amount * ratio;
+                pool_cost += round_d128_generalized(&(*cost * ratio), minor_units);
+            }
+            UkEvent::Transfer { amount } => {
+                pool_amount += *amount;
+            }
+            UkEvent::Disposal { date, .. } => {
+                let disposal_day = disposal_days.iter_mut().find(|day| day.date == *date).unwrap();
+
+                if disposal_day.drawn_from_pool || disposal_day.remaining == dec!(0) { continue }
+                disposal_day.drawn_from_pool = true;
+
+                let cost = if pool_amount <= dec!(0) {
+                    dec!(0)
+                } else if disposal_day.remaining >= pool_amount {
+                    pool_cost
+                } else {
+                    round_d128_generalized(&(pool_cost * disposal_day.remaining / pool_amount), minor_units)
+                };
+
+                pool_amount -= disposal_day.remaining;
+                pool_cost -= cost;
+                disposal_day.matches.push((UkMatchingRule::Section104, disposal_day.remaining, cost, None));
+                disposal_day.remaining = dec!(0);
+            }
+        }
+    }
+
+    // Each of the day's matches is split among its disposals by their share of the day's amount.
+    for disposal_day in disposal_days.iter() {
+
+        let shares: Vec<Decimal> = disposal_day.disposal_idxs.iter()
+            .map(|idx| disposals[*idx].amount / disposal_day.amount)
+            .collect();
+        let precision = disposal_day.disposal_idxs.iter().map(|idx| disposals[*idx].precision).max().unwrap();
+
+        for (rule, amount, cost, acquisition_date) in disposal_day.matches.iter() {
+
+            let amounts = allocate_rounded(amount, &shares, precision);
+            let costs = allocate_rounded(cost, &shares, minor_units);
+
+            for (part, idx) in disposal_day.disposal_idxs.iter().enumerate() {
+                if amounts[part] == dec!(0) && costs[part] == dec!(0) { continue }
+                disposals[*idx].matches.push((rule.clone(), amounts[part], costs[part], *acquisition_date));
+            }
+        }
+    }
+
+    let mut uk_matches = Vec::new();
+
+    for disposal in disposals.iter() {
+
+        if disposal.matches.is_empty() { continue }
+
+        let mut proceeds_used = dec!(0);
+        let last_idx = disposal.matches.len() - 1;
+
+        for (idx, (rule, amount, cost, acquisition_date)) in disposal.matches.iter().enumerate() {
+
+            // Proceeds are split in proportion to the amount matched, with the last match absorbing any rounding.
+            let proceeds = if idx == last_idx {
+                disposal.proceeds - proceeds_used
+            } else {
+                round_d128_generalized(&(disposal.proceeds * *amount / disposal.amount), minor_units)
+            };
+            proceeds_used += proceeds;
+
+            uk_matches.push(UkDisposalMatch {
+                txn_num: disposal.txn_num,
+                date: disposal.date,
+                ticker: ticker.to_string(),
+                rule: rule.clone(),
+                amount: *amount,
+                proceeds,
+                cost: *cost,
+                acquisition_date: *acquisition_date,
+            });
+        }
+    }
+
+    uk_matches
+}
+
+fn match_against_acquisition_day(disposal: &mut DisposalDay, day: &mut AcquisitionDay, rule: UkMatchingRule, minor_units: u32) {
+
+    let amount = disposal.remaining.min(day.remaining);
+
+    if amount == dec!(0) { return }
+
+    let cost = round_d128_generalized(&(day.cost * amount / day.amount), minor_units);
+
+    disposal.remaining -= amount;
+    day.remaining -= amount;
+    disposal.matches.push((rule, amount, cost, Some(day.date)));
+}
@@ -0,0 +1,269 @@
+// Copyright (c) 2017-2023, scoobybejesus
+// Redistributions must include the license: https://github.com/scoobybejesus/cryptools/blob/master/LEGAL.txt
+
+use std::rc::Rc;
+use std::collections::HashMap;
+
+use chrono::NaiveDate;
+use rust_decimal::Decimal;
+use rust_decimal_macros::dec;
+
+use crate::account::{Account, RawAccount, Lot, Movement};
+use crate::transaction::{Transaction, TxType, ActionRecord, Polarity};
+use crate::decimal_utils::round_d128_generalized;
+use crate::errors::CrptlsError;
+
+/// A loss is disallowed when the same ticker is acquired within this many days before or after the disposal.
+const WASH_SALE_WINDOW_DAYS: i64 = 30;
+
+/// Form 8949 column (f) code for a wash sale loss adjustment.
+pub const WASH_SALE_ADJUSTMENT_CODE: &str = "W";
+
+/// Basis deferred into a replacement `lot` (or a `lot` it was transferred into).
+struct Deferral {
+    lot: Rc<Lot>,
+    extra_basis: Decimal,
+    /// Only the `lot`'s outgoing `movement`s in later `transaction`s take a share of the `extra_basis`
+    after_txn_num: u32,
+    tacked_basis_date: NaiveDate,
+}
+
+/// Disallows losses under a wash sale rule.  Disposals are considered in `transaction` order, and a loss is disallowed
+/// to the extent units of the same ticker (in any non-margin `account`) were acquired in an `exchange` within 30 days
+/// before or after it.  Each acquired unit replaces at most one unit sold at a loss, and acquisitions are matched
+/// earliest first.  The disallowed loss is recorded as the disposal `movement`'s `wash_sale_adjustment`, and it is
+/// added to the replacement `lot`'s basis, which flows to that `lot`'s later `movement`s (including across `ToSelf`
+/// transfers).  The replacement `lot`'s basis date is moved back by the holding period of the units sold.  Because
+/// a `lot` has a single basis date, this applies to the whole replacement `lot`.
+///
+/// Only the like-kind (`_lk`) cost basis and proceeds are changed.  Those are the tax figures the reports use, while
+/// the plain `cost_basis` and `proceeds` stay the unadjusted figures shown beside them (as with like-kind treatment).
+/// Matching disposals to `lot`s, which reads the plain figures, is already done by this point.
+pub(crate) fn apply_wash_sale_treatment(
+    home_currency: &str,
+    minor_units: u32,
+    raw_acct_map: &HashMap<u16, RawAccount>,
+    acct_map: &HashMap<u16, Account>,
+    ars: &HashMap<u32, ActionRecord>,
+    txns_map: &HashMap<u32, Transaction>,
+) -> Result<(), CrptlsError> {
+
+    let acquisitions = get_acquisitions(home_currency, raw_acct_map, acct_map, ars, txns_map)?;
+
+    // Units of each acquisition, keyed by (account, lot number), already used as replacement units.
+    let mut replacement_units_used: HashMap<(u16, u32), Decimal> = HashMap::new();
+
+    let length = txns_map.len();
+
+    for txn_num in 1..=length {
+
+        let txn_num = txn_num as u32;
+        let txn = txns_map.get(&(txn_num)).unwrap();
+        let tx_type = txn.transaction_type(ars, raw_acct_map, acct_map)?;
+
+        // Transfers aren't disposals, and a dual-`action record` `flow` `transaction` is a margin profit or loss.
+        if tx_type == TxType::ToSelf { continue }
+        if tx_type == TxType::Flow && txn.action_record_idx_vec.len() == 2 { continue }
+
+        for ar_num in txn.ar_keys_incl_fee_leg().iter() {
+
+            let ar = ars.get(ar_num).unwrap();
+            let acct = acct_map.get(&ar.account_key).unwrap();
+            let raw_acct = raw_acct_map.get(&acct.raw_key).unwrap();
+
+            if raw_acct.is_margin || raw_acct.is_home_currency(home_currency) { continue }
+            if ar.direction() != Polarity::Outgoing { continue }
+
+            for mvmt in ar.get_mvmts_in_ar_in_lot_date_order(acct_map, txns_map).iter() {
+
+                let loss = mvmt.get_lk_gain_or_loss();
+                if loss >= dec!(0) { continue }
+
+                let sold_lot = mvmt.get_lot(acct_map, ars);
+                let holding_period = txn.date.signed_duration_since(sold_lot.date_for_basis_purposes);
+                let units_sold = -mvmt.amount;
+                let mut units_unmatched = units_sold;
+                let mut total_disallowed = dec!(0);
+
+                for acq in acquisitions.iter() {
+
+                    if units_unmatched == dec!(0) { break }
+
+                    let acq_acct_key = ars.get(&acq.action_record_key).unwrap().account_key;
+                    let acq_raw_acct = raw_acct_map.get(&acq_acct_key).unwrap();
+
+                    if acq_raw_acct.ticker != raw_acct.ticker { continue }
+                    if (acq.date - txn.date).num_days().abs() > WASH_SALE_WINDOW_DAYS { continue }
+
+                    let key = (acq_acct_key, acq.lot_num);
+                    if key == (sold_lot.account_key, sold_lot.lot_number) { continue }
+
+                    // An earlier acquisition only replaces units still held once this disposal is made.
+                    let replacement_lot = acq.get_lot(acct_map, ars);
+                    let held = if acq.transaction_key < txn_num {
+                        get_amt_in_lot_through_txn(&replacement_lot, txn_num).min(acq.amount)
+                    } else {
+                        acq.amount
+                    };
+                    let used = replacement_units_used.get(&key).copied().unwrap_or(dec!(0));
+                    let available = held - used;
+                    if available <= dec!(0) { continue }
+
+                    let units = available.min(units_unmatched);
+                    units_unmatched -= units;
+                    replacement_units_used.insert(key, used + units);
+
+                    // The last piece of a fully-replaced disposal takes whatever is left, so no loss is lost to rounding.
+                    let disallowed = if units_unmatched == dec!(0) {
+                        -loss - total_disallowed
+                    } else {
+                        round_d128_generalized(&(-loss * units / units_sold), minor_units)
+                    };
+                    if disallowed == dec!(0) { continue }
+                    total_disallowed += disallowed;
+
+                    let deferral = Deferral {
+                        lot: replacement_lot,
+                        extra_basis: disallowed,
+                        after_txn_num: txn_num.max(acq.transaction_key),
+                        tacked_basis_date: acq.date - holding_period,
+                    };
+
+                    add_basis_to_lot(deferral, minor_units, raw_acct_map, acct_map, ars, txns_map)?;
+                }
+
+                mvmt.wash_sale_adjustment.set(total_disallowed);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns the first `movement` of every non-margin, non-home currency `lot` opened by an `exchange`,
+/// in date order (and `transaction` order within a date).
+fn get_acquisitions(
+    home_currency: &str,
+    raw_acct_map: &HashMap<u16, RawAccount>,
+    acct_map: &HashMap<u16, Account>,
+    ars: &HashMap<u32, ActionRecord>,
+    txns_map: &HashMap<u32, Transaction>,
+) -> Result<Vec<Rc<Movement>>, CrptlsError> {
+
+    let mut acquisitions: Vec<Rc<Movement>> = [].to_vec();
+
+    let mut acct_keys: Vec<&u16> = acct_map.keys().collect();
+    acct_keys.sort();
+
+    for acct_key in acct_keys {
+
+        let acct = acct_map.get(acct_key).unwrap();
+        let raw_acct = raw_acct_map.get(&acct.raw_key).unwrap();
+
+        if raw_acct.is_margin || raw_acct.is_home_currency(home_currency) { continue }
+
+        for lot in acct.list_of_lots.borrow().iter() {
+
+            let first_mvmt = lot.movements.borrow().first().unwrap().clone();
+            let txn = txns_map.get(&first_mvmt.transaction_key).unwrap();
+
+            // `Movement`s of `action record`s that aren't part of their `transaction` didn't acquire anything.
+            if !txn.action_record_idx_vec.contains(&first_mvmt.action_record_key) { continue }
+
+            if first_mvmt.amount > dec!(0) && txn.transaction_type(ars, raw_acct_map, acct_map)? == TxType::Exchange {
+                acquisitions.push(first_mvmt)
+            }
+        }
+    }
+
+    acquisitions.sort_by_key(|mvmt| (mvmt.date, mvmt.transaction_key));
+
+    Ok(acquisitions)
+}
+
+fn get_amt_in_lot_through_txn(lot: &Lot, txn_num: u32) -> Decimal {
+    lot.movements.borrow().iter()
+        .filter(|mvmt| mvmt.transaction_key <= txn_num)
+        .map(|mvmt| mvmt.amount)
+        .sum()
+}
+
+/// Adds the `extra_basis` to the `lot`, spreading it over the `lot`'s outgoing `movement`s after `after_txn_num` in
+/// proportion to the units held at that point.  Any share transferred `ToSelf` is carried into the receiving `lot`.
+/// The `lot`'s basis date becomes the `tacked_basis_date`, if that is earlier.
+fn add_basis_to_lot(
+    deferral: Deferral,
+    minor_units: u32,
+    raw_acct_map: &HashMap<u16, RawAccount>,
+    acct_map: &HashMap<u16, Account>,
+    ars: &HashMap<u32, ActionRecord>,
+    txns_map: &HashMap<u32, Transaction>,
+) -> Result<(), CrptlsError> {
+
+    let Deferral { lot, extra_basis, after_txn_num, tacked_basis_date } = deferral;
+
+    let movements: Vec<Rc<Movement>> = lot.movements.borrow().clone();
+    let first_mvmt = movements.first().unwrap();
+
+    first_mvmt.cost_basis_lk.set(first_mvmt.cost_basis_lk.get() + extra_basis);
+    first_mvmt.proceeds_lk.set(-first_mvmt.cost_basis_lk.get());
+
+    let held = get_amt_in_lot_through_txn(&lot, after_txn_num);
+    let lot_is_emptied = lot.get_sum_of_amts_in_lot() == dec!(0);
+
+    let later_outgoing_mvmts: Vec<&Rc<Movement>> = movements.iter()
+        .filter(|mvmt| mvmt.transaction_key > after_txn_num && mvmt.amount < dec!(0))
+        .collect();
+
+    let mut extra_basis_remaining = extra_basis;
+
+    for (idx, mvmt) in later_outgoing_mvmts.iter().enumerate() {
+
+        if held <= dec!(0) { break }
+
+        let share = if lot_is_emptied && idx == later_outgoing_mvmts.len() - 1 {
+            extra_basis_remaining
+        } else {
+            round_d128_generalized(&(extra_basis * -mvmt.amount / held), minor_units)
+        };
+        extra_basis_remaining -= share;
+
+        mvmt.cost_basis_lk.set(mvmt.cost_basis_lk.get() - share);
+
+        let txn = txns_map.get(&mvmt.transaction_key).unwrap();
+
+        if txn.transaction_type(ars, raw_acct_map, acct_map)? == TxType::ToSelf {
+
+            mvmt.proceeds_lk.set(-mvmt.cost_basis_lk.get());
+
+            // The transfer recorded which `lot` of the receiving `account` took this `movement`'s units.
+            let ic_ar = ars.get(&txn.action_record_idx_vec[1]).unwrap();
+            let ic_acct = acct_map.get(&ic_ar.account_key).unwrap();
+            let ic_lot_num = match mvmt.carried_to_lot_num.get() {
+                Some(lot_num) => lot_num,
+                None => return Err(CrptlsError::invalid_txn(txn, "A transferred-out movement has no \
+                    receiving lot.".to_string())),
+            };
+            let ic_lot = ic_acct.list_of_lots.borrow()[ic_lot_num as usize - 1].clone();
+
+            let transferred = Deferral {
+                lot: ic_lot,
+                extra_basis: share,
+                after_txn_num: mvmt.transaction_key,
+                tacked_basis_date,
+            };
+
+            add_basis_to_lot(transferred, minor_units, raw_acct_map, acct_map, ars, txns_map)?;
+        }
+    }
+
+    if tacked_basis_date < lot.date_for_basis_purposes {
+        let acct = acct_map.get(&lot.account_key).unwrap();
+        acct.list_of_lots.borrow_mut()[lot.lot_number as usize - 1] = Rc::new(Lot {
+            date_for_basis_purposes: tacked_basis_date,
+            ..(*lot).clone()
+        });
+    }
+
+    Ok(())
+}
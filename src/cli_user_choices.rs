@@ -131,6 +131,9 @@ pub fn choose_inventory_costing_method(cmd_line_arg: String) -> Result<Inventory
     println!("2. LIFO according to the basis date of the lot.");
     println!("3. FIFO according to the order the lot was created.");
     println!("4. FIFO according to the basis date of the lot.");
+    println!("5. HIFO according to the per-unit cost basis of the lot.");
+    println!("6. LOFO according to the per-unit cost basis of the lot.");
+    println!("7. Tax-optimized: long-term losses first, then short-term losses, then the smallest gains.");
 
     let method = _costing_method(cmd_line_arg)?;
 
@@ -146,6 +149,9 @@ pub fn choose_inventory_costing_method(cmd_line_arg: String) -> Result<Inventory
             "2" => Ok(InventoryCostingMethod::LIFObyLotBasisDate),
             "3" => Ok(InventoryCostingMethod::FIFObyLotCreationDate),
             "4" => Ok(InventoryCostingMethod::FIFObyLotBasisDate),
+            "5" => Ok(InventoryCostingMethod::HIFObyLotUnitBasis),
+            "6" => Ok(InventoryCostingMethod::LOFObyLotUnitBasis),
+            "7" => Ok(InventoryCostingMethod::TaxOptimized),
             _   => { println!("Invalid choice.  Please enter a valid choice."); _costing_method(env_var_arg) }
         }
     }
@@ -159,6 +165,9 @@ pub fn inv_costing_from_cmd_arg(arg: String) -> Result<InventoryCostingMethod, &
         "2" => Ok(InventoryCostingMethod::LIFObyLotBasisDate),
         "3" => Ok(InventoryCostingMethod::FIFObyLotCreationDate),
         "4" => Ok(InventoryCostingMethod::FIFObyLotBasisDate),
+        "5" => Ok(InventoryCostingMethod::HIFObyLotUnitBasis),
+        "6" => Ok(InventoryCostingMethod::LOFObyLotUnitBasis),
+        "7" => Ok(InventoryCostingMethod::TaxOptimized),
         _ => { 
                 println!("WARN: Invalid environment variable for 'INV_COSTING_METHOD'. Using default."); 
                 Ok(InventoryCostingMethod::LIFObyLotCreationDate)
@@ -20,6 +20,32 @@ pub fn export(
 
     println!("Creating all reports now.");
 
+    // The UK matching rules aren't applied to the `movement`s, so every report that takes basis and gains from `lot`s
+    // would show the costing method's results instead.  Margin accounts aren't pooled, so their history is still exported.
+    if settings.uk_share_pooling {
+        println!("  UK share pooling is on, so only the UK share matching and margin position history reports are created.");
+
+        export_csv::_8_uk_share_matching_to_csv(
+            settings,
+            raw_acct_map,
+            account_map,
+            action_records_map,
+            transactions_map
+        )?;
+
+        if raw_acct_map.values().any(|raw_acct| raw_acct.is_margin) {
+            export_csv::_10_margin_position_history_to_csv(
+                settings,
+                raw_acct_map,
+                account_map,
+                action_records_map,
+                transactions_map,
+            )?;
+        }
+
+        return Ok(())
+    }
+
     export_csv::_1_account_sums_to_csv(
         &settings,
         &raw_acct_map,
@@ -72,6 +98,32 @@ pub fn export(
         &transactions_map
     )?;
 
+    if settings.basis_reallocation_date.is_some() {
+        export_csv::_9_basis_reallocation_to_csv(
+            settings,
+            raw_acct_map,
+            account_map,
+        )?;
+    }
+
+    if raw_acct_map.values().any(|raw_acct| raw_acct.is_margin) {
+        export_csv::_10_margin_position_history_to_csv(
+            settings,
+            raw_acct_map,
+            account_map,
+            action_records_map,
+            transactions_map,
+        )?;
+    }
+
+    if settings.price_file.is_some() {
+        export_csv::_11_unrealized_gain_loss_to_csv(
+            settings,
+            raw_acct_map,
+            account_map,
+        )?;
+    }
+
     export_txt::_1_account_lot_detail_to_txt(
         &settings,
         &raw_acct_map,
@@ -2,16 +2,25 @@
 // Redistributions must include the license: https://github.com/scoobybejesus/cryptools/blob/master/LEGAL.txt
 
 use std::fs::File;
-use std::collections::HashMap;
+use std::collections::{HashMap, BTreeMap};
 use std::path::PathBuf;
 use std::error::Error;
 
+use rust_decimal::Decimal;
 use rust_decimal_macros::dec;
 use chrono::NaiveDate;
 
 use crptls::transaction::{ActionRecord, Polarity, Transaction, TxType};
 use crptls::account::{Account, RawAccount, Term};
 use crptls::core_functions::ImportProcessParameters;
+use crptls::average_cost;
+use crptls::uk_share_pooling;
+use crptls::basis_reallocation;
+use crptls::wash_sale;
+use crptls::margin_pnl;
+use crptls::prices;
+
+use crate::export::{TermTotals, home_currency_string};
 
 
 pub fn _1_account_sums_to_csv(
@@ -20,6 +29,9 @@ pub fn _1_account_sums_to_csv(
     acct_map: &HashMap<u16, Account>
 ) {
 
+    let minor_units = settings.home_currency_minor_units;
+    let places = minor_units as usize;
+
     let mut rows: Vec<Vec<String>> = Vec::with_capacity(acct_map.len());
 
     let columns = [
@@ -35,6 +47,17 @@ pub fn _1_account_sums_to_csv(
     let mut header: Vec<String> = Vec::with_capacity(total_columns);
 
     header.extend_from_slice(&columns);
+
+    if let Some((first_date, last_date)) = settings.report_period {
+        header.push(format!("Opening balance {}", first_date));
+        header.push(format!("Opening basis {}", first_date));
+        header.push(format!("Closing balance {}", last_date));
+        header.push(format!("Closing basis {}", last_date));
+    }
+
+    if settings.average_cost_pool.is_some() {
+        header.push("Avg cost/unit".to_string())
+    }
     rows.push(header);
 
     let length = acct_map.len();
@@ -61,8 +84,7 @@ pub fn _1_account_sums_to_csv(
             } else { lk_cost_basis = tentative_lk_cost_basis.to_string() }
         }
 
-        let cb_f32 = lk_cost_basis.as_str().parse::<f32>().unwrap();
-        let cb = format!("{:.2}", cb_f32);
+        let cb = format!("{:.*}", places, lk_cost_basis.parse::<Decimal>().unwrap().round_dp(minor_units));
 
         let nonzero_lots = acct.get_num_of_nonzero_lots();
 
@@ -72,6 +94,12 @@ pub fn _1_account_sums_to_csv(
         row.push(cb);
         row.push(acct.list_of_lots.borrow().len().to_string());
         row.push(nonzero_lots.to_string());
+        if let Some((first_date, last_date)) = settings.report_period {
+            row.extend(get_period_balances_and_bases(acct, raw_acct, first_date, last_date, minor_units));
+        }
+        if let Some(pool) = &settings.average_cost_pool {
+            row.push(average_cost::get_avg_cost_per_unit_string(pool, acct.raw_key, u32::MAX, &settings.home_currency, raw_acct_map, acct_map));
+        }
         rows.push(row);
     }
     let file_name = PathBuf::from("C1_Acct_Sum_with_cost_basis.csv");
@@ -93,6 +121,9 @@ pub fn _2_account_sums_nonzero_to_csv(
     acct_map: &HashMap<u16, Account>,
 ) {
 
+    let minor_units = settings.home_currency_minor_units;
+    let places = minor_units as usize;
+
     let mut rows: Vec<Vec<String>> = Vec::with_capacity(acct_map.len());    //  more than needed...
 
     let columns = [
@@ -108,6 +139,17 @@ pub fn _2_account_sums_nonzero_to_csv(
     let mut header: Vec<String> = Vec::with_capacity(total_columns);
 
     header.extend_from_slice(&columns);
+
+    if let Some((first_date, last_date)) = settings.report_period {
+        header.push(format!("Opening balance {}", first_date));
+        header.push(format!("Opening basis {}", first_date));
+        header.push(format!("Closing balance {}", last_date));
+        header.push(format!("Closing basis {}", last_date));
+    }
+
+    if settings.average_cost_pool.is_some() {
+        header.push("Avg cost/unit".to_string())
+    }
     rows.push(header);
 
     let length = acct_map.len();
@@ -137,8 +179,7 @@ pub fn _2_account_sums_nonzero_to_csv(
             } else { lk_cost_basis = tentative_lk_cost_basis.to_string() }
         }
 
-        let cb_f32 = lk_cost_basis.as_str().parse::<f32>().unwrap();
-        let cb = format!("{:.2}", cb_f32);
+        let cb = format!("{:.*}", places, lk_cost_basis.parse::<Decimal>().unwrap().round_dp(minor_units));
 
         let nonzero_lots = acct.get_num_of_nonzero_lots();
 
@@ -149,6 +190,12 @@ pub fn _2_account_sums_nonzero_to_csv(
             row.push(cb);
             row.push(acct.list_of_lots.borrow().len().to_string());
             row.push(nonzero_lots.to_string());
+            if let Some((first_date, last_date)) = settings.report_period {
+                row.extend(get_period_balances_and_bases(acct, raw_acct, first_date, last_date, minor_units));
+            }
+            if let Some(pool) = &settings.average_cost_pool {
+                row.push(average_cost::get_avg_cost_per_unit_string(pool, acct.raw_key, u32::MAX, &settings.home_currency, raw_acct_map, acct_map));
+            }
             rows.push(row);
         }
     }
@@ -172,6 +219,9 @@ pub fn _3_account_sums_to_csv_with_orig_basis(
     acct_map: &HashMap<u16, Account>
 ) {
 
+    let minor_units = settings.home_currency_minor_units;
+    let places = minor_units as usize;
+
     let mut rows: Vec<Vec<String>> = Vec::with_capacity(acct_map.len());
 
     let columns = [
@@ -188,6 +238,17 @@ pub fn _3_account_sums_to_csv_with_orig_basis(
     let mut header: Vec<String> = Vec::with_capacity(total_columns);
 
     header.extend_from_slice(&columns);
+
+    if let Some((first_date, last_date)) = settings.report_period {
+        header.push(format!("Opening balance {}", first_date));
+        header.push(format!("Opening basis {}", first_date));
+        header.push(format!("Closing balance {}", last_date));
+        header.push(format!("Closing basis {}", last_date));
+    }
+
+    if settings.average_cost_pool.is_some() {
+        header.push("Avg cost/unit".to_string())
+    }
     rows.push(header);
 
     let length = acct_map.len();
@@ -226,11 +287,9 @@ pub fn _3_account_sums_to_csv_with_orig_basis(
             } else { orig_cost_basis = tentative_orig_cost_basis.to_string() }
         }
 
-        let cb_f32 = lk_cost_basis.to_string().as_str().parse::<f32>().unwrap();
-        let cb = format!("{:.2}", cb_f32);
+        let cb = format!("{:.*}", places, lk_cost_basis.parse::<Decimal>().unwrap().round_dp(minor_units));
 
-        let ocb_f32 = orig_cost_basis.as_str().parse::<f32>().unwrap();
-        let ocb = format!("{:.2}", ocb_f32);
+        let ocb = format!("{:.*}", places, orig_cost_basis.parse::<Decimal>().unwrap().round_dp(minor_units));
 
         let nonzero_lots = acct.get_num_of_nonzero_lots();
 
@@ -241,6 +300,12 @@ pub fn _3_account_sums_to_csv_with_orig_basis(
         row.push(cb);
         row.push(acct.list_of_lots.borrow().len().to_string());
         row.push(nonzero_lots.to_string());
+        if let Some((first_date, last_date)) = settings.report_period {
+            row.extend(get_period_balances_and_bases(acct, raw_acct, first_date, last_date, minor_units));
+        }
+        if let Some(pool) = &settings.average_cost_pool {
+            row.push(average_cost::get_avg_cost_per_unit_string(pool, acct.raw_key, u32::MAX, &settings.home_currency, raw_acct_map, acct_map));
+        }
         rows.push(row);
     }
     let file_name = PathBuf::from("C3_Acct_Sum_with_orig_and_lk_cost_basis.csv");
@@ -256,6 +321,27 @@ pub fn _3_account_sums_to_csv_with_orig_basis(
     wtr.flush().expect("Could not flush Writer, though file should exist and be complete");
 }
 
+/// The `account`'s balance and cost basis at the start of `first_date` and at the end of `last_date`, for the
+/// account sums reports' period columns.
+fn get_period_balances_and_bases(
+    acct: &Account,
+    raw_acct: &RawAccount,
+    first_date: NaiveDate,
+    last_date: NaiveDate,
+    minor_units: u32,
+) -> Vec<String> {
+
+    let places = minor_units as usize;
+    let period = acct.get_period_balances(raw_acct, first_date, last_date);
+
+    vec![
+        period.opening_balance.to_string(),
+        format!("{:.*}", places, period.opening_basis.round_dp(minor_units)),
+        period.closing_balance.to_string(),
+        format!("{:.*}", places, period.closing_basis.round_dp(minor_units)),
+    ]
+}
+
 pub fn _4_transaction_mvmt_detail_to_csv(
     settings: &ImportProcessParameters,
     raw_acct_map: &HashMap<u16, RawAccount>,
@@ -264,6 +350,8 @@ pub fn _4_transaction_mvmt_detail_to_csv(
     txns_map: &HashMap<u32, Transaction>,
 ) -> Result<(), Box<dyn Error>> {
 
+    let minor_units = settings.home_currency_minor_units;
+
     let mut rows: Vec<Vec<String>> = [].to_vec();
 
     let columns = [
@@ -285,6 +373,10 @@ pub fn _4_transaction_mvmt_detail_to_csv(
 
     let mut header: Vec<String> = Vec::with_capacity(total_columns);
     header.extend_from_slice(&columns);
+
+    if settings.average_cost_pool.is_some() {
+        header.push("Avg cost/unit".to_string())
+    }
     rows.push(header);
 
     let length = txns_map.len();
@@ -294,13 +386,18 @@ pub fn _4_transaction_mvmt_detail_to_csv(
         let txn_num = txn_num as u32;
         let txn = txns_map.get(&(txn_num)).unwrap();
 
-        let flow_or_outgoing_exchange_movements = txn.get_outgoing_exchange_and_flow_mvmts(
+        if !settings.is_in_report_period(txn.date) { continue }
+
+
+        let mut flow_or_outgoing_exchange_movements = txn.get_outgoing_exchange_and_flow_mvmts(
             &settings.home_currency,
+            settings.home_currency_minor_units,
             ars,
             raw_acct_map,
             acct_map,
             txns_map
         )?;
+        flow_or_outgoing_exchange_movements.extend(txn.get_fee_leg_mvmts(&settings.home_currency, ars, raw_acct_map, acct_map, txns_map));
 
         for mvmt in flow_or_outgoing_exchange_movements.iter() {
             let lot = mvmt.get_lot(acct_map, ars);
@@ -315,11 +412,11 @@ pub fn _4_transaction_mvmt_detail_to_csv(
             let mut amount = dec!(0);
             amount += mvmt.amount;   //  To prevent printing -5E+1 instead of 50, for example
             let ticker = raw_acct.ticker.to_string();
-            let term = mvmt.get_term(acct_map, ars, txns_map).to_string();
+            let term = mvmt.get_term(&settings.holding_period_rule, settings.as_of_date, acct_map, ars, txns_map).to_string();
             let mut proceeds_lk = mvmt.proceeds_lk.get();
             let mut cost_basis_lk = mvmt.cost_basis_lk.get();
             let mut gain_loss = mvmt.get_lk_gain_or_loss();
-            let income = mvmt.get_income(ars, &raw_acct_map, &acct_map, &txns_map)?;
+            let income = mvmt.get_income(ars, &raw_acct_map, &acct_map, &txns_map, settings.home_currency_minor_units)?;
             let expense = mvmt.get_expense(ars, &raw_acct_map, &acct_map, &txns_map)?;
 
 
@@ -338,11 +435,14 @@ pub fn _4_transaction_mvmt_detail_to_csv(
             row.push(amount.to_string());
             row.push(ticker);
             row.push(term);
-            row.push(proceeds_lk.to_string());
-            row.push(cost_basis_lk.to_string());
-            row.push(gain_loss.to_string());
-            row.push(income.to_string());
-            row.push(expense.to_string());
+            row.push(home_currency_string(proceeds_lk, minor_units));
+            row.push(home_currency_string(cost_basis_lk, minor_units));
+            row.push(home_currency_string(gain_loss, minor_units));
+            row.push(home_currency_string(income, minor_units));
+            row.push(home_currency_string(expense, minor_units));
+            if let Some(pool) = &settings.average_cost_pool {
+                row.push(average_cost::get_avg_cost_per_unit_string(pool, acct.raw_key, txn_num, &settings.home_currency, raw_acct_map, acct_map));
+            }
             rows.push(row);
         }
     }
@@ -370,6 +470,8 @@ pub fn _5_transaction_mvmt_summaries_to_csv(
     txns_map: &HashMap<u32, Transaction>,
 ) -> Result<(), Box<dyn Error>> {
 
+    let minor_units = settings.home_currency_minor_units;
+
     let mut rows: Vec<Vec<String>> = [].to_vec();
 
     let columns = [
@@ -391,6 +493,10 @@ pub fn _5_transaction_mvmt_summaries_to_csv(
     let mut header: Vec<String> = Vec::with_capacity(total_columns);
 
     header.extend_from_slice(&columns);
+
+    if settings.average_cost_pool.is_some() {
+        header.push("Avg cost/unit".to_string())
+    }
     rows.push(header);
 
     let length = txns_map.len();
@@ -401,132 +507,97 @@ pub fn _5_transaction_mvmt_summaries_to_csv(
 
         let txn_num = txn_num as u32;
         let txn = txns_map.get(&(txn_num)).unwrap();
+
+        if !settings.is_in_report_period(txn.date) { continue }
+
         let txn_date_string = txn.date.to_string();
         let tx_num_string = txn.tx_number.to_string();
         let tx_type = txn.transaction_type(ars, &raw_acct_map, &acct_map)?;
         let tx_memo_string = txn.user_memo.to_string();
-        let mut term_st: Option<Term> = None;
-        let mut term_lt: Option<Term> = None;
-        let mut ticker: Option<String> = None;
-        let mut polarity: Option<Polarity> = None;
-
-        let mut amount_st = dec!(0);
-        let mut proceeds_st = dec!(0);
-        let mut cost_basis_st = dec!(0);
-
-        let mut income_st = dec!(0);
-        let mut expense_st = dec!(0);
-
-        let mut amount_lt = dec!(0);
-        let mut proceeds_lt = dec!(0);
-        let mut cost_basis_lt = dec!(0);
-
-        let mut income_lt = dec!(0);
-        let mut expense_lt = dec!(0);
-
         let flow_or_outgoing_exchange_movements = txn.get_outgoing_exchange_and_flow_mvmts(
             &settings.home_currency,
+            settings.home_currency_minor_units,
             ars,
             raw_acct_map,
             acct_map,
             txns_map
         )?;
 
-        let mut count = 0;
-        for mvmt in flow_or_outgoing_exchange_movements.iter() {
-            let lot = mvmt.get_lot(acct_map, ars);
-            let acct = acct_map.get(&lot.account_key).unwrap();
-            let raw_acct = raw_acct_map.get(&acct.raw_key).unwrap();
+        let fee_leg_mvmts = txn.get_fee_leg_mvmts(&settings.home_currency, ars, raw_acct_map, acct_map, txns_map);
 
-            if count == 0 { tx_type_string = mvmt.friendly_tx_type(&tx_type) };
-            count += 1;
+        // A fee leg is a disposal of a different currency, so it gets its own rows.
+        for movements in [flow_or_outgoing_exchange_movements, fee_leg_mvmts] {
 
-            if ticker.is_none() { ticker = Some(raw_acct.ticker.clone()) };
+            let mut by_term: BTreeMap<Term, TermTotals> = BTreeMap::new();
+            let mut ticker: Option<String> = None;
+            let mut acct_key: Option<u16> = None;
+            let mut polarity: Option<Polarity> = None;
 
-            if polarity.is_none() {
-                polarity = if mvmt.amount > dec!(0) {
-                    Some(Polarity::Incoming)
-                    } else { Some(Polarity::Outgoing)
-                };
-            }
+            let mut count = 0;
+            for mvmt in movements.iter() {
+                let lot = mvmt.get_lot(acct_map, ars);
+                let acct = acct_map.get(&lot.account_key).unwrap();
+                let raw_acct = raw_acct_map.get(&acct.raw_key).unwrap();
 
-            let term = mvmt.get_term(acct_map, ars, txns_map);
-
-            if term == Term::LT {
-                amount_lt += mvmt.amount;
-                proceeds_lt += mvmt.proceeds_lk.get();
-                cost_basis_lt += mvmt.cost_basis_lk.get();
-                if term_lt.is_none() { term_lt = Some(term) }
-            } else {
-                assert_eq!(term, Term::ST);
-                amount_st += mvmt.amount;
-                proceeds_st += mvmt.proceeds_lk.get();
-                cost_basis_st += mvmt.cost_basis_lk.get();
-                if term_st.is_none() {
-                    term_st = Some(term);
-                }
-            }
-        }
+                if count == 0 { tx_type_string = mvmt.friendly_tx_type(&tx_type) };
+                count += 1;
 
-        if (txn.transaction_type(
-            ars,
-            &raw_acct_map,
-            &acct_map)? == TxType::Flow
-        ) & (polarity == Some(Polarity::Incoming)) {
-            income_st = -proceeds_st;   //  Proceeds are negative for incoming txns
-            proceeds_st = dec!(0);
-            cost_basis_st = dec!(0);
-            income_lt = -proceeds_lt;   //  Proceeds are negative for incoming txns
-            proceeds_lt = dec!(0);
-            cost_basis_lt = dec!(0);
-        }
+                if ticker.is_none() { ticker = Some(raw_acct.ticker.clone()) };
+                if acct_key.is_none() { acct_key = Some(acct.raw_key) };
 
-        if (txn.transaction_type(
-            ars,
-            &raw_acct_map,
-            &acct_map)? == TxType::Flow
-        ) & (polarity == Some(Polarity::Outgoing)) {
-            expense_st -= proceeds_st;
-            expense_lt -= proceeds_lt;
-        }
-
-        if let Some(term) = term_st {
-
-            let mut row: Vec<String> = Vec::with_capacity(total_columns);
+                if polarity.is_none() {
+                    polarity = if mvmt.amount > dec!(0) {
+                        Some(Polarity::Incoming)
+                        } else { Some(Polarity::Outgoing)
+                    };
+                }
 
-            row.push(txn_date_string.clone());
-            row.push(tx_num_string.clone());
-            row.push(tx_type_string.clone());
-            row.push(tx_memo_string.clone());
-            row.push(amount_st.to_string());
-            row.push(ticker.clone().unwrap());
-            row.push(term.abbr_string());
-            row.push(proceeds_st.to_string());
-            row.push(cost_basis_st.to_string());
-            row.push((proceeds_st + cost_basis_st).to_string());
-            row.push(income_st.to_string());
-            row.push(expense_st.to_string());
+                let term = mvmt.get_term(&settings.holding_period_rule, settings.as_of_date, acct_map, ars, txns_map);
+                let totals = by_term.entry(term).or_default();
 
-            rows.push(row);
-        }
-        if let Some(term) = term_lt {
+                totals.amount += mvmt.amount;
+                totals.proceeds += mvmt.proceeds_lk.get();
+                totals.cost_basis += mvmt.cost_basis_lk.get();
+                totals.wash_sale_adj += mvmt.wash_sale_adjustment.get();
+                totals.income += mvmt.get_income(ars, raw_acct_map, acct_map, txns_map, settings.home_currency_minor_units)?;
+                totals.expense += mvmt.get_expense(ars, raw_acct_map, acct_map, txns_map)?;
+            }
 
-            let mut row: Vec<String> = Vec::with_capacity(total_columns);
+            if (txn.transaction_type(
+                ars,
+                &raw_acct_map,
+                &acct_map)? == TxType::Flow
+            ) & (polarity == Some(Polarity::Incoming)) {
+                for totals in by_term.values_mut() {
+                    totals.proceeds = dec!(0);
+                    totals.cost_basis = dec!(0);
+                    totals.wash_sale_adj = dec!(0);
+                }
+            }
 
-            row.push(txn_date_string);
-            row.push(tx_num_string);
-            row.push(tx_type_string.clone());
-            row.push(tx_memo_string);
-            row.push(amount_lt.to_string());
-            row.push(ticker.unwrap());
-            row.push(term.abbr_string());
-            row.push(proceeds_lt.to_string());
-            row.push(cost_basis_lt.to_string());
-            row.push((proceeds_lt + cost_basis_lt).to_string());
-            row.push(income_lt.to_string());
-            row.push(expense_lt.to_string());
+            // Shortest term first
+            for (term, totals) in by_term.iter() {
+
+                let mut row: Vec<String> = Vec::with_capacity(total_columns);
+
+                row.push(txn_date_string.clone());
+                row.push(tx_num_string.clone());
+                row.push(tx_type_string.clone());
+                row.push(tx_memo_string.clone());
+                row.push(totals.amount.to_string());
+                row.push(ticker.clone().unwrap());
+                row.push(term.abbr_string());
+                row.push(home_currency_string(totals.proceeds, minor_units));
+                row.push(home_currency_string(totals.cost_basis, minor_units));
+                row.push(home_currency_string(totals.gain_loss(), minor_units));
+                row.push(home_currency_string(totals.income, minor_units));
+                row.push(home_currency_string(totals.expense, minor_units));
+                if let Some(pool) = &settings.average_cost_pool {
+                    row.push(average_cost::get_avg_cost_per_unit_string(pool, acct_key.unwrap(), txn_num, &settings.home_currency, raw_acct_map, acct_map));
+                }
 
-            rows.push(row);
+                rows.push(row);
+            }
         }
     }
 
@@ -553,6 +624,8 @@ pub fn _6_transaction_mvmt_detail_to_csv_w_orig(
     txns_map: &HashMap<u32, Transaction>,
 ) -> Result<(), Box<dyn Error>> {
 
+    let minor_units = settings.home_currency_minor_units;
+
     let mut rows: Vec<Vec<String>> = [].to_vec();
 
     let lk = settings.lk_treatment_enabled;
@@ -589,6 +662,10 @@ pub fn _6_transaction_mvmt_detail_to_csv_w_orig(
 
     header.extend_from_slice(&columns);
 
+    if settings.average_cost_pool.is_some() {
+        header.push("Avg cost/unit".to_string())
+    }
+
     if lk {
         header.extend_from_slice(&lk_columns)
     }
@@ -601,13 +678,18 @@ pub fn _6_transaction_mvmt_detail_to_csv_w_orig(
         let txn_num = txn_num as u32;
         let txn = txns_map.get(&(txn_num)).unwrap();
 
-        let flow_or_outgoing_exchange_movements = txn.get_outgoing_exchange_and_flow_mvmts(
+        if !settings.is_in_report_period(txn.date) { continue }
+
+
+        let mut flow_or_outgoing_exchange_movements = txn.get_outgoing_exchange_and_flow_mvmts(
             &settings.home_currency,
+            settings.home_currency_minor_units,
             ars,
             raw_acct_map,
             acct_map,
             txns_map
         )?;
+        flow_or_outgoing_exchange_movements.extend(txn.get_fee_leg_mvmts(&settings.home_currency, ars, raw_acct_map, acct_map, txns_map));
 
         for mvmt in flow_or_outgoing_exchange_movements.iter() {
             let lot = mvmt.get_lot(acct_map, ars);
@@ -619,15 +701,15 @@ pub fn _6_transaction_mvmt_detail_to_csv_w_orig(
             let tx_type = txn.transaction_type(&ars, &raw_acct_map, &acct_map)?;
             let tx_type_string = mvmt.friendly_tx_type(&tx_type);
             let user_memo = txn.user_memo.to_string();
-            let auto_memo = txn.get_auto_memo(ars, raw_acct_map,acct_map, &settings.home_currency)?;
+            let auto_memo = txn.get_auto_memo(ars, raw_acct_map,acct_map, &settings.home_currency, settings.home_currency_minor_units)?;
             let mut amount = dec!(0);
             amount += mvmt.amount;   //  To prevent printing -5E+1 instead of 50, for example
             let ticker = raw_acct.ticker.to_string();
-            let term = mvmt.get_term(acct_map, ars, txns_map).to_string();
+            let term = mvmt.get_term(&settings.holding_period_rule, settings.as_of_date, acct_map, ars, txns_map).to_string();
             let mut proceeds_lk = mvmt.proceeds_lk.get();
             let mut cost_basis_lk = mvmt.cost_basis_lk.get();
             let mut gain_loss = mvmt.get_lk_gain_or_loss();
-            let income = mvmt.get_income(ars, &raw_acct_map, &acct_map, &txns_map)?;
+            let income = mvmt.get_income(ars, &raw_acct_map, &acct_map, &txns_map, settings.home_currency_minor_units)?;
             let expense = mvmt.get_expense(ars, &raw_acct_map, &acct_map, &txns_map)?;
             let mut orig_proc = mvmt.proceeds.get();
             let mut orig_cost = mvmt.cost_basis.get();
@@ -652,15 +734,18 @@ pub fn _6_transaction_mvmt_detail_to_csv_w_orig(
             row.push(amount.to_string());
             row.push(ticker);
             row.push(term);
-            row.push(proceeds_lk.to_string());
-            row.push(cost_basis_lk.to_string());
-            row.push(gain_loss.to_string());
-            row.push(income.to_string());
-            row.push(expense.to_string());
+            row.push(home_currency_string(proceeds_lk, minor_units));
+            row.push(home_currency_string(cost_basis_lk, minor_units));
+            row.push(home_currency_string(gain_loss, minor_units));
+            row.push(home_currency_string(income, minor_units));
+            row.push(home_currency_string(expense, minor_units));
+            if let Some(pool) = &settings.average_cost_pool {
+                row.push(average_cost::get_avg_cost_per_unit_string(pool, acct.raw_key, txn_num, &settings.home_currency, raw_acct_map, acct_map));
+            }
             if lk {
-                row.push(orig_proc.to_string());
-                row.push(orig_cost.to_string());
-                row.push(orig_gain_loss.to_string());
+                row.push(home_currency_string(orig_proc, minor_units));
+                row.push(home_currency_string(orig_cost, minor_units));
+                row.push(home_currency_string(orig_gain_loss, minor_units));
             }
             rows.push(row);
         }
@@ -689,6 +774,8 @@ pub fn _7_gain_loss_8949_to_csv(
     txns_map: &HashMap<u32, Transaction>,
 ) -> Result<(), Box<dyn Error>> {
 
+    let minor_units = settings.home_currency_minor_units;
+
     let mut rows: Vec<Vec<String>> = [].to_vec();
 
     let columns = [
@@ -706,6 +793,16 @@ pub fn _7_gain_loss_8949_to_csv(
     let total_columns = columns.len();
     let mut header: Vec<String> = Vec::with_capacity(total_columns);
     header.extend_from_slice(&columns);
+
+    // Form 8949 columns (f) and (g) go just before the gain or loss.
+    if settings.wash_sale_rule {
+        header.insert(total_columns - 1, "Adjustment code".to_string());
+        header.insert(total_columns, "Adjustment".to_string());
+    }
+
+    if settings.average_cost_pool.is_some() {
+        header.push("Avg cost/unit".to_string())
+    }
     rows.push(header);
 
     let length = txns_map.len();
@@ -714,148 +811,521 @@ pub fn _7_gain_loss_8949_to_csv(
 
         let txn_num = txn_num as u32;
         let txn = txns_map.get(&(txn_num)).unwrap();
-        let txn_date_string = txn.date.to_string();
-        let tx_num_string = txn.tx_number.to_string();
-        let tx_memo_string = txn.get_auto_memo(ars,raw_acct_map,acct_map, &settings.home_currency)?;
-
-        let mut term_st: Option<Term> = None;
-        let mut term_lt: Option<Term> = None;
-        let mut ticker: Option<String> = None;
-        let mut polarity: Option<Polarity> = None;
 
-        let mut amount_st = dec!(0);
-        let mut proceeds_st = dec!(0);
-        let mut cost_basis_st = dec!(0);
+        if !settings.is_in_report_period(txn.date) { continue }
 
-        let mut expense_st = dec!(0);
-
-        let mut amount_lt = dec!(0);
-        let mut proceeds_lt = dec!(0);
-        let mut cost_basis_lt = dec!(0);
-
-        let mut expense_lt = dec!(0);
+        let txn_date_string = txn.date.to_string();
+        let tx_num_string = txn.tx_number.to_string();
+        let tx_memo_string = txn.get_auto_memo(ars,raw_acct_map,acct_map, &settings.home_currency, settings.home_currency_minor_units)?;
 
-        let flow_or_outgoing_exchange_movements = txn.get_outgoing_exchange_and_flow_mvmts(
+        // Home currency spent is only listed for a purchase's expensed fee, which isn't a disposal.
+        let flow_or_outgoing_exchange_movements: Vec<_> = txn.get_outgoing_exchange_and_flow_mvmts(
             &settings.home_currency,
+            settings.home_currency_minor_units,
             ars,
             raw_acct_map,
             acct_map,
             txns_map
-        )?;
+        )?
+            .into_iter()
+            .filter(|mvmt| {
+                let raw_acct = raw_acct_map.get(&mvmt.get_lot(acct_map, ars).account_key).unwrap();
+                !raw_acct.is_home_currency(&settings.home_currency)
+            })
+            .collect();
+
+        let fee_leg_mvmts = txn.get_fee_leg_mvmts(&settings.home_currency, ars, raw_acct_map, acct_map, txns_map);
+        let fee_leg_memo = txn.get_fee_leg_memo(ars, raw_acct_map, acct_map, &settings.home_currency, settings.home_currency_minor_units).unwrap_or_default();
+
+        // A fee leg is a disposal of a different currency, so it gets its own rows.
+        for (movements, tx_memo_string) in [(flow_or_outgoing_exchange_movements, tx_memo_string), (fee_leg_mvmts, fee_leg_memo)] {
+
+            let mut by_term: BTreeMap<Term, TermTotals> = BTreeMap::new();
+            let mut ticker: Option<String> = None;
+            let mut acct_key: Option<u16> = None;
+            let mut polarity: Option<Polarity> = None;
+
+            for mvmt in movements.iter() {
+                let lot = mvmt.get_lot(acct_map, ars);
+                let acct = acct_map.get(&lot.account_key).unwrap();
+                let raw_acct = raw_acct_map.get(&acct.raw_key).unwrap();
+
+                if ticker.is_none() { ticker = Some(raw_acct.ticker.clone()) };
+                if acct_key.is_none() { acct_key = Some(acct.raw_key) };
+
+                if polarity.is_none() {
+                    polarity = if mvmt.amount > dec!(0) {
+                        Some(Polarity::Incoming)
+                        } else { Some(Polarity::Outgoing)
+                    };
+                }
 
-        let mut purchase_date_lt: NaiveDate = NaiveDate::parse_from_str("1-1-1", "%y-%m-%d").unwrap();
-        let mut purchase_date_st: NaiveDate = NaiveDate::parse_from_str("1-1-1", "%y-%m-%d").unwrap();
-        let mut various_dates_lt: bool = false;
-        let mut various_dates_st: bool = false;
-        let mut lt_set = false;
-        let mut st_set = false;
-        for mvmt in flow_or_outgoing_exchange_movements.iter() {
-            let lot = mvmt.get_lot(acct_map, ars);
-            let acct = acct_map.get(&lot.account_key).unwrap();
-            let raw_acct = raw_acct_map.get(&acct.raw_key).unwrap();
+                let term = mvmt.get_term(&settings.holding_period_rule, settings.as_of_date, acct_map, ars, txns_map);
+                let totals = by_term.entry(term).or_default();
 
-            if ticker.is_none() { ticker = Some(raw_acct.ticker.clone()) };
+                let purchase_date = *totals.purchase_date.get_or_insert(lot.date_for_basis_purposes);
+                totals.various_dates = purchase_date != lot.date_for_basis_purposes;
 
-            if polarity.is_none() {
-                polarity = if mvmt.amount > dec!(0) {
-                    Some(Polarity::Incoming)
-                    } else { Some(Polarity::Outgoing)
-                };
+                totals.amount += mvmt.amount;
+                totals.proceeds += mvmt.proceeds_lk.get();
+                totals.cost_basis += mvmt.cost_basis_lk.get();
+                totals.wash_sale_adj += mvmt.wash_sale_adjustment.get();
             }
 
-            fn dates_are_different(existing: &NaiveDate, current: &NaiveDate) -> bool {
-                if existing != current {true} else {false}
+            if (txn.transaction_type(
+                ars,
+                &raw_acct_map,
+                &acct_map)? == TxType::Flow
+            ) & (polarity == Some(Polarity::Incoming)) {
+                // The only incoming flow transaction to report would be margin profit, which is a dual-`action record` `transaction`,
+                // unless that profit was already reported as it was realized
+                if txn.action_record_idx_vec.len() == 2 && !txn.settles_realized_margin_pnl {
+                    for totals in by_term.values_mut() {
+                        totals.proceeds = -totals.proceeds;   //  Proceeds are negative for incoming txns
+                        totals.cost_basis = dec!(0);
+                        totals.wash_sale_adj = dec!(0);
+                    }
+                } else {
+                    continue    // Plain, old income isn't reported on form 8949
+                }
             }
 
-            let term = mvmt.get_term(acct_map, ars, txns_map);
+            // Shortest term first
+            for (term, totals) in by_term.iter() {
 
-            if term == Term::LT {
-                if lt_set {} else { purchase_date_lt = lot.date_for_basis_purposes; lt_set = true }
-                various_dates_lt = dates_are_different(&purchase_date_lt, &lot.date_for_basis_purposes);
+                let purchase_date = if totals.various_dates {
+                    "Various".to_string()
+                } else {
+                    totals.purchase_date.unwrap().to_string()
+                };
 
-                amount_lt += mvmt.amount;
-                proceeds_lt += mvmt.proceeds_lk.get();
-                cost_basis_lt += mvmt.cost_basis_lk.get();
+                let mut row: Vec<String> = Vec::with_capacity(total_columns);
+
+                row.push(term.abbr_string());
+                row.push(tx_num_string.clone());
+                row.push(tx_memo_string.clone());
+                row.push(totals.amount.to_string());
+                row.push(purchase_date);
+                row.push(txn_date_string.clone());
+                row.push(home_currency_string(totals.proceeds, minor_units));
+                row.push(home_currency_string(totals.cost_basis, minor_units));
+                if settings.wash_sale_rule {
+                    row.push(wash_sale_code(totals.wash_sale_adj));
+                    row.push(home_currency_string(totals.wash_sale_adj, minor_units));
+                }
+                row.push(home_currency_string(totals.gain_loss(), minor_units));
+                if let Some(pool) = &settings.average_cost_pool {
+                    row.push(average_cost::get_avg_cost_per_unit_string(pool, acct_key.unwrap(), txn_num, &settings.home_currency, raw_acct_map, acct_map));
+                }
 
-                if term_lt.is_none() { term_lt = Some(term) }
+                rows.push(row);
+            }
+        }
+    }
 
-            } else {
-                if st_set {} else { purchase_date_st = lot.date_for_basis_purposes; st_set = true}
-                various_dates_st = dates_are_different(&purchase_date_st, &lot.date_for_basis_purposes);
+    let file_name = PathBuf::from("C7_Form_8949.csv");
+    let path = PathBuf::from(&settings.export_path);
 
-                assert_eq!(term, Term::ST);
-                amount_st += mvmt.amount;
-                proceeds_st += mvmt.proceeds_lk.get();
-                cost_basis_st += mvmt.cost_basis_lk.get();
+    let full_path: PathBuf = [path, file_name].iter().collect();
+    let buffer = File::create(full_path).unwrap();
+    let mut wtr = csv::Writer::from_writer(buffer);
 
-                if term_st.is_none() {
-                    term_st = Some(term);
-                }
-            }
-        }
-        let lt_purchase_date = if various_dates_lt { "Various".to_string() } else { purchase_date_lt.to_string() };
-        let st_purchase_date = if various_dates_st { "Various".to_string() } else { purchase_date_st.to_string() };
+    for row in rows.iter() {
+        wtr.write_record(row).expect("Could not write row to CSV file");
+    }
+    wtr.flush().expect("Could not flush Writer, though file should exist and be complete");
 
-        if (txn.transaction_type(
-            ars,
-            &raw_acct_map,
-            &acct_map)? == TxType::Flow
-        ) & (polarity == Some(Polarity::Incoming)) {
-            // The only incoming flow transaction to report would be margin profit, which is a dual-`action record` `transaction`
-            if txn.action_record_idx_vec.len() == 2 {
-                proceeds_st = -proceeds_st;   //  Proceeds are negative for incoming txns
-                cost_basis_st = dec!(0);
-                proceeds_lt = -proceeds_lt;   //  Proceeds are negative for incoming txns
-                cost_basis_lt = dec!(0);
-            } else {
-                continue    // Plain, old income isn't reported on form 8949
-            }
+    Ok(())
+}
+
+fn wash_sale_code(adjustment: Decimal) -> String {
+    if adjustment == dec!(0) { "".to_string() } else { wash_sale::WASH_SALE_ADJUSTMENT_CODE.to_string() }
+}
+
+pub fn _8_uk_share_matching_to_csv(
+    settings: &ImportProcessParameters,
+    raw_acct_map: &HashMap<u16, RawAccount>,
+    acct_map: &HashMap<u16, Account>,
+    ars: &HashMap<u32, ActionRecord>,
+    txns_map: &HashMap<u32, Transaction>,
+) -> Result<(), Box<dyn Error>> {
+
+    let minor_units = settings.home_currency_minor_units;
+
+    let mut rows: Vec<Vec<String>> = [].to_vec();
+
+    let columns = [
+        "Date".to_string(),
+        "Txn#".to_string(),
+        "Ticker".to_string(),
+        "Rule".to_string(),
+        "Amount".to_string(),
+        "Acquisition date".to_string(),  // blank for Section 104 pool
+        "Proceeds".to_string(),
+        "Allowable cost".to_string(),
+        "Gain/loss".to_string(),
+    ];
+
+    let total_columns = columns.len();
+    let mut header: Vec<String> = Vec::with_capacity(total_columns);
+    header.extend_from_slice(&columns);
+    rows.push(header);
+
+    let uk_matches = uk_share_pooling::get_uk_share_matches(
+        &settings.home_currency,
+        settings.home_currency_minor_units,
+        raw_acct_map,
+        acct_map,
+        ars,
+        txns_map,
+    )?;
+
+    for uk_match in uk_matches.iter() {
+
+        let acquisition_date = match uk_match.acquisition_date {
+            Some(date) => date.to_string(),
+            None => "".to_string(),
+        };
+
+        let mut row: Vec<String> = Vec::with_capacity(total_columns);
+
+        row.push(uk_match.date.to_string());
+        row.push(uk_match.txn_num.to_string());
+        row.push(uk_match.ticker.clone());
+        row.push(uk_match.rule.to_string());
+        row.push(uk_match.amount.to_string());
+        row.push(acquisition_date);
+        row.push(home_currency_string(uk_match.proceeds, minor_units));
+        row.push(home_currency_string(uk_match.cost, minor_units));
+        row.push(home_currency_string(uk_match.get_gain_or_loss(), minor_units));
+        rows.push(row);
+    }
+
+    let file_name = PathBuf::from("C8_UK_share_matching.csv");
+    let path = PathBuf::from(&settings.export_path);
+
+    let full_path: PathBuf = [path, file_name].iter().collect();
+    let buffer = File::create(full_path).unwrap();
+    let mut wtr = csv::Writer::from_writer(buffer);
+
+    for row in rows.iter() {
+        wtr.write_record(row).expect("Could not write row to CSV file");
+    }
+    wtr.flush().expect("Could not flush Writer, though file should exist and be complete");
+
+    Ok(())
+}
+
+pub fn _9_basis_reallocation_to_csv(
+    settings: &ImportProcessParameters,
+    raw_acct_map: &HashMap<u16, RawAccount>,
+    acct_map: &HashMap<u16, Account>,
+) -> Result<(), Box<dyn Error>> {
+
+    let date = match settings.basis_reallocation_date {
+        Some(date) => date,
+        None => return Err("No basis reallocation date was set.".into()),
+    };
+
+    let minor_units = settings.home_currency_minor_units;
+
+    let mut rows: Vec<Vec<String>> = [].to_vec();
+
+    let columns = [
+        "Reallocation date".to_string(),
+        "Ticker".to_string(),
+        "Account#".to_string(),
+        "Account".to_string(),
+        "Lot".to_string(),
+        "Status".to_string(),  //  Closed or Opened
+        "Basis date".to_string(),
+        "Amount".to_string(),
+        "Cost basis".to_string(),
+    ];
+
+    let total_columns = columns.len();
+    let mut header: Vec<String> = Vec::with_capacity(total_columns);
+    header.extend_from_slice(&columns);
+    rows.push(header);
+
+    let reallocated_lots = basis_reallocation::get_reallocated_lots(
+        &settings.home_currency,
+        raw_acct_map,
+        acct_map,
+    );
+
+    for reallocated_lot in reallocated_lots.iter() {
+
+        let raw_acct = raw_acct_map.get(&reallocated_lot.account_num).unwrap();
+        let status = if reallocated_lot.closed { "Closed" } else { "Opened" };
+
+        let mut row: Vec<String> = Vec::with_capacity(total_columns);
+
+        row.push(date.to_string());
+        row.push(reallocated_lot.ticker.clone());
+        row.push(reallocated_lot.account_num.to_string());
+        row.push(raw_acct.name.clone());
+        row.push(reallocated_lot.lot_num.to_string());
+        row.push(status.to_string());
+        row.push(reallocated_lot.basis_date.to_string());
+        row.push(reallocated_lot.amount.to_string());
+        row.push(home_currency_string(reallocated_lot.basis, minor_units));
+        rows.push(row);
+    }
+
+    let file_name = PathBuf::from("C9_Basis_reallocation.csv");
+    let path = PathBuf::from(&settings.export_path);
+
+    let full_path: PathBuf = [path, file_name].iter().collect();
+    let buffer = File::create(full_path).unwrap();
+    let mut wtr = csv::Writer::from_writer(buffer);
+
+    for row in rows.iter() {
+        wtr.write_record(row).expect("Could not write row to CSV file");
+    }
+    wtr.flush().expect("Could not flush Writer, though file should exist and be complete");
+
+    Ok(())
+}
+
+pub fn _10_margin_position_history_to_csv(
+    settings: &ImportProcessParameters,
+    raw_acct_map: &HashMap<u16, RawAccount>,
+    acct_map: &HashMap<u16, Account>,
+    ars: &HashMap<u32, ActionRecord>,
+    txns_map: &HashMap<u32, Transaction>,
+) -> Result<(), Box<dyn Error>> {
+
+    let mut rows: Vec<Vec<String>> = [].to_vec();
+
+    let columns = [
+        "Base account".to_string(),
+        "Quote account".to_string(),
+        "Lot".to_string(),
+        "Open date".to_string(),
+        "Trades".to_string(),
+        "Close date".to_string(),   //  blank while the position is open
+        "Settlement dates".to_string(),
+        "P&L (quote)".to_string(),
+        "P&L settled".to_string(),
+    ];
+
+    let total_columns = columns.len();
+    let mut header: Vec<String> = Vec::with_capacity(total_columns);
+    header.extend_from_slice(&columns);
+
+    let histories = margin_pnl::get_margin_lot_pair_histories(
+        raw_acct_map,
+        acct_map,
+        ars,
+        txns_map,
+    )?;
+
+    // Margin interest and funding are expensed when paid, apart from the P&L.
+    let has_charges = histories.iter().any(|history| history.charges != dec!(0));
+
+    if settings.realize_margin_pnl {
+        header.push("P&L realized by trades".to_string())
+    }
+    if has_charges {
+        header.push("Interest and funding".to_string())
+    }
+    header.push("Open position (base)".to_string());
+    header.push("Open position (quote)".to_string());
+    rows.push(header);
+
+    for history in histories.iter() {
+
+        let raw_base_acct = raw_acct_map.get(&history.base_acct_key).unwrap();
+        let raw_quote_acct = raw_acct_map.get(&history.quote_acct_key).unwrap();
+
+        let trades: Vec<String> = history.steps.iter().map(|step| {
+            format!("{}: {} {} {} for {} {}",
+                step.date,
+                if step.base_amount > dec!(0) { "bought" } else { "sold" },
+                step.base_amount.abs(),
+                raw_base_acct.ticker,
+                step.quote_amount.abs(),
+                raw_quote_acct.ticker,
+            )
+        }).collect();
+
+        let close_date = match history.close_date {
+            Some(date) => date.to_string(),
+            None => "".to_string(),
+        };
+
+        let settlement_dates: Vec<String> = history.settlements.iter()
+            .map(|settlement| settlement.date.to_string())
+            .collect();
+
+        let mut row: Vec<String> = Vec::with_capacity(total_columns);
+
+        row.push(format!("{} {}", raw_base_acct.name, raw_base_acct.ticker));
+        row.push(format!("{} {}", raw_quote_acct.name, raw_quote_acct.ticker));
+        row.push(history.lot_num.to_string());
+        row.push(history.open_date.to_string());
+        row.push(trades.join("; "));
+        row.push(close_date);
+        row.push(settlement_dates.join("; "));
+        row.push(history.realized_quote().normalize().to_string());
+        row.push(history.settled_value().to_string());
+        if settings.realize_margin_pnl {
+            row.push(history.realized_by_trades.normalize().to_string());
         }
+        if has_charges {
+            row.push(history.charges.to_string());
+        }
+        row.push(history.open_position().to_string());
+        row.push(history.open_quote().normalize().to_string());
+        rows.push(row);
+    }
 
-        if (txn.transaction_type(
-            ars,
-            &raw_acct_map,
-            &acct_map)? == TxType::Flow
-        ) & (polarity == Some(Polarity::Outgoing)) {
-            expense_st -= proceeds_st;
-            expense_lt -= proceeds_lt;
+    let file_name = PathBuf::from("C10_Margin_position_history.csv");
+    let path = PathBuf::from(&settings.export_path);
+
+    let full_path: PathBuf = [path, file_name].iter().collect();
+    let buffer = File::create(full_path).unwrap();
+    let mut wtr = csv::Writer::from_writer(buffer);
+
+    for row in rows.iter() {
+        wtr.write_record(row).expect("Could not write row to CSV file");
+    }
+    wtr.flush().expect("Could not flush Writer, though file should exist and be complete");
+
+    Ok(())
+}
+
+pub fn _11_unrealized_gain_loss_to_csv(
+    settings: &ImportProcessParameters,
+    raw_acct_map: &HashMap<u16, RawAccount>,
+    acct_map: &HashMap<u16, Account>,
+) -> Result<(), Box<dyn Error>> {
+
+    let price_file = match &settings.price_file {
+        Some(file) => file,
+        None => return Err("No price file was set.".into()),
+    };
+
+    let prices = prices::import_prices(price_file)?;
+
+    let date = settings.valuation_date
+        .or(settings.as_of_date)
+        .unwrap_or_else(|| chrono::Local::now().naive_utc().date());
+
+    let minor_units = settings.home_currency_minor_units;
+    let terms = settings.holding_period_rule.terms();
+
+    let mut rows: Vec<Vec<String>> = [].to_vec();
+
+    let columns = [
+        "Account#".to_string(),
+        "Account".to_string(),
+        "Ticker".to_string(),
+        "Lot".to_string(),  //  "Total" on the per-account and per-ticker total rows
+        "Basis date".to_string(),
+        "Term".to_string(),
+        "Amount".to_string(),
+        "Cost basis".to_string(),
+        format!("Price {}", date),
+        format!("Market value {}", date),
+        "Unrealized gain/loss".to_string(),
+    ];
+
+    let total_columns = columns.len() + terms.len();
+    let mut header: Vec<String> = Vec::with_capacity(total_columns);
+    header.extend_from_slice(&columns);
+
+    // With a single term, its column would only repeat the unrealized gain/loss.
+    if terms.len() > 1 {
+        for term in terms.iter() {
+            header.push(format!("{} unrealized gain/loss", term.name));
         }
+    }
+    rows.push(header);
 
-        if let Some(term) = term_st {
+    let valuations = prices::get_open_lot_valuations(
+        date,
+        &prices,
+        &settings.holding_period_rule,
+        &settings.home_currency,
+        raw_acct_map,
+        acct_map,
+    );
 
-            let mut row: Vec<String> = Vec::with_capacity(total_columns);
+    let mut unpriced_tickers: Vec<String> = [].to_vec();
+    let mut valuations_by_ticker: BTreeMap<String, Vec<&prices::LotValuation>> = BTreeMap::new();
 
-            row.push(term.abbr_string());
-            row.push(tx_num_string.clone());
-            row.push(tx_memo_string.clone());
-            row.push(amount_st.to_string());
-            row.push(st_purchase_date.clone());
-            row.push(txn_date_string.clone());
-            row.push(proceeds_st.to_string());
-            row.push(cost_basis_st.to_string());
-            row.push((proceeds_st + cost_basis_st).to_string());
+    for (idx, valuation) in valuations.iter().enumerate() {
 
-            rows.push(row);
+        let raw_acct = raw_acct_map.get(&valuation.account_num).unwrap();
+
+        if valuation.price.is_none() && !unpriced_tickers.contains(&valuation.ticker) {
+            unpriced_tickers.push(valuation.ticker.clone())
         }
-        if let Some(term) = term_lt {
+        valuations_by_ticker.entry(valuation.ticker.clone()).or_default().push(valuation);
 
-            let mut row: Vec<String> = Vec::with_capacity(total_columns);
+        let mut row: Vec<String> = Vec::with_capacity(total_columns);
 
-            row.push(term.abbr_string());
-            row.push(tx_num_string);
-            row.push(tx_memo_string);
-            row.push(amount_lt.to_string());
-            row.push(lt_purchase_date.clone());
-            row.push(txn_date_string);
-            row.push(proceeds_lt.to_string());
-            row.push(cost_basis_lt.to_string());
-            row.push((proceeds_lt + cost_basis_lt).to_string());
+        row.push(valuation.account_num.to_string());
+        row.push(raw_acct.name.clone());
+        row.push(valuation.ticker.clone());
+        row.push(valuation.lot_num.to_string());
+        row.push(valuation.basis_date.to_string());
+        row.push(valuation.term.to_string());
+        row.push(valuation.amount.to_string());
+        row.push(format!("{:.*}", minor_units as usize, valuation.cost_basis.round_dp(minor_units)));
+        row.push(valuation.price.map(|price| price.to_string()).unwrap_or_default());
+        row.push(format_optional_value(valuation.market_value(minor_units), minor_units));
+        row.push(format_optional_value(valuation.unrealized_gain_loss(minor_units), minor_units));
+        if terms.len() > 1 {
+            for term in terms.iter() {
+                if *term == valuation.term {
+                    row.push(format_optional_value(valuation.unrealized_gain_loss(minor_units), minor_units));
+                } else {
+                    row.push("".to_string());
+                }
+            }
+        }
+        rows.push(row);
 
-            rows.push(row);
+        // The account's total follows its last lot.
+        let is_accounts_last_lot = match valuations.get(idx + 1) {
+            Some(next) => next.account_num != valuation.account_num,
+            None => true,
+        };
+
+        if is_accounts_last_lot {
+            let accts_valuations: Vec<&prices::LotValuation> = valuations.iter()
+                .filter(|other| other.account_num == valuation.account_num)
+                .collect();
+            rows.push(get_valuation_totals_row(
+                valuation.account_num.to_string(),
+                raw_acct.name.clone(),
+                &valuation.ticker,
+                &accts_valuations,
+                &terms,
+                minor_units,
+            ));
         }
     }
 
-    let file_name = PathBuf::from("C7_Form_8949.csv");
+    for (ticker, tickers_valuations) in valuations_by_ticker.iter() {
+        rows.push(get_valuation_totals_row(
+            "".to_string(),
+            "All accounts".to_string(),
+            ticker,
+            tickers_valuations,
+            &terms,
+            minor_units,
+        ));
+    }
+
+    for ticker in unpriced_tickers.iter() {
+        println!("  No price for {} on or before {} in the price file. Its lots are left unvalued in C11.", ticker, date);
+    }
+
+    let file_name = PathBuf::from("C11_Unrealized_gain_loss.csv");
     let path = PathBuf::from(&settings.export_path);
 
     let full_path: PathBuf = [path, file_name].iter().collect();
@@ -868,4 +1338,56 @@ pub fn _7_gain_loss_8949_to_csv(
     wtr.flush().expect("Could not flush Writer, though file should exist and be complete");
 
     Ok(())
-}
\ No newline at end of file
+}
+
+/// Totals the `valuations` of one account or one ticker.  A market value or gain/loss total is left blank when any
+/// `lot` it would include has no price.
+fn get_valuation_totals_row(
+    account_num: String,
+    account_name: String,
+    ticker: &str,
+    valuations: &[&prices::LotValuation],
+    terms: &[Term],
+    minor_units: u32,
+) -> Vec<String> {
+
+    let places = minor_units as usize;
+
+    let amount: Decimal = valuations.iter().map(|valuation| valuation.amount).sum();
+    let cost_basis: Decimal = valuations.iter().map(|valuation| valuation.cost_basis.round_dp(minor_units)).sum();
+    let market_value: Option<Decimal> = valuations.iter().map(|valuation| valuation.market_value(minor_units)).sum();
+    let gain_loss: Option<Decimal> = valuations.iter().map(|valuation| valuation.unrealized_gain_loss(minor_units)).sum();
+
+    let mut row: Vec<String> = vec![
+        account_num,
+        account_name,
+        ticker.to_string(),
+        "Total".to_string(),
+        "".to_string(),
+        "".to_string(),
+        amount.to_string(),
+        format!("{:.*}", places, cost_basis),
+        "".to_string(),
+        format_optional_value(market_value, minor_units),
+        format_optional_value(gain_loss, minor_units),
+    ];
+
+    if terms.len() > 1 {
+        for term in terms.iter() {
+            let terms_gain_loss: Option<Decimal> = valuations.iter()
+                .filter(|valuation| valuation.term == *term)
+                .map(|valuation| valuation.unrealized_gain_loss(minor_units))
+                .sum();
+            row.push(format_optional_value(terms_gain_loss, minor_units));
+        }
+    }
+
+    row
+}
+
+fn format_optional_value(value: Option<Decimal>, minor_units: u32) -> String {
+    match value {
+        Some(value) => format!("{:.*}", minor_units as usize, value),
+        None => "".to_string(),
+    }
+}
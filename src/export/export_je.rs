@@ -2,7 +2,7 @@
 // Redistributions must include the license: https://github.com/scoobybejesus/cryptools/blob/master/LEGAL.txt
 
 use std::fs::OpenOptions;
-use std::collections::HashMap;
+use std::collections::{HashMap, BTreeMap};
 use std::path::PathBuf;
 use std::error::Error;
 use std::io::prelude::Write;
@@ -10,9 +10,14 @@ use std::io::prelude::Write;
 use rust_decimal::Decimal;
 use rust_decimal_macros::dec;
 
-use crptls::transaction::{Transaction, ActionRecord, Polarity, TxType};
+use crptls::transaction::{Transaction, ActionRecord, Polarity, TxType, TxHasMargin};
 use crptls::account::{Account, RawAccount, Term};
 use crptls::core_functions::ImportProcessParameters;
+use crptls::currency;
+use crptls::holding_period::HoldingPeriodRule;
+use crptls::costing_method::FeeTreatment;
+
+use crate::export::TermTotals;
 
 
 pub fn prepare_non_lk_journal_entries(
@@ -23,6 +28,9 @@ pub fn prepare_non_lk_journal_entries(
     txns_map: &HashMap<u32, Transaction>,
 )  -> Result<(), Box<dyn Error>> {
 
+    let minor_units = settings.home_currency_minor_units;
+    let places = minor_units as usize;
+
     let file_name = PathBuf::from("J1_Journal_Entries.txt");
     let path = PathBuf::from(&settings.export_path.clone());
     let full_path: PathBuf = [path, file_name].iter().collect();
@@ -35,19 +43,65 @@ pub fn prepare_non_lk_journal_entries(
 
     writeln!(file, "Journal Entries
 \nCosting method used: {}.
+Lot relief scope: {}.
 Home currency: {}
 Enable like-kind treatment: {}",
         settings.costing_method,
+        settings.lot_relief_scope,
         settings.home_currency,
         settings.lk_treatment_enabled
     )?;
 
+    for (effective_date, method) in settings.costing_method_schedule.iter() {
+        writeln!(file, "Costing method from {}: {}.", effective_date, method)?;
+    }
+
     if settings.lk_treatment_enabled {
         writeln!(file, "Like-kind cut-off date: {}.",
             settings.lk_cutoff_date
         )?;
     }
 
+    if let Some(date) = settings.basis_reallocation_date {
+        writeln!(file, "Basis reallocated across accounts as of: {}.", date)?;
+    }
+
+    if settings.wash_sale_rule {
+        writeln!(file, "Wash sale rule applied: losses deferred into replacement lots.")?;
+    }
+
+    if settings.fee_treatment != FeeTreatment::AddToBasis {
+        writeln!(file, "Trade fees {} where possible.", settings.fee_treatment)?;
+    }
+
+    if settings.realize_margin_pnl {
+        writeln!(file, "Margin profit and loss realized as positions change.")?;
+    }
+
+    if !settings.ticker_precision.is_empty() {
+        let mut precisions: Vec<String> = settings.ticker_precision.iter()
+            .map(|(ticker, places)| format!("{} {}", ticker, places))
+            .collect();
+        precisions.sort();
+        writeln!(file, "Decimal places by ticker: {}.", precisions.join(", "))?;
+    }
+
+    if settings.home_currency_minor_units != currency::DEFAULT_MINOR_UNITS {
+        writeln!(file, "Home currency values rounded to {} decimal places.", settings.home_currency_minor_units)?;
+    }
+
+    if settings.holding_period_rule != HoldingPeriodRule::default() {
+        writeln!(file, "Holding periods: {}.", settings.holding_period_rule)?;
+    }
+
+    if let Some(date) = settings.as_of_date {
+        writeln!(file, "As of: {} (later transactions left out).", date)?;
+    }
+
+    if let Some((first_date, last_date)) = settings.report_period {
+        writeln!(file, "Period: {} to {} (transactions outside it left out).", first_date, last_date)?;
+    }
+
     let note = "
 Note: Home currency account activity may be better represented as equity-type accounts,
 depending on the bookkeeping practices you employ.";
@@ -60,9 +114,15 @@ depending on the bookkeeping practices you employ.";
 
         let txn_num = txn_num as u32;
         let txn = txns_map.get(&(txn_num)).unwrap();
+
+        if !settings.is_in_report_period(txn.date) { continue }
+
         let date = txn.date;
         let user_memo = txn.user_memo.to_string();
-        let auto_memo = txn.get_auto_memo(ars, raw_acct_map,acct_map, &settings.home_currency)?;
+        let mut auto_memo = txn.get_auto_memo(ars, raw_acct_map,acct_map, &settings.home_currency, settings.home_currency_minor_units)?;
+        if let Some(fee_leg_memo) = txn.get_fee_leg_memo(ars, raw_acct_map, acct_map, &settings.home_currency, settings.home_currency_minor_units) {
+            auto_memo = format!("{} {}", auto_memo, fee_leg_memo);
+        }
 
         writeln!(file, "\n====================================================================================================\n")?;
 
@@ -97,24 +157,79 @@ depending on the bookkeeping practices you employ.";
             }
         }
 
-        let mut term_st: Option<Term> = None;
-        let mut term_lt: Option<Term> = None;
+        // A margin trade realizing a profit (see `ImportProcessParameters::realize_margin_pnl`) debits it to the quote
+        // account, and one realizing a loss credits it there.  The base account carries no basis either way.
+        if txn.marginness(ars, raw_acct_map, acct_map) == TxHasMargin::TwoARs {
 
-        let mut polarity: Option<Polarity> = None;
+            let (_base_acct_key, quote_acct_key) = txn.get_base_and_quote_raw_acct_keys(ars, raw_acct_map, acct_map)?;
+
+            for ar_num in txn.action_record_idx_vec.iter() {
+
+                let ar = ars.get(ar_num).unwrap();
+                if ar.account_key != quote_acct_key { continue }
+
+                let realized: Decimal = ar.movements.borrow().iter().map(|mvmt| mvmt.cost_basis_lk.get()).sum();
+                if realized == dec!(0) { continue }
+
+                if (realized > dec!(0)) != (ar.direction() == Polarity::Incoming) {
+                    std::mem::swap(&mut acct_string_ic, &mut acct_string_og);
+                }
+                cost_basis_ic = Some(realized.max(dec!(0)));
+                cost_basis_og = Some((-realized).max(dec!(0)));
+            }
+        }
 
-        let mut amount_st = dec!(0);
-        let mut proceeds_st = dec!(0);
-        let mut cost_basis_st = dec!(0);
+        // Moving margin profit out of a quote account also moves out any margin charges already expensed there (see
+        // `Transaction::margin_charge`), so the quote account is debited with them, and they're added to the income.
+        let mut margin_charges_returned: Option<Decimal> = None;
 
-        let mut amount_lt = dec!(0);
-        let mut proceeds_lt = dec!(0);
-        let mut cost_basis_lt = dec!(0);
+        if !txn.settles_realized_margin_pnl
+            && txn.action_record_idx_vec.len() == 2
+            && txn.marginness(ars, raw_acct_map, acct_map) == TxHasMargin::OneAR {
+
+            let og_ar = ars.get(&txn.action_record_idx_vec[0]).unwrap();
+            let og_acct = acct_map.get(&og_ar.account_key).unwrap();
+            let returned: Decimal = og_ar.movements.borrow().iter().map(|mvmt| mvmt.cost_basis_lk.get()).sum();
+
+            if raw_acct_map.get(&og_acct.raw_key).unwrap().is_margin && returned > dec!(0) {
+                margin_charges_returned = Some(returned);
+                cost_basis_og = None;
+            }
+        }
+
+        // A fee leg is credited from its own account, with any gain or loss on it shown on its own line.
+        let mut cost_basis_fee: Option<Decimal> = None;
+        let mut acct_string_fee = "".to_string();
+
+        if let Some(fee_leg) = &txn.fee_leg {
+
+            let ar = ars.get(&fee_leg.action_record_key).unwrap();
+            let acct = acct_map.get(&ar.account_key).unwrap();
+            let raw_acct = raw_acct_map.get(&acct.raw_key).unwrap();
+
+            cost_basis_fee = Some(ar.cost_basis_in_ar());
+            acct_string_fee = format!("{} - {} ({}) (#{})",
+                raw_acct.name,
+                raw_acct.ticker,
+                raw_acct.margin_string(),
+                raw_acct.account_num,
+            );
+        }
+
+        let mut by_term: BTreeMap<Term, TermTotals> = BTreeMap::new();
+
+        let mut polarity: Option<Polarity> = None;
 
         let mut income = dec!(0);
         let mut expense = dec!(0);
 
+        // Under the wash sale rule, gains and losses differ from the booked cost basis by the loss deferred
+        // into (or, on a later disposal, released from) a replacement lot.
+        let mut wash_sale_deferral = dec!(0);
+
         let flow_or_outgoing_exchange_movements = txn.get_outgoing_exchange_and_flow_mvmts(
             &settings.home_currency,
+            settings.home_currency_minor_units,
             ars,
             raw_acct_map,
             acct_map,
@@ -130,51 +245,66 @@ depending on the bookkeeping practices you employ.";
                 };
             }
 
-            let term = mvmt.get_term(acct_map, ars, txns_map);
+            let term = mvmt.get_term(&settings.holding_period_rule, settings.as_of_date, acct_map, ars, txns_map);
+            let totals = by_term.entry(term).or_default();
 
-            if term == Term::LT {
-                amount_lt += mvmt.amount;
-                proceeds_lt += mvmt.proceeds_lk.get();
-                cost_basis_lt += mvmt.cost_basis_lk.get();
-                if term_lt.is_none() { term_lt = Some(term) }
-            } else {
-                assert_eq!(term, Term::ST);
-                amount_st += mvmt.amount;
-                proceeds_st += mvmt.proceeds_lk.get();
-                cost_basis_st += mvmt.cost_basis_lk.get();
-                if term_st.is_none() {
-                    term_st = Some(term);
-                }
+            totals.amount += mvmt.amount;
+            totals.proceeds += mvmt.proceeds_lk.get();
+            totals.cost_basis += mvmt.cost_basis_lk.get();
+            totals.wash_sale_adj += mvmt.wash_sale_adjustment.get();
+            if settings.wash_sale_rule {
+                wash_sale_deferral += mvmt.wash_sale_adjustment.get() + mvmt.cost_basis_lk.get() - mvmt.cost_basis.get();
             }
-            income += mvmt.get_income(ars, &raw_acct_map, &acct_map, &txns_map)?;
+            income += mvmt.get_income(ars, &raw_acct_map, &acct_map, &txns_map, settings.home_currency_minor_units)?;
             expense += mvmt.get_expense(ars, &raw_acct_map, &acct_map, &txns_map)?;
         }
 
+        let mut amount_fee = dec!(0);
+        let mut fee_gain_loss = dec!(0);
+
+        for mvmt in txn.get_fee_leg_mvmts(&settings.home_currency, ars, raw_acct_map, acct_map, txns_map).iter() {
+
+            amount_fee += mvmt.amount;
+            fee_gain_loss += mvmt.get_lk_gain_or_loss();
+
+            if settings.wash_sale_rule {
+                wash_sale_deferral += mvmt.wash_sale_adjustment.get() + mvmt.cost_basis_lk.get() - mvmt.cost_basis.get();
+            }
+        }
+
         if (txn.transaction_type(
             ars,
             &raw_acct_map,
             &acct_map)? == TxType::Flow
         ) & (polarity == Some(Polarity::Incoming)) {
 
-            proceeds_st = dec!(0);
-            cost_basis_st = dec!(0);
-
-            proceeds_lt = dec!(0);
-            cost_basis_lt = dec!(0);
+            for totals in by_term.values_mut() {
+                totals.proceeds = dec!(0);
+                totals.cost_basis = dec!(0);
+                totals.wash_sale_adj = dec!(0);
+            }
         }
 
-        let lt_gain_loss = proceeds_lt + cost_basis_lt;
-        let st_gain_loss = proceeds_st + cost_basis_st;
-
         let mut debits = dec!(0);
         let mut credits = dec!(0);
 
         if let Some(cb) = cost_basis_ic {
             debits += cb;
-            writeln!(file, "{:50}{:5}{:>20.2}{:5}{:>20}",
+            writeln!(file, "{:50}{:5}{:>20.*}{:5}{:>20}",
             acct_string_ic,
             "",
-            cb.to_string().as_str().parse::<f32>()?,
+            places, cb.round_dp(minor_units),
+            "",
+            "",
+            )?;
+        }
+
+        if let Some(returned) = margin_charges_returned {
+            debits += returned;
+            writeln!(file, "{:50}{:5}{:>20.*}{:5}{:>20}",
+            acct_string_og,
+            "",
+            places, returned.round_dp(minor_units),
             "",
             "",
             )?;
@@ -182,59 +312,73 @@ depending on the bookkeeping practices you employ.";
 
         if let Some(cb) = cost_basis_og {
             credits += cb;
-            writeln!(file, "{:50}{:5}{:>20}{:5}{:>20.2}",
+            writeln!(file, "{:50}{:5}{:>20}{:5}{:>20.*}",
             acct_string_og,
             "",
             "",
             "",
-            cb.to_string().as_str().parse::<f32>()?,
+            places, cb.round_dp(minor_units),
             )?;
         }
 
-        if lt_gain_loss != dec!(0) {
+        if let Some(cb) = cost_basis_fee {
+            credits += cb;
+            writeln!(file, "{:50}{:5}{:>20}{:5}{:>20.*}",
+            acct_string_fee,
+            "",
+            "",
+            "",
+            places, cb.round_dp(minor_units),
+            )?;
+        }
+
+        // Longest term first
+        for (term, totals) in by_term.iter().rev() {
 
-            if lt_gain_loss > dec!(0) {
-                credits += lt_gain_loss.abs();
-                let ltg_string = format!("Long-term gain disposing {}", amount_lt.abs());
-                writeln!(file, "{:50}{:5}{:>20}{:5}{:>20.2}",
-                ltg_string,
+            let gain_loss = totals.gain_loss();
+
+            if gain_loss > dec!(0) {
+                credits += gain_loss.abs();
+                let gain_string = format!("{} gain disposing {}", term.name, totals.amount.abs());
+                writeln!(file, "{:50}{:5}{:>20}{:5}{:>20.*}",
+                gain_string,
                 "",
                 "",
                 "",
-                lt_gain_loss.to_string().as_str().parse::<f32>()?,
+                places, gain_loss.round_dp(minor_units),
                 )?;
-            } else {
-                debits += lt_gain_loss.abs();
-                let ltl_string = format!("Long-term loss disposing {}", amount_lt.abs());
-                writeln!(file, "{:50}{:5}{:>20.2}{:5}{:>20}",
-                ltl_string,
+            } else if gain_loss < dec!(0) {
+                debits += gain_loss.abs();
+                let loss_string = format!("{} loss disposing {}", term.name, totals.amount.abs());
+                writeln!(file, "{:50}{:5}{:>20.*}{:5}{:>20}",
+                loss_string,
                 "",
-                lt_gain_loss.abs().to_string().as_str().parse::<f32>()?,
+                places, gain_loss.abs().round_dp(minor_units),
                 "",
                 "",
                 )?;
             }
         }
 
-        if st_gain_loss != dec!(0) {
+        if fee_gain_loss != dec!(0) {
 
-            if st_gain_loss > dec!(0) {
-                credits += st_gain_loss.abs();
-                let stg_string = format!("Short-term gain disposing {}", amount_st.abs());
-                writeln!(file, "{:50}{:5}{:>20}{:5}{:>20.2}",
-                stg_string,
+            if fee_gain_loss > dec!(0) {
+                credits += fee_gain_loss.abs();
+                let fee_gain_string = format!("Gain on fee disposing {}", amount_fee.abs());
+                writeln!(file, "{:50}{:5}{:>20}{:5}{:>20.*}",
+                fee_gain_string,
                 "",
                 "",
                 "",
-                st_gain_loss.to_string().as_str().parse::<f32>()?,
+                places, fee_gain_loss.round_dp(minor_units),
                 )?;
             } else {
-                debits += st_gain_loss.abs();
-                let stl_string = format!("Short-term loss disposing {}", amount_st.abs());
-                writeln!(file, "{:50}{:5}{:>20.2}{:5}{:>20}",
-                stl_string,
+                debits += fee_gain_loss.abs();
+                let fee_loss_string = format!("Loss on fee disposing {}", amount_fee.abs());
+                writeln!(file, "{:50}{:5}{:>20.*}{:5}{:>20}",
+                fee_loss_string,
                 "",
-                st_gain_loss.abs().to_string().as_str().parse::<f32>()?,
+                places, fee_gain_loss.abs().round_dp(minor_units),
                 "",
                 "",
                 )?;
@@ -243,21 +387,45 @@ depending on the bookkeeping practices you employ.";
 
         if income != dec!(0) {
             credits += income;
-            writeln!(file, "{:50}{:5}{:>20}{:5}{:>20.2}",
+            writeln!(file, "{:50}{:5}{:>20}{:5}{:>20.*}",
             "Income",
             "",
             "",
             "",
-            income.to_string().as_str().parse::<f32>()?,
+            places, income.round_dp(minor_units),
+            )?;
+        }
+
+        if wash_sale_deferral > dec!(0) {
+            debits += wash_sale_deferral;
+            writeln!(file, "{:50}{:5}{:>20.*}{:5}{:>20}",
+            "Deferred wash sale loss",
+            "",
+            places, wash_sale_deferral.round_dp(minor_units),
+            "",
+            "",
+            )?;
+        } else if wash_sale_deferral < dec!(0) {
+            credits += wash_sale_deferral.abs();
+            writeln!(file, "{:50}{:5}{:>20}{:5}{:>20.*}",
+            "Deferred wash sale loss",
+            "",
+            "",
+            "",
+            places, wash_sale_deferral.abs().round_dp(minor_units),
             )?;
         }
 
         if expense != dec!(0) {
             debits += expense.abs();
-            writeln!(file, "{:50}{:5}{:>20.2}{:5}{:>20}",
-            "Expense",
+            let expense_string = match txn.margin_charge {
+                Some(charge) => format!("Margin {} expense", charge),
+                None => "Expense".to_string(),
+            };
+            writeln!(file, "{:50}{:5}{:>20.*}{:5}{:>20}",
+            expense_string,
             "",
-            expense.abs().to_string().as_str().parse::<f32>()?,
+            places, expense.abs().round_dp(minor_units),
             "",
             "",
             )?;
@@ -271,12 +439,12 @@ depending on the bookkeeping practices you employ.";
             "--------------------",
         )?;
 
-        writeln!(file, "{:50}{:5}{:>20.2}{:5}{:>20.2}",
+        writeln!(file, "{:50}{:5}{:>20.*}{:5}{:>20.*}",
             "    Totals",
             "",
-            debits.to_string().as_str().parse::<f32>()?,
+            places, debits.round_dp(minor_units),
             "",
-            credits.to_string().as_str().parse::<f32>()?,
+            places, credits.round_dp(minor_units),
         )?;
 
         writeln!(file, "\n    (Txn {} on {}. {}. {})",
@@ -13,6 +13,12 @@ use rust_decimal_macros::dec;
 use crptls::transaction::{Transaction, ActionRecord};
 use crptls::account::{Account, RawAccount};
 use crptls::core_functions::ImportProcessParameters;
+use crptls::currency;
+use crptls::holding_period::HoldingPeriodRule;
+use crptls::costing_method::FeeTreatment;
+use crptls::average_cost;
+
+use crate::export::home_currency_string;
 
 
 pub fn _1_account_lot_detail_to_txt(
@@ -23,6 +29,9 @@ pub fn _1_account_lot_detail_to_txt(
     txns_map: &HashMap<u32, Transaction>,
 ) -> Result<(), Box<dyn Error>> {
 
+    let minor_units = settings.home_currency_minor_units;
+    let places = minor_units as usize;
+
     // =====================================
     // Exchange BTC
     // Account balance: 0.5000000 BTC; Total cost basis: 450.0000000
@@ -77,19 +86,65 @@ pub fn _1_account_lot_detail_to_txt(
 
     writeln!(file, "Account Listing - All Lots - All Movements - with high level of detail.
 \nCosting method used: {}.
+Lot relief scope: {}.
 Home currency: {}
 Enable like-kind treatment: {}",
         settings.costing_method,
+        settings.lot_relief_scope,
         home_currency,
         settings.lk_treatment_enabled
     )?;
 
+    for (effective_date, method) in settings.costing_method_schedule.iter() {
+        writeln!(file, "Costing method from {}: {}.", effective_date, method)?;
+    }
+
     if settings.lk_treatment_enabled {
         writeln!(file, "Like-kind cut-off date: {}.",
             settings.lk_cutoff_date
         )?;
     }
 
+    if let Some(date) = settings.basis_reallocation_date {
+        writeln!(file, "Basis reallocated across accounts as of: {}.", date)?;
+    }
+
+    if settings.wash_sale_rule {
+        writeln!(file, "Wash sale rule applied: losses deferred into replacement lots.")?;
+    }
+
+    if settings.fee_treatment != FeeTreatment::AddToBasis {
+        writeln!(file, "Trade fees {} where possible.", settings.fee_treatment)?;
+    }
+
+    if settings.realize_margin_pnl {
+        writeln!(file, "Margin profit and loss realized as positions change.")?;
+    }
+
+    if !settings.ticker_precision.is_empty() {
+        let mut precisions: Vec<String> = settings.ticker_precision.iter()
+            .map(|(ticker, places)| format!("{} {}", ticker, places))
+            .collect();
+        precisions.sort();
+        writeln!(file, "Decimal places by ticker: {}.", precisions.join(", "))?;
+    }
+
+    if settings.home_currency_minor_units != currency::DEFAULT_MINOR_UNITS {
+        writeln!(file, "Home currency values rounded to {} decimal places.", settings.home_currency_minor_units)?;
+    }
+
+    if settings.holding_period_rule != HoldingPeriodRule::default() {
+        writeln!(file, "Holding periods: {}.", settings.holding_period_rule)?;
+    }
+
+    if let Some(date) = settings.as_of_date {
+        writeln!(file, "As of: {} (later transactions left out).", date)?;
+    }
+
+    if let Some(pool) = &settings.average_cost_pool {
+        writeln!(file, "Average cost pooling: {}.", pool)?;
+    }
+
     for j in 1..=length {
 
         let acct = acct_map.get(&(j as u16)).unwrap();
@@ -104,21 +159,33 @@ Enable like-kind treatment: {}",
             let acct_bal_line;
 
             if raw_acct.is_home_currency(home_currency) {
-                acct_bal_line = format!("Account balance: {:.2} {}; Total cost basis: {:.2}",
-                    acct.get_sum_of_amts_in_lots().to_string().as_str().parse::<f32>()?,
+                acct_bal_line = format!("Account balance: {:.*} {}; Total cost basis: {:.*}",
+                    places, acct.get_sum_of_amts_in_lots().round_dp(minor_units),
                     ticker,
-                    acct.get_sum_of_lk_basis_in_lots().to_string().as_str().parse::<f32>()?
+                    places, acct.get_sum_of_lk_basis_in_lots().round_dp(minor_units)
                 );
             } else {
-                acct_bal_line = format!("Account balance: {} {}; Total cost basis: {:.2}",
+                acct_bal_line = format!("Account balance: {} {}; Total cost basis: {:.*}",
                     acct.get_sum_of_amts_in_lots(),
                     ticker,
-                    acct.get_sum_of_lk_basis_in_lots().to_string().as_str().parse::<f32>()?
+                    places, acct.get_sum_of_lk_basis_in_lots().round_dp(minor_units)
                 );
             }
 
             writeln!(file, "{}", acct_bal_line)?;
 
+            if let Some(pool) = &settings.average_cost_pool {
+                if !raw_acct.is_margin && !raw_acct.is_home_currency(&settings.home_currency) {
+                    writeln!(file, "Average cost per unit: {}",
+                        average_cost::get_avg_cost_per_unit_string(pool, acct.raw_key, u32::MAX, &settings.home_currency, raw_acct_map, acct_map)
+                    )?;
+                }
+            }
+
+            if let Some(line) = account_costing_method_line(settings, raw_acct) {
+                writeln!(file, "{}", line)?;
+            }
+
         } else {
             continue
         }
@@ -129,11 +196,6 @@ Enable like-kind treatment: {}",
 
             let lk_lot_basis = lot.get_sum_of_lk_basis_in_lot();
 
-            let formatted_basis: String;
-            if lk_lot_basis == dec!(0) {
-                formatted_basis = "0.00".to_string()
-            } else { formatted_basis = lk_lot_basis.to_string() }
-
             let movements_sum = lot.get_sum_of_amts_in_lot();
 
             let formatted_sum: String;
@@ -149,18 +211,18 @@ Enable like-kind treatment: {}",
                 let lot_sum_row;
 
                 if raw_acct.is_home_currency(home_currency) {
-                    lot_sum_row = format!("    • Σ: {:.2} {}, with remaining cost basis of {:.2} {} and basis date of {}",
-                        formatted_sum.to_string().as_str().parse::<f32>()?,
+                    lot_sum_row = format!("    • Σ: {:.*} {}, with remaining cost basis of {:.*} {} and basis date of {}",
+                        places, movements_sum.round_dp(minor_units),
                         ticker,
-                        formatted_basis.to_string().as_str().parse::<f32>()?,
+                        places, lk_lot_basis.round_dp(minor_units),
                         home_currency,
                         lot.date_for_basis_purposes
                     )
                 } else {
-                    lot_sum_row = format!("    • Σ: {} {}, with remaining cost basis of {:.2} {} and basis date of {}",
+                    lot_sum_row = format!("    • Σ: {} {}, with remaining cost basis of {:.*} {} and basis date of {}",
                         formatted_sum,
                         ticker,
-                        formatted_basis.to_string().as_str().parse::<f32>()?,
+                        places, lk_lot_basis.round_dp(minor_units),
                         home_currency,
                         lot.date_for_basis_purposes
                     )
@@ -176,9 +238,9 @@ Enable like-kind treatment: {}",
                     let description_string: String;
 
                     if raw_acct.is_home_currency(home_currency) {
-                        description_string = format!("\t{}.  {:<8.2} {} (Txn #{:>4}) {:>9} txn on {:10}. - {}",
+                        description_string = format!("\t{}.  {:<8.*} {} (Txn #{:>4}) {:>9} txn on {:10}. - {}",
                             (m_idx+1),
-                            mvmt.amount.to_string().as_str().parse::<f32>()?,
+                            places, mvmt.amount.round_dp(minor_units),
                             ticker,
                             mvmt.transaction_key,
                             tx_type,
@@ -211,23 +273,29 @@ Enable like-kind treatment: {}",
                     // } else if tx_type == TxType::ToSelf {   //  Can't have a gain sending to yourself
                     //     gain_loss = dec!(0)
                     } else {
-                        gain_loss = lk_proceeds + lk_cost_basis;
+                        gain_loss = mvmt.get_lk_gain_or_loss();
                     }
 
-                    let income = mvmt.get_income(ars, raw_acct_map,	acct_map, txns_map)?;
+                    let income = mvmt.get_income(ars, raw_acct_map,	acct_map, txns_map, settings.home_currency_minor_units)?;
                     let expense = mvmt.get_expense(ars, raw_acct_map, acct_map, txns_map)?;
 
-                    let activity_str = format!("\t    Proceeds: {:>10.2}; Cost basis: {:>10.2}; for Gain/loss: {} {:>10.2}; Inc.: {:>10.2}; Exp.: {:>10.2}.",
-                        lk_proceeds.to_string().as_str().parse::<f32>()?,
-                        lk_cost_basis.to_string().as_str().parse::<f32>()?,
-                        mvmt.get_term(acct_map, ars, txns_map),
-                        gain_loss.to_string().as_str().parse::<f32>()?,
-                        income.to_string().as_str().parse::<f32>()?,
-                        expense.to_string().as_str().parse::<f32>()?,
+                    let activity_str = format!("\t    Proceeds: {:>10.*}; Cost basis: {:>10.*}; for Gain/loss: {} {:>10.*}; Inc.: {:>10.*}; Exp.: {:>10.*}.",
+                        places, lk_proceeds.round_dp(minor_units),
+                        places, lk_cost_basis.round_dp(minor_units),
+                        mvmt.get_term(&settings.holding_period_rule, settings.as_of_date, acct_map, ars, txns_map),
+                        places, gain_loss.round_dp(minor_units),
+                        places, income.round_dp(minor_units),
+                        places, expense.round_dp(minor_units),
                     );
 
                     writeln!(file, "{}", activity_str)?;
 
+                    if mvmt.wash_sale_adjustment.get() != dec!(0) {
+                        writeln!(file, "\t    Wash sale: loss of {:.*} disallowed and added to the replacement lot's basis.",
+                            places, mvmt.wash_sale_adjustment.get().round_dp(minor_units),
+                        )?;
+                    }
+
                 }
             }
         }
@@ -242,6 +310,9 @@ pub fn _2_account_lot_summary_to_txt(
     acct_map: &HashMap<u16, Account>,
 ) -> Result<(), Box<dyn Error>> {
 
+    let minor_units = settings.home_currency_minor_units;
+    let places = minor_units as usize;
+
 // =====================================
 // Bank USD
 // Account balance: -220.0000000 USD; Total cost basis: -220.0000000
@@ -271,19 +342,65 @@ pub fn _2_account_lot_summary_to_txt(
 
     writeln!(file, "Account Listing - All Lots - No Movements - Summary detail.
 \nCosting method used: {}.
+Lot relief scope: {}.
 Home currency: {}
 Enable like-kind treatment: {}",
         settings.costing_method,
+        settings.lot_relief_scope,
         settings.home_currency,
         settings.lk_treatment_enabled
     )?;
 
+    for (effective_date, method) in settings.costing_method_schedule.iter() {
+        writeln!(file, "Costing method from {}: {}.", effective_date, method)?;
+    }
+
     if settings.lk_treatment_enabled {
         writeln!(file, "Like-kind cut-off date: {}.",
             settings.lk_cutoff_date
         )?;
     }
 
+    if let Some(date) = settings.basis_reallocation_date {
+        writeln!(file, "Basis reallocated across accounts as of: {}.", date)?;
+    }
+
+    if settings.wash_sale_rule {
+        writeln!(file, "Wash sale rule applied: losses deferred into replacement lots.")?;
+    }
+
+    if settings.fee_treatment != FeeTreatment::AddToBasis {
+        writeln!(file, "Trade fees {} where possible.", settings.fee_treatment)?;
+    }
+
+    if settings.realize_margin_pnl {
+        writeln!(file, "Margin profit and loss realized as positions change.")?;
+    }
+
+    if !settings.ticker_precision.is_empty() {
+        let mut precisions: Vec<String> = settings.ticker_precision.iter()
+            .map(|(ticker, places)| format!("{} {}", ticker, places))
+            .collect();
+        precisions.sort();
+        writeln!(file, "Decimal places by ticker: {}.", precisions.join(", "))?;
+    }
+
+    if settings.home_currency_minor_units != currency::DEFAULT_MINOR_UNITS {
+        writeln!(file, "Home currency values rounded to {} decimal places.", settings.home_currency_minor_units)?;
+    }
+
+    if settings.holding_period_rule != HoldingPeriodRule::default() {
+        writeln!(file, "Holding periods: {}.", settings.holding_period_rule)?;
+    }
+
+    if let Some(date) = settings.as_of_date {
+        writeln!(file, "As of: {} (later transactions left out).", date)?;
+    }
+
+    if let Some(pool) = &settings.average_cost_pool {
+        writeln!(file, "Average cost pooling: {}.", pool)?;
+    }
+
     for j in 1..=length {
 
         let acct = acct_map.get(&(j as u16)).unwrap();
@@ -293,11 +410,23 @@ Enable like-kind treatment: {}",
 
             writeln!(file, "\n=====================================")?;
             writeln!(file, "{} {}", raw_acct.name, raw_acct.ticker)?;
-            writeln!(file, "Account balance: {} {}; Total cost basis: {:.2}",
-                acct.get_sum_of_amts_in_lots(),
+            writeln!(file, "Account balance: {} {}; Total cost basis: {:.*}",
+                amount_string(acct.get_sum_of_amts_in_lots(), raw_acct, settings),
                 raw_acct.ticker,
-                acct.get_sum_of_lk_basis_in_lots().to_string().as_str().parse::<f32>()?
+                places, acct.get_sum_of_lk_basis_in_lots().round_dp(minor_units)
             )?;
+
+            if let Some(pool) = &settings.average_cost_pool {
+                if !raw_acct.is_margin && !raw_acct.is_home_currency(&settings.home_currency) {
+                    writeln!(file, "Average cost per unit: {}",
+                        average_cost::get_avg_cost_per_unit_string(pool, acct.raw_key, u32::MAX, &settings.home_currency, raw_acct_map, acct_map)
+                    )?;
+                }
+            }
+
+            if let Some(line) = account_costing_method_line(settings, raw_acct) {
+                writeln!(file, "{}", line)?;
+            }
         }
         if raw_acct.is_margin { writeln!(file, "Margin Account")?; }
 
@@ -305,26 +434,21 @@ Enable like-kind treatment: {}",
 
             let lk_lot_basis = lot.get_sum_of_lk_basis_in_lot();
 
-            let formatted_basis: String;
-            if lk_lot_basis == dec!(0) {
-                formatted_basis = "0.00".to_string()
-            } else { formatted_basis = lk_lot_basis.to_string() }
-
             let movements_sum = lot.get_sum_of_amts_in_lot();
 
             let formatted_sum: String;
             if movements_sum == dec!(0) {
                 formatted_sum = "0.00".to_string()
-            } else { formatted_sum = movements_sum.to_string() }
+            } else { formatted_sum = amount_string(movements_sum, raw_acct, settings) }
 
             if acct.list_of_lots.borrow().len() > 0 {
 
-                writeln!(file, "  Lot {:>3} created {} w/ basis date {} • Σ: {:>12}, and cost basis of {:>10.2}",
+                writeln!(file, "  Lot {:>3} created {} w/ basis date {} • Σ: {:>12}, and cost basis of {:>10.*}",
                     (lot_idx+1),
                     lot.date_of_first_mvmt_in_lot,
                     lot.date_for_basis_purposes,
                     formatted_sum,
-                    formatted_basis.to_string().as_str().parse::<f32>()?,
+                    places, lk_lot_basis.round_dp(minor_units),
                 )?;
             }
         }
@@ -339,6 +463,9 @@ pub fn _3_account_lot_summary_non_zero_to_txt(
     acct_map: &HashMap<u16, Account>,
 ) -> Result<(), Box<dyn Error>> {
 
+    let minor_units = settings.home_currency_minor_units;
+    let places = minor_units as usize;
+
 // =====================================
 // Exchange BTC
 // Account balance: 0.5000000 BTC; Total cost basis: 450.0000000
@@ -365,19 +492,65 @@ pub fn _3_account_lot_summary_non_zero_to_txt(
 
     writeln!(file, "Account Listing - Non-zero Lots - No Movements - Summary detail.
 \nCosting method used: {}.
+Lot relief scope: {}.
 Home currency: {}
 Enable like-kind treatment: {}",
         settings.costing_method,
+        settings.lot_relief_scope,
         settings.home_currency,
         settings.lk_treatment_enabled
     )?;
 
+    for (effective_date, method) in settings.costing_method_schedule.iter() {
+        writeln!(file, "Costing method from {}: {}.", effective_date, method)?;
+    }
+
     if settings.lk_treatment_enabled {
         writeln!(file, "Like-kind cut-off date: {}.",
             settings.lk_cutoff_date
         )?;
     }
 
+    if let Some(date) = settings.basis_reallocation_date {
+        writeln!(file, "Basis reallocated across accounts as of: {}.", date)?;
+    }
+
+    if settings.wash_sale_rule {
+        writeln!(file, "Wash sale rule applied: losses deferred into replacement lots.")?;
+    }
+
+    if settings.fee_treatment != FeeTreatment::AddToBasis {
+        writeln!(file, "Trade fees {} where possible.", settings.fee_treatment)?;
+    }
+
+    if settings.realize_margin_pnl {
+        writeln!(file, "Margin profit and loss realized as positions change.")?;
+    }
+
+    if !settings.ticker_precision.is_empty() {
+        let mut precisions: Vec<String> = settings.ticker_precision.iter()
+            .map(|(ticker, places)| format!("{} {}", ticker, places))
+            .collect();
+        precisions.sort();
+        writeln!(file, "Decimal places by ticker: {}.", precisions.join(", "))?;
+    }
+
+    if settings.home_currency_minor_units != currency::DEFAULT_MINOR_UNITS {
+        writeln!(file, "Home currency values rounded to {} decimal places.", settings.home_currency_minor_units)?;
+    }
+
+    if settings.holding_period_rule != HoldingPeriodRule::default() {
+        writeln!(file, "Holding periods: {}.", settings.holding_period_rule)?;
+    }
+
+    if let Some(date) = settings.as_of_date {
+        writeln!(file, "As of: {} (later transactions left out).", date)?;
+    }
+
+    if let Some(pool) = &settings.average_cost_pool {
+        writeln!(file, "Average cost pooling: {}.", pool)?;
+    }
+
     for j in 1..=length {
 
         let acct = acct_map.get(&(j as u16)).unwrap();
@@ -389,11 +562,23 @@ Enable like-kind treatment: {}",
 
                 writeln!(file, "\n=====================================")?;
                 writeln!(file, "{} {}", raw_acct.name, raw_acct.ticker)?;
-                writeln!(file, "Account balance: {} {}; Total cost basis: {:.2}",
-                    amt_in_acct,
+                writeln!(file, "Account balance: {} {}; Total cost basis: {:.*}",
+                    amount_string(amt_in_acct, raw_acct, settings),
                     raw_acct.ticker,
-                    acct.get_sum_of_lk_basis_in_lots().to_string().as_str().parse::<f32>()?
+                    places, acct.get_sum_of_lk_basis_in_lots().round_dp(minor_units)
                 )?;
+
+                if let Some(pool) = &settings.average_cost_pool {
+                    if !raw_acct.is_margin && !raw_acct.is_home_currency(&settings.home_currency) {
+                        writeln!(file, "Average cost per unit: {}",
+                            average_cost::get_avg_cost_per_unit_string(pool, acct.raw_key, u32::MAX, &settings.home_currency, raw_acct_map, acct_map)
+                        )?;
+                    }
+                }
+
+                if let Some(line) = account_costing_method_line(settings, raw_acct) {
+                    writeln!(file, "{}", line)?;
+                }
             } else {
                 continue
             }
@@ -404,21 +589,16 @@ Enable like-kind treatment: {}",
 
             let lk_lot_basis = lot.get_sum_of_lk_basis_in_lot();
 
-            let formatted_basis: String;
-            if lk_lot_basis == dec!(0) {
-                formatted_basis = "0.00".to_string()
-            } else { formatted_basis = lk_lot_basis.to_string() }
-
             let movements_sum = lot.get_sum_of_amts_in_lot();
 
             if acct.list_of_lots.borrow().len() > 0 && movements_sum > dec!(0) {
 
-                writeln!(file, "  Lot {:>3} created {} w/ basis date {} • Σ: {:>12}, and cost basis of {:>10.2}",
+                writeln!(file, "  Lot {:>3} created {} w/ basis date {} • Σ: {:>12}, and cost basis of {:>10.*}",
                     (lot_idx+1),
                     lot.date_of_first_mvmt_in_lot,
                     lot.date_for_basis_purposes,
-                    movements_sum,
-                    formatted_basis.to_string().as_str().parse::<f32>()?,
+                    amount_string(movements_sum, raw_acct, settings),
+                    places, lk_lot_basis.round_dp(minor_units),
                 )?;
             }
         }
@@ -427,4 +607,29 @@ Enable like-kind treatment: {}",
     Ok(())
 }
 
+/// When any `account` has its own costing method, notes which method each non-margin `account` used.
+fn account_costing_method_line(settings: &ImportProcessParameters, raw_acct: &RawAccount) -> Option<String> {
+
+    if settings.costing_method_overrides.is_empty()
+        || raw_acct.is_margin
+        || raw_acct.is_home_currency(&settings.home_currency) {
+        return None
+    }
+
+    match settings.costing_method_overrides.get(&raw_acct.account_num) {
+        Some(method) => Some(format!("Costing method: {} (account override).", method)),
+        None => Some("Costing method: as in header.".to_string()),
+    }
+}
 
+
+
+/// An amount held in the `account`, printed at the minor unit when the `account` holds the home currency.
+fn amount_string(amount: Decimal, raw_acct: &RawAccount, settings: &ImportProcessParameters) -> String {
+
+    if raw_acct.is_home_currency(&settings.home_currency) {
+        home_currency_string(amount, settings.home_currency_minor_units)
+    } else {
+        amount.to_string()
+    }
+}
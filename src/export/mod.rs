@@ -4,4 +4,35 @@
 pub mod export_all;
 pub mod export_je;
 pub mod export_csv;
-pub mod export_txt;
\ No newline at end of file
+pub mod export_txt;
+
+use chrono::NaiveDate;
+use rust_decimal::Decimal;
+
+/// Sums of a `transaction`'s `movement`s that fall in the same holding period `Term`, for the reports that split
+/// disposals by term.
+#[derive(Default)]
+pub struct TermTotals {
+    pub amount: Decimal,
+    pub proceeds: Decimal,
+    pub cost_basis: Decimal,
+    pub wash_sale_adj: Decimal,
+    pub income: Decimal,
+    pub expense: Decimal,
+    /// Basis date of the first `movement`
+    pub purchase_date: Option<NaiveDate>,
+    /// Whether the latest `movement`'s basis date differs from the first one's
+    pub various_dates: bool,
+}
+
+/// Prints a home currency value at the currency's minor unit, so that -400, -400.0 and -400.00 all print as -400.00.
+pub fn home_currency_string(value: Decimal, minor_units: u32) -> String {
+    format!("{:.*}", minor_units as usize, value.round_dp(minor_units))
+}
+
+impl TermTotals {
+
+    pub fn gain_loss(&self) -> Decimal {
+        self.proceeds + self.cost_basis + self.wash_sale_adj
+    }
+}
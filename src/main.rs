@@ -10,8 +10,13 @@
 
 use std::path::PathBuf;
 use std::error::Error;
+use std::collections::HashMap;
 
 use clap::Parser;
+use chrono::NaiveDate;
+
+use crptls::costing_method::{InventoryCostingMethod, AverageCostPool, LotReliefScope, FeeTreatment};
+use crptls::holding_period::HoldingPeriodRule;
 
 mod setup;
 mod cli_user_choices;
@@ -71,6 +76,39 @@ pub struct Cli {
     #[arg(id = "date separator character is slash", short, long = "date-separator-is-slash")]
     date_separator_is_slash: bool,
 
+    /// Optional CSV file for specific identification of lots. Each row (after a header row) has four fields:
+    /// txn (transaction number or an external ID matching the memo, prefixed txn: or id: when a number could be
+    /// either), account (account number), lot (lot number), and amount. Selected lots are spent from first, in order,
+    /// before falling back to the inventory costing method.
+    /// NOTE: this flag overrides the LOT_SELECTION_FILE environment variable, including if set in the .env file.
+    #[arg(id = "lot selection file", long = "lot-selection")]
+    lot_selection_file: Option<PathBuf>,
+
+    /// Processes only the transactions dated on or before this date, so balances, basis and the holding periods of
+    /// unsold lots are as of the date (e.g., a past year-end) rather than as of today. You must use %y-%m-%d
+    /// (or %Y-%m-%d) format. The file_to_import must be in date order.
+    #[arg(id = "as-of date", long = "as-of")]
+    as_of_date: Option<String>,
+
+    /// Limits the disposal, income and expense reports (C4-C7 and J1) to a tax year, given as a calendar year
+    /// (e.g., 2023) or as first_date:last_date (e.g., 2023-04-06:2024-04-05) in %y-%m-%d (or %Y-%m-%d) format.
+    /// The whole file_to_import is still processed, and the account sums reports (C1-C3) gain opening and closing
+    /// balance and basis columns for the period.
+    #[arg(id = "tax year", long = "tax-year")]
+    tax_year: Option<String>,
+
+    /// Optional CSV file of prices in the home currency. Each row (after a header row) has three fields: date
+    /// (%Y-%m-%d), ticker, and the price of one unit. Adds a report (C11) valuing every open lot at the latest price
+    /// on or before the valuation date, with its unrealized gain or loss by term.
+    /// NOTE: this flag overrides the PRICE_FILE environment variable, including if set in the .env file.
+    #[arg(id = "price file", long = "price-file")]
+    price_file: Option<PathBuf>,
+
+    /// Date the unrealized gain/loss report values open lots at. You must use %y-%m-%d (or %Y-%m-%d) format.
+    /// The default is the as-of date, if set, or else today.
+    #[arg(id = "valuation date", long = "valuation-date")]
+    valuation_date: Option<String>,
+
     /// File to be imported.  Some notes on the columns: (a) by default, the program expects the `txDate` column to 
     /// be formatted as %m-%d-%y. You may alter this with ISO_DATE and DATE_SEPARATOR_IS_SLASH flags or environment
     /// variables; (b) the `proceeds` column and any values in transactions must have a period (".") as the decimal
@@ -95,6 +133,12 @@ pub struct Cfg {
     /// Home currency (currency from the `proceeds` column of the `Cli::file_to_import` and in which all resulting reports are denominated).  
     /// Default is `USD`.
     home_currency: String,
+    /// Decimal places of the home currency's minor unit, to which home currency values are rounded and printed.  The
+    /// default comes from the ISO 4217 table for `home_currency` (e.g., 0 for JPY, 3 for KWD, and 2 if it isn't listed).
+    home_currency_minor_units: u32,
+    /// Comma-separated `ticker:decimal_places` pairs (e.g., `ETH:18,USDC:6`). Amounts of each listed ticker are rounded
+    /// to its number of decimal places (at most 28) on import, rather than to 8. The default is empty.
+    ticker_precision: HashMap<String, u32>,
     /// Cutoff date through which like-kind exchange treatment should be applied. You must use %y-%m-%d (or %Y-%m-%d)
     /// format for like-kind cutoff date entry.  The default is blank/commented/`None`.
     lk_cutoff_date: Option<String>,
@@ -103,11 +147,67 @@ pub struct Cfg {
     /// 2. LIFO according to the basis date of the lot.
     /// 3. FIFO according to the order the lot was created.
     /// 4. FIFO according to the basis date of the lot.
+    /// 5. HIFO according to the per-unit cost basis of the lot.
+    /// 6. LOFO according to the per-unit cost basis of the lot.
+    /// 7. Tax-optimized: long-term losses first, then short-term losses, then the smallest gains.
      /// [default: 1]
     inv_costing_method: String,
+    /// Comma-separated `effective_date:method_number` pairs (e.g., `2022-01-01:5`). Each method replaces the one above
+    /// for transactions dated on or after its date, which must be in %y-%m-%d (or %Y-%m-%d) format. The default is empty.
+    inv_costing_method_schedule: Vec<(NaiveDate, InventoryCostingMethod)>,
+    /// Comma-separated `account_number:method_number` pairs (e.g., `2:3`). Each listed non-margin account always uses
+    /// its own method, regardless of the two above. The default is empty.
+    inv_costing_method_overrides: HashMap<u16, InventoryCostingMethod>,
+    /// Set to `universal` to have the costing method choose from the lots of every non-margin account with the same
+    /// ticker, rather than only from the lots of the account being spent from. The default is `account` (per-wallet).
+    lot_relief_scope: LotReliefScope,
+    /// Date as of which the unused basis of each ticker is reallocated across its non-margin accounts, for the transition
+    /// to per-wallet tracking. You must use %y-%m-%d (or %Y-%m-%d) format. The default is `None` (no reallocation).
+    basis_reallocation_date: Option<NaiveDate>,
+    /// CSV file of the lots each account holds after the basis reallocation. Without it, the global-to-wallet rule
+    /// is used. The default is `None`.
+    basis_allocation_file: Option<PathBuf>,
+    /// CSV file for specific identification of lots (see `Cli::lot_selection_file`).  The default is `None`.
+    lot_selection_file: Option<PathBuf>,
+    /// Average cost (adjusted cost base) pooling. Set to `account` for one pool per account, or `ticker` for one pool
+    /// shared by every non-margin account with the same ticker. Dispositions then take cost basis at the pool's
+    /// running average. Can't be combined with like-kind treatment. The default is `None` (lot-by-lot relief).
+    avg_cost_pooling: Option<AverageCostPool>,
+    /// Setting the corresponding environment variable to `true` (or `1`) will export an additional report that matches
+    /// each disposal under HMRC's share-pooling rules (same-day, then 30-day, then the Section 104 pool).
+    /// The default value is `false`.
+    uk_share_pooling: bool,
+    /// Comma-separated holding periods, shortest first, each `<N>y` (more than N years, by the calendar) or `<N>d`
+    /// (more than N days), or `none` for no distinction. Each period outlasted moves a disposal into the next term.
+    /// Terms are labeled by `HOLDING_PERIOD_LABELS`, which is needed for more than two. The default is `365d` (ST/LT).
+    holding_period_rule: HoldingPeriodRule,
+    /// Setting the corresponding environment variable to `true` (or `1`) will disallow a loss to the extent the same
+    /// ticker was acquired within 30 days before or after, adding it to the replacement lot's basis.
+    /// The default value is `false`.
+    wash_sale_rule: bool,
+    /// Setting the corresponding environment variable to `true` (or `1`) will realize margin profit and loss on every
+    /// trade that reduces a margin position, rather than when the quote account settles with a spot account.
+    /// The default value is `false`.
+    realize_margin_pnl: bool,
+    /// What becomes of trade fees (from the input file's `fee` column or a fee leg). Set to `basis` to add them to the
+    /// acquisition's cost basis, `proceeds` to subtract them from the disposal's proceeds, or `expense` to book them
+    /// as an expense. When `basis` or `proceeds` doesn't fit a trade, the fee goes to the other; `expense` fits any.
+    /// The default is `basis`.
+    fee_treatment: FeeTreatment,
+    /// CSV file of home currency prices by date and ticker (see `Cli::price_file`).  The default is `None`.
+    price_file: Option<PathBuf>,
+}
+
+fn main() {
+
+    //  Errors are reported by their message rather than their debug representation, and end the program with a failure status.
+    if let Err(err) = run() {
+        eprintln!("Error: {}", err);
+        std::process::exit(1)
+    }
 }
 
-fn main() -> Result<(), Box<dyn Error>> {
+fn run() -> Result<(), Box<dyn Error>> {
 
     let args = Cli::parse();
 
@@ -11,7 +11,7 @@ use ratatui::widgets::ListState;
 
 use crate::export::{export_csv, export_je, export_txt};
 
-pub (crate) const REPORTS: [&'static str; 11] = [
+pub (crate) const REPORTS: [&'static str; 15] = [
     "1. CSV: Account Sums",
     "2. CSV: Account Sums (Non-zero only)",
     "3. CSV: Account Sums (Orig. basis vs like-kind basis)",
@@ -19,10 +19,14 @@ pub (crate) const REPORTS: [&'static str; 11] = [
     "5. CSV: Transactions by movement (summarized by long-term/short-term)",
     "6. CSV: Transactions by movement (every movement, w/ orig. and like-kind basis",
     "7. CSV: Transactions summary by LT/ST for Form 8949",
-    "8. TXT: Accounts by lot (every movement)",
-    "9. TXT: Accounts by lot (every lot balance)",
-    "10. TXT: Accounts by lot (every non-zero lot balance)",
-    "11. TXT: Bookkeeping journal entries",
+    "8. CSV: UK share matching (same day, 30 day, Section 104 pool)",
+    "9. CSV: Basis reallocation across accounts",
+    "10. CSV: Margin position history (by base/quote lot pair)",
+    "11. CSV: Unrealized gain/loss at market prices",
+    "12. TXT: Accounts by lot (every movement)",
+    "13. TXT: Accounts by lot (every lot balance)",
+    "14. TXT: Accounts by lot (every non-zero lot balance)",
+    "15. TXT: Bookkeeping journal entries",
 ];
 
 pub struct StatefulList<I> {
@@ -166,6 +170,12 @@ pub fn export(
 
         println!("    {}", reports[*report_idx]);
 
+        // As in `export_all`, UK share pooling leaves out the reports that take basis and gains from `lot`s.
+        if settings.uk_share_pooling && ![8, 10].contains(&(report_idx + 1)) {
+            println!("       *Skipping report of the costing method's basis and gains (UK share pooling is on): {}", reports[*report_idx]);
+            continue
+        }
+
         match report_idx + 1 {
 
             1 => {
@@ -225,8 +235,55 @@ pub fn export(
                     &transactions_map
                 )?;
             }
-
             8 => {
+                if settings.uk_share_pooling {
+                    export_csv::_8_uk_share_matching_to_csv(
+                        settings,
+                        raw_acct_map,
+                        account_map,
+                        action_records_map,
+                        transactions_map
+                    )?;
+                } else {
+                    println!("       *Skipping UK share matching report (UK share pooling is off): {}", reports[*report_idx]);
+                }
+            }
+
+            9 => {
+                if settings.basis_reallocation_date.is_some() {
+                    export_csv::_9_basis_reallocation_to_csv(
+                        settings,
+                        raw_acct_map,
+                        account_map
+                    )?;
+                } else {
+                    println!("       *Skipping basis reallocation report (no reallocation date): {}", reports[*report_idx]);
+                }
+            }
+
+            10 => {
+                export_csv::_10_margin_position_history_to_csv(
+                    settings,
+                    raw_acct_map,
+                    account_map,
+                    action_records_map,
+                    transactions_map
+                )?;
+            }
+
+            11 => {
+                if settings.price_file.is_some() {
+                    export_csv::_11_unrealized_gain_loss_to_csv(
+                        settings,
+                        raw_acct_map,
+                        account_map,
+                    )?;
+                } else {
+                    println!("       *Skipping unrealized gain/loss report (no price file): {}", reports[*report_idx]);
+                }
+            }
+
+            12 => {
                 export_txt::_1_account_lot_detail_to_txt(
                     &settings,
                     &raw_acct_map,
@@ -235,21 +292,21 @@ pub fn export(
                     &transactions_map,
                 )?;
             }
-            9 => {
+            13 => {
                 export_txt::_2_account_lot_summary_to_txt(
                     &settings,
                     &raw_acct_map,
                     &account_map,
                 )?;
             }
-            10 => {
+            14 => {
                 export_txt::_3_account_lot_summary_non_zero_to_txt(
                     &settings,
                     &raw_acct_map,
                     &account_map,
                 )?;
             }
-            11 => {
+            15 => {
                 if !settings.lk_treatment_enabled {
                     export_je::prepare_non_lk_journal_entries(
                         &settings,
@@ -4,13 +4,16 @@
 use std::path::PathBuf;
 use std::error::Error;
 use std::env;
+use std::collections::HashMap;
 use std::fs::File;
 
 use chrono::NaiveDate;
 use dotenv;
 
 use crptls::core_functions::ImportProcessParameters;
-use crptls::costing_method::InventoryCostingMethod;
+use crptls::currency;
+use crptls::holding_period::{HoldingPeriod, HoldingPeriodRule};
+use crptls::costing_method::{InventoryCostingMethod, AverageCostPool, LotReliefScope, FeeTreatment};
 
 use crate::cli_user_choices;
 use crate::skip_wizard;
@@ -77,6 +80,21 @@ pub fn get_env(cmd_args: &super::Cli) -> Result<super::Cfg, Box<dyn Error>> {
             "USD".to_string()},
     };
 
+    let home_currency_minor_units = match env::var("HOME_CURRENCY_MINOR_UNITS") {
+        Ok(val) => {
+            println!("    Found HOME_CURRENCY_MINOR_UNITS env var: {}", val);
+            val.trim().parse::<u32>().ok().filter(|places| *places <= 28)
+                .ok_or(format!("HOME_CURRENCY_MINOR_UNITS '{}' must be 0 to 28 decimal places. See .env.example.", val))?},
+        Err(_e) => currency::iso_4217_minor_units(&home_currency),
+    };
+
+    let ticker_precision = match env::var("TICKER_PRECISION") {
+        Ok(val) => {
+            println!("    Found TICKER_PRECISION env var: {}", val);
+            parse_ticker_precision(&val)?},
+        Err(_e) => HashMap::new(),
+    };
+
     let lk_cutoff_date = match env::var("LK_CUTOFF_DATE") {
         Ok(val) => {
             println!("    Found LK_CUTOFF_DATE env var: {}", val);
@@ -93,17 +111,333 @@ pub fn get_env(cmd_args: &super::Cli) -> Result<super::Cfg, Box<dyn Error>> {
             "1".to_string()},
     };
 
+    let inv_costing_method_schedule = match env::var("INV_COSTING_METHOD_SCHEDULE") {
+        Ok(val) => {
+            println!("    Found INV_COSTING_METHOD_SCHEDULE env var: {}", val);
+            parse_costing_method_schedule(&val)?},
+        Err(_e) => [].to_vec(),
+    };
+
+    let inv_costing_method_overrides = match env::var("INV_COSTING_METHOD_BY_ACCOUNT") {
+        Ok(val) => {
+            println!("    Found INV_COSTING_METHOD_BY_ACCOUNT env var: {}", val);
+            parse_costing_method_overrides(&val)?},
+        Err(_e) => HashMap::new(),
+    };
+
+    let lot_selection_file = match cmd_args.lot_selection_file.to_owned() {
+        Some(file) => {
+            println!("    Command line flag for LOT_SELECTION_FILE was set: {}", file.display());
+            Some(file)},
+        None => match env::var("LOT_SELECTION_FILE") {
+            Ok(val) => {
+                println!("    Found LOT_SELECTION_FILE env var: {}", val);
+                Some(PathBuf::from(val))},
+            Err(_e) => None,
+        },
+    };
+
+    let price_file = match cmd_args.price_file.to_owned() {
+        Some(file) => {
+            println!("    Command line flag for PRICE_FILE was set: {}", file.display());
+            Some(file)},
+        None => match env::var("PRICE_FILE") {
+            Ok(val) => {
+                println!("    Found PRICE_FILE env var: {}", val);
+                Some(PathBuf::from(val))},
+            Err(_e) => None,
+        },
+    };
+
+    let lot_relief_scope = match env::var("LOT_RELIEF_SCOPE") {
+        Ok(val) => {
+            println!("    Found LOT_RELIEF_SCOPE env var: {}", val);
+            match val.to_lowercase().trim() {
+                "account" => LotReliefScope::PerAccount,
+                "universal" => LotReliefScope::Universal,
+                _ => {
+                    println!("WARN: Invalid environment variable for 'LOT_RELIEF_SCOPE'. Using per-account lot relief.");
+                    LotReliefScope::PerAccount
+                }
+            }},
+        Err(_e) => LotReliefScope::PerAccount,
+    };
+
+    let basis_reallocation_date = match env::var("BASIS_REALLOCATION_DATE") {
+        Ok(val) => {
+            println!("    Found BASIS_REALLOCATION_DATE env var: {}", val);
+            let date = NaiveDate::parse_from_str(&val, "%y-%m-%d")
+                .or_else(|_| NaiveDate::parse_from_str(&val, "%Y-%m-%d"))
+                .map_err(|_| "Environment variable for BASIS_REALLOCATION_DATE has an incorrect format. See .env.example.")?;
+            Some(date)},
+        Err(_e) => None,
+    };
+
+    let basis_allocation_file = match env::var("BASIS_ALLOCATION_FILE") {
+        Ok(val) => {
+            println!("    Found BASIS_ALLOCATION_FILE env var: {}", val);
+            Some(PathBuf::from(val))},
+        Err(_e) => None,
+    };
+
+    let avg_cost_pooling = match env::var("AVG_COST_POOLING") {
+        Ok(val) => {
+            println!("    Found AVG_COST_POOLING env var: {}", val);
+            match val.to_lowercase().trim() {
+                "account" => Some(AverageCostPool::Account),
+                "ticker" => Some(AverageCostPool::Ticker),
+                _ => {
+                    println!("WARN: Invalid environment variable for 'AVG_COST_POOLING'. Using lot-by-lot cost basis.");
+                    None
+                }
+            }},
+        Err(_e) => None,
+    };
+
+    let uk_share_pooling = match env::var("UK_SHARE_POOLING") {
+        Ok(val) => {
+            if val == "1" || val.to_lowercase() == "true" {
+                println!("    Found UK_SHARE_POOLING env var: {}. Exporting UK share matching report.", val);
+                true
+            } else {
+                println!("    Found UK_SHARE_POOLING env var: {} (not 1 or true). Not exporting UK share matching report.", val);
+                false
+            }
+        },
+        Err(_e) => false,
+    };
+
+    let holding_period_labels = match env::var("HOLDING_PERIOD_LABELS") {
+        Ok(val) => {
+            println!("    Found HOLDING_PERIOD_LABELS env var: {}", val);
+            Some(val.split(',').map(|label| label.trim().to_string()).collect())},
+        Err(_e) => None,
+    };
+
+    let holding_period_rule = match env::var("HOLDING_PERIOD_RULE") {
+        Ok(val) => {
+            println!("    Found HOLDING_PERIOD_RULE env var: {}", val);
+            HoldingPeriodRule::new(parse_holding_periods(&val)?, holding_period_labels)?},
+        Err(_e) => match holding_period_labels {
+            Some(labels) => HoldingPeriodRule::new(vec![HoldingPeriod::Days(365)], Some(labels))?,
+            None => HoldingPeriodRule::default(),
+        },
+    };
+
+    let wash_sale_rule = match env::var("WASH_SALE_RULE") {
+        Ok(val) => {
+            if val == "1" || val.to_lowercase() == "true" {
+                println!("    Found WASH_SALE_RULE env var: {}. Disallowing wash sale losses.", val);
+                true
+            } else {
+                println!("    Found WASH_SALE_RULE env var: {} (not 1 or true). Not applying the wash sale rule.", val);
+                false
+            }
+        },
+        Err(_e) => false,
+    };
+
+    let realize_margin_pnl = match env::var("REALIZE_MARGIN_PNL") {
+        Ok(val) => {
+            if val == "1" || val.to_lowercase() == "true" {
+                println!("    Found REALIZE_MARGIN_PNL env var: {}. Realizing margin profit and loss as positions change.", val);
+                true
+            } else {
+                println!("    Found REALIZE_MARGIN_PNL env var: {} (not 1 or true). Realizing margin profit and loss upon settlement.", val);
+                false
+            }
+        },
+        Err(_e) => false,
+    };
+
+    let fee_treatment = match env::var("FEE_TREATMENT") {
+        Ok(val) => {
+            println!("    Found FEE_TREATMENT env var: {}", val);
+            match val.to_lowercase().trim() {
+                "basis" => FeeTreatment::AddToBasis,
+                "proceeds" => FeeTreatment::SubtractFromProceeds,
+                "expense" => FeeTreatment::Expense,
+                _ => {
+                    println!("WARN: Invalid environment variable for 'FEE_TREATMENT'. Adding fees to acquisition basis.");
+                    FeeTreatment::AddToBasis
+                }
+            }},
+        Err(_e) => FeeTreatment::AddToBasis,
+    };
+
     let cfg = super::Cfg {
         iso_date,
         date_separator_is_slash,
         home_currency,
+        home_currency_minor_units,
+        ticker_precision,
         lk_cutoff_date,
         inv_costing_method,
+        inv_costing_method_schedule,
+        inv_costing_method_overrides,
+        lot_relief_scope,
+        basis_reallocation_date,
+        basis_allocation_file,
+        lot_selection_file,
+        avg_cost_pooling,
+        uk_share_pooling,
+        holding_period_rule,
+        wash_sale_rule,
+        realize_margin_pnl,
+        fee_treatment,
+        price_file,
     };
 
     Ok(cfg)
 }
 
+/// Parses comma-separated `effective_date:method_number` pairs (e.g., `2022-01-01:5,2023-07-01:3`), sorted by date.
+fn parse_costing_method_schedule(val: &str) -> Result<Vec<(NaiveDate, InventoryCostingMethod)>, Box<dyn Error>> {
+
+    let mut schedule: Vec<(NaiveDate, InventoryCostingMethod)> = [].to_vec();
+
+    for entry in val.split(',').map(|entry| entry.trim()).filter(|entry| !entry.is_empty()) {
+
+        let (date_str, method_str) = entry.split_once(':')
+            .ok_or(format!("INV_COSTING_METHOD_SCHEDULE entry '{}' isn't in effective_date:method_number form. See .env.example.", entry))?;
+
+        let date = NaiveDate::parse_from_str(date_str.trim(), "%y-%m-%d")
+            .or_else(|_| NaiveDate::parse_from_str(date_str.trim(), "%Y-%m-%d"))
+            .map_err(|_| format!("INV_COSTING_METHOD_SCHEDULE entry '{}' has an incorrect date format. See .env.example.", entry))?;
+
+        let method = costing_method_from_number(method_str)
+            .ok_or(format!("INV_COSTING_METHOD_SCHEDULE entry '{}' has an invalid method number. See .env.example.", entry))?;
+
+        if schedule.iter().any(|(existing_date, _method)| *existing_date == date) {
+            return Err(format!("INV_COSTING_METHOD_SCHEDULE has more than one method effective {}.", date).into())
+        }
+
+        schedule.push((date, method));
+    }
+
+    schedule.sort_by_key(|(date, _method)| *date);
+
+    Ok(schedule)
+}
+
+/// Parses comma-separated `account_number:method_number` pairs (e.g., `2:3,4:3`).
+fn parse_costing_method_overrides(val: &str) -> Result<HashMap<u16, InventoryCostingMethod>, Box<dyn Error>> {
+
+    let mut overrides: HashMap<u16, InventoryCostingMethod> = HashMap::new();
+
+    for entry in val.split(',').map(|entry| entry.trim()).filter(|entry| !entry.is_empty()) {
+
+        let (acct_str, method_str) = entry.split_once(':')
+            .ok_or(format!("INV_COSTING_METHOD_BY_ACCOUNT entry '{}' isn't in account_number:method_number form. See .env.example.", entry))?;
+
+        let acct_key = acct_str.trim().parse::<u16>()
+            .map_err(|_| format!("INV_COSTING_METHOD_BY_ACCOUNT entry '{}' has an invalid account number. See .env.example.", entry))?;
+
+        let method = costing_method_from_number(method_str)
+            .ok_or(format!("INV_COSTING_METHOD_BY_ACCOUNT entry '{}' has an invalid method number. See .env.example.", entry))?;
+
+        if overrides.insert(acct_key, method).is_some() {
+            return Err(format!("INV_COSTING_METHOD_BY_ACCOUNT has more than one method for account {}.", acct_key).into())
+        }
+    }
+
+    Ok(overrides)
+}
+
+/// Parses comma-separated `ticker:decimal_places` pairs (e.g., `ETH:18,USDC:6`).  Tickers are uppercased.
+fn parse_ticker_precision(val: &str) -> Result<HashMap<String, u32>, Box<dyn Error>> {
+
+    let mut precisions: HashMap<String, u32> = HashMap::new();
+
+    for entry in val.split(',').map(|entry| entry.trim()).filter(|entry| !entry.is_empty()) {
+
+        let (ticker, places_str) = entry.split_once(':')
+            .ok_or(format!("TICKER_PRECISION entry '{}' isn't in ticker:decimal_places form. See .env.example.", entry))?;
+
+        let places = places_str.trim().parse::<u32>().ok().filter(|places| *places <= 28)
+            .ok_or(format!("TICKER_PRECISION entry '{}' must have 0 to 28 decimal places. See .env.example.", entry))?;
+
+        if precisions.insert(ticker.trim().to_uppercase(), places).is_some() {
+            return Err(format!("TICKER_PRECISION has more than one precision for {}.", ticker.trim()).into())
+        }
+    }
+
+    Ok(precisions)
+}
+
+/// Parses comma-separated holding periods (e.g., `1y` or `365d,3650d`), or `none` for no periods at all.
+fn parse_holding_periods(val: &str) -> Result<Vec<HoldingPeriod>, Box<dyn Error>> {
+
+    if val.trim().to_lowercase() == "none" {
+        return Ok([].to_vec())
+    }
+
+    let mut periods: Vec<HoldingPeriod> = [].to_vec();
+
+    for entry in val.split(',').map(|entry| entry.trim().to_lowercase()).filter(|entry| !entry.is_empty()) {
+
+        let period = if let Some(years) = entry.strip_suffix('y') {
+            years.trim().parse::<u32>().ok().filter(|years| *years > 0).map(HoldingPeriod::Years)
+        } else if let Some(days) = entry.strip_suffix('d') {
+            days.trim().parse::<u32>().ok().map(HoldingPeriod::Days)
+        } else {
+            None
+        };
+
+        periods.push(period
+            .ok_or(format!("HOLDING_PERIOD_RULE entry '{}' must be a number of years (e.g., 1y) or days (e.g., 365d). See .env.example.", entry))?);
+    }
+
+    if periods.is_empty() {
+        return Err("HOLDING_PERIOD_RULE has no holding periods. Use `none` for no distinction. See .env.example.".into())
+    }
+
+    Ok(periods)
+}
+
+/// Parses a calendar year (e.g., `2023`) or a `first_date:last_date` pair (e.g., `2023-04-06:2024-04-05`).
+fn parse_tax_year(val: &str) -> Result<(NaiveDate, NaiveDate), Box<dyn Error>> {
+
+    let parse_date = |date_str: &str| {
+        NaiveDate::parse_from_str(date_str.trim(), "%y-%m-%d")
+            .or_else(|_| NaiveDate::parse_from_str(date_str.trim(), "%Y-%m-%d"))
+            .map_err(|_| format!("Command line flag for TAX_YEAR has an incorrect date: {}. Use %y-%m-%d (or %Y-%m-%d).", date_str))
+    };
+
+    let (first_date, last_date) = match val.split_once(':') {
+        Some((first_str, last_str)) => (parse_date(first_str)?, parse_date(last_str)?),
+        None => {
+            let year = val.trim().parse::<i32>()
+                .map_err(|_| format!("Command line flag for TAX_YEAR '{}' isn't a year or a first_date:last_date pair.", val))?;
+            let first_date = NaiveDate::from_ymd_opt(year, 1, 1)
+                .ok_or(format!("Command line flag for TAX_YEAR '{}' isn't a valid year.", val))?;
+            let last_date = NaiveDate::from_ymd_opt(year, 12, 31)
+                .ok_or(format!("Command line flag for TAX_YEAR '{}' isn't a valid year.", val))?;
+            (first_date, last_date)
+        }
+    };
+
+    if first_date > last_date {
+        return Err(format!("Command line flag for TAX_YEAR '{}' ends before it begins.", val).into())
+    }
+
+    Ok((first_date, last_date))
+}
+
+fn costing_method_from_number(num: &str) -> Option<InventoryCostingMethod> {
+
+    match num.trim() {
+        "1" => Some(InventoryCostingMethod::LIFObyLotCreationDate),
+        "2" => Some(InventoryCostingMethod::LIFObyLotBasisDate),
+        "3" => Some(InventoryCostingMethod::FIFObyLotCreationDate),
+        "4" => Some(InventoryCostingMethod::FIFObyLotBasisDate),
+        "5" => Some(InventoryCostingMethod::HIFObyLotUnitBasis),
+        "6" => Some(InventoryCostingMethod::LOFObyLotUnitBasis),
+        "7" => Some(InventoryCostingMethod::TaxOptimized),
+        _ => None,
+    }
+}
+
 // These fields are subject to change by the user if they use the wizard
 pub struct ArgsForImportVarsTBD {
     pub inv_costing_method_arg: String,
@@ -157,11 +491,56 @@ pub (crate) fn run_setup(cmd_args: &super::Cli, cfg: super::Cfg) -> Result<(Path
             .expect("Environment variable for LK_CUTOFF_DATE has an incorrect format. Program must abort. See .env.example."))
     } else { NaiveDate::parse_from_str(&"1-1-1", "%y-%m-%d").unwrap() };
 
+    let as_of_date = match &cmd_args.as_of_date {
+        Some(date_str) => {
+            println!("    Command line flag for AS_OF_DATE was set: {}", date_str);
+            let date = NaiveDate::parse_from_str(date_str, "%y-%m-%d")
+                .or_else(|_| NaiveDate::parse_from_str(date_str, "%Y-%m-%d"))
+                .map_err(|_| "Command line flag for AS_OF_DATE has an incorrect format. Use %y-%m-%d (or %Y-%m-%d).")?;
+            Some(date)},
+        None => None,
+    };
+
+    let report_period = match &cmd_args.tax_year {
+        Some(val) => {
+            println!("    Command line flag for TAX_YEAR was set: {}", val);
+            Some(parse_tax_year(val)?)},
+        None => None,
+    };
+
+    let valuation_date = match &cmd_args.valuation_date {
+        Some(date_str) => {
+            println!("    Command line flag for VALUATION_DATE was set: {}", date_str);
+            let date = NaiveDate::parse_from_str(date_str, "%y-%m-%d")
+                .or_else(|_| NaiveDate::parse_from_str(date_str, "%Y-%m-%d"))
+                .map_err(|_| "Command line flag for VALUATION_DATE has an incorrect format. Use %y-%m-%d (or %Y-%m-%d).")?;
+            Some(date)},
+        None => None,
+    };
+
     let settings = ImportProcessParameters {
         input_file_uses_iso_date_style: cfg.iso_date,
         input_file_date_separator: date_separator.to_string(),
         home_currency: cfg.home_currency.to_uppercase(),
+        home_currency_minor_units: cfg.home_currency_minor_units,
+        ticker_precision: cfg.ticker_precision,
         costing_method: costing_method_choice,
+        costing_method_schedule: cfg.inv_costing_method_schedule,
+        costing_method_overrides: cfg.inv_costing_method_overrides,
+        lot_relief_scope: cfg.lot_relief_scope,
+        basis_reallocation_date: cfg.basis_reallocation_date,
+        basis_allocation_file: cfg.basis_allocation_file,
+        lot_selection_file: cfg.lot_selection_file,
+        average_cost_pool: cfg.avg_cost_pooling,
+        uk_share_pooling: cfg.uk_share_pooling,
+        holding_period_rule: cfg.holding_period_rule,
+        as_of_date,
+        report_period,
+        price_file: cfg.price_file,
+        valuation_date,
+        wash_sale_rule: cfg.wash_sale_rule,
+        realize_margin_pnl: cfg.realize_margin_pnl,
+        fee_treatment: cfg.fee_treatment,
         lk_treatment_enabled: like_kind_election,
         lk_cutoff_date: like_kind_cutoff_date,
         lk_basis_date_preserved: true,  //  TODO